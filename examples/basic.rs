@@ -1,6 +1,5 @@
 use anyhow::Result;
 use reverse_ssh::{ReverseSshClient, ReverseSshConfig};
-use tracing_subscriber;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -25,6 +24,8 @@ async fn main() -> Result<()> {
         remote_port: 8080,
         local_addr: "127.0.0.1".to_string(),
         local_port: 3000,
+
+        ..Default::default()
     };
 
     // Create and run the reverse SSH client
@@ -1,35 +1,34 @@
+//! Example: Expose a local web server to the internet using localhost.run
+//!
+//! localhost.run is a free SSH tunneling service that allows you to expose
+//! local services to the internet without any registration or configuration.
+//!
+//! Usage:
+//! 1. Start a local web server on port 8080 (e.g., `python3 -m http.server 8080`)
+//! 2. Run this example: `cargo run --example localhost_run [OPTIONS]`
+//! 3. Access your service via the URL provided by localhost.run
+//!
+//! Options:
+//!   --key, -k <path>     Path to SSH private key (default: ~/.ssh/id_rsa)
+//!   --port, -p <port>    Local port to forward (default: 8080)
+//!   --help, -h           Show this help message
+//!
+//! Environment Variables (see `ReverseSshConfig::from_env`):
+//!   RRP_KEY              Path to SSH private key
+//!   RRP_LOCAL_PORT       Local port to forward
+//!
+//! Examples:
+//!   cargo run --example localhost_run
+//!   cargo run --example localhost_run --key ~/.ssh/my_key
+//!   cargo run --example localhost_run --port 3000
+//!   RRP_KEY=~/.ssh/my_key cargo run --example localhost_run
+//!
+//! Note: This example will automatically generate an SSH keypair if one doesn't exist.
+
 use anyhow::{Context, Result};
-use reverse_ssh::{ReverseSshClient, ReverseSshConfig};
+use reverse_ssh::{ReverseSshClient, ReverseSshConfig, UrlDetector};
 use std::io::{self, Write};
 use std::path::Path;
-use tracing_subscriber;
-
-/// Example: Expose a local web server to the internet using localhost.run
-///
-/// localhost.run is a free SSH tunneling service that allows you to expose
-/// local services to the internet without any registration or configuration.
-///
-/// Usage:
-/// 1. Start a local web server on port 8080 (e.g., `python3 -m http.server 8080`)
-/// 2. Run this example: `cargo run --example localhost_run [OPTIONS]`
-/// 3. Access your service via the URL provided by localhost.run
-///
-/// Options:
-///   --key, -k <path>     Path to SSH private key (default: ~/.ssh/id_rsa)
-///   --port, -p <port>    Local port to forward (default: 8080)
-///   --help, -h           Show this help message
-///
-/// Environment Variables:
-///   SSH_KEY              Path to SSH private key
-///   LOCAL_PORT           Local port to forward
-///
-/// Examples:
-///   cargo run --example localhost_run
-///   cargo run --example localhost_run --key ~/.ssh/my_key
-///   cargo run --example localhost_run --port 3000
-///   SSH_KEY=~/.ssh/my_key cargo run --example localhost_run
-///
-/// Note: This example will automatically generate an SSH keypair if one doesn't exist.
 
 struct Config {
     key_path: String,
@@ -60,30 +59,27 @@ fn parse_args() -> Result<Config> {
         println!("  --help, -h           Show this help message");
         println!();
         println!("Environment Variables:");
-        println!("  SSH_KEY              Path to SSH private key");
-        println!("  LOCAL_PORT           Local port to forward");
+        println!("  RRP_KEY              Path to SSH private key");
+        println!("  RRP_LOCAL_PORT       Local port to forward");
         println!();
         println!("Examples:");
         println!("  {} --key ~/.ssh/my_key", args[0]);
         println!("  {} --port 3000", args[0]);
-        println!("  SSH_KEY=~/.ssh/my_key {}", args[0]);
+        println!("  RRP_KEY=~/.ssh/my_key {}", args[0]);
         std::process::exit(0);
     }
 
-    // Default values
-    let home = std::env::var("HOME")
-        .context("HOME environment variable not set")?;
-    let mut key_path = format!("{}/.ssh/id_rsa", home);
-    let mut local_port: u16 = 8080;
-
-    // Check environment variables first
-    if let Ok(env_key) = std::env::var("SSH_KEY") {
-        key_path = expand_tilde(&env_key);
-    }
-    if let Ok(env_port) = std::env::var("LOCAL_PORT") {
-        local_port = env_port.parse()
-            .context("Invalid LOCAL_PORT environment variable")?;
-    }
+    // Environment variables first, then defaults
+    let env = ReverseSshConfig::from_env()?;
+    let mut key_path = match env.key_path {
+        Some(key_path) => key_path,
+        None => {
+            let home = std::env::var("HOME")
+                .context("HOME environment variable not set")?;
+            format!("{}/.ssh/id_rsa", home)
+        }
+    };
+    let mut local_port = env.local_port;
 
     // Parse command-line arguments (override env vars)
     let mut i = 1;
@@ -217,6 +213,8 @@ async fn main() -> Result<()> {
         remote_port: 80,
         local_addr: "127.0.0.1".to_string(),
         local_port: args_config.local_port,
+
+        ..Default::default()
     };
 
     println!("📡 Connecting to localhost.run...");
@@ -242,6 +240,7 @@ async fn main() -> Result<()> {
     let url_displayed_clone = url_displayed.clone();
     let start_time = std::time::Instant::now();
     let local_port = args_config.local_port;
+    let mut detector = UrlDetector::with_domains(["localhost.run", "lhr.rocks", "lhr.life"]);
 
     // Spawn a task to show a fallback message if URL isn't detected
     let url_displayed_timeout = url_displayed.clone();
@@ -287,42 +286,25 @@ async fn main() -> Result<()> {
             }
         }
 
-        // Try to extract URLs from the message - be aggressive about finding them
-        // Look for http/https URLs in the message
-        let message_lower = message.to_lowercase();
-        if message_lower.contains("http://") || message_lower.contains("https://") {
-            // Try to find the URL in the original (non-lowercased) message
-            let start_pos = message.find("http://").or_else(|| message.find("https://"));
-
-            if let Some(start) = start_pos {
-                // Find the end of the URL (whitespace, newline, or end of string)
-                let remaining = &message[start..];
-                let end = remaining
-                    .find(|c: char| c.is_whitespace() || c == '\n' || c == '\r' || c == ',' || c == ';' || c == ')' || c == ']')
-                    .unwrap_or(remaining.len());
-
-                let url = &remaining[..end].trim();
-
-                // Check if it's a localhost.run or related URL
-                if url.contains("localhost.run") || url.contains("lhr.rocks") || url.contains("lhr.life") {
-                    if !url_displayed_clone.swap(true, std::sync::atomic::Ordering::SeqCst) {
-                        let elapsed = start_time.elapsed().as_secs();
-                        println!();
-                        println!("╔══════════════════════════════════════════════════════╗");
-                        println!("║              🌐 TUNNEL ACTIVE 🌐                     ║");
-                        println!("╠══════════════════════════════════════════════════════╣");
-                        println!("║  Your local service is now accessible at:            ║");
-                        println!("║                                                      ║");
-                        println!("║  {:<52} ║", url);
-                        println!("║                                                      ║");
-                        println!("║  Local: http://127.0.0.1:{:<31} ║", local_port);
-                        println!("║  Connected in: {}s{:<37}║", elapsed, "");
-                        println!("╚══════════════════════════════════════════════════════╝");
-                        println!();
-                        println!("✨ Ready to accept connections!");
-                        println!();
-                    }
-                }
+        // Pick the tunnel URL out of the messages, even when split across packets
+        if let Some(url) = detector.push(message.as_bytes()) {
+            let url = url.preferred().unwrap_or_default();
+            if !url_displayed_clone.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                let elapsed = start_time.elapsed().as_secs();
+                println!();
+                println!("╔══════════════════════════════════════════════════════╗");
+                println!("║              🌐 TUNNEL ACTIVE 🌐                     ║");
+                println!("╠══════════════════════════════════════════════════════╣");
+                println!("║  Your local service is now accessible at:            ║");
+                println!("║                                                      ║");
+                println!("║  {:<52} ║", url);
+                println!("║                                                      ║");
+                println!("║  Local: http://127.0.0.1:{:<31} ║", local_port);
+                println!("║  Connected in: {}s{:<37}║", elapsed, "");
+                println!("╚══════════════════════════════════════════════════════╝");
+                println!();
+                println!("✨ Ready to accept connections!");
+                println!();
             }
         }
     }).await?;
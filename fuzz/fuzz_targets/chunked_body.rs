@@ -0,0 +1,24 @@
+//! Chunked bodies arrive from anyone who can reach the public URL, split across reads
+//! anywhere
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reverse_ssh::unstable::decode_chunked;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks how many bytes each read returns
+    let Some((&read_size, body)) = data.split_first() else {
+        return;
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let whole = runtime.block_on(decode_chunked(body, 4096));
+    let split = runtime.block_on(decode_chunked(body, read_size.max(1) as usize));
+    // Where reads end mustn't change what the body decodes to
+    if let (Ok(whole), Ok(split)) = (&whole, &split) {
+        assert_eq!(whole, split);
+    }
+    assert_eq!(whole.is_ok(), split.is_ok());
+});
@@ -0,0 +1,19 @@
+//! Request and response heads arrive from anyone who can reach the public URL
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reverse_ssh::unstable::{RequestHead, ResponseHead};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut request) = RequestHead::parse(data) {
+        let _ = request.header("host");
+        let _ = request.is_upgrade();
+        request.set_header("X-Forwarded-For", "203.0.113.7");
+        request.remove_header("connection");
+    }
+    if let Ok(mut response) = ResponseHead::parse(data) {
+        let _ = response.header("content-length");
+        response.set_header("Connection", "close");
+    }
+});
@@ -0,0 +1,25 @@
+//! Server output is reassembled into lines, stripped of terminal escapes and scanned
+//! for the tunnel URL, from chunks split anywhere
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use reverse_ssh::UrlDetector;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks where the rest is split in two chunks
+    let Some((&split, output)) = data.split_first() else {
+        return;
+    };
+    let split = (split as usize).min(output.len());
+    let (first, second) = output.split_at(split);
+    for mut detector in [
+        UrlDetector::new(),
+        UrlDetector::with_domains(["lhr.life", "*.pinggy.link"]),
+    ] {
+        detector.push(first);
+        if let Some(url) = detector.push(second) {
+            assert!(url.preferred().is_some());
+        }
+    }
+});
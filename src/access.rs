@@ -0,0 +1,231 @@
+//! Which originators may reach the tunnel
+//!
+//! A dev tunnel on a public server is reachable by the whole internet. An
+//! [`AccessList`] restricts it to the networks on its allowlist, or keeps out those
+//! on its denylist: the originator address the server reports for each forwarded
+//! channel is checked before the channel is relayed, and a channel that doesn't pass
+//! is rejected like a connection a [`wire_gate`](crate::ReverseSshConfig::wire_gate)
+//! turns away.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+/// An IP network, such as `10.0.0.0/8` or `2001:db8::/32`; a single address when
+/// written without a prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// The network of the addresses whose first `prefix` bits are those of `addr`
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > bits {
+            bail!("Prefix length {} is too long for {}", prefix, addr);
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    /// Whether `ip` is in the network. IPv4 addresses mapped into IPv6, as dual-stack
+    /// servers report them, count as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let host_bits = u32::from(bits - prefix);
+    (net ^ ip).checked_shr(host_bits).unwrap_or(0) == 0
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid network {}", text))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid prefix length in {}", text))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Networks forwarded connections may come from. The denylist wins; a non-empty
+/// allowlist admits only the originators on it. Both empty, the default, admit
+/// everyone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Admit only originators in `networks`, e.g. `["203.0.113.0/24", "2001:db8::/32"]`
+    pub fn allow<S: AsRef<str>>(networks: &[S]) -> Result<Self> {
+        Ok(Self {
+            allow: parse_list(networks)?,
+            deny: Vec::new(),
+        })
+    }
+
+    /// Admit everyone but the originators in `networks`
+    pub fn deny<S: AsRef<str>>(networks: &[S]) -> Result<Self> {
+        Ok(Self {
+            allow: Vec::new(),
+            deny: parse_list(networks)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Why a connection from `originator`, as the server reports it, is refused;
+    /// `None` if it is admitted
+    pub(crate) fn refuses(&self, originator: &str) -> Option<&'static str> {
+        if self.is_empty() {
+            return None;
+        }
+        // Some servers put IPv6 addresses in brackets
+        let ip = originator
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .ok();
+        match ip {
+            Some(ip) if self.deny.iter().any(|net| net.contains(ip)) => Some("on the denylist"),
+            Some(ip) if self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)) => {
+                None
+            }
+            Some(_) => Some("not on the allowlist"),
+            // Nothing to check against the allowlist
+            None if self.allow.is_empty() => None,
+            None => Some("originator address unknown"),
+        }
+    }
+}
+
+/// Parse networks given one by one or comma-separated
+pub(crate) fn parse_list<S: AsRef<str>>(networks: &[S]) -> Result<Vec<IpNet>> {
+    networks
+        .iter()
+        .flat_map(|networks| networks.as_ref().split(','))
+        .filter(|network| !network.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim, ReverseSshClient, ReverseSshConfig, SecurityEventKind, TunnelEvent, TunnelState,
+    };
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_originators_off_the_allowlist_are_rejected() {
+        let list = AccessList {
+            allow: parse_list(&["203.0.113.0/24, 2001:db8::/32"]).unwrap(),
+            deny: parse_list(&["203.0.113.66"]).unwrap(),
+        };
+        assert_eq!(list.refuses("203.0.113.7"), None);
+        assert_eq!(list.refuses("::ffff:203.0.113.7"), None);
+        assert_eq!(list.refuses("[2001:db8::1]"), None);
+        assert_eq!(list.refuses("203.0.113.66"), Some("on the denylist"));
+        assert_eq!(list.refuses("198.51.100.1"), Some("not on the allowlist"));
+        assert_eq!(list.refuses("sim"), Some("originator address unknown"));
+        assert_eq!(AccessList::default().refuses("198.51.100.1"), None);
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("0.0.0.0/0"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut service = network.listen("127.0.0.1", 8080).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        let config = ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 8000,
+            access_list: list,
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        };
+        let mut client = ReverseSshClient::new(config);
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        let mut security = handle.subscribe_security();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::StateChanged {
+                to: TunnelState::Ready,
+                ..
+            })
+        ) {}
+
+        let mut peer = network
+            .dial_from("203.0.113.7", "ssh.sim", 8000)
+            .await
+            .unwrap();
+        peer.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        peer.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+
+        let mut peer = network
+            .dial_from("198.51.100.1", "ssh.sim", 8000)
+            .await
+            .unwrap();
+        let _ = peer.write_all(b"ping").await;
+        assert_eq!(peer.read(&mut reply).await.unwrap_or(0), 0);
+        let event = security.recv().await.unwrap();
+        assert!(matches!(
+            event.kind,
+            SecurityEventKind::ConnectionRejected { reason, .. } if reason == "not on the allowlist"
+        ));
+        assert_eq!(handle.metrics().counter("connections_denied_total"), 1);
+        assert_eq!(handle.stats().total_connections, 1);
+    }
+}
@@ -0,0 +1,456 @@
+//! Alerting on threshold breaches
+//!
+//! While connections are being handled, a monitor task periodically evaluates the
+//! configured [`AlertRule`]s. A rule fires once when its condition starts to hold and
+//! is re-armed when the condition clears, so a persistent problem doesn't page twice.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+use crate::stats::OriginatorLog;
+use crate::{targets, Clock};
+
+/// How long connect attempts are remembered
+const CONNECT_RETENTION: Duration = Duration::from_secs(3600);
+
+/// A condition worth alerting on
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlertCondition {
+    /// More than `max_connects` calls to `connect()` within `window`
+    ReconnectStorm {
+        max_connects: usize,
+        window: Duration,
+    },
+    /// The share of forwarded connections ending in an error exceeds `max_rate`
+    /// (0.0 to 1.0), once at least `min_connections` finished within `window`
+    ErrorRate {
+        max_rate: f64,
+        min_connections: u64,
+        window: Duration,
+    },
+    /// Traffic in both directions averages more than `max_bytes_per_sec` over `window`
+    Bandwidth {
+        max_bytes_per_sec: u64,
+        window: Duration,
+    },
+    /// No bytes moved through any forward for `idle`
+    NoTraffic { idle: Duration },
+}
+
+/// What to do when an alert fires
+#[derive(Clone)]
+pub enum AlertAction {
+    /// Call a function with the alert
+    Callback(Arc<dyn Fn(&Alert) + Send + Sync>),
+    /// Run a command with `sh -c` (`cmd /C` on Windows), passing the alert in the
+    /// `ALERT_NAME` and `ALERT_MESSAGE` environment variables. Webhooks can be called
+    /// with `curl`.
+    Command(String),
+}
+
+impl fmt::Debug for AlertAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertAction::Callback(_) => f.write_str("Callback"),
+            AlertAction::Command(command) => f.debug_tuple("Command").field(command).finish(),
+        }
+    }
+}
+
+/// A named condition and the action taken when it fires
+#[derive(Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+    pub condition: AlertCondition,
+    pub action: AlertAction,
+}
+
+impl AlertRule {
+    pub fn new(name: impl Into<String>, condition: AlertCondition, action: AlertAction) -> Self {
+        Self {
+            name: name.into(),
+            condition,
+            action,
+        }
+    }
+}
+
+/// A fired alert
+#[derive(Debug, Clone)]
+pub struct Alert {
+    /// Name of the rule that fired
+    pub name: String,
+    /// Human-readable description of the breach
+    pub message: String,
+}
+
+/// Timestamps of recent `connect()` calls
+#[derive(Debug)]
+pub(crate) struct ConnectLog {
+    attempts: Mutex<VecDeque<Instant>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ConnectLog {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            attempts: Mutex::default(),
+            clock,
+        }
+    }
+
+    pub(crate) fn record(&self) {
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().unwrap();
+        while attempts
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > CONNECT_RETENTION)
+        {
+            attempts.pop_front();
+        }
+        attempts.push_back(now);
+    }
+
+    fn count(&self, window: Duration) -> usize {
+        let now = self.clock.now();
+        let attempts = self.attempts.lock().unwrap();
+        attempts
+            .iter()
+            .rev()
+            .take_while(|t| now.duration_since(**t) <= window)
+            .count()
+    }
+}
+
+/// Sources the monitor samples
+pub(crate) struct AlertInputs<'a> {
+    pub(crate) connects: &'a ConnectLog,
+    pub(crate) originators: &'a OriginatorLog,
+    /// Bytes moved through all forwards so far
+    pub(crate) traffic: &'a AtomicU64,
+}
+
+/// Evaluates alert rules against sampled traffic
+pub(crate) struct AlertMonitor {
+    rules: Vec<AlertRule>,
+    firing: Vec<bool>,
+    /// Total traffic over time, for bandwidth averages
+    samples: VecDeque<(Instant, u64)>,
+    last_traffic: Instant,
+    clock: Arc<dyn Clock>,
+}
+
+impl AlertMonitor {
+    pub(crate) fn new(rules: Vec<AlertRule>, clock: Arc<dyn Clock>) -> Self {
+        let firing = vec![false; rules.len()];
+        Self {
+            rules,
+            firing,
+            samples: VecDeque::new(),
+            last_traffic: clock.now(),
+            clock,
+        }
+    }
+
+    /// Check every rule, run the actions of those that started firing, and
+    /// return their alerts
+    pub(crate) fn check(&mut self, inputs: &AlertInputs<'_>) -> Vec<Alert> {
+        let now = self.clock.now();
+        let total = inputs.traffic.load(Ordering::Relaxed);
+        if self.samples.back().is_some_and(|(_, last)| *last != total) {
+            self.last_traffic = now;
+        }
+        self.samples.push_back((now, total));
+        let longest = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule.condition {
+                AlertCondition::Bandwidth { window, .. } => Some(window),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+        while self.samples.len() > 1
+            && self
+                .samples
+                .get(1)
+                .is_some_and(|(t, _)| now.duration_since(*t) >= longest)
+        {
+            self.samples.pop_front();
+        }
+
+        let mut fired = Vec::new();
+        for i in 0..self.rules.len() {
+            let breach = self.evaluate(&self.rules[i].condition, inputs, now, total);
+            match breach {
+                Some(message) if !self.firing[i] => {
+                    self.firing[i] = true;
+                    let alert = Alert {
+                        name: self.rules[i].name.clone(),
+                        message,
+                    };
+                    warn!(target: targets::HEALTH, "Alert {}: {}", alert.name, alert.message);
+                    trigger(&self.rules[i].action, &alert);
+                    fired.push(alert);
+                }
+                Some(_) => {}
+                None => self.firing[i] = false,
+            }
+        }
+        fired
+    }
+
+    /// Describe the breach if `condition` currently holds
+    fn evaluate(
+        &self,
+        condition: &AlertCondition,
+        inputs: &AlertInputs<'_>,
+        now: Instant,
+        total: u64,
+    ) -> Option<String> {
+        match *condition {
+            AlertCondition::ReconnectStorm {
+                max_connects,
+                window,
+            } => {
+                let connects = inputs.connects.count(window);
+                (connects > max_connects)
+                    .then(|| format!("{} connection attempts in {:?}", connects, window))
+            }
+            AlertCondition::ErrorRate {
+                max_rate,
+                min_connections,
+                window,
+            } => {
+                let (connections, errors) = inputs
+                    .originators
+                    .stats(window)
+                    .iter()
+                    .fold((0, 0), |(c, e), s| (c + s.connections, e + s.errors));
+                let rate = errors as f64 / connections.max(1) as f64;
+                (connections >= min_connections && rate > max_rate).then(|| {
+                    format!(
+                        "{} of {} connections failed in {:?}",
+                        errors, connections, window
+                    )
+                })
+            }
+            AlertCondition::Bandwidth {
+                max_bytes_per_sec,
+                window,
+            } => {
+                let (start, start_total) = self
+                    .samples
+                    .iter()
+                    .find(|(t, _)| now.duration_since(*t) <= window)
+                    .copied()?;
+                let elapsed = now.duration_since(start).as_secs_f64();
+                if elapsed <= 0.0 {
+                    return None;
+                }
+                let rate = (total - start_total) as f64 / elapsed;
+                (rate > max_bytes_per_sec as f64).then(|| {
+                    format!(
+                        "{:.0} bytes/s over {:?} exceeds {} bytes/s",
+                        rate, window, max_bytes_per_sec
+                    )
+                })
+            }
+            AlertCondition::NoTraffic { idle } => {
+                let quiet = now.duration_since(self.last_traffic);
+                (quiet >= idle).then(|| format!("No traffic for {:?}", quiet))
+            }
+        }
+    }
+}
+
+fn trigger(action: &AlertAction, alert: &Alert) {
+    match action {
+        AlertAction::Callback(callback) => callback(alert),
+        AlertAction::Command(command) => {
+            let (shell, flag) = if cfg!(windows) {
+                ("cmd", "/C")
+            } else {
+                ("sh", "-c")
+            };
+            let spawned = tokio::process::Command::new(shell)
+                .arg(flag)
+                .arg(command)
+                .env("ALERT_NAME", &alert.name)
+                .env("ALERT_MESSAGE", &alert.message)
+                .spawn();
+            match spawned {
+                Ok(mut child) => {
+                    tokio::spawn(async move {
+                        let _ = child.wait().await;
+                    });
+                }
+                Err(e) => {
+                    error!(target: targets::HEALTH, "Failed to run alert command for {}: {}", alert.name, e)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::TrafficCounters;
+    use crate::ManualClock;
+
+    /// Names of the alerts a callback action was called with
+    fn recorder() -> (AlertAction, Arc<Mutex<Vec<String>>>) {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let log = fired.clone();
+        let callback = AlertAction::Callback(Arc::new(move |alert: &Alert| {
+            log.lock().unwrap().push(alert.name.clone());
+        }));
+        (callback, fired)
+    }
+
+    #[tokio::test]
+    async fn test_rules_fire_once_until_cleared() {
+        let clock = Arc::new(ManualClock::new());
+        let (callback, fired) = recorder();
+        let mut monitor = AlertMonitor::new(
+            vec![
+                AlertRule::new(
+                    "errors",
+                    AlertCondition::ErrorRate {
+                        max_rate: 0.5,
+                        min_connections: 2,
+                        window: Duration::from_secs(60),
+                    },
+                    callback.clone(),
+                ),
+                AlertRule::new(
+                    "storm",
+                    AlertCondition::ReconnectStorm {
+                        max_connects: 2,
+                        window: Duration::from_secs(60),
+                    },
+                    callback,
+                ),
+            ],
+            clock.clone(),
+        );
+
+        let connects = ConnectLog::new(clock.clone());
+        let originators = OriginatorLog::new(clock.clone());
+        let traffic = AtomicU64::new(0);
+        let inputs = AlertInputs {
+            connects: &connects,
+            originators: &originators,
+            traffic: &traffic,
+        };
+
+        originators.record("203.0.113.7", &TrafficCounters::default(), true);
+        assert!(monitor.check(&inputs).is_empty());
+        originators.record("203.0.113.7", &TrafficCounters::default(), true);
+        for _ in 0..3 {
+            connects.record();
+        }
+        assert_eq!(monitor.check(&inputs).len(), 2);
+        assert!(monitor.check(&inputs).is_empty());
+        assert_eq!(*fired.lock().unwrap(), vec!["errors", "storm"]);
+
+        // The attempts age out of the storm's window
+        clock.advance(Duration::from_secs(61));
+        connects.record();
+        monitor.check(&inputs);
+        for _ in 0..2 {
+            connects.record();
+        }
+        assert_eq!(monitor.check(&inputs)[0].name, "storm");
+    }
+
+    #[test]
+    fn test_bandwidth_alert_fires_above_the_rate() {
+        let clock = Arc::new(ManualClock::new());
+        let (callback, fired) = recorder();
+        let mut monitor = AlertMonitor::new(
+            vec![AlertRule::new(
+                "bandwidth",
+                AlertCondition::Bandwidth {
+                    max_bytes_per_sec: 1000,
+                    window: Duration::from_secs(10),
+                },
+                callback,
+            )],
+            clock.clone(),
+        );
+        let connects = ConnectLog::new(clock.clone());
+        let originators = OriginatorLog::new(clock.clone());
+        let traffic = AtomicU64::new(0);
+        let inputs = AlertInputs {
+            connects: &connects,
+            originators: &originators,
+            traffic: &traffic,
+        };
+
+        assert!(monitor.check(&inputs).is_empty());
+        clock.advance(Duration::from_secs(5));
+        traffic.store(4_000, Ordering::Relaxed);
+        assert!(monitor.check(&inputs).is_empty());
+        clock.advance(Duration::from_secs(5));
+        traffic.store(20_000, Ordering::Relaxed);
+        let alerts = monitor.check(&inputs);
+        assert_eq!(
+            alerts[0].message,
+            "2000 bytes/s over 10s exceeds 1000 bytes/s"
+        );
+
+        // Once the burst leaves the window the rule re-arms
+        clock.advance(Duration::from_secs(10));
+        assert!(monitor.check(&inputs).is_empty());
+        clock.advance(Duration::from_secs(10));
+        traffic.store(50_000, Ordering::Relaxed);
+        assert_eq!(monitor.check(&inputs).len(), 1);
+        assert_eq!(*fired.lock().unwrap(), vec!["bandwidth", "bandwidth"]);
+    }
+
+    #[test]
+    fn test_no_traffic_alert_rearms_when_traffic_resumes() {
+        let clock = Arc::new(ManualClock::new());
+        let (callback, fired) = recorder();
+        let mut monitor = AlertMonitor::new(
+            vec![AlertRule::new(
+                "quiet",
+                AlertCondition::NoTraffic {
+                    idle: Duration::from_secs(60),
+                },
+                callback,
+            )],
+            clock.clone(),
+        );
+        let connects = ConnectLog::new(clock.clone());
+        let originators = OriginatorLog::new(clock.clone());
+        let traffic = AtomicU64::new(0);
+        let inputs = AlertInputs {
+            connects: &connects,
+            originators: &originators,
+            traffic: &traffic,
+        };
+
+        assert!(monitor.check(&inputs).is_empty());
+        clock.advance(Duration::from_secs(59));
+        assert!(monitor.check(&inputs).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(monitor.check(&inputs)[0].message, "No traffic for 60s");
+        clock.advance(Duration::from_secs(30));
+        assert!(monitor.check(&inputs).is_empty());
+
+        traffic.store(100, Ordering::Relaxed);
+        clock.advance(Duration::from_secs(1));
+        assert!(monitor.check(&inputs).is_empty());
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(monitor.check(&inputs).len(), 1);
+        assert_eq!(*fired.lock().unwrap(), vec!["quiet", "quiet"]);
+    }
+}
@@ -0,0 +1,210 @@
+//! The SSH algorithms the client offers
+//!
+//! By default the client offers russh's choice of key exchanges, host key
+//! algorithms, ciphers and MACs. [`Algorithms`] replaces any of these lists, most
+//! preferred first, to hold sessions to modern algorithms or to reach an old server
+//! that only speaks legacy ones. Names are those of the protocol, e.g.
+//! `curve25519-sha256` or `aes256-gcm@openssh.com`; one russh doesn't implement
+//! fails `connect()`.
+
+use anyhow::{bail, Result};
+use russh::{cipher, kex, mac, Preferred};
+use russh_keys::key;
+
+/// SSH algorithms offered to the server, most preferred first. An empty list keeps
+/// russh's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Algorithms {
+    /// Key exchange algorithms, e.g. `curve25519-sha256`
+    pub kex: Vec<String>,
+    /// Host key algorithms accepted from the server, e.g. `ssh-ed25519`
+    pub host_key: Vec<String>,
+    /// Ciphers, e.g. `chacha20-poly1305@openssh.com`
+    pub cipher: Vec<String>,
+    /// MACs, e.g. `hmac-sha2-256-etm@openssh.com`; AEAD ciphers don't use one
+    pub mac: Vec<String>,
+}
+
+impl Algorithms {
+    /// Only current algorithms: curve25519 key exchange, AEAD ciphers and
+    /// encrypt-then-MAC SHA-2 MACs. Servers older than OpenSSH 6.5 can't connect.
+    pub fn modern() -> Self {
+        Self {
+            kex: names(&["curve25519-sha256", "curve25519-sha256@libssh.org"]),
+            host_key: names(&[
+                "ssh-ed25519",
+                "ecdsa-sha2-nistp256",
+                "ecdsa-sha2-nistp384",
+                "ecdsa-sha2-nistp521",
+                "rsa-sha2-512",
+                "rsa-sha2-256",
+            ]),
+            cipher: names(&["chacha20-poly1305@openssh.com", "aes256-gcm@openssh.com"]),
+            mac: names(&[
+                "hmac-sha2-512-etm@openssh.com",
+                "hmac-sha2-256-etm@openssh.com",
+            ]),
+        }
+    }
+
+    /// russh's defaults, then the legacy algorithms old servers and appliances may
+    /// only speak: SHA-1 and NIST key exchanges, `ssh-rsa` host keys and CBC ciphers
+    pub fn legacy() -> Self {
+        let defaults = Preferred::default();
+        let with = |defaults: Vec<String>, legacy: &[&str]| {
+            defaults.into_iter().chain(names(legacy)).collect()
+        };
+        Self {
+            kex: with(
+                offered(&defaults.kex),
+                &[
+                    "ecdh-sha2-nistp256",
+                    "ecdh-sha2-nistp384",
+                    "ecdh-sha2-nistp521",
+                    "diffie-hellman-group14-sha1",
+                    "diffie-hellman-group1-sha1",
+                ],
+            ),
+            host_key: with(offered(&defaults.key), &["ssh-rsa"]),
+            cipher: with(
+                offered(&defaults.cipher),
+                &["aes256-cbc", "aes192-cbc", "aes128-cbc", "3des-cbc"],
+            ),
+            mac: offered(&defaults.mac),
+        }
+    }
+
+    /// The lists to hand to russh
+    pub(crate) fn preferred(&self) -> Result<Preferred> {
+        let mut preferred = Preferred::default();
+        if let Some(mut names) = parse(&self.kex, "key exchange", |name| {
+            kex::Name::try_from(name).ok()
+        })? {
+            // Not algorithms but markers of extensions, strict key exchange among
+            // them, which russh offers along with its defaults
+            names.push(kex::EXTENSION_SUPPORT_AS_CLIENT);
+            names.push(kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT);
+            preferred.kex = names.into();
+        }
+        if let Some(names) = parse(&self.host_key, "host key algorithm", key_name)? {
+            preferred.key = names.into();
+        }
+        if let Some(names) = parse(&self.cipher, "cipher", |name| {
+            cipher::Name::try_from(name).ok()
+        })? {
+            preferred.cipher = names.into();
+        }
+        if let Some(names) = parse(&self.mac, "MAC", |name| mac::Name::try_from(name).ok())? {
+            preferred.mac = names.into();
+        }
+        Ok(preferred)
+    }
+}
+
+fn key_name(name: &str) -> Option<key::Name> {
+    // russh-keys leaves ssh-ed25519 out of its list of key types
+    std::iter::once(&key::ED25519)
+        .chain(key::ALL_KEY_TYPES.iter().copied())
+        .find(|known| known.0 == name)
+        .copied()
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/// The names of russh's list, without the extension markers
+fn offered<N: AsRef<str>>(names: &[N]) -> Vec<String> {
+    names
+        .iter()
+        .map(|name| name.as_ref().to_string())
+        .filter(|name| !name.starts_with("ext-info-") && !name.starts_with("kex-strict-"))
+        .collect()
+}
+
+fn parse<N>(
+    names: &[String],
+    what: &str,
+    known: impl Fn(&str) -> Option<N>,
+) -> Result<Option<Vec<N>>> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+    names
+        .iter()
+        .map(|name| match known(name) {
+            // Without encryption or integrity, a session isn't worth having
+            Some(_) if name == "none" || name == "clear" => {
+                bail!("Refusing to offer {} {}", what, name)
+            }
+            Some(known) => Ok(known),
+            None => bail!("Unsupported {} {}", what, name),
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sim, ReverseSshClient, ReverseSshConfig};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_algorithms_restrict_the_offer() {
+        let modern = Algorithms::modern().preferred().unwrap();
+        assert_eq!(modern.cipher[0], cipher::CHACHA20_POLY1305);
+        assert!(modern
+            .kex
+            .contains(&kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT));
+        let legacy = Algorithms::legacy().preferred().unwrap();
+        assert!(legacy.kex.contains(&kex::DH_G1_SHA1));
+        assert!(legacy.key.contains(&key::SSH_RSA));
+        let error = |algorithms: Algorithms| algorithms.preferred().unwrap_err().to_string();
+        assert_eq!(
+            error(Algorithms {
+                cipher: names(&["aes256-gcm@openssh.com", "blowfish-cbc"]),
+                ..Default::default()
+            }),
+            "Unsupported cipher blowfish-cbc"
+        );
+        assert_eq!(
+            error(Algorithms {
+                cipher: names(&["none"]),
+                ..Default::default()
+            }),
+            "Refusing to offer cipher none"
+        );
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let connect = |algorithms: Algorithms| {
+            let config = ReverseSshConfig {
+                server_addr: "ssh.sim".to_string(),
+                algorithms,
+                network: Arc::new(network.clone()),
+                ..Default::default()
+            };
+            async move {
+                let mut client = ReverseSshClient::new(config);
+                let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+                let (message_tx, _messages) = tokio::sync::mpsc::channel(16);
+                client.connect_session(tx, message_tx).await
+            }
+        };
+        connect(Algorithms {
+            cipher: names(&["aes256-ctr"]),
+            mac: names(&["hmac-sha2-256"]),
+            ..Algorithms::modern()
+        })
+        .await
+        .unwrap();
+        // The server doesn't speak SHA-1 key exchanges
+        assert!(connect(Algorithms {
+            kex: names(&["diffie-hellman-group1-sha1"]),
+            ..Default::default()
+        })
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//! Authentication methods, tried in order until the server accepts one
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+use russh::client::{Handle, Handler, KeyboardInteractiveAuthResponse};
+use russh_keys::agent::client::AgentClient;
+use tracing::{debug, info, warn};
+
+use crate::{cert, targets, PrivateKey};
+
+#[cfg(unix)]
+type Agent = AgentClient<tokio::net::UnixStream>;
+#[cfg(not(unix))]
+type Agent = AgentClient<tokio::net::TcpStream>;
+
+/// Keyboard-interactive rounds answered before giving up
+const MAX_INTERACTIVE_ROUNDS: usize = 5;
+
+/// A way of authenticating to the SSH server
+#[derive(Clone)]
+pub enum AuthMethod {
+    /// Keys held by the SSH agent listening on `SSH_AUTH_SOCK`
+    Agent,
+    /// A private key held in memory
+    Key(PrivateKey),
+    /// A private key file
+    KeyFile(String),
+    /// A private key and the OpenSSH certificate signed for it, e.g. by Vault or
+    /// step-ca; see [`CertificateRefresh`](crate::CertificateRefresh) for short-lived ones
+    Certificate {
+        key: PrivateKey,
+        certificate: String,
+    },
+    Password(String),
+    /// Keyboard-interactive, answering every prompt with the given response (e.g. a
+    /// password or one-time code)
+    KeyboardInteractive(String),
+    /// No credentials, for relays that accept anyone (e.g. localhost.run's `nokey` user)
+    None,
+}
+
+impl AuthMethod {
+    /// Short name used in logs and [`TunnelStatus::auth_method`](crate::TunnelStatus::auth_method)
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthMethod::Agent => "agent",
+            AuthMethod::Key(_) => "key",
+            AuthMethod::KeyFile(_) => "key-file",
+            AuthMethod::Certificate { .. } => "certificate",
+            AuthMethod::Password(_) => "password",
+            AuthMethod::KeyboardInteractive(_) => "keyboard-interactive",
+            AuthMethod::None => "none",
+        }
+    }
+
+    /// Try this method, returning whether the server accepted it
+    async fn try_with<H: Handler>(&self, session: &mut Handle<H>, user: &str) -> Result<bool> {
+        Ok(match self {
+            AuthMethod::Agent => authenticate_agent(session, user).await?,
+            AuthMethod::Key(key) => {
+                session
+                    .authenticate_publickey(user, key.key_pair()?)
+                    .await?
+            }
+            AuthMethod::KeyFile(path) => {
+                let key_pair = russh_keys::load_secret_key(path, None)
+                    .with_context(|| format!("Failed to load private key {}", path))?;
+                session
+                    .authenticate_publickey(user, key_pair.into())
+                    .await?
+            }
+            AuthMethod::Certificate { key, certificate } => {
+                session
+                    .authenticate_openssh_cert(user, key.key_pair()?, cert::parse(certificate)?)
+                    .await?
+            }
+            AuthMethod::Password(password) => session.authenticate_password(user, password).await?,
+            AuthMethod::KeyboardInteractive(response) => {
+                authenticate_interactive(session, user, response).await?
+            }
+            AuthMethod::None => session.authenticate_none(user).await?,
+        })
+    }
+}
+
+// Never print credentials
+impl fmt::Debug for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthMethod::Agent => f.write_str("Agent"),
+            AuthMethod::Key(key) => write!(f, "Key({:?})", key),
+            AuthMethod::KeyFile(path) => write!(f, "KeyFile({:?})", path),
+            AuthMethod::Certificate { key, .. } => write!(f, "Certificate({:?}, ..)", key),
+            AuthMethod::Password(_) => f.write_str("Password(..)"),
+            AuthMethod::KeyboardInteractive(_) => f.write_str("KeyboardInteractive(..)"),
+            AuthMethod::None => f.write_str("None"),
+        }
+    }
+}
+
+/// Try `methods` in order, returning the name of the one the server accepted
+pub(crate) async fn authenticate<H: Handler>(
+    session: &mut Handle<H>,
+    user: &str,
+    methods: &[AuthMethod],
+) -> Result<&'static str> {
+    let mut last_error = None;
+    for method in methods {
+        debug!(target: targets::AUTH, "Trying {} authentication", method.name());
+        match method.try_with(session, user).await {
+            Ok(true) => {
+                info!(target: targets::AUTH, "Authenticated with {}", method.name());
+                return Ok(method.name());
+            }
+            Ok(false) => {
+                debug!(target: targets::AUTH, "Server rejected {} authentication", method.name())
+            }
+            Err(e) => {
+                warn!(target: targets::AUTH, "{} authentication failed: {:#}", method.name(), e);
+                last_error = Some(e.context(format!("{} authentication failed", method.name())));
+            }
+        }
+    }
+    let tried: Vec<_> = methods.iter().map(AuthMethod::name).collect();
+    let rejected = anyhow::anyhow!(
+        "Authentication rejected by server (tried {})",
+        tried.join(", ")
+    );
+    Err(match last_error {
+        Some(e) => e.context(rejected),
+        None => rejected,
+    })
+}
+
+async fn authenticate_agent<H: Handler>(session: &mut Handle<H>, user: &str) -> Result<bool> {
+    let mut agent = Agent::connect_env()
+        .await
+        .context("Failed to connect to SSH agent")?;
+    let identities = agent
+        .request_identities()
+        .await
+        .context("Failed to list SSH agent keys")?;
+    for key in identities {
+        let (returned, result) = session.authenticate_future(user, key, agent).await;
+        agent = returned;
+        if result? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn authenticate_interactive<H: Handler>(
+    session: &mut Handle<H>,
+    user: &str,
+    response: &str,
+) -> Result<bool> {
+    let mut reply = session
+        .authenticate_keyboard_interactive_start(user, None)
+        .await?;
+    for _ in 0..MAX_INTERACTIVE_ROUNDS {
+        match reply {
+            KeyboardInteractiveAuthResponse::Success => return Ok(true),
+            KeyboardInteractiveAuthResponse::Failure => return Ok(false),
+            KeyboardInteractiveAuthResponse::InfoRequest { prompts, .. } => {
+                let responses = vec![response.to_string(); prompts.len()];
+                reply = session
+                    .authenticate_keyboard_interactive_respond(responses)
+                    .await?;
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_hides_credentials() {
+        let methods = vec![
+            AuthMethod::Agent,
+            AuthMethod::Password("hunter2".into()),
+            AuthMethod::KeyboardInteractive("123456".into()),
+            AuthMethod::Key(PrivateKey::Pem("-----BEGIN".into())),
+        ];
+        let debug = format!("{:?}", methods);
+        assert_eq!(
+            debug,
+            "[Agent, Password(..), KeyboardInteractive(..), Key(PrivateKey::Pem(..))]"
+        );
+    }
+}
@@ -0,0 +1,1008 @@
+//! `rrp`: run reverse SSH tunnels, or install them as a service
+//!
+//! ```text
+//! rrp http 8080
+//! rrp tcp 5432 --provider serveo
+//! rrp run --server localhost.run --user nokey --remote-port 80 --local-port 8080
+//! rrp up --config rrp.json
+//! rrp install-service --name web [--manager systemd] [--per-user] [--print] <run or up options>
+//! rrp udp-helper --listen 0.0.0.0:53 --tunnel 127.0.0.1:5353
+//! rrp --capabilities
+//! ```
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use reverse_ssh::{
+    AccessList, AuthMethod, CertificateRefresh, ClientHandle, ForwardConflict, HttpConfig,
+    JumpHost, PrivateKey, Profile, Provider, ProviderError, ProxyConfig, ReverseSshClient,
+    ReverseSshConfig, ServiceManager, ServiceSpec, TunnelEvent, TunnelManager, TunnelStatus,
+    UdpHelper,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+#[cfg(feature = "profiling")]
+#[global_allocator]
+static ALLOCATOR: reverse_ssh::CountingAllocator = reverse_ssh::CountingAllocator;
+
+/// Exit status when a tunnel failed
+const EXIT_FAILED: u8 = 1;
+/// Exit status for invalid options or configuration
+const EXIT_USAGE: u8 = 2;
+/// Exit status when the provider refused the tunnel
+const EXIT_REFUSED: u8 = 3;
+
+const USAGE: &str = "\
+Usage:
+  rrp http PORT [options]
+  rrp tcp PORT [options]
+  rrp run [options]
+  rrp up [--config FILE] [--only NAME,...] [--drain SECS]
+  rrp install-service [--name NAME] [--manager systemd|launchd|windows] [--per-user]
+                      [--restart-delay SECS] [--print] [options | up options]
+  rrp udp-helper --listen ADDR --tunnel ADDR [--idle SECS]
+  rrp --capabilities
+
+`rrp http PORT` exposes the HTTP service on local PORT, forwarding it as HTTP (see
+--http). `rrp tcp PORT` exposes a TCP service: the server listens on the same port
+(or --remote-port), through serveo unless --server, --provider or --config says
+otherwise. `rrp run` takes every setting from options; `rrp [options]` is short for it.
+
+Options can also be given as RRP_ variables named after them, e.g. RRP_LOCAL_PORT for
+--local-port and RRP_UDP=true for --udp. Options on the command line win over the
+variables, which win over the --config file.
+
+`rrp up` runs every tunnel of a profiles file (default: rrp.json; files ending with .toml
+are read as TOML) and restarts each one according to its restart policy. Given --config, install-service installs `rrp up`.
+
+`rrp udp-helper` runs on the SSH server next to a `rrp run --udp` tunnel: it receives
+datagrams on --listen and relays them through the forwarded port at --tunnel, closing
+a peer's relay after --idle seconds of silence (default: 60).
+
+`rrp --capabilities` prints what this build supports (providers, HTTP mode, TLS,
+metrics, optional features) as JSON.
+
+Options:
+  --config FILE        Take the tunnel from this profiles file
+  --tunnel NAME        The tunnel to take from a --config file with several
+  --provider NAME      Follow the conventions of localhost.run, serveo, pinggy or sish
+                       (on --server)
+  --domain NAME        Custom domain, subdomain or sish alias to ask the provider for
+  --server HOST        SSH server (default: localhost.run)
+  --port PORT          SSH server port (default: 22)
+  --user NAME          SSH user name (default: nokey)
+  --key PATH           Private key file
+  --key-env VAR        Environment variable holding the private key
+  --cert PATH          OpenSSH certificate for the key, read again before every
+                       connect so a renewed certificate is picked up
+  --proxy URL          Reach the server through an HTTP or SOCKS5 proxy,
+                       http://[user:password@]host:port or socks5://...
+  -J, --jump HOSTS     Hop through these SSH hosts to reach the server, like ssh -J:
+                       [user@]host[:port], comma-separated
+  --remote-port PORT   Port the server listens on (default: 80)
+  --allow NETS         Only relay connections from these networks, e.g.
+                       203.0.113.0/24,2001:db8::/32, comma-separated
+  --deny NETS          Reject connections from these networks, comma-separated
+  --local-addr ADDR    Local address to forward to (default: 127.0.0.1)
+  --local-port PORT    Local port to forward to (default: 8080)
+  --local-socket PATH  Unix domain socket to forward to instead
+  --http               Forward as HTTP, with request timeouts and forwarding headers
+  --udp                The local service speaks UDP, see udp-helper
+  -D, --dynamic-forward ADDR
+                       Also serve a SOCKS5 proxy on ADDR (e.g. 127.0.0.1:1080) whose
+                       connections are made by the SSH server, like ssh -D
+  --drain SECS         Time open connections get to finish on stop (default: 30)
+  --profiling-socket PATH
+                       Answer `profile`, `tasks` and `allocations` with JSON on this
+                       Unix socket (builds with the profiling feature)
+
+Exit status: 0 once stopped, 1 when the tunnel failed, 2 for invalid options or
+configuration, 3 when the provider refused the tunnel or reported its forward taken.
+
+Logging is filtered per subsystem with RUST_LOG, e.g. RUST_LOG=rrp::proxy=trace,rrp=info.
+Subsystems: rrp::auth, rrp::session, rrp::proxy, rrp::provider, rrp::server,
+rrp::reconnect, rrp::health, rrp::config, rrp::security.";
+
+/// Options that take no value
+const SWITCHES: &[&str] = &["--http", "--udp"];
+
+/// Options of `rrp run`, also recorded in installed services. Unset options keep
+/// the value of the --config file, or the default.
+#[derive(Debug)]
+struct RunArgs {
+    config: Option<PathBuf>,
+    tunnel: Option<String>,
+    provider: Option<String>,
+    domain: Option<String>,
+    server: Option<String>,
+    port: Option<u16>,
+    user: Option<String>,
+    key: Option<PathBuf>,
+    key_env: Option<String>,
+    cert: Option<PathBuf>,
+    proxy: Option<String>,
+    jump: Option<String>,
+    allow: Option<String>,
+    deny: Option<String>,
+    remote_port: Option<u32>,
+    local_addr: Option<String>,
+    local_port: Option<u16>,
+    local_socket: Option<PathBuf>,
+    http: bool,
+    udp: bool,
+    dynamic_forward: Option<SocketAddr>,
+    drain: Duration,
+    profiling_socket: Option<PathBuf>,
+}
+
+impl Default for RunArgs {
+    fn default() -> Self {
+        Self {
+            config: None,
+            tunnel: None,
+            provider: None,
+            domain: None,
+            server: None,
+            port: None,
+            user: None,
+            key: None,
+            key_env: None,
+            cert: None,
+            proxy: None,
+            jump: None,
+            allow: None,
+            deny: None,
+            remote_port: None,
+            local_addr: None,
+            local_port: None,
+            local_socket: None,
+            http: false,
+            udp: false,
+            dynamic_forward: None,
+            drain: Duration::from_secs(30),
+            profiling_socket: None,
+        }
+    }
+}
+
+impl RunArgs {
+    /// Parse one option, returning `false` if it isn't a run option
+    fn parse_option(
+        &mut self,
+        flag: &str,
+        value: &mut impl FnMut() -> Result<String>,
+    ) -> Result<bool> {
+        match flag {
+            "--config" => self.config = Some(PathBuf::from(value()?)),
+            "--tunnel" => self.tunnel = Some(value()?),
+            "--provider" => self.provider = Some(value()?),
+            "--domain" => self.domain = Some(value()?),
+            "--server" => self.server = Some(value()?),
+            "--port" => self.port = Some(parse(flag, &value()?)?),
+            "--user" => self.user = Some(value()?),
+            "--key" => self.key = Some(PathBuf::from(value()?)),
+            "--key-env" => self.key_env = Some(value()?),
+            "--cert" => self.cert = Some(PathBuf::from(value()?)),
+            "--proxy" => {
+                let url = value()?;
+                ProxyConfig::parse(&url)?;
+                self.proxy = Some(url);
+            }
+            "-J" | "--jump" => {
+                let hosts = value()?;
+                JumpHost::parse_list(&hosts)?;
+                self.jump = Some(hosts);
+            }
+            "--allow" => {
+                let networks = value()?;
+                AccessList::allow(&[&networks])?;
+                self.allow = Some(networks);
+            }
+            "--deny" => {
+                let networks = value()?;
+                AccessList::deny(&[&networks])?;
+                self.deny = Some(networks);
+            }
+            "--remote-port" => self.remote_port = Some(parse(flag, &value()?)?),
+            "--local-addr" => self.local_addr = Some(value()?),
+            "--local-port" => self.local_port = Some(parse(flag, &value()?)?),
+            "--local-socket" => self.local_socket = Some(PathBuf::from(value()?)),
+            "--http" => self.http = true,
+            "--udp" => self.udp = true,
+            "-D" | "--dynamic-forward" => self.dynamic_forward = Some(parse(flag, &value()?)?),
+            "--drain" => self.drain = Duration::from_secs(parse(flag, &value()?)?),
+            "--profiling-socket" => {
+                if !cfg!(all(feature = "profiling", unix)) {
+                    bail!("--profiling-socket needs rrp built with the profiling feature");
+                }
+                self.profiling_socket = Some(PathBuf::from(value()?));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Take options from `RRP_*` variables named after them, `RRP_LOCAL_PORT` for
+    /// `--local-port`; switches take `true` or `false`
+    fn parse_env(&mut self, vars: impl Iterator<Item = (String, String)>) -> Result<()> {
+        for (name, value) in vars {
+            let Some(option) = name.strip_prefix("RRP_") else {
+                continue;
+            };
+            if value.is_empty() {
+                continue;
+            }
+            let flag = format!("--{}", option.to_lowercase().replace('_', "-"));
+            if SWITCHES.contains(&flag.as_str()) {
+                match value.to_lowercase().as_str() {
+                    "1" | "true" | "yes" | "on" => {}
+                    "0" | "false" | "no" | "off" => continue,
+                    _ => bail!("{} must be true or false, not {:?}", name, value),
+                }
+            }
+            let mut value = || Ok(value.clone());
+            let known = self
+                .parse_option(&flag, &mut value)
+                .with_context(|| format!("Invalid {}", name))?;
+            if !known {
+                bail!("{} is not an rrp option", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Options of `rrp http PORT` and `rrp tcp PORT`, given once everything else is
+    /// parsed
+    fn apply_mode(&mut self, mode: &str, port: u16) {
+        self.local_port = Some(port);
+        match mode {
+            "http" => self.http = true,
+            _ => {
+                if self.config.is_none() {
+                    self.remote_port.get_or_insert(port as u32);
+                    if self.server.is_none() && self.provider.is_none() {
+                        self.provider = Some("serveo".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    fn config(&self) -> Result<ReverseSshConfig> {
+        let key_path = self
+            .key
+            .as_ref()
+            .map(|key| key.to_string_lossy().into_owned());
+        let mut config = match (&self.config, &self.provider) {
+            (Some(_), Some(_)) => {
+                bail!("--provider can't be combined with --config, name it in the file")
+            }
+            (Some(path), None) => match &self.tunnel {
+                Some(name) => Profile::load(path, name)?.config,
+                None => ReverseSshConfig::from_file(path)?,
+            },
+            (None, Some(name)) => ReverseSshConfig::for_provider(Provider::named(
+                name,
+                self.domain.clone(),
+                self.server.as_deref(),
+                self.port,
+                key_path.as_deref(),
+            )?),
+            (None, None) => {
+                if self.tunnel.is_some() {
+                    bail!("--tunnel needs --config");
+                }
+                if self.domain.is_some() {
+                    bail!("--domain needs --provider");
+                }
+                ReverseSshConfig {
+                    server_addr: "localhost.run".to_string(),
+                    username: "nokey".to_string(),
+                    ..Default::default()
+                }
+            }
+        };
+        if let Some(server) = &self.server {
+            config.server_addr = server.clone();
+        }
+        if let Some(port) = self.port {
+            config.server_port = port;
+        }
+        if let Some(user) = &self.user {
+            config.username = user.clone();
+        }
+        let key = self
+            .key_env
+            .as_deref()
+            .map(PrivateKey::from_env)
+            .transpose()?;
+        // Keys given here replace the ones the provider or the file set up
+        if key.is_some() || key_path.is_some() {
+            if !config.auth.is_empty() {
+                config.auth = key.clone().map(AuthMethod::Key).into_iter().collect();
+                config
+                    .auth
+                    .extend(key_path.clone().map(AuthMethod::KeyFile));
+            }
+            config.key = key;
+            config.key_path = key_path;
+        }
+        if let Some(url) = &self.proxy {
+            config.proxy = Some(ProxyConfig::parse(url)?);
+        }
+        if let Some(hosts) = &self.jump {
+            config.jump_hosts = JumpHost::parse_list(hosts)?;
+        }
+        if let Some(networks) = &self.allow {
+            config.access_list.allow = AccessList::allow(&[networks])?.allow;
+        }
+        if let Some(networks) = &self.deny {
+            config.access_list.deny = AccessList::deny(&[networks])?.deny;
+        }
+        if let Some(port) = self.remote_port {
+            config.remote_port = port;
+        }
+        if let Some(addr) = &self.local_addr {
+            config.local_addr = addr.clone();
+        }
+        if let Some(port) = self.local_port {
+            config.local_port = port;
+        }
+        if let Some(socket) = &self.local_socket {
+            config.local_socket = Some(socket.clone());
+        }
+        if self.http {
+            config.http.get_or_insert_with(HttpConfig::default);
+        }
+        config.udp |= self.udp;
+        if let Some(addr) = self.dynamic_forward {
+            config.dynamic_forward = Some(addr);
+        }
+        if let Some(path) = &self.cert {
+            let key = match (config.key.take(), config.key_path.take()) {
+                (Some(key), _) => key,
+                (None, Some(key_path)) => PrivateKey::Pem(
+                    std::fs::read_to_string(&key_path)
+                        .with_context(|| format!("Failed to read {}", key_path))?,
+                ),
+                (None, None) => bail!("--cert needs --key or --key-env"),
+            };
+            let read = |path: &PathBuf| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))
+            };
+            config.auth = vec![AuthMethod::Certificate {
+                key,
+                certificate: read(path)?,
+            }];
+            let path = path.clone();
+            config.certificate_refresh = Some(CertificateRefresh::new(move || {
+                let certificate = read(&path);
+                async move { certificate }
+            }));
+        }
+        Ok(config)
+    }
+
+    /// The arguments reproducing these options, with paths made absolute since
+    /// services don't start in the current directory
+    fn to_args(&self) -> Result<Vec<String>> {
+        let absolute = |path: &PathBuf| -> Result<String> {
+            let path = std::path::absolute(path)
+                .with_context(|| format!("Failed to resolve {}", path.display()))?;
+            Ok(path.to_string_lossy().into_owned())
+        };
+        let mut args = vec!["run".to_string()];
+        let mut push = |flag: &str, value: Option<String>| {
+            if let Some(value) = value {
+                args.extend([flag.to_string(), value]);
+            }
+        };
+        push("--config", self.config.as_ref().map(absolute).transpose()?);
+        push("--tunnel", self.tunnel.clone());
+        push("--provider", self.provider.clone());
+        push("--domain", self.domain.clone());
+        push("--server", self.server.clone());
+        push("--port", self.port.map(|port| port.to_string()));
+        push("--user", self.user.clone());
+        push("--key", self.key.as_ref().map(absolute).transpose()?);
+        push("--key-env", self.key_env.clone());
+        push("--cert", self.cert.as_ref().map(absolute).transpose()?);
+        push("--proxy", self.proxy.clone());
+        push("--jump", self.jump.clone());
+        push("--allow", self.allow.clone());
+        push("--deny", self.deny.clone());
+        push(
+            "--remote-port",
+            self.remote_port.map(|port| port.to_string()),
+        );
+        push("--local-addr", self.local_addr.clone());
+        push("--local-port", self.local_port.map(|port| port.to_string()));
+        push(
+            "--local-socket",
+            self.local_socket.as_ref().map(absolute).transpose()?,
+        );
+        push(
+            "--dynamic-forward",
+            self.dynamic_forward.map(|addr| addr.to_string()),
+        );
+        push("--drain", Some(self.drain.as_secs().to_string()));
+        push(
+            "--profiling-socket",
+            self.profiling_socket.as_ref().map(absolute).transpose()?,
+        );
+        if self.http {
+            args.push("--http".to_string());
+        }
+        if self.udp {
+            args.push("--udp".to_string());
+        }
+        Ok(args)
+    }
+}
+
+/// Options of `rrp up`
+#[derive(Debug)]
+struct UpArgs {
+    config: PathBuf,
+    only: Option<Vec<String>>,
+    drain: Duration,
+}
+
+impl Default for UpArgs {
+    fn default() -> Self {
+        Self {
+            config: PathBuf::from("rrp.json"),
+            only: None,
+            drain: Duration::from_secs(30),
+        }
+    }
+}
+
+impl UpArgs {
+    /// Parse one option, returning `false` if it isn't an up option
+    fn parse_option(
+        &mut self,
+        flag: &str,
+        value: &mut impl FnMut() -> Result<String>,
+    ) -> Result<bool> {
+        match flag {
+            "--config" => self.config = PathBuf::from(value()?),
+            "--only" => self.only = Some(value()?.split(',').map(str::to_string).collect()),
+            "--drain" => self.drain = Duration::from_secs(parse(flag, &value()?)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn profiles(&self) -> Result<Vec<Profile>> {
+        let mut profiles = Profile::load_all(&self.config)?;
+        if let Some(only) = &self.only {
+            if let Some(missing) = only
+                .iter()
+                .find(|name| !profiles.iter().any(|p| &p.name == *name))
+            {
+                bail!("No tunnel {} in {}", missing, self.config.display());
+            }
+            profiles.retain(|profile| only.contains(&profile.name));
+        }
+        if profiles.is_empty() {
+            bail!("No tunnels in {}", self.config.display());
+        }
+        Ok(profiles)
+    }
+
+    fn to_args(&self) -> Result<Vec<String>> {
+        let config = std::path::absolute(&self.config)
+            .with_context(|| format!("Failed to resolve {}", self.config.display()))?;
+        let mut args = vec![
+            "up".to_string(),
+            "--config".to_string(),
+            config.to_string_lossy().into_owned(),
+        ];
+        if let Some(only) = &self.only {
+            args.extend(["--only".to_string(), only.join(",")]);
+        }
+        args.extend(["--drain".to_string(), self.drain.as_secs().to_string()]);
+        Ok(args)
+    }
+}
+
+/// Log subsystems at the levels given in `RUST_LOG` (e.g. `rrp::proxy=trace,rrp=info`),
+/// or everything at `info`
+fn init_logging() {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => directives.parse().unwrap_or_else(|e| {
+            eprintln!("Ignoring invalid RUST_LOG {:?}: {}", directives, e);
+            Targets::new().with_default(Level::INFO)
+        }),
+        Err(_) => Targets::new().with_default(Level::INFO),
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(filter)
+        .init();
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("Invalid value for {}: {}", flag, value))
+}
+
+/// What to do, with its options parsed and checked
+enum Command {
+    Run(Box<RunArgs>, Box<ReverseSshConfig>),
+    Up(UpArgs, Vec<Profile>),
+    InstallService(ServiceSpec, ServiceManager, bool),
+    UdpHelper(UdpHelper),
+    Capabilities,
+    Help,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    // Mistakes in the options fail with their own status, before anything runs
+    let vars = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    let command = match parse_command(std::env::args().skip(1), vars) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    let result = match command {
+        Command::Run(options, config) => run(*options, *config).await,
+        Command::Up(options, profiles) => up(options, profiles).await,
+        Command::InstallService(spec, manager, print) => install_service(spec, manager, print),
+        Command::UdpHelper(helper) => {
+            init_logging();
+            helper.run().await
+        }
+        Command::Capabilities => {
+            println!("{}", reverse_ssh::capabilities().to_json());
+            Ok(())
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(exit_status(&e))
+        }
+    }
+}
+
+/// Exit status of a command that failed with `error` once it ran
+fn exit_status(error: &anyhow::Error) -> u8 {
+    let refused = error
+        .chain()
+        .any(|cause| cause.is::<ProviderError>() || cause.is::<ForwardConflict>());
+    if refused {
+        EXIT_REFUSED
+    } else {
+        EXIT_FAILED
+    }
+}
+
+/// The command `args` ask for, with `RRP_*` options taken from `vars`. Errors are
+/// mistakes in the options, which exit with [`EXIT_USAGE`].
+fn parse_command(
+    mut args: impl Iterator<Item = String>,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Command> {
+    let Some(command) = args.next() else {
+        bail!("{}", USAGE);
+    };
+    Ok(match command.as_str() {
+        "run" => parse_run(None, args, vars)?,
+        "http" | "tcp" => {
+            let port = args
+                .next()
+                .with_context(|| format!("rrp {} needs a local PORT\n\n{}", command, USAGE))?;
+            let port = parse(&command, &port)?;
+            parse_run(Some((&command, port)), args, vars)?
+        }
+        "up" => parse_up(args)?,
+        "install-service" => parse_install_service(args)?,
+        "udp-helper" => Command::UdpHelper(parse_udp_helper(args)?),
+        "--capabilities" => Command::Capabilities,
+        "--help" | "-h" | "help" => Command::Help,
+        // `rrp --provider serveo` runs a tunnel like `rrp run --provider serveo`
+        flag if flag.starts_with('-') => {
+            parse_run(None, std::iter::once(command).chain(args), vars)?
+        }
+        _ => bail!("Unknown command {}\n\n{}", command, USAGE),
+    })
+}
+
+/// Options of `rrp run`, or of `rrp http` or `rrp tcp` on `mode`, from the
+/// environment variables `vars` and `args`
+fn parse_run(
+    mode: Option<(&str, u16)>,
+    mut args: impl Iterator<Item = String>,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Command> {
+    let mut options = RunArgs::default();
+    options.parse_env(vars)?;
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", flag))
+        };
+        if !options.parse_option(&flag, &mut value)? {
+            bail!("Unknown option {}\n\n{}", flag, USAGE);
+        }
+    }
+    if let Some((mode, port)) = mode {
+        options.apply_mode(mode, port);
+    }
+    let config = options.config()?;
+    Ok(Command::Run(Box::new(options), Box::new(config)))
+}
+
+async fn run(options: RunArgs, config: ReverseSshConfig) -> Result<()> {
+    init_logging();
+    let mut client = ReverseSshClient::new(config);
+    tokio::spawn(stop_on_signal(client.handle(), options.drain));
+    #[cfg(all(feature = "profiling", unix))]
+    if let Some(path) = options.profiling_socket.clone() {
+        let handle = client.handle();
+        tokio::spawn(async move {
+            if let Err(e) = handle.serve_profiling(path).await {
+                eprintln!("Profiling socket failed: {:#}", e);
+            }
+        });
+    }
+    client.run().await
+}
+
+fn parse_udp_helper(mut args: impl Iterator<Item = String>) -> Result<UdpHelper> {
+    let (mut listen, mut tunnel, mut idle) = (None, None, None);
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("{} needs a value", flag))?;
+        match flag.as_str() {
+            "--listen" => listen = Some(parse(&flag, &value)?),
+            "--tunnel" => tunnel = Some(parse(&flag, &value)?),
+            "--idle" => idle = Some(Duration::from_secs(parse(&flag, &value)?)),
+            _ => bail!("Unknown option {}\n\n{}", flag, USAGE),
+        }
+    }
+    let mut helper = UdpHelper::new(
+        listen.context("udp-helper needs --listen")?,
+        tunnel.context("udp-helper needs --tunnel")?,
+    );
+    if let Some(idle) = idle {
+        helper.idle_timeout = idle;
+    }
+    Ok(helper)
+}
+
+fn parse_up(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let mut options = UpArgs::default();
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", flag))
+        };
+        if !options.parse_option(&flag, &mut value)? {
+            bail!("Unknown option {}\n\n{}", flag, USAGE);
+        }
+    }
+    // Read the file before anything starts
+    let profiles = options.profiles()?;
+    Ok(Command::Up(options, profiles))
+}
+
+async fn up(options: UpArgs, profiles: Vec<Profile>) -> Result<()> {
+    init_logging();
+    let mut manager = TunnelManager::new();
+    for profile in profiles {
+        manager.add(profile.name, profile.config, profile.restart);
+    }
+
+    // Show the combined status whenever a tunnel changes state or URL, until every
+    // tunnel has stopped for good or we are asked to stop
+    let mut events = manager.subscribe();
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+    let signal = wait_for_signal();
+    tokio::pin!(signal);
+    let stopped = loop {
+        tokio::select! {
+            _ = &mut signal => break true,
+            event = events.recv() => match event {
+                Ok((_, TunnelEvent::StateChanged { .. } | TunnelEvent::UrlChanged { .. })) => {
+                    print_status(&manager.status());
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break false,
+            },
+            _ = check.tick() => {
+                if manager.is_finished() {
+                    break false;
+                }
+            }
+        }
+    };
+    if stopped {
+        eprintln!(
+            "Stopping, draining open connections for up to {:?}",
+            options.drain
+        );
+        manager.shutdown(options.drain).await;
+    }
+
+    let results = manager.wait().await;
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    for (name, result) in &results {
+        if let Err(e) = result {
+            eprintln!("[{}] {:#}", name, e);
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} tunnels failed", failed, results.len());
+    }
+    Ok(())
+}
+
+fn print_status(status: &[(String, TunnelStatus)]) {
+    println!(
+        "{:<16} {:<14} {:>8} {:>10}  URL",
+        "TUNNEL", "STATE", "UPTIME", "RECONNECTS"
+    );
+    for (name, status) in status {
+        let uptime = status
+            .uptime
+            .map(|uptime| format!("{}s", uptime.as_secs()))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<16} {:<14} {:>8} {:>10}  {}",
+            name,
+            status.state.to_string(),
+            uptime,
+            status.reconnects,
+            status.url.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+/// On Ctrl-C or SIGTERM, give open connections up to `drain` to finish, then disconnect
+async fn stop_on_signal(handle: ClientHandle, drain: Duration) {
+    wait_for_signal().await;
+    eprintln!("Stopping, draining open connections for up to {:?}", drain);
+    if !handle.drain(drain).await {
+        eprintln!(
+            "Closing {} connections still open",
+            handle.stats().active_connections
+        );
+    }
+    if let Err(e) = handle.shutdown().await {
+        eprintln!("{:#}", e);
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn parse_install_service(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let mut options = RunArgs::default();
+    let mut up: Option<UpArgs> = None;
+    let mut name = "rrp".to_string();
+    let mut manager = ServiceManager::current();
+    let mut per_user = false;
+    let mut print = false;
+    let mut restart_delay = None;
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", flag))
+        };
+        match flag.as_str() {
+            "--name" => name = value()?,
+            "--manager" => {
+                let value = value()?;
+                manager = Some(
+                    ServiceManager::named(&value)
+                        .with_context(|| format!("Unknown service manager {}", value))?,
+                );
+            }
+            "--per-user" => per_user = true,
+            "--print" => print = true,
+            "--restart-delay" => restart_delay = Some(parse(&flag, &value()?)?),
+            "--config" | "--only" => {
+                up.get_or_insert_with(UpArgs::default)
+                    .parse_option(&flag, &mut value)?;
+            }
+            _ => {
+                if !options.parse_option(&flag, &mut value)? {
+                    bail!("Unknown option {}\n\n{}", flag, USAGE);
+                }
+            }
+        }
+    }
+    let manager =
+        manager.context("No supported service manager on this platform, pass --manager")?;
+
+    let program = std::env::current_exe().context("Failed to locate the rrp executable")?;
+    let mut spec = match &mut up {
+        Some(up) => {
+            // Check the file now rather than when the service starts
+            up.drain = options.drain;
+            up.profiles()?;
+            ServiceSpec::new(name, program, up.to_args()?)
+        }
+        None => ServiceSpec::new(name, program, options.to_args()?),
+    };
+    spec.drain_timeout = options.drain;
+    spec.per_user = per_user;
+    if let Some(delay) = restart_delay {
+        spec.restart_delay = Duration::from_secs(delay);
+    }
+    Ok(Command::InstallService(spec, manager, print))
+}
+
+fn install_service(spec: ServiceSpec, manager: ServiceManager, print: bool) -> Result<()> {
+    if print {
+        print!("{}", spec.render(manager));
+        return Ok(());
+    }
+    let path = spec.install(manager)?;
+    println!("Installed {} and started the service", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The configuration `rrp ARGS` runs with `vars` set
+    fn resolve(args: &[&str], vars: &[(&str, &str)]) -> Result<ReverseSshConfig> {
+        let args = args.iter().map(|arg| arg.to_string());
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        match parse_command(args, vars)? {
+            Command::Run(_, config) => Ok(*config),
+            _ => bail!("Not a run command"),
+        }
+    }
+
+    #[test]
+    fn test_options_win_over_variables_over_the_config_file() {
+        let path = std::env::temp_dir().join(format!("rrp-cli-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{ "version": 2, "tunnels": { "web": {
+                "server": "file.example", "user": "file", "remote_port": 81, "local_port": 3000
+            } } }"#,
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let from_file = resolve(&["run", "--config", config], &[]).unwrap();
+        assert_eq!(from_file.server_addr, "file.example");
+        assert_eq!(from_file.username, "file");
+        assert_eq!(from_file.remote_port, 81);
+        assert_eq!(from_file.local_port, 3000);
+
+        let vars = [
+            ("RRP_USER", "env"),
+            ("RRP_LOCAL_PORT", "4000"),
+            ("HOME", "/"),
+        ];
+        let resolved = resolve(
+            &["--config", config, "--local-port", "5000", "--http"],
+            &vars,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resolved.server_addr, "file.example");
+        assert_eq!(resolved.username, "env");
+        assert_eq!(resolved.remote_port, 81);
+        assert_eq!(resolved.local_port, 5000);
+        assert!(resolved.http.is_some());
+
+        // `rrp tcp PORT` listens on the same port through serveo, unless told otherwise
+        let tcp = resolve(&["tcp", "5432"], &[]).unwrap();
+        assert_eq!(tcp.server_addr, "serveo.net");
+        assert_eq!((tcp.remote_port, tcp.local_port), (5432, 5432));
+        let tcp = resolve(&["tcp", "5432", "--server", "ssh.example"], &[]).unwrap();
+        assert_eq!(tcp.server_addr, "ssh.example");
+        let http = resolve(&["http", "3000"], &[("RRP_LOCAL_PORT", "4000")]).unwrap();
+        assert_eq!(http.local_port, 3000);
+        assert!(http.http.is_some());
+    }
+
+    #[test]
+    fn test_access_lists() {
+        let config = resolve(
+            &["run", "--allow", "203.0.113.0/24, 2001:db8::/32"],
+            &[("RRP_DENY", "203.0.113.66")],
+        )
+        .unwrap();
+        let allow: Vec<_> = config
+            .access_list
+            .allow
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let deny: Vec<_> = config
+            .access_list
+            .deny
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(allow, ["203.0.113.0/24", "2001:db8::/32"]);
+        assert_eq!(deny, ["203.0.113.66/32"]);
+
+        let error = resolve(&["run", "--allow", "203.0.113.0/33"], &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Prefix length 33 is too long for 203.0.113.0"
+        );
+        assert!(resolve(&["run"], &[("RRP_DENY", "nowhere")]).is_err());
+    }
+
+    #[test]
+    fn test_exit_statuses() {
+        // Mistakes in the options are errors of `parse_command`, which exit with EXIT_USAGE
+        for args in [
+            &["run", "--bogus"][..],
+            &["run", "--local-port"],
+            &["run", "--local-port", "http"],
+            &["tcp"],
+            &["run", "--tunnel", "web"],
+            &["frobnicate"],
+        ] {
+            assert!(resolve(args, &[]).is_err(), "{:?}", args);
+        }
+        assert!(resolve(&["run"], &[("RRP_UDP", "maybe")]).is_err());
+        assert!(resolve(&["run"], &[("RRP_BOGUS", "1")]).is_err());
+
+        let refused = anyhow::Error::new(ProviderError::QuotaExceeded).context("Tunnel failed");
+        assert_eq!(exit_status(&refused), EXIT_REFUSED);
+        let conflict = anyhow::Error::new(ForwardConflict {
+            provider: "serveo".to_string(),
+            bind_address: String::new(),
+            port: 80,
+            suggestions: Vec::new(),
+        });
+        assert_eq!(exit_status(&conflict), EXIT_REFUSED);
+        assert_eq!(
+            exit_status(&anyhow::anyhow!("Connection refused")),
+            EXIT_FAILED
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! What this build of the crate can do
+//!
+//! The same version of the crate can be built with or without the `profiling` and
+//! `console` features, and on platforms without Unix domain sockets. Orchestration
+//! layers driving several builds call [`capabilities()`], or run
+//! `rrp --capabilities`, and adapt to what it reports instead of guessing from the
+//! version.
+
+use serde_json::{json, Value};
+
+use crate::provider::PROVIDERS;
+
+/// The features of this build
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Version of the crate
+    pub version: &'static str,
+    /// TLS termination in the client; without it TLS is forwarded untouched
+    pub tls: bool,
+    /// HTTP-aware forwarding, see [`HttpConfig`](crate::HttpConfig)
+    pub http: bool,
+    /// Counters and histograms, see [`ClientHandle::metrics`](crate::ClientHandle::metrics)
+    pub metrics: bool,
+    /// A SOCKS5 proxy through the server, like `ssh -D`
+    pub socks: bool,
+    /// Forwarding to a local UDP service
+    pub udp: bool,
+    /// Forwarding to a local Unix domain socket
+    pub unix_sockets: bool,
+    /// Self-profiling (the `profiling` feature)
+    pub profiling: bool,
+    /// Named tasks for tokio-console (the `console` feature with `tokio_unstable`)
+    pub console: bool,
+    /// Tunnel providers with built-in support, by the name profiles use
+    pub providers: Vec<&'static str>,
+    /// Formats profiles files are read in
+    pub profile_formats: Vec<&'static str>,
+}
+
+/// The features of the build the caller is linked against
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        tls: false,
+        http: true,
+        metrics: true,
+        socks: true,
+        udp: true,
+        unix_sockets: cfg!(unix),
+        profiling: cfg!(feature = "profiling"),
+        console: cfg!(all(tokio_unstable, feature = "console")),
+        providers: PROVIDERS.to_vec(),
+        profile_formats: vec!["json", "toml"],
+    }
+}
+
+impl Capabilities {
+    /// The capabilities as a JSON object
+    pub fn to_json(&self) -> Value {
+        json!({
+            "version": self.version,
+            "tls": self.tls,
+            "http": self.http,
+            "metrics": self.metrics,
+            "socks": self.socks,
+            "udp": self.udp,
+            "unix_sockets": self.unix_sockets,
+            "profiling": self.profiling,
+            "console": self.console,
+            "providers": self.providers,
+            "profile_formats": self.profile_formats,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(capabilities.profiling, cfg!(feature = "profiling"));
+        let json = capabilities.to_json();
+        assert_eq!(json["tls"], false);
+        assert_eq!(
+            json["providers"],
+            json!(["localhost.run", "serveo", "pinggy", "sish"])
+        );
+        // Every provider listed is one a profile can name
+        for name in &capabilities.providers {
+            let server = (*name == "sish").then_some("tuns.sh");
+            crate::Provider::named(name, None, server, None, None).unwrap();
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Short-lived OpenSSH certificates
+//!
+//! Certificate authorities like Vault's SSH secrets engine or step-ca sign keys for
+//! minutes or hours. [`AuthMethod::Certificate`] presents such a certificate along
+//! with its key, and a [`CertificateRefresh`] hook set as
+//! [`ReverseSshConfig::certificate_refresh`](crate::ReverseSshConfig::certificate_refresh)
+//! mints a fresh one before every connect and reconnect. A certificate that has
+//! expired anyway fails the connect with [`CertificateExpired`] instead of an
+//! authentication error the server gives no reason for.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use ssh_key::Certificate;
+use tracing::info;
+
+use crate::handle::Shared;
+use crate::http::format_rfc3339;
+use crate::{targets, AuthMethod};
+
+type RefreshFuture = Pin<Box<dyn Future<Output = Result<String>> + Send>>;
+
+/// Hook returning a freshly signed certificate in OpenSSH format
+/// (`ssh-ed25519-cert-v01@openssh.com AAAA...`)
+#[derive(Clone)]
+pub struct CertificateRefresh(Arc<dyn Fn() -> RefreshFuture + Send + Sync>);
+
+impl CertificateRefresh {
+    pub fn new<F, Fut>(refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String>> + Send + 'static,
+    {
+        Self(Arc::new(move || Box::pin(refresh())))
+    }
+
+    /// Whether `other` is a clone of this hook
+    pub(crate) fn same_as(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for CertificateRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CertificateRefresh(..)")
+    }
+}
+
+/// The certificate to authenticate with is past its validity period
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateExpired {
+    /// Key ID the certificate authority signed the certificate with
+    pub key_id: String,
+    pub valid_before: SystemTime,
+}
+
+impl fmt::Display for CertificateExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "certificate {:?} expired at {}",
+            self.key_id,
+            format_rfc3339(self.valid_before)
+        )
+    }
+}
+
+impl std::error::Error for CertificateExpired {}
+
+pub(crate) fn parse(certificate: &str) -> Result<Certificate> {
+    Certificate::from_openssh(certificate.trim()).context("Failed to parse OpenSSH certificate")
+}
+
+/// Fail if `certificate` is no longer valid at `now`
+fn check_expiry(certificate: &Certificate, now: SystemTime) -> Result<(), CertificateExpired> {
+    let valid_before = certificate.valid_before_time();
+    if now < valid_before {
+        Ok(())
+    } else {
+        Err(CertificateExpired {
+            key_id: certificate.key_id().to_string(),
+            valid_before,
+        })
+    }
+}
+
+/// Before connecting: fetch a fresh certificate with `refresh` for every
+/// [`AuthMethod::Certificate`] credential, then check none of them has expired
+pub(crate) async fn prepare(shared: &Shared, refresh: Option<&CertificateRefresh>) -> Result<()> {
+    let uses_certificate = |methods: &[AuthMethod]| {
+        methods
+            .iter()
+            .any(|method| matches!(method, AuthMethod::Certificate { .. }))
+    };
+    if !uses_certificate(&shared.credentials()) {
+        return Ok(());
+    }
+    if let Some(refresh) = refresh {
+        let certificate = (refresh.0)()
+            .await
+            .context("Failed to refresh certificate")?;
+        let parsed = parse(&certificate)?;
+        info!(target: targets::AUTH,
+            "Refreshed certificate {:?}, valid until {}",
+            parsed.key_id(),
+            format_rfc3339(parsed.valid_before_time())
+        );
+        shared.replace_certificates(&certificate);
+    }
+    for method in shared.credentials() {
+        if let AuthMethod::Certificate { certificate, .. } = &method {
+            check_expiry(&parse(certificate)?, SystemTime::now())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Signed with `ssh-keygen -s ca -I tunnel -n nokey -V 20200101:20200102`
+    const EXPIRED: &str = "ssh-ed25519-cert-v01@openssh.com AAAAIHNzaC1lZDI1NTE5LWNlcnQtdjAxQG9wZW5zc2guY29tAAAAIGs/UKHuCNOwloRH9pHlS/TIK33P2X3l1fRLqzQW8ODvAAAAIF5W8hkvVJ4Igg+LgF6x4whivkw+DSByyKRO25K+/IDiAAAAAAAAAAAAAAABAAAABnR1bm5lbAAAAAkAAAAFbm9rZXkAAAAAXgvhAAAAAABeDTKAAAAAAAAAAIIAAAAVcGVybWl0LVgxMS1mb3J3YXJkaW5nAAAAAAAAABdwZXJtaXQtYWdlbnQtZm9yd2FyZGluZwAAAAAAAAAWcGVybWl0LXBvcnQtZm9yd2FyZGluZwAAAAAAAAAKcGVybWl0LXB0eQAAAAAAAAAOcGVybWl0LXVzZXItcmMAAAAAAAAAAAAAADMAAAALc3NoLWVkMjU1MTkAAAAgzAioWSVawj5FNOMsU48WhjPqMAjLGnYcrBAdyHOrNHQAAABTAAAAC3NzaC1lZDI1NTE5AAAAQBcpYRvdm0MdavccZSqNvyAMwrbaIdvqLafGvXdGd1PRTT9ZDfFf0WARke+L8u4XiSdb3noY0jEnxVWp7RJoiAs= user";
+
+    #[test]
+    fn test_certificate_expiry() {
+        let certificate = parse(&format!("{}\n", EXPIRED)).unwrap();
+        let valid_before = UNIX_EPOCH + Duration::from_secs(1_577_923_200);
+        assert_eq!(certificate.valid_before_time(), valid_before);
+        assert!(check_expiry(&certificate, valid_before - Duration::from_secs(1)).is_ok());
+
+        let error = check_expiry(&certificate, SystemTime::now()).unwrap_err();
+        assert_eq!(
+            error,
+            CertificateExpired {
+                key_id: "tunnel".to_string(),
+                valid_before,
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "certificate \"tunnel\" expired at 2020-01-02T00:00:00.000Z"
+        );
+        assert!(parse("ssh-ed25519 AAAA").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! The time source behind the client's timers
+//!
+//! Every timer of the client reads the [`Clock`] of the configuration rather than
+//! tokio's: restart backoff, connect timeouts, connection deadlines, idle timeouts
+//! and keepalives, HTTP request and body timeouts, the file descriptor backoff, quota
+//! periods, alert windows, health and endpoint probes, bandwidth limits and shaping,
+//! response cache expiry, draining, tarpits and the wait for a tunnel URL, as well as
+//! uptime and the startup timeline. [`SystemClock`] is the real time; [`ManualClock`]
+//! only moves when a test calls [`ManualClock::advance`], so that logic can be tested
+//! deterministically, without sleeping. Only the SSH keepalives, which russh runs
+//! itself, stay on real time.
+
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// A future completing once a [`Clock`] reaches a deadline
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// A source of time for the client's timers
+pub trait Clock: Debug + Send + Sync {
+    /// The current instant
+    fn now(&self) -> Instant;
+
+    /// Complete once `deadline` has passed on this clock
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+
+    /// Complete once `duration` has passed on this clock
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// Real time, through tokio's timers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// The [`SystemClock`] configurations start with, shared so that configurations
+/// built separately compare as having the same clock
+pub(crate) fn system() -> Arc<dyn Clock> {
+    static SYSTEM: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+    SYSTEM.get_or_init(|| Arc::new(SystemClock)).clone()
+}
+
+/// Simulated time for tests: it starts at the real current instant and only moves
+/// forward by [`advance`](Self::advance), waking the sleeps that are then due
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: watch::channel(Duration::ZERO).0,
+        }
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let (start, mut elapsed) = (self.start, self.elapsed.subscribe());
+        Box::pin(async move {
+            if elapsed
+                .wait_for(|elapsed| start + *elapsed >= deadline)
+                .await
+                .is_err()
+            {
+                // The clock is gone, so it never gets there
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+/// A [`timeout`] ran out before its future completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Run `future` for at most `limit` on `clock`, like [`tokio::time::timeout`]
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    limit: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    tokio::select! {
+        biased;
+        output = future => Ok(output),
+        _ = clock.sleep(limit) => Err(Elapsed),
+    }
+}
+
+/// Ticks every `period` on a clock, the first one right away, like
+/// [`tokio::time::interval`]
+pub(crate) struct Interval {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    pub(crate) fn new(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        let next = clock.now();
+        Self {
+            clock,
+            period,
+            next,
+        }
+    }
+
+    pub(crate) async fn tick(&mut self) {
+        self.clock.sleep_until(self.next).await;
+        // Ticks missed while busy are skipped rather than fired in a burst
+        self.next = (self.next + self.period).max(self.clock.now());
+    }
+
+    /// Make the next tick a full period from now
+    pub(crate) fn reset(&mut self) {
+        self.next = self.clock.now() + self.period;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{Context, Poll, Waker};
+
+    fn ready(sleep: &mut Sleep) -> bool {
+        let mut cx = Context::from_waker(Waker::noop());
+        sleep.as_mut().poll(&mut cx) == Poll::Ready(())
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(30));
+        assert!(!ready(&mut sleep));
+        clock.advance(Duration::from_secs(29));
+        assert!(!ready(&mut sleep));
+        clock.advance(Duration::from_secs(1));
+        assert!(ready(&mut sleep));
+        assert_eq!(clock.now() - start, Duration::from_secs(30));
+        assert!(Arc::ptr_eq(&system(), &system()));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_runs_on_the_clock() {
+        let clock = Arc::new(ManualClock::new());
+        assert_eq!(
+            timeout(&*clock, Duration::from_secs(5), async { 7 }).await,
+            Ok(7)
+        );
+
+        let pending = timeout(
+            &*clock,
+            Duration::from_secs(5),
+            std::future::pending::<()>(),
+        );
+        let (result, ()) = tokio::join!(pending, async {
+            tokio::task::yield_now().await;
+            clock.advance(Duration::from_secs(5));
+        });
+        assert_eq!(result, Err(Elapsed));
+    }
+}
@@ -0,0 +1,138 @@
+//! Checking a forward is free before asking for it
+//!
+//! A server refuses a forward whose port or subdomain is taken with a bare failure,
+//! saying neither why nor what to ask for instead. Providers that can tell whether
+//! a forward is free, through an API or otherwise, do so in
+//! [`TunnelProvider::check_forward`](crate::TunnelProvider::check_forward); with
+//! [`precheck_forwards`](crate::ReverseSshConfig::precheck_forwards) set, a taken
+//! forward fails with a [`ForwardConflict`] listing alternatives before it is
+//! requested.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+use anyhow::Result;
+
+/// Whether a forward is free on the provider's side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Availability {
+    Free,
+    /// Someone else holds it; `suggestions` are ports or subdomains that are free
+    Taken {
+        suggestions: Vec<String>,
+    },
+}
+
+/// The pending result of [`TunnelProvider::check_forward`](crate::TunnelProvider::check_forward)
+pub type AvailabilityCheck<'a> = Pin<Box<dyn Future<Output = Result<Availability>> + Send + 'a>>;
+
+/// A forward the provider reported taken before it was requested
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardConflict {
+    /// Name of the provider that was asked
+    pub provider: String,
+    /// Address of the forward; providers read a requested subdomain from it
+    pub bind_address: String,
+    pub port: u32,
+    /// Free alternatives, as the provider suggested them
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for ForwardConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.bind_address.is_empty() {
+            write!(f, "Remote port {} is taken on {}", self.port, self.provider)?;
+        } else {
+            write!(
+                f,
+                "{} (port {}) is taken on {}",
+                self.bind_address, self.port, self.provider
+            )?;
+        }
+        if !self.suggestions.is_empty() {
+            write!(f, "; try {}", self.suggestions.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ForwardConflict {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sim, ReverseSshClient, ReverseSshConfig, TunnelProvider};
+    use std::sync::Arc;
+
+    /// A provider over the simulated server, which knows which ports are taken
+    #[derive(Debug)]
+    struct Checked(sim::SimNetwork);
+
+    impl TunnelProvider for Checked {
+        fn name(&self) -> &str {
+            "checked"
+        }
+
+        fn server(&self) -> (String, u16) {
+            ("ssh.sim".to_string(), 22)
+        }
+
+        fn username(&self) -> String {
+            "tunnel".to_string()
+        }
+
+        fn check_forward(&self, _: &str, port: u32) -> Option<AvailabilityCheck<'_>> {
+            Some(Box::pin(async move {
+                let taken = |port: u32| self.0.is_listening("ssh.sim", port as u16);
+                if !taken(port) {
+                    return Ok(Availability::Free);
+                }
+                let suggestions = (port + 1..)
+                    .filter(|&port| !taken(port))
+                    .take(2)
+                    .map(|port| port.to_string())
+                    .collect();
+                Ok(Availability::Taken { suggestions })
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_taken_forward_fails_with_suggestions() {
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let _taken = network.listen("ssh.sim", 8000).unwrap();
+        let _also_taken = network.listen("ssh.sim", 8001).unwrap();
+        let client = |precheck_forwards| {
+            ReverseSshClient::new(ReverseSshConfig {
+                remote_port: 8000,
+                precheck_forwards,
+                network: Arc::new(network.clone()),
+                ..ReverseSshConfig::for_provider(Checked(network.clone()))
+            })
+        };
+
+        let mut checked = client(true);
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (message_tx, _messages) = tokio::sync::mpsc::channel(16);
+        checked.connect_session(tx, message_tx).await.unwrap();
+        let error = checked.handle().add_forward(8000).await.unwrap_err();
+        let conflict = error.downcast_ref::<ForwardConflict>().unwrap();
+        assert_eq!(conflict.suggestions, ["8002", "8003"]);
+        assert_eq!(
+            conflict.to_string(),
+            "Remote port 8000 is taken on checked; try 8002, 8003"
+        );
+        assert_eq!(checked.metrics().counter("forward_conflicts_total"), 1);
+        assert_eq!(checked.handle().add_forward(8002).await.unwrap(), 8002);
+
+        // Without the pre-check, the server's refusal is all there is
+        let mut unchecked = client(false);
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let (message_tx, _messages) = tokio::sync::mpsc::channel(16);
+        unchecked.connect_session(tx, message_tx).await.unwrap();
+        let error = unchecked.handle().add_forward(8001).await.unwrap_err();
+        assert!(error.downcast_ref::<ForwardConflict>().is_none());
+    }
+}
@@ -0,0 +1,92 @@
+//! Deadlines for individual forwarded connections
+//!
+//! A deadline can be set, moved or cleared at any time through
+//! [`ClientHandle::set_connection_deadline`](crate::ClientHandle::set_connection_deadline).
+//! The connection task waits on [`DeadlineWatch::expired`] alongside the relay and
+//! closes the connection when it fires.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::Clock;
+
+/// Controlling side of a connection's deadline, kept with the connection's state
+#[derive(Debug)]
+pub(crate) struct Deadline {
+    tx: watch::Sender<Option<Instant>>,
+}
+
+/// Waiting side of a connection's deadline, held by the connection task
+#[derive(Debug)]
+pub(crate) struct DeadlineWatch {
+    rx: watch::Receiver<Option<Instant>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Deadline {
+    /// A deadline on `clock`, unset
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> (Self, DeadlineWatch) {
+        let (tx, rx) = watch::channel(None);
+        (Self { tx }, DeadlineWatch { rx, clock })
+    }
+
+    /// Move the deadline to `at`, or clear it with `None`
+    pub(crate) fn set(&self, at: Option<Instant>) {
+        self.tx.send_replace(at);
+    }
+
+    pub(crate) fn get(&self) -> Option<Instant> {
+        *self.tx.borrow()
+    }
+}
+
+impl DeadlineWatch {
+    /// Resolve once the current deadline passes. Never resolves while no deadline is
+    /// set, or once the connection's state is gone.
+    pub(crate) async fn expired(&mut self) {
+        loop {
+            let at = *self.rx.borrow_and_update();
+            let changed = match at {
+                Some(at) => tokio::select! {
+                    _ = self.clock.sleep_until(at) => return,
+                    changed = self.rx.changed() => changed,
+                },
+                None => self.rx.changed().await,
+            };
+            if changed.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_deadline_can_move() {
+        let (deadline, mut watch) = Deadline::new(crate::clock::system());
+        let short = Duration::from_millis(20);
+
+        // No deadline: never fires
+        assert!(tokio::time::timeout(short, watch.expired()).await.is_err());
+
+        // Pushed back before it fires
+        deadline.set(Some(Instant::now() + short));
+        let pushed = async {
+            tokio::time::sleep(short / 2).await;
+            deadline.set(Some(Instant::now() + Duration::from_secs(60)));
+        };
+        let (fired, ()) = tokio::join!(tokio::time::timeout(short * 3, watch.expired()), pushed);
+        assert!(fired.is_err());
+
+        // Brought forward, fires right away
+        deadline.set(Some(Instant::now()));
+        assert!(tokio::time::timeout(short, watch.expired()).await.is_ok());
+        assert!(deadline.get().is_some());
+    }
+}
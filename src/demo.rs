@@ -0,0 +1,407 @@
+//! Demo services to try a tunnel with
+//!
+//! The HTTP page and echo service the examples used to carry are part of the crate
+//! as [`DemoServer`]. [`expose_demo`] runs one behind a tunnel as a single unit: the
+//! service starts on a free local port before the tunnel connects, and stops once
+//! the tunnel does, whether [`Demo::shutdown`] drained and stopped it, the session
+//! failed or the [`Demo`] was dropped.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, info};
+
+use crate::clock::{self, Clock};
+use crate::{
+    targets, tasks, ClientHandle, ReverseSshClient, ReverseSshConfig, TunnelEvent, TunnelState,
+};
+
+/// Time the service's connections get to finish once the tunnel stopped
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+/// Request head read before answering anyway
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// What a [`DemoServer`] serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DemoService {
+    /// A page telling the tunnel works, with the request it answers
+    Http,
+    /// Sends back whatever it receives, for raw TCP tunnels
+    Echo,
+}
+
+impl DemoService {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Echo => "echo",
+        }
+    }
+}
+
+/// A [`DemoService`] listening on a local port
+#[derive(Debug)]
+pub struct DemoServer {
+    service: DemoService,
+    local_addr: SocketAddr,
+    /// Time connections get to finish, sent to stop; dropping it stops right away
+    stop: watch::Sender<Option<Duration>>,
+    task: JoinHandle<()>,
+}
+
+impl DemoServer {
+    /// Serve `service` on `addr`, e.g. `127.0.0.1:0` for a free port
+    pub async fn start(service: DemoService, addr: SocketAddr) -> Result<Self> {
+        Self::start_with_clock(service, addr, clock::system()).await
+    }
+
+    /// Like [`start`](Self::start), timing the drain of [`stop`](Self::stop) on `clock`
+    pub async fn start_with_clock(
+        service: DemoService,
+        addr: SocketAddr,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        let local_addr = listener.local_addr()?;
+        info!(target: targets::PROXY, "Demo {} service on {}", service.name(), local_addr);
+        let (stop, stopping) = watch::channel(None);
+        let task = tasks::spawn("demo service", serve(service, listener, stopping, clock));
+        Ok(Self {
+            service,
+            local_addr,
+            stop,
+            task,
+        })
+    }
+
+    pub fn service(&self) -> DemoService {
+        self.service
+    }
+
+    /// The address the service listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting connections, give the open ones up to `drain` to finish,
+    /// then close them
+    pub async fn stop(self, drain: Duration) {
+        self.stop.send_replace(Some(drain));
+        let _ = self.task.await;
+    }
+}
+
+async fn serve(
+    service: DemoService,
+    listener: TcpListener,
+    mut stopping: watch::Receiver<Option<Duration>>,
+    clock: Arc<dyn Clock>,
+) {
+    let port = listener.local_addr().map_or(0, |addr| addr.port());
+    let mut connections = JoinSet::new();
+    let mut requests = 0;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, peer)) = accepted else {
+                    continue;
+                };
+                requests += 1;
+                debug!(target: targets::PROXY, "Demo connection #{} from {}", requests, peer);
+                connections.spawn(async move {
+                    let _ = match service {
+                        DemoService::Http => answer(stream, requests, port).await,
+                        DemoService::Echo => echo(stream).await,
+                    };
+                });
+            }
+            // Also when the server was dropped, leaving no time to drain
+            _ = stopping.changed() => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+    drop(listener);
+    let drain = stopping.borrow().unwrap_or_default();
+    let drained = clock::timeout(&*clock, drain, async {
+        while connections.join_next().await.is_some() {}
+    });
+    if drained.await.is_err() {
+        debug!(target: targets::PROXY,
+            "Closing {} demo connections still open", connections.len());
+    }
+    connections.shutdown().await;
+}
+
+async fn echo(mut stream: TcpStream) -> io::Result<()> {
+    let (mut rx, mut tx) = stream.split();
+    tokio::io::copy(&mut rx, &mut tx).await?;
+    tx.shutdown().await
+}
+
+async fn answer(mut stream: TcpStream, request: u64, port: u16) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 2048];
+    while !head.windows(4).any(|end| end == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut words = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = words.next().unwrap_or("GET");
+    let path = words.next().unwrap_or("/");
+    debug!(target: targets::PROXY, "Demo request #{}: {} {}", request, method, path);
+
+    // The path comes from anyone who found the tunnel
+    let body = PAGE
+        .replace("{request}", &request.to_string())
+        .replace("{method}", &escape(method))
+        .replace("{path}", &escape(path))
+        .replace("{port}", &port.to_string());
+    let response = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         Server: reverse-ssh-demo\r\n\
+         \r\n\
+         {}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>Reverse SSH Test Server</title>
+    <style>
+        body { font-family: -apple-system, "Segoe UI", Roboto, sans-serif; max-width: 800px;
+               margin: 50px auto; padding: 20px; color: white;
+               background: linear-gradient(135deg, #667eea 0%, #764ba2 100%); }
+        .container { background: rgba(255, 255, 255, 0.1); border-radius: 10px; padding: 30px; }
+        .info { background: rgba(0, 0, 0, 0.2); padding: 15px; border-radius: 5px;
+                font-family: monospace; }
+        .success { background: rgba(76, 175, 80, 0.3); padding: 10px;
+                   border-left: 4px solid #4CAF50; margin: 20px 0; }
+    </style>
+</head>
+<body>
+    <div class="container">
+        <h1>Reverse SSH Tunnel Working!</h1>
+        <div class="success">
+            Connection successful! Your reverse SSH tunnel is functioning correctly.
+        </div>
+        <div class="info">
+            <strong>Request #{request}</strong><br>
+            Method: {method}<br>
+            Path: {path}<br>
+            Server: localhost:{port}
+        </div>
+        <p>This page is served from localhost:{port} and reached through the tunnel.
+        Replace it with your application once you are done testing.</p>
+    </div>
+</body>
+</html>
+"#;
+
+/// A [`DemoServer`] exposed through a tunnel, started by [`expose_demo`]
+pub struct Demo {
+    handle: ClientHandle,
+    local_addr: SocketAddr,
+    /// Runs the tunnel, then stops the service
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl Demo {
+    /// Handle of the tunnel, for its URL, stats and events
+    pub fn handle(&self) -> &ClientHandle {
+        &self.handle
+    }
+
+    /// The tunnel's public URL, if the server announced one
+    pub fn url(&self) -> Option<String> {
+        self.handle.url()
+    }
+
+    /// The address the demo service listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop both: give the tunnel's connections up to `drain` to finish, disconnect,
+    /// and stop the service. Returns the error the tunnel stopped with, if any.
+    pub async fn shutdown(mut self, drain: Duration) -> Result<()> {
+        if !self.handle.drain(drain).await {
+            debug!(target: targets::SESSION,
+                "Closing {} demo connections still open",
+                self.handle.stats().active_connections
+            );
+        }
+        let disconnected = self.handle.shutdown().await;
+        self.wait_task().await?;
+        disconnected
+    }
+
+    /// Wait for the tunnel to stop on its own, e.g. when the session fails, with
+    /// the service stopped along with it
+    pub async fn wait(mut self) -> Result<()> {
+        self.wait_task().await
+    }
+
+    async fn wait_task(&mut self) -> Result<()> {
+        match self.task.take() {
+            Some(task) => task.await.context("Demo tunnel task failed")?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Demo {
+    fn drop(&mut self) {
+        // The tunnel winds down in the background, taking the service with it
+        if self.task.is_some() {
+            self.handle.shared.shutdown.send_replace(true);
+        }
+    }
+}
+
+/// Serve `service` on a free local port and open a tunnel to it with `config`,
+/// whose local target is replaced. Returns once the tunnel is ready; if it fails to
+/// come up, the service is stopped and the error returned.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use reverse_ssh::{expose_demo, DemoService, Provider, ReverseSshConfig};
+///
+/// let config = ReverseSshConfig::for_provider(Provider::LocalhostRun);
+/// let demo = expose_demo(config, DemoService::Http).await?;
+/// println!("Try {}", demo.url().unwrap_or_default());
+/// tokio::signal::ctrl_c().await?;
+/// demo.shutdown(std::time::Duration::from_secs(5)).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn expose_demo(mut config: ReverseSshConfig, service: DemoService) -> Result<Demo> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let server = DemoServer::start_with_clock(service, addr, config.clock.clone()).await?;
+    let local_addr = server.local_addr();
+    config.local_addr = local_addr.ip().to_string();
+    config.local_port = local_addr.port();
+    config.local_socket = None;
+
+    let mut client = ReverseSshClient::new(config);
+    let handle = client.handle();
+    let mut events = handle.subscribe();
+    let mut task = tasks::spawn("demo tunnel", async move {
+        let result = client.run().await;
+        server.stop(STOP_GRACE).await;
+        result
+    });
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(TunnelEvent::StateChanged { to: TunnelState::Ready, .. }) => break,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            result = &mut task => {
+                result.context("Demo tunnel task failed")??;
+                bail!("The tunnel stopped before it was ready");
+            }
+        }
+    }
+    Ok(Demo {
+        handle,
+        local_addr,
+        task: Some(task),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Connecting, Network, TcpNetwork};
+    use crate::sim;
+
+    /// The SSH server on a [`sim::SimNetwork`], the demo service on the machine's
+    #[derive(Debug)]
+    struct Mixed(sim::SimNetwork);
+
+    impl Network for Mixed {
+        fn connect(&self, host: &str, port: u16) -> Connecting {
+            match host {
+                "ssh.sim" => self.0.connect(host, port),
+                _ => TcpNetwork.connect(host, port),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_demo_starts_and_stops_with_the_tunnel() {
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let config = |remote_port| ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port,
+            network: Arc::new(Mixed(network.clone())),
+            ..Default::default()
+        };
+
+        let demo = expose_demo(config(8000), DemoService::Echo).await.unwrap();
+        let mut peer = network.dial("ssh.sim", 8000).await.unwrap();
+        peer.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        peer.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        drop(peer);
+        let local_addr = demo.local_addr();
+        demo.shutdown(Duration::from_secs(1)).await.unwrap();
+        assert!(TcpStream::connect(local_addr).await.is_err());
+
+        let demo = expose_demo(config(8001), DemoService::Http).await.unwrap();
+        let mut peer = network.dial("ssh.sim", 8001).await.unwrap();
+        peer.write_all(b"GET /<script> HTTP/1.1\r\nHost: demo\r\n\r\n")
+            .await
+            .unwrap();
+        let mut page = String::new();
+        peer.read_to_string(&mut page).await.unwrap();
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains("Path: /&lt;script&gt;<br>"));
+        // Dropped, the demo stops all the same
+        let local_addr = demo.local_addr();
+        drop(demo);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(TcpStream::connect(local_addr).await.is_err());
+
+        // Nothing listens for SSH there, so the service doesn't outlive the attempt
+        let unreachable = ReverseSshConfig {
+            server_port: 2222,
+            ..config(8002)
+        };
+        assert!(expose_demo(unreachable, DemoService::Echo).await.is_err());
+    }
+}
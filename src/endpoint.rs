@@ -0,0 +1,178 @@
+//! Probing the tunnel's public URL from outside
+//!
+//! Keepalives and forwarded connections only show that the SSH side works; the
+//! provider's edge can still fail to route requests to the tunnel. With
+//! [`ReverseSshConfig::endpoint_probe`](crate::ReverseSshConfig::endpoint_probe) set, a
+//! monitor task requests the public URL as soon as the provider announces it and then
+//! every [`EndpointProbe::interval`], and reports an edge that stops answering as
+//! [`TunnelEvent::EndpointUnreachable`](crate::TunnelEvent::EndpointUnreachable).
+//!
+//! The client has no TLS stack, so the built-in probe requests the `http://` form of
+//! the URL, which providers answer or redirect to `https://`. An
+//! [`EndpointChecker`] can do the request instead, with an HTTP client of the
+//! application's or through an external monitoring service.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::net::TcpStream;
+
+use crate::clock::{self, Clock};
+use crate::health::get_status;
+
+type CheckFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Hook checking that the public URL it is given answers
+#[derive(Clone)]
+pub struct EndpointChecker(Arc<dyn Fn(String) -> CheckFuture + Send + Sync>);
+
+impl EndpointChecker {
+    pub fn new<F, Fut>(check: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Self(Arc::new(move |url| Box::pin(check(url))))
+    }
+}
+
+impl fmt::Debug for EndpointChecker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EndpointChecker(..)")
+    }
+}
+
+/// Periodic requests to the tunnel's public URL
+#[derive(Debug, Clone)]
+pub struct EndpointProbe {
+    /// Path requested on the public URL
+    pub path: String,
+    /// Time between probes
+    pub interval: Duration,
+    /// Time a probe may take before it counts as failed
+    pub timeout: Duration,
+    /// Consecutive failed probes before the endpoint is reported unreachable
+    pub unreachable_threshold: u32,
+    /// Checks the URL in place of the built-in request
+    pub checker: Option<EndpointChecker>,
+}
+
+impl EndpointProbe {
+    /// Request `path` on the public URL every minute
+    pub fn http(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+            unreachable_threshold: 2,
+            checker: None,
+        }
+    }
+
+    /// Check the public URL with `checker` every minute
+    pub fn with_checker(checker: EndpointChecker) -> Self {
+        Self {
+            checker: Some(checker),
+            ..Self::http("/")
+        }
+    }
+
+    /// Probe the public `url` once, for at most `timeout` on `clock`
+    pub(crate) async fn probe(&self, url: &str, clock: &dyn Clock) -> Result<()> {
+        let probe = async {
+            match &self.checker {
+                Some(checker) => (checker.0)(url.to_string()).await,
+                None => self.request(url).await,
+            }
+        };
+        clock::timeout(clock, self.timeout, probe)
+            .await
+            .with_context(|| format!("Endpoint probe timed out after {:?}", self.timeout))?
+    }
+
+    /// `GET path` on the plain HTTP form of `url`, expecting a 2xx or 3xx status
+    async fn request(&self, url: &str) -> Result<()> {
+        let (host, port) = http_authority(url)?;
+        let stream = TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("Failed to connect to {}:{}", host, port))?;
+        let (mut rx, mut tx) = stream.into_split();
+        let status = get_status(&mut rx, &mut tx, host, &self.path).await?;
+        if !(200..400).contains(&status) {
+            bail!("GET {} on {} answered {}", self.path, host, status);
+        }
+        Ok(())
+    }
+}
+
+/// Host and port to reach `url` over plain HTTP: its own port if it has one, 80
+/// otherwise
+fn http_authority(url: &str) -> Result<(&str, u16)> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => {
+            let port = port
+                .parse()
+                .with_context(|| format!("Invalid port in tunnel URL {:?}", url))?;
+            Ok((host, port))
+        }
+        _ if !authority.is_empty() => Ok((authority, 80)),
+        _ => bail!("No host in tunnel URL {:?}", url),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SystemClock;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_endpoint_probe() {
+        assert_eq!(
+            http_authority("https://8d3c1a.lhr.life/").unwrap(),
+            ("8d3c1a.lhr.life", 80)
+        );
+        assert_eq!(
+            http_authority("http://tuns.sh:8080/x").unwrap(),
+            ("tuns.sh", 8080)
+        );
+        assert!(http_authority("https://").is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for status in ["301 Moved Permanently", "502 Bad Gateway"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let url = format!("https://127.0.0.1:{}", port);
+        let probe = EndpointProbe::http("/");
+        probe.probe(&url, &SystemClock).await.unwrap();
+        let error = probe.probe(&url, &SystemClock).await.unwrap_err();
+        assert_eq!(error.to_string(), "GET / on 127.0.0.1 answered 502");
+
+        let probe = EndpointProbe::with_checker(EndpointChecker::new(|url| async move {
+            match url.ends_with(".lhr.life") {
+                true => Ok(()),
+                false => bail!("unexpected URL {}", url),
+            }
+        }));
+        let clock = SystemClock;
+        probe
+            .probe("https://8d3c1a.lhr.life", &clock)
+            .await
+            .unwrap();
+        assert!(probe.probe("https://example.com", &clock).await.is_err());
+    }
+}
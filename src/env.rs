@@ -0,0 +1,200 @@
+//! A tunnel configured from `RRP_*` environment variables
+//!
+//! Containers and CI jobs configure through the environment rather than files.
+//! [`ReverseSshConfig::from_env`](crate::ReverseSshConfig::from_env) reads one tunnel
+//! from the variables below, with the meaning and defaults of the profiles file field
+//! of the same name (see [`Profile`](crate::Profile)). Empty variables count as unset.
+//!
+//! | Variable | Profile field |
+//! |---|---|
+//! | `RRP_PROVIDER`, `RRP_DOMAIN` | `provider`, `domain` |
+//! | `RRP_SERVER`, `RRP_PORT`, `RRP_USER` | `server`, `port`, `user` |
+//! | `RRP_KEY`, `RRP_KEY_ENV`, `RRP_PASSWORD`, `RRP_AGENT` | `key_file`, `key_env`, `password`, `agent` |
+//! | `RRP_PROXY`, `RRP_JUMP`, `RRP_HOST_KEY_FINGERPRINT` | `proxy`, jump hosts as `ssh -J` takes them, `host_key_fingerprint` |
+//! | `RRP_REMOTE_PORT`, `RRP_LOCAL_ADDR`, `RRP_LOCAL_PORT`, `RRP_LOCAL_SOCKET` | `remote_port`, `local_addr`, `local_port`, `local_socket` |
+//! | `RRP_UDP`, `RRP_PRESET`, `RRP_WIRE_GATE`, `RRP_JSON` | `udp`, `preset`, `wire_gate`, `json` |
+//! | `RRP_KEEPALIVE_INTERVAL`, `RRP_KEEPALIVE_COUNT_MAX`, `RRP_IDLE_KEEPALIVE` | `keepalive_interval`, `keepalive_count_max`, `idle_keepalive` |
+//!
+//! `RRP_LOCAL_PORT` defaults to 8080, as with `rrp run`. Flags take `1`, `true`,
+//! `yes` or `on`, and `0`, `false`, `no` or `off`. Any other `RRP_` variable is
+//! refused, so a misspelled one doesn't silently go unused.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+use crate::profiles::Entry;
+use crate::{JumpHost, ReverseSshConfig};
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Text,
+    /// A number up to `max`
+    Number(u64),
+    Flag,
+}
+
+/// The variables read; each sets the profile field named like it without `RRP_`
+const VARIABLES: &[(&str, Kind)] = &[
+    ("RRP_PROVIDER", Kind::Text),
+    ("RRP_DOMAIN", Kind::Text),
+    ("RRP_SERVER", Kind::Text),
+    ("RRP_PORT", Kind::Number(u16::MAX as u64)),
+    ("RRP_USER", Kind::Text),
+    ("RRP_KEY", Kind::Text),
+    ("RRP_KEY_ENV", Kind::Text),
+    ("RRP_PASSWORD", Kind::Text),
+    ("RRP_AGENT", Kind::Flag),
+    ("RRP_PROXY", Kind::Text),
+    ("RRP_HOST_KEY_FINGERPRINT", Kind::Text),
+    ("RRP_REMOTE_PORT", Kind::Number(u32::MAX as u64)),
+    ("RRP_LOCAL_ADDR", Kind::Text),
+    ("RRP_LOCAL_PORT", Kind::Number(u16::MAX as u64)),
+    ("RRP_LOCAL_SOCKET", Kind::Text),
+    ("RRP_UDP", Kind::Flag),
+    ("RRP_PRESET", Kind::Text),
+    ("RRP_WIRE_GATE", Kind::Text),
+    ("RRP_JSON", Kind::Flag),
+    ("RRP_KEEPALIVE_INTERVAL", Kind::Number(u64::MAX)),
+    ("RRP_KEEPALIVE_COUNT_MAX", Kind::Number(u32::MAX as u64)),
+    ("RRP_IDLE_KEEPALIVE", Kind::Number(u64::MAX)),
+];
+
+/// Read with `ssh -J` syntax rather than as a profile field
+const JUMP: &str = "RRP_JUMP";
+
+/// The configuration set by the `RRP_*` variables among `vars`
+pub(crate) fn config_from_vars<I>(vars: I) -> Result<ReverseSshConfig>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut entry = Map::new();
+    let mut jump = None;
+    for (name, value) in vars {
+        if !name.starts_with("RRP_") || value.is_empty() {
+            continue;
+        }
+        if name == JUMP {
+            let hosts =
+                JumpHost::parse_list(&value).with_context(|| format!("Invalid {}", name))?;
+            jump = Some(hosts);
+            continue;
+        }
+        let Some(&(_, kind)) = VARIABLES.iter().find(|(var, _)| *var == name) else {
+            bail!("Unknown variable {}", name);
+        };
+        let field = match &name["RRP_".len()..] {
+            // The key is always a file here; `RRP_KEY_ENV` names a variable holding one
+            "KEY" => "key_file".to_string(),
+            field => field.to_lowercase(),
+        };
+        entry.insert(field, parse(&name, &value, kind)?);
+    }
+    if !["local_port", "local_socket", "preset"]
+        .iter()
+        .any(|field| entry.contains_key(*field))
+    {
+        entry.insert("local_port".to_string(), Value::from(8080));
+    }
+    let entry: Entry = serde_json::from_value(Value::Object(entry))?;
+    let mut config = entry
+        .into_profile("env", Path::new(""))
+        .context("Invalid configuration in RRP_* variables")?
+        .config;
+    if let Some(jump) = jump {
+        config.jump_hosts = jump;
+    }
+    Ok(config)
+}
+
+fn parse(name: &str, value: &str, kind: Kind) -> Result<Value> {
+    Ok(match kind {
+        Kind::Text => Value::from(value),
+        Kind::Number(max) => {
+            let number: u64 = value
+                .trim()
+                .parse()
+                .ok()
+                .filter(|number| *number <= max)
+                .with_context(|| {
+                    format!("{} must be a number up to {}, not {:?}", name, max, value)
+                })?;
+            Value::from(number)
+        }
+        Kind::Flag => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Value::Bool(true),
+            "0" | "false" | "no" | "off" => Value::Bool(false),
+            _ => bail!("{} must be true or false, not {:?}", name, value),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AuthMethod;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let config = config_from_vars(vars(&[
+            ("HOME", "/home/deploy"),
+            ("RRP_SERVER", "tunnel.example.com"),
+            ("RRP_PORT", "2222"),
+            ("RRP_USER", "deploy"),
+            ("RRP_KEY", "keys/id_ed25519"),
+            ("RRP_AGENT", "yes"),
+            ("RRP_JUMP", "bastion.corp,relay:23"),
+            ("RRP_REMOTE_PORT", "5432"),
+            ("RRP_PROXY", ""),
+        ]))
+        .unwrap();
+        assert_eq!(config.server_addr, "tunnel.example.com");
+        assert_eq!(config.server_port, 2222);
+        assert_eq!(config.username, "deploy");
+        assert_eq!(config.key_path.as_deref(), Some("keys/id_ed25519"));
+        assert!(matches!(
+            config.auth.as_slice(),
+            [AuthMethod::Agent, AuthMethod::KeyFile(_)]
+        ));
+        assert_eq!(config.jump_hosts.len(), 2);
+        assert_eq!(config.remote_port, 5432);
+        assert_eq!(config.local_port, 8080);
+        assert!(config.proxy.is_none());
+
+        let config = config_from_vars(vars(&[
+            ("RRP_PROVIDER", "serveo"),
+            ("RRP_DOMAIN", "myapp"),
+            ("RRP_PRESET", "postgres"),
+        ]))
+        .unwrap();
+        assert_eq!(config.server_addr, "serveo.net");
+        assert_eq!(config.bind_address, "myapp");
+        assert_eq!(config.local_port, 5432);
+
+        let error = |pairs| config_from_vars(vars(pairs)).unwrap_err().to_string();
+        assert_eq!(
+            error(&[("RRP_LOCAL_PORT", "70000")]),
+            "RRP_LOCAL_PORT must be a number up to 65535, not \"70000\""
+        );
+        assert_eq!(
+            error(&[("RRP_UDP", "maybe")]),
+            "RRP_UDP must be true or false, not \"maybe\""
+        );
+        assert_eq!(
+            error(&[("RRP_REMOTEPORT", "80")]),
+            "Unknown variable RRP_REMOTEPORT"
+        );
+        assert_eq!(
+            error(&[("RRP_DOMAIN", "myapp")]),
+            "Invalid configuration in RRP_* variables"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Deduplicated logging of repeated errors
+//!
+//! While the local service is down, every forwarded connection fails the same way,
+//! and logging each failure buries everything else. Within
+//! [`error_log_window`](crate::ReverseSshConfig::error_log_window), an error is
+//! logged the first time it happens; its repeats are only counted, in
+//! `errors_suppressed_total`, and summarised in one line once the window ends. The
+//! [`on_error`](crate::on_error) hook and the status still get every error.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::metrics::Metrics;
+use crate::{targets, Clock};
+
+struct Repeats {
+    since: Instant,
+    count: u64,
+}
+
+/// Errors logged in the current windows, by message
+pub(crate) struct ErrorLog {
+    clock: Arc<dyn Clock>,
+    window: Option<Duration>,
+    seen: Mutex<HashMap<String, Repeats>>,
+}
+
+impl ErrorLog {
+    pub(crate) fn new(window: Option<Duration>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn window(&self) -> Option<Duration> {
+        self.window
+    }
+
+    /// Whether an error logged as `message` is the first of its window and should
+    /// be logged; if not, it is counted as a repeat
+    pub(crate) fn first(&self, message: &str, metrics: &Metrics) -> bool {
+        let Some(window) = self.window else {
+            return true;
+        };
+        let now = self.clock.now();
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(repeats) = seen.get_mut(message) {
+            if now.saturating_duration_since(repeats.since) < window {
+                repeats.count += 1;
+                metrics.increment("errors_suppressed_total", 1);
+                return false;
+            }
+        }
+        let previous = seen.insert(
+            message.to_string(),
+            Repeats {
+                since: now,
+                count: 0,
+            },
+        );
+        if let Some(previous) = previous {
+            summarize(message, &previous, window);
+        }
+        true
+    }
+
+    /// Summarise the repeats of windows that ended, forgetting their errors
+    pub(crate) fn flush(&self) {
+        let Some(window) = self.window else {
+            return;
+        };
+        let now = self.clock.now();
+        self.seen.lock().unwrap().retain(|message, repeats| {
+            if now.saturating_duration_since(repeats.since) < window {
+                return true;
+            }
+            summarize(message, repeats, window);
+            false
+        });
+    }
+}
+
+fn summarize(message: &str, repeats: &Repeats, window: Duration) {
+    if repeats.count > 0 {
+        error!(target: targets::PROXY,
+            "{} (repeated {} more times in {}s)", message, repeats.count, window.as_secs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_repeated_errors_are_counted_once_per_window() {
+        let clock = Arc::new(ManualClock::new());
+        let log = ErrorLog::new(Some(Duration::from_secs(30)), clock.clone());
+        let metrics = Metrics::default();
+        let refused = "Error handling connection: Failed to connect to local service";
+
+        assert!(log.first(refused, &metrics));
+        assert!((0..300).all(|_| !log.first(refused, &metrics)));
+        assert!(log.first("Error reading from local service: reset", &metrics));
+        assert_eq!(metrics.snapshot().counter("errors_suppressed_total"), 300);
+
+        // A new window logs the error again; flushing forgets those that stopped
+        clock.advance(Duration::from_secs(30));
+        assert!(log.first(refused, &metrics));
+        clock.advance(Duration::from_secs(30));
+        log.flush();
+        assert!(log.seen.lock().unwrap().is_empty());
+
+        let unlimited = ErrorLog::new(None, clock);
+        assert!((0..3).all(|_| unlimited.first(refused, &metrics)));
+    }
+}
@@ -0,0 +1,302 @@
+//! Events emitted while a tunnel runs
+//!
+//! Subscribers get a [`tokio::sync::broadcast`] receiver from
+//! [`ClientHandle::subscribe`](crate::ClientHandle::subscribe), or an [`EventStream`]
+//! from [`ClientHandle::events`](crate::ClientHandle::events). Events are dropped when
+//! nobody is subscribed, and a subscriber that falls more than [`EVENT_CAPACITY`]
+//! events behind loses the oldest ones.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::handle::ConnectionInfo;
+use crate::provider::{ProviderError, TunnelInfo};
+use crate::reconfig::ConfigDiff;
+use crate::security::SecurityEvent;
+use crate::session_id::SessionId;
+use crate::status::TunnelState;
+use crate::targets;
+use crate::timeline::StartupPhase;
+use crate::url::TunnelUrl;
+
+/// Events buffered per subscriber
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened in the lifetime of a tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TunnelEvent {
+    /// `connect()` started the session `session` with `server` (`host:port`); the
+    /// events up to the next `Connecting` belong to it
+    Connecting { server: String, session: SessionId },
+    /// The server accepted the named [`AuthMethod`](crate::AuthMethod)
+    Authenticated { method: &'static str },
+    /// The server listens on `port` for the tunnel. `requested` is the port that was
+    /// asked for; when it is 0, `port` is the one the server picked.
+    ForwardEstablished { port: u32, requested: u32 },
+    /// The server refused the [`optional`](crate::Forward::optional) forward of
+    /// `port`; the tunnel goes on without it
+    ForwardRefused { port: u32, error: String },
+    /// The provider announced a public URL different from the last one
+    UrlReceived { url: String },
+    /// A forwarded connection from `originator` (`address:port`) was accepted
+    ConnectionOpened { id: u64, originator: String },
+    /// The session ended; `error` says why if it failed
+    Disconnected { error: Option<String> },
+    /// A [`RestartPolicy`](crate::RestartPolicy) will start a new session after `delay`
+    Reconnecting { delay: Duration },
+    /// A startup phase completed, `elapsed` after `connect()` was called
+    StartupPhase {
+        phase: StartupPhase,
+        elapsed: Duration,
+    },
+    /// The tunnel moved to another lifecycle state
+    StateChanged { from: TunnelState, to: TunnelState },
+    /// The provider announced the tunnel URL, or new details about it
+    TunnelInfo(TunnelInfo),
+    /// The [`UrlDetector`](crate::UrlDetector) found the tunnel's URLs, or more of them
+    TunnelUrl(TunnelUrl),
+    /// The provider assigned a different URL than before, mid-session or after a
+    /// reconnect; webhooks registered with `old` should move to `new`
+    UrlChanged { old: String, new: String },
+    /// The provider offers a web console for the tunnel at `url`, such as sish's
+    /// service and admin consoles. The URL usually carries an access token.
+    ConsoleUrl { url: String },
+    /// The provider assigned the tunnel a domain, e.g. localhost.run in its JSON
+    /// output; `custom` when it is the custom domain requested as the bind address
+    DomainAssigned { domain: String, custom: bool },
+    /// The provider refused the tunnel; the session is shut down and `run()` returns
+    /// the same error
+    ProviderError(ProviderError),
+    /// A forwarded connection ended; `stats` has its originator, duration and bytes
+    ConnectionClosed {
+        id: u64,
+        reason: CloseReason,
+        stats: ConnectionInfo,
+    },
+    /// Health checks found the local target up or down; `error` is the failed
+    /// probe that marked it down
+    TargetHealthChanged {
+        healthy: bool,
+        error: Option<String>,
+    },
+    /// The [`EndpointProbe`](crate::EndpointProbe) failed to reach the public `url`
+    /// several times in a row, though the SSH session is up; `error` is the last
+    /// failure
+    EndpointUnreachable { url: String, error: String },
+    /// The public `url` answers again after being unreachable
+    EndpointReachable { url: String },
+    /// The [`TrafficQuota`](crate::TrafficQuota) is used up: `used` bytes were relayed
+    /// this period, against a `limit`. Its action is taken right after.
+    QuotaExhausted { used: u64, limit: u64 },
+    /// A new quota period started, and connections are accepted again
+    QuotaReset,
+    /// The process ran out of file descriptors (`open_fds` of `limit` open, where the
+    /// platform tells); new connections are shed for `backoff`
+    FdExhausted {
+        open_fds: Option<usize>,
+        limit: Option<u64>,
+        backoff: Duration,
+    },
+    /// This many server messages were dropped because the message handler fell
+    /// behind and the [`message_queue`](crate::ReverseSshConfig::message_queue) was
+    /// full. Sent once the queue has room again, or when the session ends.
+    MessagesDropped(u64),
+    /// A new configuration was applied; the session is rebuilt when
+    /// `diff.requires_reconnect()`
+    Reconfigured { diff: ConfigDiff },
+}
+
+/// Why a forwarded connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// Either side closed it
+    Completed,
+    /// Relaying failed, e.g. the local service refused the connection
+    Failed,
+    /// Its deadline, set with
+    /// [`ClientHandle::set_connection_deadline`](crate::ClientHandle::set_connection_deadline),
+    /// passed
+    DeadlineExceeded,
+    /// No data flowed either way for its
+    /// [`max_idle`](crate::ConnectionBudget::max_idle)
+    IdleTimeout,
+    /// The [`TrafficQuota`](crate::TrafficQuota) was used up
+    QuotaExhausted,
+    /// Its first bytes didn't match the forward's
+    /// [`wire_gate`](crate::Forward::wire_gate)
+    Rejected,
+    /// Shed because the client ran out of file descriptors
+    Overloaded,
+    /// Refused because its forward already had as many connections open as its
+    /// [`ConnectionBudget`](crate::ConnectionBudget) allows, or because the client
+    /// was at its [`max_connections`](crate::ReverseSshConfig::max_connections)
+    LimitReached,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Completed => "completed",
+            CloseReason::Failed => "failed",
+            CloseReason::DeadlineExceeded => "deadline exceeded",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::QuotaExhausted => "quota exhausted",
+            CloseReason::Rejected => "rejected",
+            CloseReason::Overloaded => "overloaded",
+            CloseReason::LimitReached => "connection limit reached",
+        })
+    }
+}
+
+/// Sending side of the event streams
+#[derive(Debug)]
+pub(crate) struct Events {
+    tx: broadcast::Sender<TunnelEvent>,
+    security: broadcast::Sender<SecurityEvent>,
+    /// ID of the current session, stamped on security events
+    session: Mutex<Option<SessionId>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_CAPACITY).0,
+            security: broadcast::channel(EVENT_CAPACITY).0,
+            session: Mutex::new(None),
+        }
+    }
+}
+
+impl Events {
+    pub(crate) fn emit(&self, event: TunnelEvent) {
+        // Sending only fails when there are no subscribers
+        let _ = self.tx.send(event);
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Start the session `id`, announcing it with a `Connecting` event
+    pub(crate) fn begin_session(&self, id: SessionId, server: String) {
+        *self.session.lock().unwrap() = Some(id);
+        self.emit(TunnelEvent::Connecting {
+            server,
+            session: id,
+        });
+    }
+
+    /// ID of the current session, if one started
+    pub(crate) fn session(&self) -> Option<SessionId> {
+        *self.session.lock().unwrap()
+    }
+
+    /// Log a security event and send it to the security stream
+    pub(crate) fn emit_security(&self, mut event: SecurityEvent) {
+        event.session = self.session();
+        warn!(target: targets::SECURITY, "{}", event);
+        let _ = self.security.send(event);
+    }
+
+    pub(crate) fn subscribe_security(&self) -> broadcast::Receiver<SecurityEvent> {
+        self.security.subscribe()
+    }
+}
+
+type Recv<T> = Pin<
+    Box<
+        dyn Future<
+                Output = (
+                    Result<T, broadcast::error::RecvError>,
+                    broadcast::Receiver<T>,
+                ),
+            > + Send,
+    >,
+>;
+
+/// [`TunnelEvent`]s, or [`SecurityEvent`]s, as a [`Stream`](futures_core::Stream).
+/// Events missed by falling behind are skipped; the stream ends when the client is
+/// dropped.
+pub struct EventStream<T = TunnelEvent> {
+    recv: Recv<T>,
+}
+
+impl<T: Clone + Send + 'static> EventStream<T> {
+    pub(crate) fn new(rx: broadcast::Receiver<T>) -> Self {
+        Self {
+            recv: Self::next(rx),
+        }
+    }
+
+    fn next(mut rx: broadcast::Receiver<T>) -> Recv<T> {
+        Box::pin(async move { (rx.recv().await, rx) })
+    }
+}
+
+impl<T: Clone + Send + 'static> futures_core::Stream for EventStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        loop {
+            let (result, rx) = match self.recv.as_mut().poll(cx) {
+                Poll::Ready(ready) => ready,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.recv = Self::next(rx);
+            match result {
+                Ok(event) => return Poll::Ready(Some(event)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for EventStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventStream")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_core::Stream;
+
+    async fn next(stream: &mut EventStream) -> Option<TunnelEvent> {
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let events = Events::default();
+        let mut stream = EventStream::new(events.subscribe());
+        events.emit(TunnelEvent::ForwardEstablished {
+            port: 80,
+            requested: 80,
+        });
+        events.emit(TunnelEvent::Disconnected { error: None });
+
+        assert_eq!(
+            next(&mut stream).await,
+            Some(TunnelEvent::ForwardEstablished {
+                port: 80,
+                requested: 80
+            })
+        );
+        assert_eq!(
+            next(&mut stream).await,
+            Some(TunnelEvent::Disconnected { error: None })
+        );
+        drop(events);
+        assert_eq!(next(&mut stream).await, None);
+    }
+}
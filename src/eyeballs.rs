@@ -0,0 +1,249 @@
+//! Dialing a server with several addresses (Happy Eyeballs, RFC 8305)
+//!
+//! A name with both IPv6 and IPv4 addresses stalls connects on hosts whose IPv6 is
+//! broken: trying the addresses one after the other waits out a TCP timeout on each
+//! unreachable one. Instead, the addresses are interleaved by family, the preferred
+//! one first, and raced: a new attempt starts every
+//! [`connection_attempt_delay`](crate::ReverseSshConfig::connection_attempt_delay),
+//! or as soon as the last one failed, and the first connection made wins.
+
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::task::Poll;
+
+use tracing::debug;
+
+use crate::network::{Connecting, Connection};
+use crate::{targets, ReverseSshConfig};
+
+/// The IP versions the SSH server is dialed over, and which goes first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Both, IPv6 addresses first
+    #[default]
+    PreferIpv6,
+    /// Both, IPv4 addresses first
+    PreferIpv4,
+    /// IPv6 addresses only
+    Ipv6Only,
+    /// IPv4 addresses only
+    Ipv4Only,
+}
+
+impl AddressFamily {
+    /// Look up a preference by name: `prefer-ipv6`, `prefer-ipv4`, `ipv6` or `ipv4`
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "prefer-ipv6" => Some(Self::PreferIpv6),
+            "prefer-ipv4" => Some(Self::PreferIpv4),
+            "ipv6" => Some(Self::Ipv6Only),
+            "ipv4" => Some(Self::Ipv4Only),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PreferIpv6 => "prefer-ipv6",
+            Self::PreferIpv4 => "prefer-ipv4",
+            Self::Ipv6Only => "ipv6",
+            Self::Ipv4Only => "ipv4",
+        }
+    }
+
+    /// Whether the server may be dialed at `ip`
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::PreferIpv6 | Self::PreferIpv4 => true,
+            Self::Ipv6Only => ip.is_ipv6(),
+            Self::Ipv4Only => ip.is_ipv4(),
+        }
+    }
+
+    /// The allowed `addresses` in the order they are tried: alternating families,
+    /// the preferred one first, each in the order the resolver gave
+    fn order(&self, addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (ipv6, ipv4): (Vec<_>, Vec<_>) = addresses
+            .into_iter()
+            .filter(|address| self.allows(address.ip()))
+            .partition(SocketAddr::is_ipv6);
+        let (first, second) = match self {
+            Self::PreferIpv4 => (ipv4, ipv6),
+            _ => (ipv6, ipv4),
+        };
+        let (mut first, mut second) = (first.into_iter(), second.into_iter());
+        let mut ordered = Vec::new();
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => return ordered,
+                (a, b) => ordered.extend(a.into_iter().chain(b)),
+            }
+        }
+    }
+}
+
+/// Connect to the SSH server at `host:port` on the configured network, racing its
+/// addresses
+pub(crate) async fn connect(
+    config: &ReverseSshConfig,
+    host: &str,
+    port: u16,
+) -> io::Result<Box<dyn Connection>> {
+    let network = &*config.network;
+    let addresses = network.resolve(host, port).await?;
+    if addresses.is_empty() {
+        // The network resolves names itself
+        return network.connect(host, port).await;
+    }
+    let mut pending = config.address_family.order(addresses).into_iter();
+    let mut attempts: Vec<(SocketAddr, Connecting)> = Vec::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            let Some(address) = pending.next() else {
+                return Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "{} has no address allowed by {}",
+                            host,
+                            config.address_family.name()
+                        ),
+                    )
+                }));
+            };
+            attempts.push(attempt(config, address));
+        }
+        let finished = tokio::select! {
+            finished = first_finished(&mut attempts) => Some(finished),
+            _ = config.clock.sleep(config.connection_attempt_delay), if pending.len() > 0 => None,
+        };
+        match finished {
+            Some((index, Ok(stream))) => {
+                debug!(target: targets::SESSION, "Connected to {}", attempts[index].0);
+                // Dropping the other attempts cancels them
+                return Ok(stream);
+            }
+            Some((index, Err(e))) => {
+                let (address, _) = attempts.remove(index);
+                debug!(target: targets::SESSION, "Failed to connect to {}: {}", address, e);
+                last_error = Some(e);
+                if let Some(address) = pending.next() {
+                    attempts.push(attempt(config, address));
+                }
+            }
+            None => {
+                let address = pending.next().unwrap();
+                debug!(target: targets::SESSION,
+                    "No connection after {:?}, also trying {}",
+                    config.connection_attempt_delay, address
+                );
+                attempts.push(attempt(config, address));
+            }
+        }
+    }
+}
+
+fn attempt(config: &ReverseSshConfig, address: SocketAddr) -> (SocketAddr, Connecting) {
+    let ip = address.ip().to_string();
+    (address, config.network.connect(&ip, address.port()))
+}
+
+/// The index and outcome of the first of `attempts` to finish
+async fn first_finished(
+    attempts: &mut [(SocketAddr, Connecting)],
+) -> (usize, io::Result<Box<dyn Connection>>) {
+    poll_fn(|cx| {
+        for (index, (_, connecting)) in attempts.iter_mut().enumerate() {
+            if let Poll::Ready(result) = connecting.as_mut().poll(cx) {
+                return Poll::Ready((index, result));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Network, Resolving};
+    use crate::{sim, ReverseSshClient};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// A dual-stack server whose IPv6 address is unreachable, as on a host with
+    /// broken IPv6 routing
+    #[derive(Debug)]
+    struct BrokenIpv6 {
+        network: sim::SimNetwork,
+        dialed: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Network for BrokenIpv6 {
+        fn connect(&self, host: &str, port: u16) -> Connecting {
+            self.dialed.lock().unwrap().push(host.to_string());
+            if host.contains(':') {
+                return Box::pin(std::future::pending());
+            }
+            self.network.connect(host, port)
+        }
+
+        fn resolve(&self, _: &str, port: u16) -> Resolving {
+            let addresses = ["2001:db8::1", "192.0.2.1"]
+                .map(|ip| SocketAddr::new(ip.parse().unwrap(), port))
+                .to_vec();
+            Box::pin(std::future::ready(Ok(addresses)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broken_ipv6_does_not_stall_connect() {
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "192.0.2.1", 22).unwrap();
+        let connect = |address_family| {
+            let dialed = Arc::new(Mutex::new(Vec::new()));
+            let config = ReverseSshConfig {
+                server_addr: "ssh.example.com".to_string(),
+                address_family,
+                connection_attempt_delay: Duration::from_millis(50),
+                // Waiting on the IPv6 address alone would fail the connect
+                connect_timeout: Some(Duration::from_secs(2)),
+                network: Arc::new(BrokenIpv6 {
+                    network: network.clone(),
+                    dialed: dialed.clone(),
+                }),
+                ..Default::default()
+            };
+            async move {
+                let mut client = ReverseSshClient::new(config);
+                let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+                let (message_tx, _messages) = tokio::sync::mpsc::channel(16);
+                client.connect_session(tx, message_tx).await.unwrap();
+                let dialed = dialed.lock().unwrap().clone();
+                dialed
+            }
+        };
+
+        assert_eq!(
+            connect(AddressFamily::PreferIpv6).await,
+            ["2001:db8::1", "192.0.2.1"]
+        );
+        assert_eq!(connect(AddressFamily::PreferIpv4).await, ["192.0.2.1"]);
+        assert_eq!(connect(AddressFamily::Ipv4Only).await, ["192.0.2.1"]);
+
+        let addresses = ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 22))
+            .to_vec();
+        let ordered: Vec<_> = AddressFamily::PreferIpv6
+            .order(addresses)
+            .iter()
+            .map(|address| address.ip().to_string())
+            .collect();
+        assert_eq!(
+            ordered,
+            ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"]
+        );
+    }
+}
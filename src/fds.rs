@@ -0,0 +1,185 @@
+//! Running out of file descriptors
+//!
+//! Under a flood of connections, or with a low `ulimit -n`, connecting to the local
+//! service fails with `EMFILE` (or `ENFILE` when the whole system is out). Trying
+//! again right away fails the same way, so on the first such error the client sheds
+//! new connections for a while instead: HTTP clients get a `503`, others a closed
+//! channel. The pause starts at a second and doubles, up to 30 seconds, while
+//! descriptors stay exhausted, and is reported as a
+//! [`TunnelEvent::FdExhausted`](crate::TunnelEvent::FdExhausted).
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use russh::client::Msg;
+use russh::Channel;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+use crate::Clock;
+
+/// Too many open files in the process, and in the system; the same on Linux, macOS
+/// and the BSDs
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Whether `error` means the process or the system ran out of file descriptors
+pub(crate) fn is_exhaustion(error: &io::Error) -> bool {
+    cfg!(unix) && matches!(error.raw_os_error(), Some(EMFILE | ENFILE))
+}
+
+/// Whether an I/O error in the chain of `error` is an exhaustion
+pub(crate) fn caused_by_exhaustion(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(is_exhaustion)
+}
+
+/// When connections are shed, and for how long the next exhaustion sheds them
+#[derive(Debug)]
+pub(crate) struct FdPressure {
+    backoff: Mutex<Option<Backoff>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    until: Instant,
+    delay: Duration,
+}
+
+impl FdPressure {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            backoff: Mutex::new(None),
+            clock,
+        }
+    }
+
+    /// Record an exhaustion, returning how long connections are shed for if this
+    /// starts a new pause
+    pub(crate) fn exhausted(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        let mut backoff = self.backoff.lock().unwrap();
+        let delay = match *backoff {
+            Some(current) if now < current.until => return None,
+            // Still exhausted right after the last pause
+            Some(current) => (current.delay * 2).min(MAX_BACKOFF),
+            None => FIRST_BACKOFF,
+        };
+        *backoff = Some(Backoff {
+            until: now + delay,
+            delay,
+        });
+        Some(delay)
+    }
+
+    /// Whether new connections are being shed
+    pub(crate) fn shedding(&self) -> bool {
+        self.backoff
+            .lock()
+            .unwrap()
+            .is_some_and(|backoff| self.clock.now() < backoff.until)
+    }
+
+    /// Time left in the current pause
+    pub(crate) fn remaining(&self) -> Duration {
+        self.backoff
+            .lock()
+            .unwrap()
+            .map_or(Duration::ZERO, |backoff| {
+                backoff.until.saturating_duration_since(self.clock.now())
+            })
+    }
+
+    /// A local connection succeeded: the next exhaustion starts over at a second
+    pub(crate) fn recovered(&self) {
+        let mut backoff = self.backoff.lock().unwrap();
+        if backoff.is_some_and(|backoff| self.clock.now() >= backoff.until) {
+            *backoff = None;
+        }
+    }
+}
+
+/// Turn a connection away while descriptors are exhausted, with a `503` when `http`
+pub(crate) async fn shed(channel: &mut Channel<Msg>, http: bool, retry_after: Duration) {
+    if http {
+        let body = "Service temporarily overloaded\n";
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nRetry-After: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            retry_after.as_secs().max(1),
+            body
+        );
+        let mut writer = channel.make_writer();
+        let _ = writer.write_all(response.as_bytes()).await;
+        let _ = writer.flush().await;
+        let _ = channel.eof().await;
+    }
+    let _ = channel.close().await;
+}
+
+/// File descriptors the process has open, where the platform lists them
+pub(crate) fn open_fds() -> Option<usize> {
+    let dir = ["/proc/self/fd", "/dev/fd"]
+        .into_iter()
+        .find_map(|path| std::fs::read_dir(path).ok())?;
+    // Listing the directory takes a descriptor of its own
+    Some(dir.count().saturating_sub(1))
+}
+
+/// The soft limit on open file descriptors (Linux only)
+pub(crate) fn fd_limit() -> Option<u64> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_fd_exhaustion_backoff() {
+        let error = anyhow::Error::new(io::Error::from_raw_os_error(EMFILE))
+            .context("Failed to connect to local service");
+        assert_eq!(caused_by_exhaustion(&error), cfg!(unix));
+        assert!(!caused_by_exhaustion(&anyhow::Error::new(io::Error::from(
+            io::ErrorKind::ConnectionRefused
+        ))));
+
+        let clock = Arc::new(ManualClock::new());
+        let pressure = FdPressure::new(clock.clone());
+        assert!(!pressure.shedding());
+        assert_eq!(pressure.exhausted(), Some(FIRST_BACKOFF));
+        assert!(pressure.shedding());
+        assert_eq!(pressure.remaining(), FIRST_BACKOFF);
+        // Errors from connections already under way don't extend the pause
+        assert_eq!(pressure.exhausted(), None);
+
+        // Exhausted again right after the pause: twice as long
+        clock.advance(FIRST_BACKOFF);
+        assert!(!pressure.shedding());
+        assert_eq!(pressure.exhausted(), Some(FIRST_BACKOFF * 2));
+        clock.advance(FIRST_BACKOFF * 2);
+        pressure.recovered();
+        assert_eq!(pressure.exhausted(), Some(FIRST_BACKOFF));
+
+        if cfg!(target_os = "linux") {
+            assert!(open_fds().unwrap() >= 3);
+            assert!(fd_limit().unwrap() > 0);
+        }
+    }
+}
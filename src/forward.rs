@@ -0,0 +1,354 @@
+//! Remote ports and the local targets they are forwarded to
+//!
+//! One session can carry several forwards. The server tells which port a connection
+//! arrived on, and the client relays it to the local target registered for that port:
+//! a TCP address, or a Unix domain socket as served by gunicorn or php-fpm.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+use crate::network::Network;
+use crate::{targets, udp, ProtocolPreset, ShapingProfile, TraceSampling, WireProtocol};
+
+/// A remote port on the SSH server forwarded to a local address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    /// Port the server listens on, 0 to let the server pick one
+    pub remote_port: u32,
+    /// Address sent with the forward request, empty to let the server choose
+    pub bind_address: String,
+    /// Local address to forward connections to
+    pub local_addr: String,
+    /// Local port to forward connections to
+    pub local_port: u16,
+    /// Unix domain socket to forward connections to, instead of `local_addr` and
+    /// `local_port`
+    pub local_socket: Option<PathBuf>,
+    /// Only relay connections whose first bytes start this protocol's handshake
+    pub wire_gate: Option<WireProtocol>,
+    /// Handle connections as HTTP/1.x exchanges, with the configuration's
+    /// [`http`](crate::ReverseSshConfig::http) settings, instead of relaying raw bytes
+    pub http: bool,
+    /// Copy bytes untouched even though `http` is set, for gRPC and other h2c
+    /// services that HTTP handling would break
+    pub raw: bool,
+    /// The local service speaks UDP: connections carry framed datagrams from a
+    /// [`UdpHelper`](crate::UdpHelper)
+    pub udp: bool,
+    /// Limits on this forward's connections, in place of the configuration's
+    /// [`connection_budget`](crate::ReverseSshConfig::connection_budget)
+    pub budget: ConnectionBudget,
+    /// Keep this forward's quiet connections alive after this long, in place of the
+    /// configuration's [`idle_keepalive`](crate::ReverseSshConfig::idle_keepalive)
+    pub idle_keepalive: Option<Duration>,
+    /// Link conditions simulated on this forward's connections, switchable at runtime
+    /// with [`ClientHandle::set_shaping`](crate::ClientHandle::set_shaping)
+    pub shaping: Option<ShapingProfile>,
+    /// Connections whose log lines are kept, in place of the configuration's
+    /// [`trace_sampling`](crate::ReverseSshConfig::trace_sampling)
+    pub sampling: Option<TraceSampling>,
+    /// A server refusing this forward is reported as
+    /// [`TunnelEvent::ForwardRefused`](crate::TunnelEvent::ForwardRefused) and the
+    /// tunnel goes on with the other forwards, instead of failing `run()`
+    pub optional: bool,
+    /// Request this forward only once another one is acknowledged
+    pub after: Option<StartAfter>,
+}
+
+/// The forward a [`Forward`] waits for before it is requested. Some providers tie
+/// the account or session context to the first forward, so later ones fail until
+/// it is set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartAfter {
+    /// `remote_port` of the forward waited for, as configured
+    pub remote_port: u32,
+    /// Also wait for the provider to announce the tunnel's URL
+    pub url: bool,
+}
+
+/// Limits on the connections of a forward. Unset limits fall back to the
+/// configuration's defaults, and are unlimited there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionBudget {
+    /// Bytes a raw connection reads from the local service at a time, `buffer_size`
+    /// when unset
+    pub max_buffered: Option<usize>,
+    /// Connections open at once; more are refused with
+    /// [`CloseReason::LimitReached`](crate::CloseReason::LimitReached)
+    pub max_concurrent: Option<usize>,
+    /// How long a connection may stay open before it is closed with
+    /// [`CloseReason::DeadlineExceeded`](crate::CloseReason::DeadlineExceeded)
+    pub max_lifetime: Option<Duration>,
+    /// How long a connection may go without data in either direction before it is
+    /// closed with [`CloseReason::IdleTimeout`](crate::CloseReason::IdleTimeout)
+    pub max_idle: Option<Duration>,
+}
+
+impl ConnectionBudget {
+    /// These limits, with the unset ones taken from `defaults`
+    pub fn or(self, defaults: ConnectionBudget) -> Self {
+        Self {
+            max_buffered: self.max_buffered.or(defaults.max_buffered),
+            max_concurrent: self.max_concurrent.or(defaults.max_concurrent),
+            max_lifetime: self.max_lifetime.or(defaults.max_lifetime),
+            max_idle: self.max_idle.or(defaults.max_idle),
+        }
+    }
+}
+
+impl Forward {
+    pub fn new(remote_port: u32, local_addr: impl Into<String>, local_port: u16) -> Self {
+        Self {
+            remote_port,
+            bind_address: String::new(),
+            local_addr: local_addr.into(),
+            local_port,
+            local_socket: None,
+            wire_gate: None,
+            http: false,
+            raw: false,
+            udp: false,
+            budget: ConnectionBudget::default(),
+            idle_keepalive: None,
+            shaping: None,
+            sampling: None,
+            optional: false,
+            after: None,
+        }
+    }
+
+    /// Forward `remote_port` to the Unix domain socket at `path`
+    pub fn unix(remote_port: u32, path: impl Into<PathBuf>) -> Self {
+        Self {
+            local_socket: Some(path.into()),
+            ..Self::new(remote_port, "127.0.0.1", 0)
+        }
+    }
+
+    /// Forward `remote_port` to the UDP service at `local_addr:local_port`
+    pub fn udp(remote_port: u32, local_addr: impl Into<String>, local_port: u16) -> Self {
+        Self {
+            udp: true,
+            ..Self::new(remote_port, local_addr, local_port)
+        }
+    }
+
+    /// Refuse connections that don't start a `protocol` handshake
+    pub fn gated(self, protocol: WireProtocol) -> Self {
+        Self {
+            wire_gate: Some(protocol),
+            ..self
+        }
+    }
+
+    /// Handle this forward's connections as HTTP/1.x exchanges
+    pub fn http(self) -> Self {
+        Self { http: true, ..self }
+    }
+
+    /// Copy this forward's bytes untouched, whatever `http` says
+    pub fn raw(self) -> Self {
+        Self { raw: true, ..self }
+    }
+
+    /// Hold this forward's connections to `budget`
+    pub fn with_budget(self, budget: ConnectionBudget) -> Self {
+        Self { budget, ..self }
+    }
+
+    /// Set this forward up for `preset`'s protocol, see [`ProtocolPreset::apply`]
+    pub fn with_preset(mut self, preset: ProtocolPreset) -> Self {
+        preset.apply(&mut self);
+        self
+    }
+
+    /// Slow this forward's connections down as `profile` says
+    pub fn with_shaping(self, profile: ShapingProfile) -> Self {
+        Self {
+            shaping: Some(profile),
+            ..self
+        }
+    }
+
+    /// Keep the log lines of this forward's connections as `sampling` says
+    pub fn with_sampling(self, sampling: TraceSampling) -> Self {
+        Self {
+            sampling: Some(sampling),
+            ..self
+        }
+    }
+
+    /// Go on without this forward if the server refuses it
+    pub fn optional(self) -> Self {
+        Self {
+            optional: true,
+            ..self
+        }
+    }
+
+    /// Request this forward only once the server acknowledged the forward of
+    /// `remote_port`, the configuration's own or one of its `forwards`
+    pub fn after(self, remote_port: u32) -> Self {
+        Self {
+            after: Some(StartAfter {
+                remote_port,
+                url: false,
+            }),
+            ..self
+        }
+    }
+
+    /// Like [`after`](Self::after), and once the provider announced the tunnel's URL
+    pub fn after_url(self, remote_port: u32) -> Self {
+        Self {
+            after: Some(StartAfter {
+                remote_port,
+                url: true,
+            }),
+            ..self
+        }
+    }
+
+    /// Refuse settings that contradict each other
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.parses_http() && self.wire_gate.is_some() {
+            bail!(
+                "The forward of port {} sets both http and wire_gate; wire gates only apply to raw forwards",
+                self.remote_port
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether connections are handled as HTTP exchanges
+    pub(crate) fn parses_http(&self) -> bool {
+        self.http && !self.raw
+    }
+
+    pub(crate) fn target(&self) -> LocalTarget {
+        let (addr, port) = (self.local_addr.clone(), self.local_port);
+        match &self.local_socket {
+            Some(path) => LocalTarget::Unix(path.clone()),
+            None if self.udp => LocalTarget::Udp { addr, port },
+            None => LocalTarget::Tcp { addr, port },
+        }
+    }
+}
+
+pub(crate) type LocalReader = Box<dyn AsyncRead + Send + Unpin>;
+pub(crate) type LocalWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where forwarded connections are relayed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LocalTarget {
+    Tcp {
+        addr: String,
+        port: u16,
+    },
+    Unix(PathBuf),
+    /// A UDP service, reached through frames, see [`crate::udp`]
+    Udp {
+        addr: String,
+        port: u16,
+    },
+}
+
+impl LocalTarget {
+    /// Connect to the target, enabling TCP keepalives after `keepalive` of silence
+    pub(crate) async fn connect(
+        &self,
+        network: &dyn Network,
+        keepalive: Option<Duration>,
+    ) -> Result<(LocalReader, LocalWriter)> {
+        match self {
+            LocalTarget::Tcp { addr, port } => {
+                let stream = network
+                    .connect(addr, *port)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", self))?;
+                if let Some(idle) = keepalive {
+                    if let Err(e) = stream.set_keepalive(idle) {
+                        debug!(target: targets::PROXY, "Could not enable TCP keepalives: {}", e);
+                    }
+                }
+                let (rx, tx) = tokio::io::split(stream);
+                Ok((Box::new(rx), Box::new(tx)))
+            }
+            LocalTarget::Udp { addr, port } => udp::connect(addr, *port)
+                .await
+                .with_context(|| format!("Failed to connect to {}", self)),
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", self))?;
+                let (rx, tx) = stream.into_split();
+                Ok((Box::new(rx), Box::new(tx)))
+            }
+            #[cfg(not(unix))]
+            LocalTarget::Unix(_) => {
+                anyhow::bail!("Unix domain sockets are not supported on this platform")
+            }
+        }
+    }
+
+    /// Value of the `Host` header for requests to the target
+    pub(crate) fn host(&self) -> String {
+        match self {
+            LocalTarget::Tcp { addr, port } | LocalTarget::Udp { addr, port } => {
+                format!("{}:{}", addr, port)
+            }
+            LocalTarget::Unix(_) => "localhost".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LocalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalTarget::Tcp { addr, port } => write!(f, "{}:{}", addr, port),
+            LocalTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+            LocalTarget::Udp { addr, port } => write!(f, "udp:{}:{}", addr, port),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::TcpNetwork;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn test_connect_unix_socket() {
+        let path = std::env::temp_dir().join(format!("rrp-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let target = Forward::unix(80, &path).target();
+        assert_eq!(target.to_string(), format!("unix:{}", path.display()));
+        let (mut rx, mut tx) = target.connect(&TcpNetwork, None).await.unwrap();
+        tx.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        rx.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+        std::fs::remove_file(&path).unwrap();
+
+        let Err(error) = target.connect(&TcpNetwork, None).await else {
+            panic!("Connected to a removed socket");
+        };
+        assert!(error.to_string().starts_with("Failed to connect to unix:"));
+    }
+}
@@ -0,0 +1,178 @@
+//! Wire-aware gating of database forwards
+//!
+//! A database port exposed through a tunnel gets probed by internet scanners that
+//! speak HTTP, TLS or nothing at all. With a gate, a raw forward only relays a
+//! connection once the client's first message looks like the start of the expected
+//! protocol; anything else is closed before it reaches the database's
+//! authentication. This filters drive-by traffic, it does not replace authentication.
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Result;
+use russh::client::Msg;
+use russh::{Channel, ChannelMsg};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::clock::{self, Clock};
+
+/// How long a client gets to send its first message
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of the client's first message that are checked
+const HANDSHAKE_PREFIX: usize = 8;
+
+/// Postgres protocol version 3.x startup message
+const POSTGRES_V3: u32 = 3;
+const POSTGRES_CANCEL_REQUEST: u32 = 80877102;
+const POSTGRES_SSL_REQUEST: u32 = 80877103;
+const POSTGRES_GSSENC_REQUEST: u32 = 80877104;
+/// Largest startup message Postgres accepts
+const POSTGRES_MAX_STARTUP: u32 = 10000;
+
+/// `CLIENT_PROTOCOL_41`, set by every MySQL client since 4.1
+const MYSQL_PROTOCOL_41: u32 = 0x200;
+/// Shortest handshake response: the `SSLRequest` packet
+const MYSQL_MIN_RESPONSE: u32 = 32;
+
+/// Protocol whose handshake a gated forward expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WireProtocol {
+    /// Postgres: a startup, `SSLRequest`, `GSSENCRequest` or `CancelRequest` message
+    Postgres,
+    /// MySQL and MariaDB: the server greets first, then the client's handshake
+    /// response or `SSLRequest` must follow
+    Mysql,
+}
+
+impl WireProtocol {
+    /// Look up a protocol by name: `postgres` or `mysql` (case-insensitive)
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "postgres" | "postgresql" => Some(Self::Postgres),
+            "mysql" | "mariadb" => Some(Self::Mysql),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Postgres => "postgres",
+            Self::Mysql => "mysql",
+        }
+    }
+
+    /// Whether the service sends its greeting before the client's first message
+    pub(crate) fn server_first(&self) -> bool {
+        matches!(self, Self::Mysql)
+    }
+
+    /// Whether `prefix`, the first bytes from the client, start a valid message
+    fn admits(&self, prefix: &[u8]) -> bool {
+        let Some(prefix) = prefix.get(..HANDSHAKE_PREFIX) else {
+            return false;
+        };
+        match self {
+            Self::Postgres => {
+                let len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+                let code = u32::from_be_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+                match code {
+                    POSTGRES_SSL_REQUEST | POSTGRES_GSSENC_REQUEST => len == 8,
+                    POSTGRES_CANCEL_REQUEST => (16..=264).contains(&len),
+                    _ => code >> 16 == POSTGRES_V3 && (9..=POSTGRES_MAX_STARTUP).contains(&len),
+                }
+            }
+            Self::Mysql => {
+                let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], 0]);
+                let sequence = prefix[3];
+                let capabilities = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+                sequence == 1 && len >= MYSQL_MIN_RESPONSE && capabilities & MYSQL_PROTOCOL_41 != 0
+            }
+        }
+    }
+}
+
+impl fmt::Display for WireProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Read the start of the client's first message from `channel`. Returns what was
+/// read, as an error if `protocol` doesn't admit it and the connection is rejected.
+pub(crate) async fn read_handshake(
+    channel: &mut Channel<Msg>,
+    protocol: WireProtocol,
+    clock: &dyn Clock,
+) -> Result<Vec<u8>, Vec<u8>> {
+    let mut bytes = Vec::new();
+    let read = async {
+        while bytes.len() < HANDSHAKE_PREFIX {
+            match channel.wait().await {
+                Some(ChannelMsg::Data { data }) => bytes.extend_from_slice(&data),
+                Some(ChannelMsg::Eof | ChannelMsg::Close) | None => return false,
+                Some(_) => {}
+            }
+        }
+        true
+    };
+    let complete = clock::timeout(clock, HANDSHAKE_TIMEOUT, read)
+        .await
+        .unwrap_or(false);
+    if complete && protocol.admits(&bytes) {
+        Ok(bytes)
+    } else {
+        Err(bytes)
+    }
+}
+
+/// Pass one MySQL packet, the server greeting, from the local service to the client
+pub(crate) async fn relay_greeting<R, W>(local: &mut R, client: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 4];
+    local.read_exact(&mut header).await?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+    let mut payload = vec![0u8; len as usize];
+    local.read_exact(&mut payload).await?;
+    client.write_all(&header).await?;
+    client.write_all(&payload).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshakes() {
+        let postgres = WireProtocol::Postgres;
+        // StartupMessage for protocol 3.0, SSLRequest, CancelRequest
+        assert!(postgres.admits(&[0, 0, 0, 41, 0, 3, 0, 0, b'u', b's']));
+        assert!(postgres.admits(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]));
+        assert!(postgres.admits(&[0, 0, 0, 16, 0x04, 0xd2, 0x16, 0x2e]));
+        assert!(!postgres.admits(&[0, 0, 0, 41, 0, 2, 0, 0]));
+        assert!(!postgres.admits(b"GET / HTTP/1.1\r\n"));
+        assert!(!postgres.admits(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01]));
+        assert!(!postgres.admits(&[0, 0, 0, 8]));
+
+        let mysql = WireProtocol::Mysql;
+        assert!(mysql.admits(&[0x55, 0, 0, 1, 0x05, 0xa6, 0x0f, 0]));
+        assert!(mysql.admits(&[0x20, 0, 0, 1, 0x05, 0xae, 0xff, 0]));
+        assert!(!mysql.admits(&[0x55, 0, 0, 0, 0x05, 0xa6, 0x0f, 0]));
+        assert!(!mysql.admits(&[0x55, 0, 0, 1, 0x05, 0x04, 0x0f, 0]));
+        assert!(!mysql.admits(b"GET / HTTP/1.1\r\n"));
+
+        assert_eq!(WireProtocol::named("MariaDB"), Some(mysql));
+        assert_eq!(WireProtocol::named(postgres.name()), Some(postgres));
+        assert_eq!(WireProtocol::named("redis"), None);
+
+        let greeting = [5, 0, 0, 0, 10, b'8', b'.', b'0', 0];
+        let mut local = &greeting[..];
+        let mut client = Vec::new();
+        relay_greeting(&mut local, &mut client).await.unwrap();
+        assert_eq!(client, greeting);
+    }
+}
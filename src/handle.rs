@@ -0,0 +1,1305 @@
+//! Shareable control surface of a client
+//!
+//! [`ClientHandle`] is a cheap, clonable reference to the state of a
+//! [`ReverseSshClient`](crate::ReverseSshClient). Application components can hold one to
+//! inspect and control the tunnel while the client itself is busy in `run()`.
+
+use anyhow::{Context, Result};
+use russh::client::Handle;
+use russh::Disconnect;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::instrument::WithSubscriber;
+use tracing::{debug, info, warn};
+
+use crate::access::AccessList;
+use crate::alerts::ConnectLog;
+use crate::conflict::{Availability, ForwardConflict};
+use crate::deadline::{Deadline, DeadlineWatch};
+use crate::errorlog::ErrorLog;
+use crate::events::{CloseReason, EventStream, Events, TunnelEvent};
+use crate::fds::{self, FdPressure};
+use crate::forward::{ConnectionBudget, Forward};
+use crate::http::HttpProxy;
+use crate::messages::MessageHandlerSlot;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::network::Network;
+#[cfg(unix)]
+use crate::notify::{self, Notification};
+use crate::overhead::WireAccounting;
+#[cfg(feature = "profiling")]
+use crate::profiling::{self, ResourceUsage};
+use crate::provider::{ProviderError, TunnelInfo};
+use crate::quota::{QuotaChange, QuotaTracker, QuotaUsage};
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::reject::RejectAction;
+use crate::report::{self, ErrorEvent, ErrorPhase};
+use crate::sampling::TraceSampling;
+use crate::security::SecurityEvent;
+use crate::session_id::SessionId;
+use crate::shaping::{Shaper, ShapingProfile};
+use crate::snapshot::{Snapshot, SnapshotStore};
+use crate::stats::{OriginatorLog, OriginatorStats, TrafficCounters};
+use crate::status::{StatusTracker, TunnelState, TunnelStatus};
+use crate::tee::{TeeDirection, Tees};
+use crate::timeline::{StartupRecorder, StartupTimeline};
+use crate::{targets, tasks};
+use crate::{AuthMethod, Client, Clock, ForwardedConnection, ReverseSshConfig, TunnelProvider};
+
+/// How often [`ClientHandle::drain`] checks for open connections
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A forwarded connection that is open, or just closed in a
+/// [`ConnectionClosed`](TunnelEvent::ConnectionClosed) event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Connection number, counted from 1 per client
+    pub id: u64,
+    /// Address of the remote peer that opened the connection
+    pub originator_address: String,
+    /// Port of the remote peer that opened the connection
+    pub originator_port: u32,
+    /// Port the server accepted the connection on
+    pub connected_port: u32,
+    /// Time since the connection was forwarded, or how long it was open
+    pub duration: Duration,
+    /// Bytes received from the originator so far
+    pub bytes_received: u64,
+    /// Bytes sent back to the originator so far
+    pub bytes_sent: u64,
+    /// Time left before the connection is closed by its deadline, if it has one
+    pub deadline: Option<Duration>,
+}
+
+/// Overall traffic through a client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    /// Forwarded connections currently open
+    pub active_connections: usize,
+    /// Forwarded connections received since the client was created
+    pub total_connections: u64,
+    /// Bytes relayed in either direction since the client was created
+    pub bytes_transferred: u64,
+    /// Of those, bytes received from originators
+    pub bytes_received: u64,
+    /// Of those, bytes sent back to originators
+    pub bytes_sent: u64,
+    /// Estimated bytes the SSH connection carried for that traffic, including packet
+    /// framing, padding, MACs, channel setup and rekeys
+    pub wire_bytes: u64,
+    /// Remote ports forwarded on the current session
+    pub forwards: Vec<u32>,
+    /// File descriptors the process has open, where the platform lists them
+    pub open_fds: Option<usize>,
+    /// Limit on open file descriptors (Linux only)
+    pub fd_limit: Option<u64>,
+    /// Connections currently open, as [`ClientHandle::connections`] lists them
+    pub connections: Vec<ConnectionInfo>,
+    /// Server messages waiting for the message handler
+    pub queued_messages: usize,
+}
+
+impl TunnelStats {
+    /// Estimated bytes the SSH connection added on top of the relayed payload
+    pub fn overhead_bytes(&self) -> u64 {
+        self.wire_bytes.saturating_sub(self.bytes_transferred)
+    }
+}
+
+#[derive(Debug)]
+struct ActiveConnection {
+    originator_address: String,
+    originator_port: u32,
+    connected_port: u32,
+    started: Instant,
+    counters: TrafficCounters,
+    deadline: Deadline,
+}
+
+impl ActiveConnection {
+    fn info(&self, id: u64, now: Instant) -> ConnectionInfo {
+        ConnectionInfo {
+            id,
+            originator_address: self.originator_address.clone(),
+            originator_port: self.originator_port,
+            connected_port: self.connected_port,
+            duration: now.saturating_duration_since(self.started),
+            bytes_received: self.counters.received.load(Ordering::Relaxed),
+            bytes_sent: self.counters.sent.load(Ordering::Relaxed),
+            deadline: self
+                .deadline
+                .get()
+                .map(|at| at.saturating_duration_since(now)),
+        }
+    }
+}
+
+/// State shared by a client, its handles and its connection tasks
+pub(crate) struct Shared {
+    pub(crate) session: tokio::sync::Mutex<Option<Handle<Client>>>,
+    pub(crate) metrics: Metrics,
+    /// Proxy of the forwards handling HTTP
+    pub(crate) http: HttpProxy,
+    pub(crate) maintenance: AtomicBool,
+    /// Shaping of each forward, by the port the server listens on
+    shapers: Mutex<BTreeMap<u32, Arc<Shaper>>>,
+    pub(crate) rate_limiter: RateLimiter,
+    /// Networks forwarded connections may come from
+    access_list: Mutex<AccessList>,
+    pub(crate) originators: OriginatorLog,
+    pub(crate) connects: ConnectLog,
+    pub(crate) traffic: Arc<AtomicU64>,
+    pub(crate) wire: Arc<WireAccounting>,
+    pub(crate) message_handler: MessageHandlerSlot,
+    pub(crate) shutdown: watch::Sender<bool>,
+    pub(crate) events: Events,
+    pub(crate) startup: StartupRecorder,
+    pub(crate) status: StatusTracker,
+    /// Task owning the session channel of the current session
+    pub(crate) session_channel: Mutex<Option<JoinHandle<()>>>,
+    /// Refusal announced by the provider, returned from `run()`
+    pub(crate) provider_error: Mutex<Option<ProviderError>>,
+    /// Why the SSH session died, e.g. unanswered keepalives, returned from `run()`
+    pub(crate) session_error: Mutex<Option<anyhow::Error>>,
+    connections: Mutex<BTreeMap<u64, ActiveConnection>>,
+    next_connection_id: AtomicU64,
+    /// Forwards of the current session, by the port the server listens on
+    forwards: Mutex<Vec<Forward>>,
+    /// Target of forwards added without one
+    local_target: Mutex<Forward>,
+    /// Provider asked whether a forward is free before it is requested
+    forward_check: Mutex<Option<Arc<dyn TunnelProvider>>>,
+    quota: Option<Mutex<QuotaTracker>>,
+    pub(crate) idle_keepalive: Option<Duration>,
+    pub(crate) buffer_size: usize,
+    connection_budget: ConnectionBudget,
+    trace_sampling: TraceSampling,
+    max_connections: Option<usize>,
+    /// Places under `max_connections`
+    connection_slots: Arc<Semaphore>,
+    connection_queue: Option<Duration>,
+    pub(crate) reject_action: RejectAction,
+    pub(crate) fd_pressure: FdPressure,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) network: Arc<dyn Network>,
+    /// Sinks receiving copies of relayed bytes
+    pub(crate) tees: Tees,
+    /// `host:port` of the SSH server
+    server: Mutex<String>,
+    /// Tasks handling forwarded connections that are still running
+    connection_tasks: Arc<AtomicUsize>,
+    /// Authentication methods the next `connect()` tries, replaceable at runtime
+    credentials: Mutex<Vec<AuthMethod>>,
+    /// Pinned SHA256 fingerprint of the server's host key, without the `SHA256:` prefix
+    host_key_fingerprint: Mutex<Option<String>>,
+    /// Known-good connection parameters
+    pub(crate) snapshot: SnapshotStore,
+    /// Errors logged in the current window, whose repeats are only counted
+    pub(crate) errors: ErrorLog,
+    /// Queue of the current session's server messages
+    pub(crate) message_queue: Mutex<Option<mpsc::WeakSender<String>>>,
+}
+
+impl Shared {
+    pub(crate) fn new(config: &ReverseSshConfig) -> Self {
+        Self {
+            session: tokio::sync::Mutex::new(None),
+            metrics: Metrics::default(),
+            http: HttpProxy::new(
+                config.http.clone().unwrap_or_default(),
+                config.clock.clone(),
+            ),
+            maintenance: AtomicBool::new(false),
+            shapers: Mutex::new(BTreeMap::new()),
+            rate_limiter: RateLimiter::new(config.rate_limits, config.clock.clone()),
+            access_list: Mutex::new(config.access_list.clone()),
+            originators: OriginatorLog::new(config.clock.clone()),
+            connects: ConnectLog::new(config.clock.clone()),
+            traffic: Arc::new(AtomicU64::new(0)),
+            wire: Arc::default(),
+            message_handler: MessageHandlerSlot::default(),
+            shutdown: watch::channel(false).0,
+            events: Events::default(),
+            startup: StartupRecorder::new(config.clock.clone()),
+            status: StatusTracker::new(config.clock.clone()),
+            session_channel: Mutex::new(None),
+            provider_error: Mutex::new(None),
+            session_error: Mutex::new(None),
+            connections: Mutex::new(BTreeMap::new()),
+            next_connection_id: AtomicU64::new(1),
+            forwards: Mutex::new(Vec::new()),
+            local_target: Mutex::new(config.local_forward()),
+            forward_check: Mutex::new(forward_check(config)),
+            quota: config
+                .quota
+                .map(|quota| Mutex::new(QuotaTracker::new(quota))),
+            idle_keepalive: config.idle_keepalive,
+            buffer_size: config.buffer_size,
+            connection_budget: config.connection_budget,
+            trace_sampling: config.trace_sampling,
+            max_connections: config.max_connections,
+            connection_slots: Arc::new(Semaphore::new(
+                config.max_connections.unwrap_or(Semaphore::MAX_PERMITS),
+            )),
+            connection_queue: config.connection_queue,
+            reject_action: config.reject_action.clone(),
+            fd_pressure: FdPressure::new(config.clock.clone()),
+            clock: config.clock.clone(),
+            network: config.network.clone(),
+            tees: Tees::default(),
+            server: Mutex::new(format!("{}:{}", config.server_addr, config.server_port)),
+            connection_tasks: Arc::default(),
+            credentials: Mutex::new(config.auth_methods()),
+            host_key_fingerprint: Mutex::new(
+                config
+                    .host_key_fingerprint
+                    .as_deref()
+                    .map(normalize_fingerprint),
+            ),
+            snapshot: SnapshotStore::new(config.snapshot_path.clone()),
+            errors: ErrorLog::new(config.error_log_window, config.clock.clone()),
+            message_queue: Mutex::new(None),
+        }
+    }
+
+    /// Take the settings of `config` that live here, for a client whose
+    /// configuration was replaced (see [`ConfigDiff`](crate::ConfigDiff))
+    pub(crate) fn reconfigure(&self, config: &ReverseSshConfig) {
+        *self.server.lock().unwrap() = format!("{}:{}", config.server_addr, config.server_port);
+        *self.credentials.lock().unwrap() = config.auth_methods();
+        *self.host_key_fingerprint.lock().unwrap() = config
+            .host_key_fingerprint
+            .as_deref()
+            .map(normalize_fingerprint);
+        self.rate_limiter.set(config.rate_limits);
+        *self.access_list.lock().unwrap() = config.access_list.clone();
+        let target = config.local_forward();
+        // The first forward of a session is the one of `remote_port`
+        match self.forwards.lock().unwrap().first_mut() {
+            Some(first) => {
+                *first = Forward {
+                    remote_port: first.remote_port,
+                    ..target.clone()
+                };
+                self.shaper(first).set(config.shaping);
+            }
+            None => self.shaper(&target).set(config.shaping),
+        }
+        *self.local_target.lock().unwrap() = target;
+        *self.forward_check.lock().unwrap() = forward_check(config);
+    }
+
+    /// Fail with a [`ForwardConflict`] if the provider knows `forward` is taken.
+    /// A check that fails itself doesn't hold the forward back.
+    async fn precheck_forward(&self, forward: &Forward) -> Result<()> {
+        let Some(provider) = self.forward_check.lock().unwrap().clone() else {
+            return Ok(());
+        };
+        let (bind_address, port) = (forward.bind_address.as_str(), forward.remote_port);
+        let Some(check) = provider.check_forward(bind_address, port) else {
+            return Ok(());
+        };
+        match check.await {
+            Ok(Availability::Free) => Ok(()),
+            Ok(Availability::Taken { suggestions }) => {
+                self.metrics.increment("forward_conflicts_total", 1);
+                Err(ForwardConflict {
+                    provider: provider.name().to_string(),
+                    bind_address: bind_address.to_string(),
+                    port,
+                    suggestions,
+                }
+                .into())
+            }
+            Err(e) => {
+                warn!(target: targets::PROVIDER,
+                    "Could not check with {} that port {} is free: {:#}",
+                    provider.name(), port, e
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// `host:port` of the SSH server
+    pub(crate) fn server(&self) -> String {
+        self.server.lock().unwrap().clone()
+    }
+
+    /// Why a forwarded connection from `originator` is refused, if it is
+    pub(crate) fn refuses(&self, originator: &str) -> Option<&'static str> {
+        self.access_list.lock().unwrap().refuses(originator)
+    }
+
+    pub(crate) fn host_key_fingerprint(&self) -> Option<String> {
+        self.host_key_fingerprint.lock().unwrap().clone()
+    }
+
+    /// Track a new forwarded connection, returning its id, traffic counters and deadline
+    pub(crate) fn register(
+        &self,
+        forwarded: &ForwardedConnection,
+    ) -> (u64, TrafficCounters, DeadlineWatch) {
+        self.wire
+            .record_channel(&forwarded.connected_address, &forwarded.originator_address);
+        self.register_origin(
+            &forwarded.originator_address,
+            forwarded.originator_port,
+            forwarded.connected_port,
+        )
+    }
+
+    fn register_origin(
+        &self,
+        originator_address: &str,
+        originator_port: u32,
+        connected_port: u32,
+    ) -> (u64, TrafficCounters, DeadlineWatch) {
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let counters = TrafficCounters::new(self.traffic.clone());
+        let (deadline, watch) = Deadline::new(self.clock.clone());
+        self.connections.lock().unwrap().insert(
+            id,
+            ActiveConnection {
+                originator_address: originator_address.to_string(),
+                originator_port,
+                connected_port,
+                started: self.clock.now(),
+                counters: counters.clone(),
+                deadline,
+            },
+        );
+        (id, counters, watch)
+    }
+
+    /// Forget connection `id`, announcing it closed for `reason` with its final stats
+    pub(crate) fn unregister(&self, id: u64, reason: CloseReason) {
+        let Some(connection) = self.connections.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let stats = ConnectionInfo {
+            deadline: None,
+            ..connection.info(id, self.clock.now())
+        };
+        self.events
+            .emit(TunnelEvent::ConnectionClosed { id, reason, stats });
+    }
+
+    /// The limits `forward`'s connections are held to
+    pub(crate) fn budget(&self, forward: &Forward) -> ConnectionBudget {
+        let budget = forward.budget.or(self.connection_budget);
+        ConnectionBudget {
+            max_buffered: Some(budget.max_buffered.unwrap_or(self.buffer_size)),
+            ..budget
+        }
+    }
+
+    /// The connections of `forward` whose log lines are kept
+    pub(crate) fn sampling(&self, forward: &Forward) -> TraceSampling {
+        forward.sampling.unwrap_or(self.trace_sampling)
+    }
+
+    /// Take a place under `max_connections` for connection `id`, queueing for up to
+    /// `connection_queue` if they are all taken. `None` if the connection has to be
+    /// refused.
+    pub(crate) async fn connection_slot(&self, id: u64) -> Option<ConnectionSlot> {
+        let Some(max) = self.max_connections else {
+            return Some(ConnectionSlot { _permit: None });
+        };
+        let slots = self.connection_slots.clone();
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Some(ConnectionSlot {
+                _permit: Some(permit),
+            });
+        }
+        let Some(queue) = self.connection_queue else {
+            info!(target: targets::PROXY,
+                "At the limit of {} connections, refusing connection #{}",
+                max, id
+            );
+            self.metrics.increment("connections_limited_total", 1);
+            return None;
+        };
+        debug!(target: targets::PROXY,
+            "At the limit of {} connections, queueing connection #{}",
+            max, id
+        );
+        self.metrics.increment("connections_queued_total", 1);
+        tokio::select! {
+            permit = slots.acquire_owned() => permit.ok().map(|permit| ConnectionSlot {
+                _permit: Some(permit),
+            }),
+            _ = self.clock.sleep(queue) => {
+                info!(target: targets::PROXY,
+                    "Connection #{} waited {:?} for a place, refusing it",
+                    id, queue
+                );
+                self.metrics.increment("connections_limited_total", 1);
+                None
+            }
+        }
+    }
+
+    /// Connections open on `connected_port`, counting those just registered
+    pub(crate) fn open_on(&self, connected_port: u32) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.connected_port == connected_port)
+            .count()
+    }
+
+    /// Move the state machine to `state`, publishing the transition
+    pub(crate) fn set_state(&self, state: TunnelState) {
+        match self.status.transition(state) {
+            Ok(from) if from != state => {
+                info!(target: targets::SESSION, "Tunnel state {} -> {}", from, state);
+                self.events
+                    .emit(TunnelEvent::StateChanged { from, to: state });
+            }
+            Ok(_) => {}
+            Err(from) => warn!(target: targets::SESSION,
+                "Ignoring invalid tunnel state transition {} -> {}",
+                from, state
+            ),
+        }
+    }
+
+    /// Switch between `Ready` and `Degraded` after a forwarded connection finished
+    pub(crate) fn set_healthy(&self, healthy: bool) {
+        if matches!(
+            self.status.state(),
+            TunnelState::Ready | TunnelState::Degraded
+        ) {
+            self.set_state(if healthy {
+                TunnelState::Ready
+            } else {
+                TunnelState::Degraded
+            });
+        }
+    }
+
+    /// Publish a change in the health of the local target. An unhealthy target also
+    /// degrades the tunnel.
+    pub(crate) fn set_target_health(&self, healthy: bool, error: Option<anyhow::Error>) {
+        match &error {
+            Some(e) => warn!(target: targets::HEALTH, "Local target is unhealthy: {:#}", e),
+            None if healthy => info!(target: targets::HEALTH, "Local target is healthy"),
+            None => warn!(target: targets::HEALTH, "Local target is unhealthy"),
+        }
+        self.status.set_target_healthy(Some(healthy));
+        self.set_healthy(healthy);
+        self.events.emit(TunnelEvent::TargetHealthChanged {
+            healthy,
+            error: error.map(|e| format!("{:#}", e)),
+        });
+    }
+
+    /// Remember an error for [`TunnelStatus`] and pass it to the [`on_error`](crate::on_error) hook
+    pub(crate) fn report(&self, mut event: ErrorEvent) {
+        event.session = self.events.session();
+        self.status.set_error(&event);
+        report::report(event);
+    }
+
+    /// Server messages queued for the handler, while a session is up
+    pub(crate) fn queued_messages(&self) -> usize {
+        let queue = self.message_queue.lock().unwrap();
+        match queue.as_ref().and_then(|queue| queue.upgrade()) {
+            Some(queue) => queue.max_capacity() - queue.capacity(),
+            None => 0,
+        }
+    }
+
+    /// Whether an error logged as `message` should be logged, rather than counted as
+    /// a repeat of one logged in the current window
+    pub(crate) fn should_log(&self, message: &str) -> bool {
+        self.errors.first(message, &self.metrics)
+    }
+
+    /// Report that the server refused the optional `forward`
+    pub(crate) fn forward_refused(&self, forward: &Forward, error: &anyhow::Error) {
+        self.report(ErrorEvent::new(ErrorPhase::Tunnel, error));
+        self.metrics.increment("forwards_refused_total", 1);
+        self.events.emit(TunnelEvent::ForwardRefused {
+            port: forward.remote_port,
+            error: format!("{:#}", error),
+        });
+    }
+
+    /// Forget the forwards of a previous session
+    pub(crate) fn reset_forwards(&self) {
+        self.forwards.lock().unwrap().clear();
+    }
+
+    /// Compare the traffic so far with the quota, if there is one
+    pub(crate) fn check_quota(&self) -> Option<QuotaChange> {
+        let mut tracker = self.quota.as_ref()?.lock().unwrap();
+        tracker.check(self.traffic.load(Ordering::Relaxed), self.clock.now())
+    }
+
+    /// Start shedding connections after running out of file descriptors, unless
+    /// already shedding
+    pub(crate) fn fd_exhausted(&self) {
+        let Some(backoff) = self.fd_pressure.exhausted() else {
+            return;
+        };
+        let (open_fds, limit) = (fds::open_fds(), fds::fd_limit());
+        warn!(target: targets::PROXY,
+            "Out of file descriptors ({} open, limit {}), shedding new connections for {:?}",
+            open_fds.map_or("?".to_string(), |n| n.to_string()),
+            limit.map_or("?".to_string(), |n| n.to_string()),
+            backoff
+        );
+        self.metrics.increment("fd_exhaustions_total", 1);
+        self.events.emit(TunnelEvent::FdExhausted {
+            open_fds,
+            limit,
+            backoff,
+        });
+    }
+
+    pub(crate) fn quota_exhausted(&self) -> bool {
+        self.quota
+            .as_ref()
+            .is_some_and(|tracker| tracker.lock().unwrap().is_exhausted())
+    }
+
+    /// Close every open connection now, through its deadline
+    pub(crate) fn close_connections(&self) {
+        for connection in self.connections.lock().unwrap().values() {
+            connection.deadline.set(Some(self.clock.now()));
+        }
+    }
+
+    /// Remote ports forwarded on the current session
+    fn forward_ports(&self) -> Vec<u32> {
+        let forwards = self.forwards.lock().unwrap();
+        forwards.iter().map(|forward| forward.remote_port).collect()
+    }
+
+    /// Spawn the task handling a forwarded connection, counted while it runs
+    pub(crate) fn spawn_connection_task<F>(&self, name: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let running = RunningTask::new(self.connection_tasks.clone());
+        let task = async move {
+            let _running = running;
+            task.await
+        };
+        // With the connection's sampling, see `Sampler::enter`
+        tasks::spawn(name, task.with_current_subscriber());
+    }
+
+    /// Tasks handling forwarded connections that are still running
+    #[cfg(feature = "profiling")]
+    pub(crate) fn connection_tasks(&self) -> usize {
+        self.connection_tasks.load(Ordering::Relaxed)
+    }
+
+    /// The authentication methods to try, in order
+    pub(crate) fn credentials(&self) -> Vec<AuthMethod> {
+        self.credentials.lock().unwrap().clone()
+    }
+
+    /// Present `certificate` in every certificate method from now on
+    pub(crate) fn replace_certificates(&self, certificate: &str) {
+        for method in self.credentials.lock().unwrap().iter_mut() {
+            if let AuthMethod::Certificate {
+                certificate: old, ..
+            } = method
+            {
+                *old = certificate.to_string();
+            }
+        }
+    }
+
+    /// Shaping of `forward`'s connections, starting out with its own profile
+    pub(crate) fn shaper(&self, forward: &Forward) -> Arc<Shaper> {
+        let mut shapers = self.shapers.lock().unwrap();
+        let shaper = shapers
+            .entry(forward.remote_port)
+            .or_insert_with(|| Arc::new(Shaper::new(forward.shaping, self.clock.clone())));
+        shaper.clone()
+    }
+
+    /// Local target of connections the server accepted on `connected_port`. Falls back
+    /// to the configured local target for ports it doesn't know, as servers may report
+    /// the port differently than it was requested.
+    pub(crate) fn route(&self, connected_port: u32) -> Forward {
+        let forwards = self.forwards.lock().unwrap();
+        match forwards.iter().find(|f| f.remote_port == connected_port) {
+            Some(forward) => forward.clone(),
+            None => self.local_target.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// A place under `max_connections`, held while a connection is relayed
+pub(crate) struct ConnectionSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Counts a task as running until it is dropped, finished or aborted
+struct RunningTask(Arc<AtomicUsize>);
+
+impl RunningTask {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for RunningTask {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The provider to check forwards with, if `config` asks for it
+fn forward_check(config: &ReverseSshConfig) -> Option<Arc<dyn TunnelProvider>> {
+    config.provider.clone().filter(|_| config.precheck_forwards)
+}
+
+/// A pinned host key fingerprint without the `SHA256:` prefix and padding
+pub(crate) fn normalize_fingerprint(fingerprint: &str) -> String {
+    let fingerprint = fingerprint.trim();
+    let fingerprint = fingerprint.strip_prefix("SHA256:").unwrap_or(fingerprint);
+    fingerprint.trim_end_matches('=').to_string()
+}
+
+/// Cheap, clonable handle to a [`ReverseSshClient`](crate::ReverseSshClient), obtained
+/// with [`ReverseSshClient::handle`](crate::ReverseSshClient::handle).
+#[derive(Clone)]
+pub struct ClientHandle {
+    pub(crate) shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    /// Ask the server to forward another remote port to the local service, returning
+    /// the port the server listens on (useful when requesting port 0)
+    pub async fn add_forward(&self, remote_port: u32) -> Result<u32> {
+        let target = self.shared.local_target.lock().unwrap().clone();
+        self.add_forward_to(Forward {
+            remote_port,
+            ..target
+        })
+        .await
+    }
+
+    /// Ask the server to forward `forward.remote_port` to its own local target,
+    /// returning the port the server listens on
+    pub async fn add_forward_to(&self, forward: Forward) -> Result<u32> {
+        let remote_port = forward.remote_port;
+        forward.check()?;
+        self.shared.precheck_forward(&forward).await?;
+        let mut session = self.shared.session.lock().await;
+        let session = session
+            .as_mut()
+            .context("Not connected - call connect() first")?;
+        // A port the server picked before is asked for again, so it stays the same
+        let mut known = None;
+        if let Some(port) = (remote_port == 0)
+            .then(|| self.shared.snapshot.forward_port(&forward))
+            .flatten()
+        {
+            match session
+                .tcpip_forward(forward.bind_address.as_str(), port)
+                .await
+            {
+                Ok(_) => known = Some(port),
+                Err(e) => {
+                    debug!(target: targets::SESSION, "Server refused port {} from the snapshot: {}", port, e);
+                    self.shared
+                        .snapshot
+                        .forget_forward(&forward, &self.shared.metrics)
+                        .await;
+                }
+            }
+        }
+        let port = match known {
+            Some(port) => port,
+            None => {
+                let port = session
+                    .tcpip_forward(forward.bind_address.as_str(), remote_port)
+                    .await
+                    .context("Failed to set up remote port forwarding")?;
+                // The server only reports the port when it picked one
+                if port == 0 {
+                    remote_port
+                } else {
+                    port
+                }
+            }
+        };
+        if remote_port == 0 {
+            self.shared.snapshot.observe_forward(&forward, port);
+            info!(target: targets::SESSION,
+                "Server assigned remote port {}, forwarded to {}",
+                port, forward.target()
+            );
+        } else {
+            info!(target: targets::SESSION, "Remote port {} forwarded to {}", port, forward.target());
+        }
+        self.shared.forwards.lock().unwrap().push(Forward {
+            remote_port: port,
+            ..forward
+        });
+        self.shared.events.emit(TunnelEvent::ForwardEstablished {
+            port,
+            requested: remote_port,
+        });
+        Ok(port)
+    }
+
+    /// Parameters of the last session that came up, as kept in
+    /// [`snapshot_path`](crate::ReverseSshConfig::snapshot_path), if any
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.shared.snapshot.known()
+    }
+
+    /// Forwards of the current session, with the port the server listens on
+    pub fn forwards(&self) -> Vec<Forward> {
+        self.shared.forwards.lock().unwrap().clone()
+    }
+
+    /// Overall traffic through the client
+    pub fn stats(&self) -> TunnelStats {
+        let connections = self.connections();
+        let (bytes_received, bytes_sent) = self.shared.wire.payload();
+        TunnelStats {
+            active_connections: connections.len(),
+            total_connections: self.shared.next_connection_id.load(Ordering::Relaxed) - 1,
+            bytes_transferred: self.shared.traffic.load(Ordering::Relaxed),
+            bytes_received,
+            bytes_sent,
+            wire_bytes: self.shared.wire.wire_bytes(),
+            forwards: self.shared.forward_ports(),
+            open_fds: fds::open_fds(),
+            fd_limit: fds::fd_limit(),
+            connections,
+            queued_messages: self.shared.queued_messages(),
+        }
+    }
+
+    /// Traffic relayed in the current period of the
+    /// [`TrafficQuota`](crate::TrafficQuota), if one is configured
+    pub fn quota(&self) -> Option<QuotaUsage> {
+        let tracker = self.shared.quota.as_ref()?.lock().unwrap();
+        Some(tracker.usage(
+            self.shared.traffic.load(Ordering::Relaxed),
+            self.shared.clock.now(),
+        ))
+    }
+
+    /// Current state, uptime, reconnect count, last error, URL and forwards
+    pub fn status(&self) -> TunnelStatus {
+        self.shared.status.status(self.shared.forward_ports())
+    }
+
+    /// The public URL of the tunnel, as last announced by the provider
+    pub fn url(&self) -> Option<String> {
+        self.shared.status.info().map(|info| info.url)
+    }
+
+    /// The latest tunnel details announced by the provider: parsed from
+    /// localhost.run's JSON output when enabled, scraped from the banner otherwise
+    pub fn tunnel_info(&self) -> Option<TunnelInfo> {
+        self.shared.status.info()
+    }
+
+    /// Forwarded connections currently open, oldest first
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, connection)| connection.info(*id, self.shared.clock.now()))
+            .collect()
+    }
+
+    /// Close connection `id` (see [`connections`](Self::connections)) once `budget`
+    /// has passed from now, or clear its deadline with `None`. Setting it again moves
+    /// the deadline. The connection ends with
+    /// [`CloseReason::DeadlineExceeded`](crate::CloseReason::DeadlineExceeded).
+    /// Returns `false` if the connection is no longer open.
+    pub fn set_connection_deadline(&self, id: u64, budget: Option<Duration>) -> bool {
+        match self.shared.connections.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection
+                    .deadline
+                    .set(budget.map(|budget| self.shared.clock.now() + budget));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Disconnect from the server and make `run()` return. Connections already being
+    /// relayed are closed along with the session.
+    pub async fn shutdown(&self) -> Result<()> {
+        info!(target: targets::SESSION, "Shutting down");
+        self.shared.shutdown.send_replace(true);
+        if let Some(session) = self.shared.session.lock().await.as_ref() {
+            session
+                .disconnect(Disconnect::ByApplication, "Client shutting down", "en")
+                .await
+                .context("Failed to disconnect from SSH server")?;
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for open forwarded connections to finish, e.g. before
+    /// [`shutdown`](Self::shutdown). Returns whether they all did.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let clock = &self.shared.clock;
+        let deadline = clock.now() + timeout;
+        while !self.shared.connections.lock().unwrap().is_empty() {
+            if clock.now() >= deadline {
+                return false;
+            }
+            clock
+                .sleep(DRAIN_POLL_INTERVAL.min(deadline - clock.now()))
+                .await;
+        }
+        true
+    }
+
+    /// ID of the current, or last, session; `None` before the first `connect()`
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.shared.events.session()
+    }
+
+    /// Copy the bytes the forward of `port` relays in `direction` to `sink`, replacing
+    /// any sink attached there before. `port` is the one the server listens on, as
+    /// [`add_forward`](Self::add_forward) returns it for a forward of port 0.
+    /// Connections accepted from now on are copied; copies `sink` is too slow for are
+    /// dropped rather than holding the tunnel back (see [`TeeDirection`]). Needs a
+    /// tokio runtime.
+    pub fn tee<S>(&self, port: u32, direction: TeeDirection, sink: S)
+    where
+        S: tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        self.shared.tees.attach(port, direction, sink);
+    }
+
+    /// Stop copying new connections of `port` in `direction`; the sink is shut down
+    /// once the connections copied to it close. Returns whether a sink was attached.
+    pub fn remove_tee(&self, port: u32, direction: TeeDirection) -> bool {
+        self.shared.tees.detach(port, direction)
+    }
+
+    /// Snapshot of the metrics recorded so far (see [`MetricsSnapshot`])
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let mut snapshot = self.shared.metrics.snapshot();
+        snapshot.session = self.shared.events.session();
+        snapshot.counters.insert(
+            "tee_dropped_bytes_total".to_string(),
+            self.shared.tees.dropped(),
+        );
+        snapshot.counters.extend(
+            self.shared
+                .wire
+                .counters()
+                .map(|(name, value)| (name.to_string(), value)),
+        );
+        snapshot
+    }
+
+    /// Traffic per originator address over the last `window` (up to an hour),
+    /// busiest first. Counts connections that finished within the window.
+    pub fn originator_stats(&self, window: Duration) -> Vec<OriginatorStats> {
+        self.shared.originators.stats(window)
+    }
+
+    /// Traffic recorded by the HTTP inspector as a HAR document, or `None` if
+    /// [`HttpConfig::inspector`](crate::HttpConfig::inspector) isn't enabled
+    pub fn har(&self) -> Option<String> {
+        self.shared.http.har()
+    }
+
+    /// Write the traffic recorded by the HTTP inspector to a HAR file
+    pub async fn export_har(&self, path: impl AsRef<Path>) -> Result<()> {
+        let har = self
+            .har()
+            .context("HTTP inspector is not enabled for this client")?;
+        tokio::fs::write(path.as_ref(), har)
+            .await
+            .with_context(|| format!("Failed to write HAR file {}", path.as_ref().display()))
+    }
+
+    /// Turn maintenance mode on or off. While it is on, new forwarded connections are
+    /// not passed to the local service: HTTP-aware forwards answer with
+    /// [`HttpConfig::maintenance_page`](crate::HttpConfig::maintenance_page) and raw
+    /// forwards are closed. The remote forward stays in place, so the public URL
+    /// survives a local redeploy.
+    pub fn set_maintenance(&self, enabled: bool) {
+        info!(target: targets::PROXY,
+            "Maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        self.shared.maintenance.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether maintenance mode is on
+    pub fn is_maintenance(&self) -> bool {
+        self.shared.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// Replace the keys or passwords used to authenticate, e.g. after a scheduled key
+    /// rotation. The current session stays up; reconnects use the new methods, tried
+    /// in order as with [`ReverseSshConfig::auth`]. An empty list means the `none`
+    /// method.
+    pub fn set_credentials(&self, methods: Vec<AuthMethod>) {
+        let methods = if methods.is_empty() {
+            vec![AuthMethod::None]
+        } else {
+            methods
+        };
+        let names: Vec<_> = methods.iter().map(AuthMethod::name).collect();
+        info!(target: targets::AUTH,
+            "Credentials replaced, reconnects will try {}",
+            names.join(", ")
+        );
+        *self.shared.credentials.lock().unwrap() = methods;
+    }
+
+    /// Switch the traffic shaping profile of the forward of `port`, the port the
+    /// server listens on, or turn its shaping off with `None`. Takes effect
+    /// immediately, including for connections already open.
+    pub fn set_shaping(&self, port: u32, profile: Option<ShapingProfile>) {
+        info!(target: targets::PROXY, "Traffic shaping of port {} set to {:?}", port, profile);
+        self.shared.shaper(&self.shared.route(port)).set(profile);
+    }
+
+    /// The traffic shaping profile currently applied to the forward of `port`
+    pub fn shaping(&self, port: u32) -> Option<ShapingProfile> {
+        self.shared.shaper(&self.shared.route(port)).get()
+    }
+
+    /// Replace the bandwidth limits. Global limits apply immediately to every
+    /// connection, per-connection limits to connections accepted from then on.
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        info!(target: targets::PROXY, "Rate limits set to {:?}", limits);
+        self.shared.rate_limiter.set(limits);
+    }
+
+    /// The bandwidth limits currently applied
+    pub fn rate_limits(&self) -> RateLimits {
+        self.shared.rate_limiter.get()
+    }
+
+    /// Replace the networks forwarded connections may come from. Applies to
+    /// connections accepted from then on.
+    pub fn set_access_list(&self, access_list: AccessList) {
+        info!(target: targets::SECURITY, "Access list set to {:?}", access_list);
+        *self.shared.access_list.lock().unwrap() = access_list;
+    }
+
+    /// The networks forwarded connections may currently come from
+    pub fn access_list(&self) -> AccessList {
+        self.shared.access_list.lock().unwrap().clone()
+    }
+
+    /// How long each phase of the latest connection attempt took
+    pub fn startup_timeline(&self) -> StartupTimeline {
+        self.shared.startup.timeline()
+    }
+
+    /// Receive [`TunnelEvent`]s from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// [`TunnelEvent`]s from now on, as a stream
+    pub fn events(&self) -> EventStream {
+        EventStream::new(self.shared.events.subscribe())
+    }
+
+    /// Snapshot of the runtime, tasks and allocations behind the client
+    #[cfg(feature = "profiling")]
+    pub fn profile(&self) -> ResourceUsage {
+        ResourceUsage::take(self)
+    }
+
+    /// Answer `profile`, `tasks` and `allocations` commands with JSON on a Unix
+    /// socket at `path`, until an error occurs on the listening socket
+    #[cfg(all(feature = "profiling", unix))]
+    pub async fn serve_profiling(&self, path: impl AsRef<Path>) -> Result<()> {
+        profiling::serve(self.clone(), path.as_ref()).await
+    }
+
+    /// Write to `fd`, an `eventfd` or the write end of a pipe, every time
+    /// `notification` occurs, for hosts that poll descriptors rather than take
+    /// callbacks. The client writes to a duplicate, so the host may close `fd`.
+    #[cfg(unix)]
+    pub fn notify_fd(
+        &self,
+        notification: Notification,
+        fd: impl std::os::fd::AsFd,
+    ) -> Result<JoinHandle<()>> {
+        notify::spawn(self.subscribe(), notification, fd)
+    }
+
+    /// Receive [`SecurityEvent`]s from now on
+    pub fn subscribe_security(&self) -> broadcast::Receiver<SecurityEvent> {
+        self.shared.events.subscribe_security()
+    }
+
+    /// [`SecurityEvent`]s from now on, as a stream, separate from [`TunnelEvent`]s
+    pub fn security_events(&self) -> EventStream<SecurityEvent> {
+        EventStream::new(self.shared.events.subscribe_security())
+    }
+
+    /// The slot holding the server message handler, to replace or remove the handler
+    /// while the client is running
+    pub fn message_handler(&self) -> MessageHandlerSlot {
+        self.shared.message_handler.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    /// A handle on a client that isn't connected
+    fn handle(config: &ReverseSshConfig) -> ClientHandle {
+        ClientHandle {
+            shared: Arc::new(Shared::new(config)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_tracks_connections() {
+        let handle = handle(&ReverseSshConfig::default());
+        let other = handle.clone();
+        assert!(handle.add_forward(8080).await.is_err());
+
+        let (id, counters, _deadline) = handle.shared.register_origin("203.0.113.7", 41000, 80);
+        counters.received.store(10, Ordering::Relaxed);
+
+        let connections = other.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].id, id);
+        assert_eq!(connections[0].bytes_received, 10);
+        assert_eq!(other.stats().total_connections, 1);
+
+        handle.shared.unregister(id, CloseReason::Completed);
+        assert!(other.connections().is_empty());
+        assert_eq!(other.stats().active_connections, 0);
+    }
+
+    #[test]
+    fn test_connection_deadlines_are_set_while_open() {
+        let handle = handle(&ReverseSshConfig::default());
+        let (id, _, _deadline) = handle.shared.register_origin("203.0.113.7", 41000, 80);
+        assert_eq!(handle.connections()[0].deadline, None);
+
+        assert!(handle.set_connection_deadline(id, Some(Duration::from_secs(30))));
+        assert!(handle.connections()[0].deadline.unwrap() <= Duration::from_secs(30));
+        assert!(handle.set_connection_deadline(id, None));
+        assert_eq!(handle.connections()[0].deadline, None);
+
+        handle.shared.unregister(id, CloseReason::Completed);
+        assert!(!handle.set_connection_deadline(id, Some(Duration::from_secs(30))));
+    }
+
+    #[test]
+    fn test_connections_are_routed_by_port() {
+        let handle = handle(&ReverseSshConfig::default());
+        handle
+            .shared
+            .forwards
+            .lock()
+            .unwrap()
+            .push(Forward::new(5432, "10.0.0.5", 5433));
+        assert_eq!(
+            handle.shared.route(5432).target().to_string(),
+            "10.0.0.5:5433"
+        );
+        // Anything else goes to the configuration's local target
+        assert_eq!(
+            handle.shared.route(80).target().to_string(),
+            "127.0.0.1:8080"
+        );
+        assert_eq!(handle.stats().forwards, [5432]);
+    }
+
+    #[test]
+    fn test_credentials_rotate_without_restart() {
+        let handle = handle(&ReverseSshConfig::default());
+        assert_eq!(handle.shared.credentials()[0].name(), "none");
+        handle.clone().set_credentials(vec![
+            AuthMethod::KeyFile("/keys/rotated".to_string()),
+            AuthMethod::Password("fallback".to_string()),
+        ]);
+        let names: Vec<_> = handle
+            .shared
+            .credentials()
+            .iter()
+            .map(AuthMethod::name)
+            .collect();
+        assert_eq!(names, ["key-file", "password"]);
+    }
+
+    #[test]
+    fn test_forward_budgets_win_over_the_configuration() {
+        let shared = Shared::new(&ReverseSshConfig {
+            buffer_size: 4096,
+            connection_budget: ConnectionBudget {
+                max_concurrent: Some(100),
+                max_lifetime: Some(Duration::from_secs(3600)),
+                max_idle: Some(Duration::from_secs(300)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let admin = Forward::new(2222, "127.0.0.1", 22).with_budget(ConnectionBudget {
+            max_buffered: Some(1024),
+            max_concurrent: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(
+            shared.budget(&admin),
+            ConnectionBudget {
+                max_buffered: Some(1024),
+                max_concurrent: Some(2),
+                max_lifetime: Some(Duration::from_secs(3600)),
+                max_idle: Some(Duration::from_secs(300)),
+            }
+        );
+        let web = shared.budget(&Forward::new(80, "127.0.0.1", 8080));
+        assert_eq!(
+            (web.max_buffered, web.max_concurrent),
+            (Some(4096), Some(100))
+        );
+        shared.register_origin("203.0.113.7", 41001, 2222);
+        shared.register_origin("203.0.113.8", 41002, 2222);
+        shared.register_origin("203.0.113.9", 41003, 80);
+        assert_eq!(shared.open_on(2222), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refused_optional_forward_is_reported() {
+        let handle = handle(&ReverseSshConfig::default());
+        let mut events = handle.subscribe();
+        let ssh = Forward::new(2222, "127.0.0.1", 22).optional();
+        let error = handle.add_forward_to(ssh.clone()).await.unwrap_err();
+        handle.shared.forward_refused(&ssh, &error);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(TunnelEvent::ForwardRefused { port: 2222, .. })
+        ));
+        assert_eq!(handle.metrics().counter("forwards_refused_total"), 1);
+    }
+
+    #[test]
+    fn test_closed_connections_report_their_stats() {
+        let handle = handle(&ReverseSshConfig::default());
+        let mut events = handle.subscribe();
+        let (id, counters, _deadline) = handle.shared.register_origin("203.0.113.7", 41000, 80);
+        counters.received.store(10, Ordering::Relaxed);
+        assert_eq!(handle.stats().connections[0].id, id);
+
+        handle.shared.unregister(id, CloseReason::Completed);
+        match events.try_recv() {
+            Ok(TunnelEvent::ConnectionClosed { stats, .. }) => {
+                assert_eq!(stats.originator_address, "203.0.113.7");
+                assert_eq!(stats.bytes_received, 10);
+                assert_eq!(stats.deadline, None);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(handle.stats().connections.is_empty());
+    }
+
+    #[test]
+    fn test_forwards_are_shaped_separately() {
+        let client = crate::ReverseSshClient::new(ReverseSshConfig {
+            remote_port: 80,
+            shaping: Some(ShapingProfile::DSL),
+            forwards: vec![
+                Forward::new(5432, "127.0.0.1", 5432),
+                Forward::new(9000, "127.0.0.1", 9000).with_shaping(ShapingProfile::GPRS),
+            ],
+            ..Default::default()
+        });
+        let config = &client.config;
+        let mut forwards = vec![config.local_forward()];
+        forwards.extend(config.forwards.iter().cloned());
+        client.shared.forwards.lock().unwrap().extend(forwards);
+        assert_eq!(client.shaping(80), Some(ShapingProfile::DSL));
+        assert_eq!(client.shaping(5432), None);
+        assert_eq!(client.shaping(9000), Some(ShapingProfile::GPRS));
+
+        // Switching one forward leaves the others alone
+        client.set_shaping(5432, Some(ShapingProfile::THREE_G));
+        client.set_shaping(80, None);
+        assert_eq!(client.shaping(5432), Some(ShapingProfile::THREE_G));
+        assert_eq!(client.shaping(80), None);
+        assert_eq!(client.shaping(9000), Some(ShapingProfile::GPRS));
+    }
+
+    #[tokio::test]
+    async fn test_server_picks_the_port_of_a_port_zero_forward() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        for (port, greeting) in [(8080, b"web"), (9000, b"api")] {
+            let mut service = network.listen("127.0.0.1", port).unwrap();
+            tokio::spawn(async move {
+                while let Some((mut stream, _)) = service.accept().await {
+                    let _ = stream.write_all(greeting).await;
+                }
+            });
+        }
+        let mut client = crate::ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 80,
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::StateChanged {
+                to: TunnelState::Ready,
+                ..
+            })
+        ) {}
+
+        let port = handle
+            .add_forward_to(Forward::new(0, "127.0.0.1", 9000))
+            .await
+            .unwrap();
+        assert_ne!(port, 0);
+        assert!(matches!(
+            events.recv().await,
+            Ok(TunnelEvent::ForwardEstablished { port: p, requested: 0 }) if p == port
+        ));
+        assert_eq!(handle.stats().forwards, [80, port]);
+
+        // Connections to the picked port reach the forward's own target
+        for (port, greeting) in [(port, b"api"), (80, b"web")] {
+            let mut peer = network.dial("ssh.sim", port as u16).await.unwrap();
+            let mut reply = [0u8; 3];
+            peer.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, greeting, "port {}", port);
+        }
+        handle.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+//! Active health checks of the local target
+//!
+//! While connections are being handled, a monitor task probes the local service every
+//! [`HealthCheck::interval`], so an outage is noticed before a public request runs
+//! into it. The target changes between healthy and unhealthy only after
+//! [`HealthCheck::unhealthy_threshold`] failed or [`HealthCheck::healthy_threshold`]
+//! passed probes in a row, so a single slow probe doesn't flap the state.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::clock::{self, Clock};
+use crate::forward::LocalTarget;
+use crate::network::Network;
+
+/// Largest status line read from an HTTP probe
+const MAX_STATUS_LINE: usize = 1024;
+
+/// How the local target is probed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthProbe {
+    /// The target accepts a TCP connection
+    Tcp,
+    /// `GET path` answers with a 2xx or 3xx status
+    Http { path: String },
+}
+
+/// Periodic probing of the local target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub probe: HealthProbe,
+    /// Time between probes
+    pub interval: Duration,
+    /// Time a probe may take before it counts as failed
+    pub timeout: Duration,
+    /// Consecutive failed probes before the target is marked unhealthy
+    pub unhealthy_threshold: u32,
+    /// Consecutive passed probes before an unhealthy target is marked healthy again
+    pub healthy_threshold: u32,
+}
+
+impl HealthCheck {
+    /// Check that the target accepts TCP connections, every 10 seconds
+    pub fn tcp() -> Self {
+        Self {
+            probe: HealthProbe::Tcp,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+
+    /// Check that `GET path` succeeds, every 10 seconds
+    pub fn http(path: impl Into<String>) -> Self {
+        Self {
+            probe: HealthProbe::Http { path: path.into() },
+            ..Self::tcp()
+        }
+    }
+
+    /// Probe the target once, for at most `timeout` on `clock`
+    pub(crate) async fn probe(
+        &self,
+        network: &dyn Network,
+        target: &LocalTarget,
+        clock: &dyn Clock,
+    ) -> Result<()> {
+        clock::timeout(clock, self.timeout, self.probe.run(network, target))
+            .await
+            .with_context(|| format!("Health check timed out after {:?}", self.timeout))?
+    }
+}
+
+impl HealthProbe {
+    async fn run(&self, network: &dyn Network, target: &LocalTarget) -> Result<()> {
+        let (mut rx, mut tx) = target.connect(network, None).await?;
+        let HealthProbe::Http { path } = self else {
+            return Ok(());
+        };
+        let status = get_status(&mut rx, &mut tx, &target.host(), path).await?;
+        if !(200..400).contains(&status) {
+            bail!("Health check GET {} answered {}", path, status);
+        }
+        Ok(())
+    }
+}
+
+/// Send `GET path` for `host` and read the status of the response
+pub(crate) async fn get_status<R, W>(rx: &mut R, tx: &mut W, host: &str, path: &str) -> Result<u16>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: reverse-ssh\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    tx.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::new();
+    let mut buf = [0u8; 256];
+    while !head.windows(2).any(|w| w == b"\r\n") {
+        if head.len() >= MAX_STATUS_LINE {
+            bail!("Response to GET {} has no status line", path);
+        }
+        let n = rx.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Connection closed without answering GET {}", path);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let line = String::from_utf8_lossy(&head);
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("Invalid response {:?} to GET {}", line.lines().next(), path))
+}
+
+/// Consecutive probe results, turned into healthy/unhealthy changes
+#[derive(Debug)]
+pub(crate) struct HealthTracker {
+    healthy: Option<bool>,
+    streak: u32,
+    unhealthy_threshold: u32,
+    healthy_threshold: u32,
+}
+
+impl HealthTracker {
+    pub(crate) fn new(check: &HealthCheck) -> Self {
+        Self {
+            healthy: None,
+            streak: 0,
+            unhealthy_threshold: check.unhealthy_threshold.max(1),
+            healthy_threshold: check.healthy_threshold.max(1),
+        }
+    }
+
+    /// Record a probe result, returning the new health when it changes. The first
+    /// probe decides the initial health on its own.
+    pub(crate) fn record(&mut self, passed: bool) -> Option<bool> {
+        let Some(healthy) = self.healthy else {
+            self.healthy = Some(passed);
+            return Some(passed);
+        };
+        if passed == healthy {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        let threshold = if passed {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        };
+        if self.streak < threshold {
+            return None;
+        }
+        self.streak = 0;
+        self.healthy = Some(passed);
+        Some(passed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SystemClock, TcpNetwork};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_probes_and_thresholds() {
+        let mut tracker = HealthTracker::new(&HealthCheck::tcp());
+        assert_eq!(tracker.record(true), Some(true));
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(true), None);
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(false), None);
+        assert_eq!(tracker.record(false), Some(false));
+        assert_eq!(tracker.record(true), None);
+        assert_eq!(tracker.record(true), Some(true));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for status in ["200 OK", "503 Service Unavailable"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let check = HealthCheck::http("/healthz");
+        let target = LocalTarget::Tcp {
+            addr: "127.0.0.1".to_string(),
+            port,
+        };
+        check
+            .probe(&TcpNetwork, &target, &SystemClock)
+            .await
+            .unwrap();
+        let error = check
+            .probe(&TcpNetwork, &target, &SystemClock)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Health check GET /healthz answered 503");
+
+        let closed = LocalTarget::Tcp {
+            addr: "127.0.0.1".to_string(),
+            port: 1,
+        };
+        assert!(HealthCheck::tcp()
+            .probe(&TcpNetwork, &closed, &SystemClock)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,2141 @@
+//! HTTP-aware forwarding
+//!
+//! When a forward is configured with an [`HttpConfig`], forwarded connections are
+//! handled as a sequence of HTTP/1.x exchanges instead of being copied byte for byte.
+//! This lets the proxy apply per-request policies such as request timeouts, while
+//! upgraded connections (WebSockets) are handed over to a plain bidirectional copy.
+//!
+//! Connections that do not start with an HTTP/1.x request, such as HTTP/2 with prior
+//! knowledge (h2c, used by most gRPC dev servers), fall back to raw passthrough.
+
+mod cache;
+mod hooks;
+mod inspector;
+mod redact;
+mod webhook;
+
+pub use cache::CacheConfig;
+pub use hooks::RequestHook;
+pub use inspector::InspectorConfig;
+pub use redact::{JsonPath, Redaction, RedactionPattern};
+pub use webhook::{WebhookConfig, WebhookScheme};
+
+use crate::clock::{self, Clock};
+use crate::metrics::{Metrics, DURATION_BUCKETS, SIZE_BUCKETS};
+use crate::targets;
+use anyhow::{Context, Result};
+use cache::{CacheKey, ResponseCache};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+pub(crate) use inspector::{encode_base64, format_rfc3339};
+use inspector::{CapturedExchange, Inspector, Tap};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+/// Configuration for HTTP-aware forwarding
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    /// Maximum time between receiving a request head and receiving the head of its
+    /// response, request body included. Response bodies stream for as long as they
+    /// keep moving, under `body_idle_timeout`, and upgraded connections (e.g.
+    /// WebSockets) under `idle_timeout`.
+    pub request_timeout: Option<Duration>,
+    /// Cut a response body off when the local service sends none of it for this long
+    pub body_idle_timeout: Option<Duration>,
+    /// Close the connection when no bytes flow in either direction for this long.
+    /// Unlike `request_timeout`, this also applies to upgraded connections.
+    pub idle_timeout: Option<Duration>,
+    /// Maximum size of a request or response head in bytes
+    pub max_head_size: usize,
+    /// Bytes read from either side at a time when relaying bodies and upgraded
+    /// connections; larger buffers suit large uploads and downloads
+    pub buffer_size: usize,
+    /// Headers added to every final response, replacing any the local service set
+    /// (e.g. `X-Robots-Tag: noindex` or CORS headers)
+    pub response_headers: Vec<(String, String)>,
+    /// Largest response body relayed to the client. Larger responses are replaced by
+    /// a 502 when announced up front, or cut off once the limit is crossed.
+    pub max_response_size: Option<u64>,
+    /// Compress text-like responses with gzip or deflate when the client accepts it
+    /// and the local service didn't already encode them
+    pub compression: bool,
+    /// Cache cacheable `GET` responses in memory, so repeated loads of static assets
+    /// don't reach the local service
+    pub cache: Option<CacheConfig>,
+    /// Reject requests without a valid webhook signature before they reach the local service
+    pub webhook: Option<WebhookConfig>,
+    /// Inspect or rewrite requests before they are forwarded
+    pub request_hook: Option<Arc<dyn RequestHook>>,
+    /// Record exchanges for inspection and HAR export
+    pub inspector: Option<InspectorConfig>,
+    /// Secrets removed from what is recorded of the traffic: the inspector's
+    /// exchanges, HAR files and the request targets in logs
+    pub redaction: Redaction,
+    /// HTML body of the `503` page served while the client is in maintenance mode
+    pub maintenance_page: String,
+}
+
+const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+const DEFAULT_MAINTENANCE_PAGE: &str = "<!DOCTYPE html>\n<html><head><title>Be right back</title></head>\n<body><h1>Be right back</h1><p>This service is being updated and will return shortly.</p></body></html>\n";
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            request_timeout: Some(Duration::from_secs(30)),
+            body_idle_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(300)),
+            max_head_size: 64 * 1024,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            response_headers: Vec::new(),
+            max_response_size: None,
+            compression: false,
+            cache: None,
+            webhook: None,
+            request_hook: None,
+            inspector: None,
+            redaction: Redaction::default(),
+            maintenance_page: DEFAULT_MAINTENANCE_PAGE.to_string(),
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request line and header block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// A parsed HTTP/1.x status line and header block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Parse a request head, including its terminating empty line
+    pub fn parse(head: &[u8]) -> Result<Self> {
+        let (start, headers) = split_head(head)?;
+        let mut parts = start.splitn(3, ' ');
+        let method = parts.next().unwrap_or_default().to_string();
+        let target = parts.next().context("Missing request target")?.to_string();
+        let version = parts.next().context("Missing HTTP version")?.to_string();
+        if method.is_empty() || !version.starts_with("HTTP/") {
+            anyhow::bail!("Malformed request line: {}", start);
+        }
+        Ok(Self {
+            method,
+            target,
+            version,
+            headers,
+        })
+    }
+
+    /// Look up a header value by case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Whether the client asked to switch protocols (e.g. `Upgrade: websocket`)
+    pub fn is_upgrade(&self) -> bool {
+        has_token(&self.headers, "connection", "upgrade") && self.header("upgrade").is_some()
+    }
+
+    /// Set a header, replacing every existing header of the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.headers, name, value);
+    }
+
+    /// Remove every header with the given name
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub(crate) fn body_kind(&self) -> BodyKind {
+        if has_token(&self.headers, "transfer-encoding", "chunked") {
+            BodyKind::Chunked
+        } else if let Some(len) = content_length(&self.headers) {
+            BodyKind::Length(len)
+        } else {
+            BodyKind::Empty
+        }
+    }
+
+    fn wants_close(&self) -> bool {
+        wants_close(&self.version, &self.headers)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let start = format!("{} {} {}", self.method, self.target, self.version);
+        encode_head(&start, &self.headers)
+    }
+}
+
+impl ResponseHead {
+    /// Parse a response head, including its terminating empty line
+    pub fn parse(head: &[u8]) -> Result<Self> {
+        let (start, headers) = split_head(head)?;
+        let mut parts = start.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default().to_string();
+        if !version.starts_with("HTTP/") {
+            anyhow::bail!("Malformed status line: {}", start);
+        }
+        let status = parts
+            .next()
+            .and_then(|s| s.parse().ok())
+            .with_context(|| format!("Malformed status line: {}", start))?;
+        let reason = parts.next().unwrap_or_default().to_string();
+        Ok(Self {
+            version,
+            status,
+            reason,
+            headers,
+        })
+    }
+
+    /// Look up a header value by case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Set a header, replacing every existing header of the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.headers, name, value);
+    }
+
+    /// Append a header, keeping any existing ones of the same name
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove every header with the given name
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    fn body_kind(&self, request_method: &str) -> BodyKind {
+        if request_method.eq_ignore_ascii_case("HEAD")
+            || (100..200).contains(&self.status)
+            || self.status == 204
+            || self.status == 304
+        {
+            BodyKind::Empty
+        } else if has_token(&self.headers, "transfer-encoding", "chunked") {
+            BodyKind::Chunked
+        } else if let Some(len) = content_length(&self.headers) {
+            BodyKind::Length(len)
+        } else {
+            BodyKind::UntilClose
+        }
+    }
+
+    fn wants_close(&self) -> bool {
+        wants_close(&self.version, &self.headers)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let start = format!("{} {} {}", self.version, self.status, self.reason);
+        encode_head(&start, &self.headers)
+    }
+}
+
+/// How the body following a head is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyKind {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// What to do with the connection once an exchange has completed
+enum Exchange {
+    KeepAlive,
+    Close,
+    Upgraded,
+}
+
+/// Progress of the exchange in flight, readable after it was cancelled by a timeout
+#[derive(Default)]
+struct ExchangeState {
+    response_started: bool,
+    request_bytes: u64,
+    response_bytes: u64,
+    /// What the inspector records: the response as sent and copies of both bodies
+    response: Option<ResponseHead>,
+    request_tap: Option<Tap>,
+    response_tap: Option<Tap>,
+}
+
+/// Everything an exchange needs besides the streams themselves
+struct ProxyContext<'a> {
+    config: &'a HttpConfig,
+    /// Port the server received the connection on, telling forwards apart
+    port: u32,
+    metrics: &'a Metrics,
+    cache: Option<&'a ResponseCache>,
+    inspector: Option<&'a Inspector>,
+    clock: &'a Arc<dyn Clock>,
+}
+
+/// A body grew past `max_response_size` while it was being relayed
+#[derive(Debug)]
+struct BodyTooLarge(u64);
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Response body exceeds {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for BodyTooLarge {}
+
+/// The local service didn't answer within `request_timeout`
+#[derive(Debug)]
+struct RequestTimedOut(Duration);
+
+impl fmt::Display for RequestTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No response within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for RequestTimedOut {}
+
+/// HTTP-aware forwarding for one forward: its configuration plus state shared
+/// by all of its connections
+#[derive(Debug)]
+pub(crate) struct HttpProxy {
+    config: HttpConfig,
+    cache: Option<ResponseCache>,
+    inspector: Option<Inspector>,
+    clock: Arc<dyn Clock>,
+}
+
+impl HttpProxy {
+    pub(crate) fn new(config: HttpConfig, clock: Arc<dyn Clock>) -> Self {
+        let cache = config
+            .cache
+            .clone()
+            .map(|cache| ResponseCache::new(cache, clock.clone()));
+        let inspector = config
+            .inspector
+            .clone()
+            .map(|inspector| Inspector::new(inspector, config.redaction.clone()));
+        Self {
+            config,
+            cache,
+            inspector,
+            clock,
+        }
+    }
+
+    /// Recorded exchanges as a HAR document, if the inspector is enabled
+    pub(crate) fn har(&self) -> Option<String> {
+        self.inspector.as_ref().map(Inspector::har)
+    }
+
+    /// Proxy HTTP/1.x exchanges between a forwarded channel, received on `port`, and
+    /// the local service
+    pub(crate) async fn proxy<CR, CW, LR, LW>(
+        &self,
+        port: u32,
+        client_rx: CR,
+        client_tx: CW,
+        local_rx: LR,
+        local_tx: LW,
+        metrics: &Metrics,
+    ) -> Result<()>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+        LR: AsyncRead + Unpin,
+        LW: AsyncWrite + Unpin,
+    {
+        let ctx = ProxyContext {
+            config: &self.config,
+            port,
+            metrics,
+            cache: self.cache.as_ref(),
+            inspector: self.inspector.as_ref(),
+            clock: &self.clock,
+        };
+        proxy(client_rx, client_tx, local_rx, local_tx, &ctx).await
+    }
+
+    /// Answer a connection with the maintenance page instead of reaching the local
+    /// service
+    pub(crate) async fn serve_maintenance<CR, CW>(
+        &self,
+        client_rx: CR,
+        mut client_tx: CW,
+        metrics: &Metrics,
+    ) -> Result<()>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+    {
+        let mut client = BufferedReader::new(client_rx)
+            .with_buffer_size(self.config.buffer_size)
+            .with_clock(self.clock.clone());
+        let idle = self.config.idle_timeout;
+        if client
+            .read_head(self.config.max_head_size, idle)
+            .await?
+            .is_none()
+        {
+            return Ok(());
+        }
+        metrics.increment("http_maintenance_responses_total", 1);
+        let page = &self.config.maintenance_page;
+        let head = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nRetry-After: 30\r\nConnection: close\r\n\r\n",
+            page.len()
+        );
+        client_tx.write_all(head.as_bytes()).await?;
+        client_tx.write_all(page.as_bytes()).await?;
+        client_tx.flush().await?;
+        Ok(())
+    }
+}
+
+async fn proxy<CR, CW, LR, LW>(
+    client_rx: CR,
+    mut client_tx: CW,
+    local_rx: LR,
+    mut local_tx: LW,
+    ctx: &ProxyContext<'_>,
+) -> Result<()>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    LR: AsyncRead + Unpin,
+    LW: AsyncWrite + Unpin,
+{
+    let (config, metrics) = (ctx.config, ctx.metrics);
+    let mut client = BufferedReader::new(client_rx)
+        .with_buffer_size(config.buffer_size)
+        .with_clock(ctx.clock.clone());
+    let mut local = BufferedReader::new(local_rx)
+        .with_buffer_size(config.buffer_size)
+        .with_clock(ctx.clock.clone());
+
+    match client.sniff_http1(config.idle_timeout).await {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!(target: targets::PROXY, "Not an HTTP/1.x connection, switching to passthrough");
+            return tunnel(client, client_tx, local, local_tx, config.idle_timeout).await;
+        }
+        Err(e) if is_idle(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    let mut first = true;
+    loop {
+        let head = match client
+            .read_head(config.max_head_size, config.idle_timeout)
+            .await
+        {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(()),
+            Err(e) if is_idle(&e) => {
+                debug!(target: targets::PROXY, "Closing idle HTTP connection");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let mut request = match RequestHead::parse(&head) {
+            Ok(request) if request.version.starts_with("HTTP/1.") => request,
+            _ if first => {
+                // HTTP/2 prior knowledge sends `PRI * HTTP/2.0`; rewriting it would corrupt the stream
+                debug!(target: targets::PROXY, "Not an HTTP/1.x request, switching to passthrough");
+                client.unread(head);
+                return tunnel(client, client_tx, local, local_tx, config.idle_timeout).await;
+            }
+            Ok(request) => anyhow::bail!("Unsupported HTTP version: {}", request.version),
+            Err(e) => return Err(e),
+        };
+        first = false;
+        debug!(target: targets::PROXY,
+            "{} {} {}", request.method, config.redaction.text(&request.target), request.version);
+
+        if let Some(webhook) = config.webhook.as_ref().filter(|w| w.applies_to(&request)) {
+            let idle = config.idle_timeout;
+            match verify_webhook(&mut client, &mut client_tx, &mut request, webhook, idle).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(target: targets::PROXY,
+                        "Rejected webhook with an invalid signature: {} {}",
+                        request.method, config.redaction.text(&request.target)
+                    );
+                    metrics.increment("http_webhooks_rejected_total", 1);
+                    client_tx
+                        .write_all(&error_response(401, "Unauthorized"))
+                        .await?;
+                    client_tx.flush().await?;
+                    return Ok(());
+                }
+                Err(e) if is_idle(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(hook) = &config.request_hook {
+            let idle = config.idle_timeout;
+            match apply_request_hook(&mut client, &mut client_tx, &mut request, &**hook, idle).await
+            {
+                Ok(true) => {}
+                Ok(false) => {
+                    client_tx
+                        .write_all(&error_response(413, "Payload Too Large"))
+                        .await?;
+                    client_tx.flush().await?;
+                    return Ok(());
+                }
+                Err(e) if is_idle(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let started = ctx.clock.now();
+        let started_at = SystemTime::now();
+        let mut state = ExchangeState::default();
+        if let Some(inspector) = ctx.inspector {
+            state.request_tap = Some(inspector.tap());
+            state.response_tap = Some(inspector.tap());
+        }
+        let result = forward_exchange(
+            &mut client,
+            &mut client_tx,
+            &mut local,
+            &mut local_tx,
+            &request,
+            ctx,
+            &mut state,
+        )
+        .await;
+        if let Some(RequestTimedOut(limit)) = result.as_ref().err().and_then(|e| e.downcast_ref()) {
+            warn!(target: targets::PROXY,
+                "Request timed out after {:?}: {} {}",
+                limit, request.method, config.redaction.text(&request.target)
+            );
+            if !state.response_started {
+                let _ = client_tx
+                    .write_all(&error_response(504, "Gateway Timeout"))
+                    .await;
+                let _ = client_tx.flush().await;
+            }
+            return Ok(());
+        }
+
+        if matches!(result, Ok(Exchange::KeepAlive | Exchange::Close)) {
+            let elapsed = (ctx.clock.now() - started).as_secs_f64();
+            metrics.increment("http_requests_total", 1);
+            metrics.observe("http_request_duration_seconds", DURATION_BUCKETS, elapsed);
+            metrics.observe(
+                "http_request_size_bytes",
+                SIZE_BUCKETS,
+                state.request_bytes as f64,
+            );
+            metrics.observe(
+                "http_response_size_bytes",
+                SIZE_BUCKETS,
+                state.response_bytes as f64,
+            );
+            if let (Some(inspector), Some(response)) = (ctx.inspector, state.response.take()) {
+                inspector
+                    .record(CapturedExchange {
+                        started: started_at,
+                        duration: ctx.clock.now() - started,
+                        request: request.clone(),
+                        request_body: state.request_tap.take().unwrap_or_default(),
+                        response,
+                        response_body: state.response_tap.take().unwrap_or_default(),
+                    })
+                    .await;
+            }
+        }
+
+        match result {
+            Ok(Exchange::KeepAlive) => continue,
+            Ok(Exchange::Close) => return Ok(()),
+            Ok(Exchange::Upgraded) => {
+                debug!(target: targets::PROXY, "Connection upgraded, switching to passthrough");
+                return tunnel(client, client_tx, local, local_tx, config.idle_timeout).await;
+            }
+            Err(e) if is_idle(&e) => {
+                debug!(target: targets::PROXY, "Closing idle HTTP connection");
+                return Ok(());
+            }
+            Err(e) => {
+                if !state.response_started {
+                    let _ = client_tx
+                        .write_all(&error_response(502, "Bad Gateway"))
+                        .await;
+                    let _ = client_tx.flush().await;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Check the webhook signature of `request`, buffering its body
+async fn verify_webhook<CR, CW>(
+    client: &mut BufferedReader<CR>,
+    client_tx: &mut CW,
+    request: &mut RequestHead,
+    webhook: &WebhookConfig,
+    idle: Option<Duration>,
+) -> Result<bool>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+{
+    let limit = webhook.max_body_size;
+    let Some(body) = read_full_body(client, client_tx, request, limit, idle).await? else {
+        return Ok(false);
+    };
+    if !webhook.verify(request, &body) {
+        return Ok(false);
+    }
+    restore_body(client, request, body);
+    Ok(true)
+}
+
+/// Run the request hook, buffering the body first if the hook asks for it.
+/// Returns `false` if the body grew past the hook's limit.
+async fn apply_request_hook<CR, CW>(
+    client: &mut BufferedReader<CR>,
+    client_tx: &mut CW,
+    request: &mut RequestHead,
+    hook: &dyn RequestHook,
+    idle: Option<Duration>,
+) -> Result<bool>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+{
+    let limit = match (hook.body_limit(), request.body_kind()) {
+        (Some(limit), BodyKind::Length(len)) if len > limit => None,
+        (limit, _) => limit,
+    };
+    let Some(limit) = limit else {
+        hook.on_request(request, None).await;
+        return Ok(true);
+    };
+    let Some(mut body) = read_full_body(client, client_tx, request, limit, idle).await? else {
+        return Ok(false);
+    };
+    hook.on_request(request, Some(&mut body)).await;
+    restore_body(client, request, body);
+    Ok(true)
+}
+
+/// Read the complete body of `request`, or `None` if it is larger than `limit`
+async fn read_full_body<CR, CW>(
+    client: &mut BufferedReader<CR>,
+    client_tx: &mut CW,
+    request: &mut RequestHead,
+    limit: u64,
+    idle: Option<Duration>,
+) -> Result<Option<Vec<u8>>>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+{
+    if has_token(&request.headers, "expect", "100-continue") {
+        // The body is needed before the local service sees the request
+        client_tx
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await?;
+        client_tx.flush().await?;
+        request.remove_header("expect");
+    }
+
+    let mut body = Vec::new();
+    let mut decoder = BodyDecoder::new(request.body_kind());
+    while let Some(data) = decoder.next(client, idle).await? {
+        body.extend_from_slice(&data);
+        if body.len() as u64 > limit {
+            return Ok(None);
+        }
+    }
+    Ok(Some(body))
+}
+
+/// Put a buffered body back in front of the client stream, re-framed with a `Content-Length`
+fn restore_body<R: AsyncRead + Unpin>(
+    client: &mut BufferedReader<R>,
+    request: &mut RequestHead,
+    body: Vec<u8>,
+) {
+    if !body.is_empty() || request.body_kind() != BodyKind::Empty {
+        request.remove_header("transfer-encoding");
+        request.set_header("Content-Length", &body.len().to_string());
+    }
+    client.unread(body);
+}
+
+/// Forward one request to the local service and relay its response back
+async fn forward_exchange<CR, CW, LR, LW>(
+    client: &mut BufferedReader<CR>,
+    client_tx: &mut CW,
+    local: &mut BufferedReader<LR>,
+    local_tx: &mut LW,
+    request: &RequestHead,
+    ctx: &ProxyContext<'_>,
+    state: &mut ExchangeState,
+) -> Result<Exchange>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    LR: AsyncRead + Unpin,
+    LW: AsyncWrite + Unpin,
+{
+    let config = ctx.config;
+    let cache_key = ctx
+        .cache
+        .and_then(|_| CacheKey::for_request(request, ctx.port));
+    if let (Some(cache), Some(key)) = (ctx.cache, &cache_key) {
+        if let Some(cached) = cache.get(key) {
+            debug!(target: targets::PROXY,
+                "Serving {} from cache", config.redaction.text(&request.target));
+            ctx.metrics.increment("http_cache_hits_total", 1);
+            let mut response = cached.head.clone();
+            response.set_header("Age", &cache.age(&cached).to_string());
+            let kind = BodyKind::Length(cached.body.len() as u64);
+            let mut body = BufferedReader::new(&cached.body[..]);
+            return send_response(&mut body, client_tx, request, response, kind, ctx, state).await;
+        }
+        ctx.metrics.increment("http_cache_misses_total", 1);
+    }
+
+    // The request timeout runs until the response head arrives; the body streams on
+    let response = send_request(client, client_tx, local, local_tx, request, ctx, state);
+    let response = match config.request_timeout {
+        Some(limit) => clock::timeout(&**ctx.clock, limit, response)
+            .await
+            .map_err(|_| RequestTimedOut(limit))??,
+        None => response.await?,
+    };
+    let Some(response) = response else {
+        return Ok(Exchange::Upgraded);
+    };
+
+    let kind = response.body_kind(&request.method);
+    if let (Some(cache), Some(key)) = (ctx.cache, cache_key) {
+        if let Some(lifetime) = cache.lifetime(&response, kind) {
+            let mut body = Vec::new();
+            let mut decoder = BodyDecoder::new(kind);
+            while let Some(data) = decoder.next(local, config.body_idle_timeout).await? {
+                body.extend_from_slice(&data);
+            }
+            cache.insert(key, response.clone(), body.clone(), lifetime);
+            let mut body = BufferedReader::new(&body[..]);
+            return send_response(&mut body, client_tx, request, response, kind, ctx, state).await;
+        }
+    }
+    send_response(local, client_tx, request, response, kind, ctx, state).await
+}
+
+/// Send a request and its body to the local service and read the head of its final
+/// response, relaying interim responses to the client. `None` once the local service
+/// accepted an upgrade, which was relayed too.
+async fn send_request<CR, CW, LR, LW>(
+    client: &mut BufferedReader<CR>,
+    client_tx: &mut CW,
+    local: &mut BufferedReader<LR>,
+    local_tx: &mut LW,
+    request: &RequestHead,
+    ctx: &ProxyContext<'_>,
+    state: &mut ExchangeState,
+) -> Result<Option<ResponseHead>>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    LR: AsyncRead + Unpin,
+    LW: AsyncWrite + Unpin,
+{
+    let config = ctx.config;
+    let idle = config.idle_timeout;
+
+    local_tx.write_all(&request.to_bytes()).await?;
+    let kind = request.body_kind();
+    let tap = state.request_tap.as_mut();
+    state.request_bytes = copy_body(client, local_tx, kind, None, idle, tap).await?;
+    local_tx.flush().await?;
+
+    loop {
+        let head = local
+            .read_head(config.max_head_size, idle)
+            .await?
+            .context("Local service closed the connection without responding")?;
+        let response = ResponseHead::parse(&head)?;
+        debug!(target: targets::PROXY, "{} {}", response.status, response.reason);
+
+        if response.status == 101 && request.is_upgrade() {
+            state.response_started = true;
+            client_tx.write_all(&response.to_bytes()).await?;
+            client_tx.flush().await?;
+            return Ok(None);
+        }
+        if (100..200).contains(&response.status) {
+            // Interim response (e.g. 100 Continue); the final one follows
+            state.response_started = true;
+            client_tx.write_all(&response.to_bytes()).await?;
+            client_tx.flush().await?;
+            continue;
+        }
+        return Ok(Some(response));
+    }
+}
+
+/// Relay a final response to the client, applying header injection, the size
+/// ceiling and compression. `body` supplies the payload framed as `kind`.
+async fn send_response<B, CW>(
+    body: &mut BufferedReader<B>,
+    client_tx: &mut CW,
+    request: &RequestHead,
+    mut response: ResponseHead,
+    kind: BodyKind,
+    ctx: &ProxyContext<'_>,
+    state: &mut ExchangeState,
+) -> Result<Exchange>
+where
+    B: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+{
+    let config = ctx.config;
+    for (name, value) in &config.response_headers {
+        response.set_header(name, value);
+    }
+
+    if let (Some(limit), BodyKind::Length(len)) = (config.max_response_size, kind) {
+        if len > limit {
+            warn!(target: targets::PROXY,
+                "Response of {} bytes exceeds the {} byte limit: {} {}",
+                len, limit, request.method, config.redaction.text(&request.target)
+            );
+            ctx.metrics.increment("http_responses_too_large_total", 1);
+            state.response_started = true;
+            client_tx
+                .write_all(&error_response(502, "Response Too Large"))
+                .await?;
+            client_tx.flush().await?;
+            return Ok(Exchange::Close);
+        }
+    }
+
+    let encoding = if config.compression {
+        compression_for(request, &response, kind)
+    } else {
+        None
+    };
+    if let Some(encoding) = encoding {
+        debug!(target: targets::PROXY, "Compressing response with {}", encoding.name());
+        response.remove_header("content-length");
+        response.set_header("Content-Encoding", encoding.name());
+        response.set_header("Transfer-Encoding", "chunked");
+        response.add_header("Vary", "Accept-Encoding");
+    }
+
+    state.response_started = true;
+    client_tx.write_all(&response.to_bytes()).await?;
+    if ctx.inspector.is_some() {
+        state.response = Some(response.clone());
+    }
+
+    let limit = config.max_response_size;
+    let idle = config.body_idle_timeout;
+    let tap = state.response_tap.as_mut();
+    let relayed = match encoding {
+        Some(encoding) => {
+            let encoder = Encoder::new(encoding);
+            compress_body(body, client_tx, kind, encoder, limit, idle, tap).await
+        }
+        None => copy_body(body, client_tx, kind, limit, idle, tap).await,
+    };
+    state.response_bytes = match relayed {
+        Ok(n) => n,
+        Err(e) => {
+            if e.is::<BodyTooLarge>() {
+                ctx.metrics.increment("http_responses_too_large_total", 1);
+            }
+            return Err(e);
+        }
+    };
+    client_tx.flush().await?;
+
+    Ok(
+        if kind == BodyKind::UntilClose || request.wants_close() || response.wants_close() {
+            Exchange::Close
+        } else {
+            Exchange::KeepAlive
+        },
+    )
+}
+
+/// Copy bytes in both directions after a successful protocol upgrade.
+/// Only the idle timeout applies from here on.
+async fn tunnel<CR, CW, LR, LW>(
+    mut client: BufferedReader<CR>,
+    mut client_tx: CW,
+    mut local: BufferedReader<LR>,
+    mut local_tx: LW,
+    idle: Option<Duration>,
+) -> Result<()>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    LR: AsyncRead + Unpin,
+    LW: AsyncWrite + Unpin,
+{
+    // Bytes read ahead while parsing heads belong to the upgraded stream
+    if !client.buf.is_empty() {
+        local_tx.write_all(&std::mem::take(&mut client.buf)).await?;
+        local_tx.flush().await?;
+    }
+    if !local.buf.is_empty() {
+        client_tx.write_all(&std::mem::take(&mut local.buf)).await?;
+        client_tx.flush().await?;
+    }
+
+    let mut client_buf = vec![0u8; client.chunk.len()];
+    let mut local_buf = vec![0u8; local.chunk.len()];
+    let clock = client.clock.clone();
+    let mut last_activity = clock.now();
+
+    loop {
+        let idle_expired = async {
+            match idle {
+                Some(limit) => clock.sleep_until(last_activity + limit).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = client.inner.read(&mut client_buf) => {
+                let n = result?;
+                if n == 0 {
+                    let _ = local_tx.shutdown().await;
+                    break;
+                }
+                local_tx.write_all(&client_buf[..n]).await?;
+                local_tx.flush().await?;
+            }
+            result = local.inner.read(&mut local_buf) => {
+                let n = result?;
+                if n == 0 {
+                    let _ = client_tx.shutdown().await;
+                    break;
+                }
+                client_tx.write_all(&local_buf[..n]).await?;
+                client_tx.flush().await?;
+            }
+            _ = idle_expired => {
+                debug!(target: targets::PROXY, "Closing idle upgraded connection");
+                break;
+            }
+        }
+        last_activity = clock.now();
+    }
+
+    Ok(())
+}
+
+/// Copy a message body, re-framing chunked bodies chunk by chunk.
+/// Fails with [`BodyTooLarge`] once more than `limit` bytes would be relayed.
+async fn copy_body<R, W>(
+    from: &mut BufferedReader<R>,
+    to: &mut W,
+    kind: BodyKind,
+    limit: Option<u64>,
+    idle: Option<Duration>,
+    mut tap: Option<&mut Tap>,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let framing = Framing::for_kind(kind);
+    let mut decoder = BodyDecoder::new(kind);
+    let mut total = 0u64;
+    while let Some(data) = decoder.next(from, idle).await? {
+        total += data.len() as u64;
+        check_limit(total, limit)?;
+        if let Some(tap) = tap.as_deref_mut() {
+            tap.record(&data);
+        }
+        framing.write(to, &data).await?;
+    }
+    framing.finish(to).await?;
+    Ok(total)
+}
+
+/// Copy a response body through `encoder`, sending the output chunked.
+/// Returns the number of compressed bytes sent.
+async fn compress_body<R, W>(
+    from: &mut BufferedReader<R>,
+    to: &mut W,
+    kind: BodyKind,
+    mut encoder: Encoder,
+    limit: Option<u64>,
+    idle: Option<Duration>,
+    mut tap: Option<&mut Tap>,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut decoder = BodyDecoder::new(kind);
+    let mut read = 0u64;
+    let mut sent = 0u64;
+    while let Some(data) = decoder.next(from, idle).await? {
+        read += data.len() as u64;
+        check_limit(read, limit)?;
+        if let Some(tap) = tap.as_deref_mut() {
+            tap.record(&data);
+        }
+        let output = encoder.write(&data)?;
+        sent += output.len() as u64;
+        Framing::Chunked.write(to, &output).await?;
+    }
+    let output = encoder.finish()?;
+    sent += output.len() as u64;
+    Framing::Chunked.write(to, &output).await?;
+    Framing::Chunked.finish(to).await?;
+    Ok(sent)
+}
+
+fn check_limit(total: u64, limit: Option<u64>) -> Result<(), BodyTooLarge> {
+    match limit {
+        Some(limit) if total > limit => Err(BodyTooLarge(limit)),
+        _ => Ok(()),
+    }
+}
+
+/// Incremental decoder yielding the payload of a message body
+struct BodyDecoder {
+    kind: BodyKind,
+    /// Bytes left in the body (`Length`) or in the current chunk (`Chunked`)
+    remaining: u64,
+    /// A chunk ended and its trailing CRLF hasn't been consumed yet
+    chunk_ended: bool,
+    done: bool,
+}
+
+impl BodyDecoder {
+    fn new(kind: BodyKind) -> Self {
+        let remaining = match kind {
+            BodyKind::Length(len) => len,
+            _ => 0,
+        };
+        Self {
+            kind,
+            remaining,
+            chunk_ended: false,
+            done: kind == BodyKind::Empty,
+        }
+    }
+
+    /// The next piece of payload, or `None` at the end of the body
+    async fn next<R: AsyncRead + Unpin>(
+        &mut self,
+        from: &mut BufferedReader<R>,
+        idle: Option<Duration>,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+        match self.kind {
+            BodyKind::Empty => Ok(None),
+            BodyKind::Length(_) => {
+                if self.remaining == 0 {
+                    self.done = true;
+                    return Ok(None);
+                }
+                let max = self.remaining.min(from.chunk.len() as u64) as usize;
+                let data = from.read_some(max, idle).await?;
+                if data.is_empty() {
+                    anyhow::bail!("Connection closed before the end of the body");
+                }
+                self.remaining -= data.len() as u64;
+                Ok(Some(data))
+            }
+            BodyKind::Chunked => {
+                if self.remaining == 0 {
+                    if self.chunk_ended && !from.read_line(idle).await?.is_empty() {
+                        anyhow::bail!("Missing CRLF after chunk data");
+                    }
+                    let line = from.read_line(idle).await?;
+                    let size_field = line.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size_field, 16)
+                        .with_context(|| format!("Invalid chunk size: {:?}", size_field))?;
+                    if size == 0 {
+                        // Trailers are dropped; read up to the terminating empty line
+                        while !from.read_line(idle).await?.is_empty() {}
+                        self.done = true;
+                        return Ok(None);
+                    }
+                    self.remaining = size;
+                }
+                let max = self.remaining.min(from.chunk.len() as u64) as usize;
+                let data = from.read_some(max, idle).await?;
+                if data.is_empty() {
+                    anyhow::bail!("Connection closed in the middle of a chunk");
+                }
+                self.remaining -= data.len() as u64;
+                self.chunk_ended = self.remaining == 0;
+                Ok(Some(data))
+            }
+            BodyKind::UntilClose => {
+                let data = from.read_some(from.chunk.len(), idle).await?;
+                if data.is_empty() {
+                    self.done = true;
+                    return Ok(None);
+                }
+                Ok(Some(data))
+            }
+        }
+    }
+}
+
+/// The payload of a chunked message body, read from `body` `read_size` bytes at a
+/// time like the proxy reads from a connection. Chunk extensions and trailers are
+/// dropped.
+pub async fn decode_chunked(body: &[u8], read_size: usize) -> Result<Vec<u8>> {
+    let mut reader = BufferedReader::new(body).with_buffer_size(read_size);
+    let mut decoder = BodyDecoder::new(BodyKind::Chunked);
+    let mut payload = Vec::new();
+    while let Some(data) = decoder.next(&mut reader, None).await? {
+        payload.extend_from_slice(&data);
+    }
+    Ok(payload)
+}
+
+/// How payload is framed on the way out
+#[derive(Clone, Copy)]
+enum Framing {
+    Identity,
+    Chunked,
+}
+
+impl Framing {
+    fn for_kind(kind: BodyKind) -> Self {
+        if kind == BodyKind::Chunked {
+            Framing::Chunked
+        } else {
+            Framing::Identity
+        }
+    }
+
+    async fn write<W: AsyncWrite + Unpin>(self, to: &mut W, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if let Framing::Chunked = self {
+            to.write_all(format!("{:x}\r\n", data.len()).as_bytes())
+                .await?;
+            to.write_all(data).await?;
+            to.write_all(b"\r\n").await
+        } else {
+            to.write_all(data).await
+        }
+    }
+
+    async fn finish<W: AsyncWrite + Unpin>(self, to: &mut W) -> io::Result<()> {
+        match self {
+            Framing::Chunked => to.write_all(b"0\r\n\r\n").await,
+            Framing::Identity => Ok(()),
+        }
+    }
+}
+
+/// Content codings the proxy can apply to responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Pick the coding to use from an `Accept-Encoding` header, preferring gzip
+    fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut gzip = false;
+        let mut deflate = false;
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let acceptable = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .all(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+            match coding.as_str() {
+                "gzip" | "x-gzip" | "*" => gzip |= acceptable,
+                "deflate" => deflate |= acceptable,
+                _ => {}
+            }
+        }
+        if gzip {
+            Some(Encoding::Gzip)
+        } else if deflate {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+/// Streaming compressor for a single response body
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+            }
+        }
+    }
+
+    /// Feed payload in and take whatever compressed output is ready
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+            Encoder::Deflate(e) => {
+                e.write_all(data)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
+
+/// Decide whether a response should be compressed, and with which coding
+fn compression_for(
+    request: &RequestHead,
+    response: &ResponseHead,
+    kind: BodyKind,
+) -> Option<Encoding> {
+    const MIN_SIZE: u64 = 1024;
+
+    // Re-framing as chunked needs HTTP/1.1 on both ends
+    if request.version != "HTTP/1.1" || response.version != "HTTP/1.1" {
+        return None;
+    }
+    match kind {
+        BodyKind::Empty => return None,
+        BodyKind::Length(len) if len < MIN_SIZE => return None,
+        _ => {}
+    }
+    if response.status == 206
+        || response.header("content-encoding").is_some()
+        || has_token(&response.headers, "cache-control", "no-transform")
+    {
+        return None;
+    }
+    let content_type = response.header("content-type")?.to_ascii_lowercase();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let compressible = mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime,
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        );
+    if !compressible {
+        return None;
+    }
+    Encoding::negotiate(request.header("accept-encoding")?)
+}
+
+/// A read half with a lookahead buffer, so heads can be parsed without losing body bytes
+struct BufferedReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// What a single read from `inner` lands in
+    chunk: Vec<u8>,
+    /// The clock idle timeouts on reads run on
+    clock: Arc<dyn Clock>,
+}
+
+impl<R: AsyncRead + Unpin> BufferedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            chunk: vec![0; DEFAULT_BUFFER_SIZE],
+            clock: clock::system(),
+        }
+    }
+
+    fn with_buffer_size(self, size: usize) -> Self {
+        Self {
+            chunk: vec![0; size.max(1)],
+            ..self
+        }
+    }
+
+    fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Read more bytes into the buffer, returning how many arrived (0 on EOF)
+    async fn fill(&mut self, idle: Option<Duration>) -> Result<usize> {
+        let n = with_idle(&*self.clock, idle, self.inner.read(&mut self.chunk)).await??;
+        self.buf.extend_from_slice(&self.chunk[..n]);
+        Ok(n)
+    }
+
+    /// Check whether the stream starts like an HTTP/1.x request line (`METHOD `).
+    /// An immediately closed stream counts as HTTP so it is handled as a normal close.
+    async fn sniff_http1(&mut self, idle: Option<Duration>) -> Result<bool> {
+        loop {
+            if let Some(verdict) = classify_start(&self.buf) {
+                return Ok(verdict);
+            }
+            if self.fill(idle).await? == 0 {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Put bytes back in front of the buffered data
+    fn unread(&mut self, mut bytes: Vec<u8>) {
+        bytes.extend_from_slice(&self.buf);
+        self.buf = bytes;
+    }
+
+    /// Read a head terminated by an empty line.
+    /// Returns `None` if the stream ends before a new head starts.
+    async fn read_head(&mut self, max: usize, idle: Option<Duration>) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = find_subslice(&self.buf, b"\r\n\r\n") {
+                return Ok(Some(self.buf.drain(..end + 4).collect()));
+            }
+            if self.buf.len() > max {
+                anyhow::bail!("HTTP head exceeds {} bytes", max);
+            }
+            if self.fill(idle).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                anyhow::bail!("Connection closed in the middle of an HTTP head");
+            }
+        }
+    }
+
+    /// Read a CRLF-terminated line, without the terminator
+    async fn read_line(&mut self, idle: Option<Duration>) -> Result<String> {
+        loop {
+            if let Some(end) = find_subslice(&self.buf, b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..end + 2).collect();
+                return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            if self.buf.len() > 4096 {
+                anyhow::bail!("Line exceeds 4096 bytes");
+            }
+            if self.fill(idle).await? == 0 {
+                anyhow::bail!("Connection closed in the middle of a line");
+            }
+        }
+    }
+
+    /// Take up to `max` bytes, reading from the stream if nothing is buffered.
+    /// An empty result means EOF.
+    async fn read_some(&mut self, max: usize, idle: Option<Duration>) -> Result<Vec<u8>> {
+        if self.buf.is_empty() && self.fill(idle).await? == 0 {
+            return Ok(Vec::new());
+        }
+        let n = max.min(self.buf.len());
+        Ok(self.buf.drain(..n).collect())
+    }
+}
+
+async fn with_idle<F: Future>(
+    clock: &dyn Clock,
+    idle: Option<Duration>,
+    fut: F,
+) -> io::Result<F::Output> {
+    match idle {
+        Some(limit) => clock::timeout(clock, limit, fut)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection idle")),
+        None => Ok(fut.await),
+    }
+}
+
+fn is_idle(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+}
+
+/// Decide from the first bytes whether they can start an HTTP/1.x request line.
+/// Returns `None` while more bytes are needed.
+fn classify_start(buf: &[u8]) -> Option<bool> {
+    const MAX_METHOD_LEN: usize = 16;
+    for (i, b) in buf.iter().enumerate().take(MAX_METHOD_LEN + 1) {
+        if *b == b' ' {
+            return Some(i > 0);
+        }
+        if !(b.is_ascii_uppercase() || *b == b'-') {
+            return Some(false);
+        }
+    }
+    if buf.len() > MAX_METHOD_LEN {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Split a head into its start line and header fields
+fn split_head(head: &[u8]) -> Result<(String, Vec<(String, String)>)> {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.split("\r\n");
+    let start = lines.next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("Malformed header line: {}", line))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok((start, headers))
+}
+
+fn encode_head(start: &str, headers: &[(String, String)]) -> Vec<u8> {
+    let mut out = String::with_capacity(256);
+    out.push_str(start);
+    out.push_str("\r\n");
+    for (name, value) in headers {
+        out.push_str(name);
+        out.push_str(": ");
+        out.push_str(value);
+        out.push_str("\r\n");
+    }
+    out.push_str("\r\n");
+    out.into_bytes()
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    match headers
+        .iter()
+        .position(|(n, _)| n.eq_ignore_ascii_case(name))
+    {
+        Some(first) => {
+            headers[first].1 = value.to_string();
+            let mut index = 0;
+            headers.retain(|(n, _)| {
+                index += 1;
+                index - 1 == first || !n.eq_ignore_ascii_case(name)
+            });
+        }
+        None => headers.push((name.to_string(), value.to_string())),
+    }
+}
+
+/// Whether any `name` header contains `token` in its comma-separated list
+fn has_token(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+        .flat_map(|(_, v)| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn content_length(headers: &[(String, String)]) -> Option<u64> {
+    header(headers, "content-length").and_then(|v| v.trim().parse().ok())
+}
+
+fn wants_close(version: &str, headers: &[(String, String)]) -> bool {
+    if version == "HTTP/1.0" {
+        !has_token(headers, "connection", "keep-alive")
+    } else {
+        has_token(headers, "connection", "close")
+    }
+}
+
+/// A minimal response generated by the proxy itself
+fn error_response(status: u16, reason: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        reason,
+        reason.len() + 1,
+        reason
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock;
+    use tokio::io::{duplex, split};
+
+    #[test]
+    fn test_parse_request_head() {
+        let head = b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\r\n";
+        let request = RequestHead::parse(head).unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "/chat");
+        assert_eq!(request.header("host"), Some("example.com"));
+        assert!(request.is_upgrade());
+        assert_eq!(request.body_kind(), BodyKind::Empty);
+    }
+
+    /// Send `request` through the proxy to a local service that answers with `response`,
+    /// returning everything the client received
+    async fn roundtrip(config: HttpConfig, request: &[u8], response: &[u8]) -> Vec<u8> {
+        let (mut client, proxy_client) = duplex(64 * 1024);
+        let (proxy_local, local) = duplex(64 * 1024);
+        let response = response.to_vec();
+
+        tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            let (local_rx, local_tx) = split(proxy_local);
+            HttpProxy::new(config, clock::system())
+                .proxy(
+                    80,
+                    client_rx,
+                    client_tx,
+                    local_rx,
+                    local_tx,
+                    &Metrics::default(),
+                )
+                .await
+        });
+        tokio::spawn(async move {
+            let (mut local_rx, mut local_tx) = split(local);
+            let mut buf = vec![0u8; 4096];
+            let _ = local_rx.read(&mut buf).await;
+            local_tx.write_all(&response).await.unwrap();
+            local_tx.shutdown().await.unwrap();
+        });
+
+        client.write_all(request).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn test_response_headers_injected() {
+        let config = HttpConfig {
+            response_headers: vec![
+                ("X-Robots-Tag".to_string(), "noindex".to_string()),
+                ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ],
+            ..Default::default()
+        };
+        let received = roundtrip(
+            config,
+            b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nx-robots-tag: all\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .await;
+
+        let head = ResponseHead::parse(&received[..received.len() - 2]).unwrap();
+        assert_eq!(
+            head.headers,
+            vec![
+                ("x-robots-tag".to_string(), "noindex".to_string()),
+                ("Content-Length".to_string(), "2".to_string()),
+                ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ]
+        );
+        assert!(received.ends_with(b"\r\n\r\nok"));
+    }
+
+    #[tokio::test]
+    async fn test_response_size_ceiling() {
+        let config = HttpConfig {
+            max_response_size: Some(4),
+            ..Default::default()
+        };
+        let received = roundtrip(
+            config,
+            b"GET /big HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789",
+        )
+        .await;
+
+        assert!(received.starts_with(b"HTTP/1.1 502 Response Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_oversize_response_logged_redacted() {
+        struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || Captured(writer.clone()))
+            .finish();
+        let _logging = tracing::subscriber::set_default(subscriber);
+        let config = HttpConfig {
+            max_response_size: Some(4),
+            redaction: Redaction {
+                patterns: vec![RedactionPattern::new("token=[^&]+").unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let received = roundtrip(
+            config,
+            b"GET /big?token=s3cret&page=2 HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789",
+        )
+        .await;
+
+        assert!(received.starts_with(b"HTTP/1.1 502 Response Too Large\r\n"));
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let warning = logs
+            .lines()
+            .find(|line| line.contains("exceeds the 4 byte limit"))
+            .unwrap();
+        assert!(
+            warning.ends_with("GET /big?[REDACTED]&page=2"),
+            "{}",
+            warning
+        );
+        assert!(!logs.contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_compression() {
+        let config = HttpConfig {
+            compression: true,
+            ..Default::default()
+        };
+        let body = "hello world ".repeat(200);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let received = roundtrip(
+            config,
+            b"GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: br;q=1, gzip;q=0.8\r\nConnection: close\r\n\r\n",
+            response.as_bytes(),
+        )
+        .await;
+
+        let mut reader = BufferedReader::new(&received[..]);
+        let head = reader.read_head(4096, None).await.unwrap().unwrap();
+        let head = ResponseHead::parse(&head).unwrap();
+        assert_eq!(head.header("content-encoding"), Some("gzip"));
+        assert_eq!(head.header("content-length"), None);
+
+        let mut decoder = BodyDecoder::new(BodyKind::Chunked);
+        let mut compressed = Vec::new();
+        while let Some(data) = decoder.next(&mut reader, None).await.unwrap() {
+            compressed.extend_from_slice(&data);
+        }
+        assert!(compressed.len() < body.len());
+        let mut decompressed = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(&compressed[..]),
+            &mut decompressed,
+        )
+        .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[tokio::test]
+    async fn test_h2c_prior_knowledge_passes_through() {
+        let (client, proxy_client) = duplex(4096);
+        let (proxy_local, mut local) = duplex(4096);
+        let config = HttpConfig::default();
+
+        let proxy_task = tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            let (local_rx, local_tx) = split(proxy_local);
+            HttpProxy::new(config, clock::system())
+                .proxy(
+                    80,
+                    client_rx,
+                    client_tx,
+                    local_rx,
+                    local_tx,
+                    &Metrics::default(),
+                )
+                .await
+        });
+
+        let mut sent = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        sent.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        let mut client = client;
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        local.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, sent);
+        drop(local);
+        proxy_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_exempt_from_request_timeout() {
+        let (client, proxy_client) = duplex(4096);
+        let (proxy_local, local) = duplex(4096);
+        let config = HttpConfig {
+            request_timeout: Some(Duration::from_millis(50)),
+            idle_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        let proxy_task = tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            let (local_rx, local_tx) = split(proxy_local);
+            HttpProxy::new(config, clock::system())
+                .proxy(
+                    80,
+                    client_rx,
+                    client_tx,
+                    local_rx,
+                    local_tx,
+                    &Metrics::default(),
+                )
+                .await
+        });
+
+        let (mut local_rx, mut local_tx) = split(local);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let _ = local_rx.read(&mut buf).await;
+            local_tx
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+                .await
+                .unwrap();
+            // Echo frames well past the request timeout
+            loop {
+                let n = local_rx.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                local_tx.write_all(&buf[..n]).await.unwrap();
+            }
+        });
+
+        let (mut client_rx, mut client_tx) = split(client);
+        client_tx
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = client_rx.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 101"));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        client_tx.write_all(b"ping").await.unwrap();
+        let n = client_rx.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        drop(client_tx);
+        drop(client_rx);
+        proxy_task.await.unwrap().unwrap();
+    }
+
+    /// Send a `GET` through the proxy to a local service that writes each of `parts`
+    /// after its delay in milliseconds, returning everything the client received
+    async fn timed_roundtrip(config: HttpConfig, parts: &[(u64, &'static str)]) -> String {
+        let (mut client, proxy_client) = duplex(4096);
+        let (proxy_local, mut local) = duplex(4096);
+        let proxy = tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            let (local_rx, local_tx) = split(proxy_local);
+            HttpProxy::new(config, clock::system())
+                .proxy(
+                    80,
+                    client_rx,
+                    client_tx,
+                    local_rx,
+                    local_tx,
+                    &Metrics::default(),
+                )
+                .await
+        });
+        let parts = parts.to_vec();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            let _ = local.read(&mut buf).await;
+            for (delay, part) in parts {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                if local.write_all(part.as_bytes()).await.is_err() {
+                    return;
+                }
+            }
+            // Keep the connection open until the proxy is done with it
+            let _ = local.read(&mut buf).await;
+        });
+
+        client
+            .write_all(b"GET /events HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).await.unwrap();
+        proxy.await.unwrap().unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout_ends_with_the_response_head() {
+        let config = HttpConfig {
+            request_timeout: Some(Duration::from_millis(50)),
+            body_idle_timeout: Some(Duration::from_millis(150)),
+            ..Default::default()
+        };
+
+        // A body that keeps moving streams past the request timeout
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut parts = vec![(0, head)];
+        parts.extend([(30, "5\r\nhello\r\n"); 5]);
+        parts.push((30, "0\r\n\r\n"));
+        let received = timed_roundtrip(config.clone(), &parts).await;
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(received.matches("hello").count(), 5);
+        assert!(received.ends_with("0\r\n\r\n"));
+
+        // A head that doesn't come in time is a timeout
+        let received = timed_roundtrip(config.clone(), &[(200, head)]).await;
+        assert!(received.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+
+        // A body that stops moving is cut off
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        let received = timed_roundtrip(config, &[(0, head), (0, "hello")]).await;
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(received.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn test_cacheable_response_served_from_cache() {
+        let (client, proxy_client) = duplex(4096);
+        let (proxy_local, local) = duplex(4096);
+        let config = HttpConfig {
+            cache: Some(CacheConfig::default()),
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+
+        let proxy_metrics = metrics.clone();
+        let proxy_task = tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            let (local_rx, local_tx) = split(proxy_local);
+            HttpProxy::new(config, clock::system())
+                .proxy(80, client_rx, client_tx, local_rx, local_tx, &proxy_metrics)
+                .await
+        });
+        let local_task = tokio::spawn(async move {
+            let (mut local_rx, mut local_tx) = split(local);
+            let mut buf = vec![0u8; 1024];
+            let _ = local_rx.read(&mut buf).await.unwrap();
+            local_tx
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nCache-Control: max-age=60\r\n\r\nhello")
+                .await
+                .unwrap();
+            // Anything read after the first request means the cache was bypassed
+            let mut rest = Vec::new();
+            local_rx.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        let (mut client_rx, mut client_tx) = split(client);
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            client_tx
+                .write_all(b"GET /app.js HTTP/1.1\r\nHost: x\r\n\r\n")
+                .await
+                .unwrap();
+            let mut reader = BufferedReader::new(&mut client_rx);
+            let head = reader.read_head(4096, None).await.unwrap().unwrap();
+            let response = ResponseHead::parse(&head).unwrap();
+            let mut body = vec![0u8; 5];
+            body[..reader.buf.len()].copy_from_slice(&reader.buf);
+            let filled = reader.buf.len();
+            client_rx.read_exact(&mut body[filled..]).await.unwrap();
+            responses.push((response, body));
+        }
+        drop(client_tx);
+        drop(client_rx);
+        proxy_task.await.unwrap().unwrap();
+
+        assert_eq!(responses[0].1, b"hello");
+        assert_eq!(responses[1].1, b"hello");
+        assert!(responses[0].0.header("age").is_none());
+        assert_eq!(responses[1].0.header("age"), Some("0"));
+        assert!(local_task.await.unwrap().is_empty());
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.counter("http_cache_misses_total"), 1);
+        assert_eq!(snapshot.counter("http_cache_hits_total"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_keeps_forwards_and_hosts_apart() {
+        let clock = Arc::new(crate::ManualClock::new());
+        let proxy = HttpProxy::new(
+            HttpConfig {
+                cache: Some(CacheConfig::default()),
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+        let metrics = Metrics::default();
+        // One request to the forward of `port`, whose local service answers `body`
+        let fetch = |port, host: &str, body: &str, vary: &str| {
+            let request = format!("GET /app.js HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            let answer = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nCache-Control: max-age=60\r\n{}\r\n{}",
+                body.len(),
+                vary,
+                body
+            );
+            let (proxy, metrics) = (&proxy, &metrics);
+            async move {
+                let (mut client, proxy_client) = duplex(4096);
+                let (proxy_local, mut local) = duplex(4096);
+                let local = async move {
+                    let mut buf = vec![0u8; 1024];
+                    if local.read(&mut buf).await.unwrap() > 0 {
+                        local.write_all(answer.as_bytes()).await.unwrap();
+                    }
+                };
+                let (client_rx, client_tx) = split(proxy_client);
+                let (local_rx, local_tx) = split(proxy_local);
+                let exchange = async {
+                    let result = proxy
+                        .proxy(port, client_rx, client_tx, local_rx, local_tx, metrics)
+                        .await;
+                    result.unwrap();
+                };
+                let client = async {
+                    client.write_all(request.as_bytes()).await.unwrap();
+                    client.shutdown().await.unwrap();
+                    let mut response = String::new();
+                    client.read_to_string(&mut response).await.unwrap();
+                    response
+                };
+                let ((), (), response) = tokio::join!(exchange, local, client);
+                response
+            }
+        };
+
+        assert!(fetch(80, "a.sim", "web", "").await.ends_with("web"));
+        // Another forward serving the same path has its own entries, and so has
+        // another virtual host
+        assert!(fetch(81, "a.sim", "admin", "").await.ends_with("admin"));
+        assert!(fetch(80, "b.sim", "blog", "").await.ends_with("blog"));
+        assert!(fetch(80, "A.sim", "stale", "").await.ends_with("web"));
+        assert!(fetch(81, "a.sim", "stale", "").await.ends_with("admin"));
+        assert_eq!(metrics.snapshot().counter("http_cache_hits_total"), 2);
+
+        // Responses varying on anything but the encoding aren't stored
+        let vary = "Vary: Accept-Encoding, Cookie\r\n";
+        assert!(fetch(82, "a.sim", "mine", vary).await.ends_with("mine"));
+        assert!(fetch(82, "a.sim", "yours", vary).await.ends_with("yours"));
+        let vary = "Vary: Accept-Encoding\r\n";
+        assert!(fetch(83, "a.sim", "gzip", vary).await.ends_with("gzip"));
+        assert!(fetch(83, "a.sim", "stale", vary).await.ends_with("gzip"));
+
+        // Entries age and expire on the configured clock
+        clock.advance(Duration::from_secs(59));
+        let cached = fetch(83, "a.sim", "stale", vary).await;
+        assert!(cached.contains("Age: 59\r\n") && cached.ends_with("gzip"));
+        clock.advance(Duration::from_secs(1));
+        assert!(fetch(83, "a.sim", "fresh", vary).await.ends_with("fresh"));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_page() {
+        let (mut client, proxy_client) = duplex(4096);
+        let config = HttpConfig {
+            maintenance_page: "<h1>brb</h1>".to_string(),
+            ..Default::default()
+        };
+
+        let proxy_task = tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            HttpProxy::new(config, clock::system())
+                .serve_maintenance(client_rx, client_tx, &Metrics::default())
+                .await
+        });
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        proxy_task.await.unwrap().unwrap();
+
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(received.ends_with("\r\n\r\n<h1>brb</h1>"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_webhook_signature_rejected() {
+        let config = HttpConfig {
+            webhook: Some(WebhookConfig::new(WebhookScheme::GitHub, "s3cret")),
+            ..Default::default()
+        };
+        let received = roundtrip(
+            config,
+            b"POST /hooks HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\nX-Hub-Signature-256: sha256=00\r\n\r\n{}",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .await;
+        assert!(received.starts_with(b"HTTP/1.1 401 Unauthorized\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_request_hook_rewrites_path_and_body() {
+        struct Rewrite;
+
+        #[async_trait::async_trait]
+        impl RequestHook for Rewrite {
+            async fn on_request(&self, request: &mut RequestHead, body: Option<&mut Vec<u8>>) {
+                request.target = request.target.replacen("/api", "", 1);
+                if let Some(body) = body {
+                    *body = b"changed".to_vec();
+                }
+            }
+
+            fn body_limit(&self) -> Option<u64> {
+                Some(1024)
+            }
+        }
+
+        let (mut client, proxy_client) = duplex(4096);
+        let (proxy_local, local) = duplex(4096);
+        let config = HttpConfig {
+            request_hook: Some(Arc::new(Rewrite)),
+            ..Default::default()
+        };
+
+        tokio::spawn(async move {
+            let (client_rx, client_tx) = split(proxy_client);
+            let (local_rx, local_tx) = split(proxy_local);
+            HttpProxy::new(config, clock::system())
+                .proxy(
+                    80,
+                    client_rx,
+                    client_tx,
+                    local_rx,
+                    local_tx,
+                    &Metrics::default(),
+                )
+                .await
+        });
+        let expected = b"POST /items HTTP/1.1\r\nHost: x\r\nContent-Length: 7\r\n\r\nchanged";
+        let local_task = tokio::spawn(async move {
+            let (mut local_rx, mut local_tx) = split(local);
+            let mut forwarded = vec![0u8; expected.len()];
+            local_rx.read_exact(&mut forwarded).await.unwrap();
+            local_tx
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            forwarded
+        });
+
+        client
+            .write_all(b"POST /api/items HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert!(received.starts_with(b"HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(local_task.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_decode_chunked() {
+        let body = b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n";
+        for read_size in [1, 3, 4096] {
+            let payload = decode_chunked(body, read_size).await.unwrap();
+            assert_eq!(payload, b"hello world", "read size {}", read_size);
+        }
+
+        for (body, error) in [
+            (&b"zz\r\nhello\r\n"[..], "Invalid chunk size: \"zz\""),
+            (b"5\r\nhelloX\r\n0\r\n\r\n", "Missing CRLF after chunk data"),
+            (b"5\r\nhel", "Connection closed in the middle of a chunk"),
+            (
+                b"10000000000000000\r\n",
+                "Invalid chunk size: \"10000000000000000\"",
+            ),
+        ] {
+            let e = decode_chunked(body, 4096).await.unwrap_err();
+            assert_eq!(e.to_string(), error);
+        }
+        assert!(decode_chunked(b"5\r\nhello\r\n", 4096).await.is_err());
+    }
+}
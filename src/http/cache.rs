@@ -0,0 +1,190 @@
+//! In-memory cache of local service responses for HTTP-aware forwarding
+
+use super::{has_token, BodyKind, RequestHead, ResponseHead};
+use crate::Clock;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Configuration for the response cache
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Maximum number of cached responses; the oldest entry is evicted first
+    pub max_entries: usize,
+    /// Responses with larger bodies are never cached
+    pub max_entry_size: u64,
+    /// Freshness lifetime for responses that don't specify `Cache-Control: max-age`
+    pub default_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 256,
+            max_entry_size: 1024 * 1024,
+            default_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Forward, host, method, path and conditional headers of a cacheable request
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    /// Port the server received the request on
+    port: u32,
+    host: Option<String>,
+    method: String,
+    target: String,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    accept_encoding: Option<String>,
+}
+
+impl CacheKey {
+    /// Key for a request to the forward of `port` that may be answered from the cache
+    pub(crate) fn for_request(request: &RequestHead, port: u32) -> Option<Self> {
+        if request.method != "GET"
+            || request.body_kind() != BodyKind::Empty
+            || request.header("authorization").is_some()
+            || has_token(&request.headers, "cache-control", "no-cache")
+        {
+            return None;
+        }
+        Some(Self {
+            port,
+            host: request.header("host").map(|host| host.to_ascii_lowercase()),
+            method: request.method.clone(),
+            target: request.target.clone(),
+            if_none_match: request.header("if-none-match").map(str::to_string),
+            if_modified_since: request.header("if-modified-since").map(str::to_string),
+            accept_encoding: request.header("accept-encoding").map(str::to_string),
+        })
+    }
+}
+
+/// A stored response, with its body fully buffered
+#[derive(Debug, Clone)]
+pub(crate) struct CachedResponse {
+    pub(crate) head: ResponseHead,
+    pub(crate) body: Arc<[u8]>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Shared response cache, bounded by entry count and entry size
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    config: CacheConfig,
+    entries: Mutex<HashMap<CacheKey, CachedResponse>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(config: CacheConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Seconds since `response` was stored, for the `Age` header
+    pub(crate) fn age(&self, response: &CachedResponse) -> u64 {
+        (self.clock.now() - response.stored).as_secs()
+    }
+
+    /// A fresh cached response for `key`, dropping it if it has expired
+    pub(crate) fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires > self.clock.now() => Some(entry.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// How long a response may be served from the cache, or `None` if it must not be stored
+    pub(crate) fn lifetime(&self, response: &ResponseHead, kind: BodyKind) -> Option<Duration> {
+        if response.status != 200 || response.header("set-cookie").is_some() {
+            return None;
+        }
+        // The key only tells requests apart by `Accept-Encoding`
+        let varies = response
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("vary"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .any(|field| !field.is_empty() && !field.eq_ignore_ascii_case("accept-encoding"));
+        if varies {
+            return None;
+        }
+        match kind {
+            BodyKind::Length(len) if len <= self.config.max_entry_size => {}
+            _ => return None,
+        }
+        let directives: Vec<String> = response
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("cache-control"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|d| d.trim().to_ascii_lowercase())
+            .collect();
+        if directives
+            .iter()
+            .any(|d| d == "no-store" || d == "no-cache" || d == "private")
+        {
+            return None;
+        }
+        let max_age = directives
+            .iter()
+            .filter_map(|d| {
+                d.strip_prefix("s-maxage=")
+                    .or_else(|| d.strip_prefix("max-age="))
+            })
+            .find_map(|v| v.parse::<u64>().ok());
+        match max_age {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(self.config.default_ttl),
+        }
+    }
+
+    /// Store a response, evicting the oldest entry when full
+    pub(crate) fn insert(
+        &self,
+        key: CacheKey,
+        head: ResponseHead,
+        body: Vec<u8>,
+        lifetime: Duration,
+    ) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires > now);
+        if entries.len() >= self.config.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                head,
+                body: body.into(),
+                stored: now,
+                expires: now + lifetime,
+            },
+        );
+    }
+}
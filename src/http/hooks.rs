@@ -0,0 +1,56 @@
+//! Request transformation hooks for HTTP-aware forwarding
+
+use std::fmt;
+
+use super::RequestHead;
+
+/// Inspects or rewrites requests before they are forwarded to the local service
+///
+/// Plain closures taking `&mut RequestHead` implement this trait, which covers the
+/// common cases (rewriting a path prefix, dropping or stubbing a header):
+///
+/// ```
+/// use reverse_ssh::unstable::RequestHead;
+/// use reverse_ssh::HttpConfig;
+/// use std::sync::Arc;
+///
+/// let http = HttpConfig {
+///     request_hook: Some(Arc::new(|request: &mut RequestHead| {
+///         if let Some(path) = request.target.strip_prefix("/api") {
+///             request.target = path.to_string();
+///         }
+///         request.set_header("Authorization", "Bearer dev-token");
+///     })),
+///     ..Default::default()
+/// };
+/// ```
+#[async_trait::async_trait]
+pub trait RequestHook: Send + Sync {
+    /// Called for every request. `body` is the complete payload when
+    /// [`body_limit`](Self::body_limit) allows buffering it, and `None` otherwise.
+    /// Changes to the body are forwarded with an updated `Content-Length`.
+    async fn on_request(&self, request: &mut RequestHead, body: Option<&mut Vec<u8>>);
+
+    /// Buffer request bodies up to this many bytes so they can be inspected or
+    /// rewritten. Bodies announced as larger are streamed untouched, and chunked
+    /// bodies that grow past the limit are rejected with `413`.
+    fn body_limit(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl<F> RequestHook for F
+where
+    F: Fn(&mut RequestHead) + Send + Sync,
+{
+    async fn on_request(&self, request: &mut RequestHead, _body: Option<&mut Vec<u8>>) {
+        self(request)
+    }
+}
+
+impl fmt::Debug for dyn RequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestHook")
+    }
+}
@@ -0,0 +1,314 @@
+//! Traffic inspector for HTTP-aware forwarding, with HAR export
+//!
+//! Recorded exchanges can be written as a [HAR 1.2](http://www.softwareishard.com/blog/har-12-spec/)
+//! document, which browser devtools and most HTTP tooling can import. Exchanges
+//! go through the forward's [`Redaction`] before they are kept.
+
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use super::{Redaction, RequestHead, ResponseHead};
+use crate::targets;
+
+/// Configuration for recording HTTP exchanges
+#[derive(Debug, Clone)]
+pub struct InspectorConfig {
+    /// Number of exchanges kept; the oldest is dropped first
+    pub max_entries: usize,
+    /// Bodies are recorded up to this many bytes
+    pub max_body_size: usize,
+    /// Rewrite this HAR file after every recorded exchange
+    pub har_path: Option<PathBuf>,
+}
+
+impl Default for InspectorConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 500,
+            max_body_size: 64 * 1024,
+            har_path: None,
+        }
+    }
+}
+
+/// Copy of a body as it passes through the proxy, truncated at a size limit
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tap {
+    data: Vec<u8>,
+    size: u64,
+    limit: usize,
+    redacted: bool,
+}
+
+impl Tap {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            data: Vec::new(),
+            size: 0,
+            limit,
+            redacted: false,
+        }
+    }
+
+    pub(crate) fn record(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        let room = self.limit.saturating_sub(self.data.len());
+        self.data.extend_from_slice(&data[..data.len().min(room)]);
+    }
+}
+
+/// One request and the response relayed for it
+#[derive(Debug, Clone)]
+pub(crate) struct CapturedExchange {
+    pub(crate) started: SystemTime,
+    pub(crate) duration: Duration,
+    pub(crate) request: RequestHead,
+    pub(crate) request_body: Tap,
+    pub(crate) response: ResponseHead,
+    pub(crate) response_body: Tap,
+}
+
+/// Bounded log of recent exchanges
+#[derive(Debug)]
+pub(crate) struct Inspector {
+    config: InspectorConfig,
+    redaction: Redaction,
+    entries: Mutex<VecDeque<CapturedExchange>>,
+}
+
+impl Inspector {
+    pub(crate) fn new(config: InspectorConfig, redaction: Redaction) -> Self {
+        Self {
+            config,
+            redaction,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(crate) fn tap(&self) -> Tap {
+        Tap::new(self.config.max_body_size)
+    }
+
+    /// Add an exchange, redacted, saving the HAR file if one is configured
+    pub(crate) async fn record(&self, mut exchange: CapturedExchange) {
+        let redaction = &self.redaction;
+        let request_type = exchange.request.header("content-type").unwrap_or_default();
+        let request_body = &mut exchange.request_body;
+        request_body.redacted = redaction.body(&mut request_body.data, request_type);
+        let response_type = exchange.response.header("content-type").unwrap_or_default();
+        let response_body = &mut exchange.response_body;
+        response_body.redacted = redaction.body(&mut response_body.data, response_type);
+        redaction.request(&mut exchange.request);
+        redaction.response(&mut exchange.response);
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.push_back(exchange);
+            while entries.len() > self.config.max_entries {
+                entries.pop_front();
+            }
+        }
+        if let Some(path) = &self.config.har_path {
+            if let Err(e) = tokio::fs::write(path, self.har()).await {
+                warn!(target: targets::PROXY, "Failed to save HAR file {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// The recorded exchanges as a HAR document
+    pub(crate) fn har(&self) -> String {
+        let entries: Vec<Value> = self.entries.lock().unwrap().iter().map(har_entry).collect();
+        let har = json!({
+            "log": {
+                "version": "1.2",
+                "creator": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "entries": entries,
+            }
+        });
+        serde_json::to_string_pretty(&har).expect("HAR is valid JSON")
+    }
+}
+
+fn har_entry(exchange: &CapturedExchange) -> Value {
+    let request = &exchange.request;
+    let response = &exchange.response;
+    let millis = exchange.duration.as_secs_f64() * 1000.0;
+    let url = format!(
+        "http://{}{}",
+        request.header("host").unwrap_or("localhost"),
+        request.target
+    );
+    let query: Vec<Value> = request
+        .target
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({ "name": name, "value": value })
+        })
+        .collect();
+
+    let mut har_request = json!({
+        "method": request.method,
+        "url": url,
+        "httpVersion": request.version,
+        "cookies": [],
+        "headers": har_headers(&request.headers),
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": exchange.request_body.size,
+    });
+    if exchange.request_body.size > 0 {
+        let mime_type = request.header("content-type").unwrap_or_default();
+        let mut post_data = har_content(&exchange.request_body, mime_type);
+        post_data.as_object_mut().unwrap().remove("size");
+        har_request["postData"] = post_data;
+    }
+
+    let mime_type = response.header("content-type").unwrap_or_default();
+    json!({
+        "startedDateTime": format_rfc3339(exchange.started),
+        "time": millis,
+        "request": har_request,
+        "response": {
+            "status": response.status,
+            "statusText": response.reason,
+            "httpVersion": response.version,
+            "cookies": [],
+            "headers": har_headers(&response.headers),
+            "content": har_content(&exchange.response_body, mime_type),
+            "redirectURL": response.header("location").unwrap_or_default(),
+            "headersSize": -1,
+            "bodySize": exchange.response_body.size,
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": millis, "receive": 0 },
+    })
+}
+
+fn har_headers(headers: &[(String, String)]) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+/// Body content; text is stored as is, anything else base64-encoded
+fn har_content(tap: &Tap, mime_type: &str) -> Value {
+    let mut content = json!({ "size": tap.size, "mimeType": mime_type });
+    if tap.data.is_empty() {
+        return content;
+    }
+    match std::str::from_utf8(&tap.data) {
+        Ok(text) => content["text"] = text.into(),
+        Err(_) => {
+            content["text"] = encode_base64(&tap.data).into();
+            content["encoding"] = "base64".into();
+        }
+    }
+    if tap.redacted {
+        content["comment"] = "Redacted".into();
+    } else if (tap.data.len() as u64) < tap.size {
+        content["comment"] = format!("Truncated to {} bytes", tap.data.len()).into();
+    }
+    content
+}
+
+pub(crate) fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Format a timestamp as RFC 3339 in UTC with millisecond precision
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_har_entry() {
+        let inspector = Inspector::new(InspectorConfig::default(), Redaction::default());
+        let request = RequestHead::parse(
+            b"POST /api/items?page=2 HTTP/1.1\r\nHost: demo.lhr.life\r\nContent-Type: application/json\r\n\r\n",
+        )
+        .unwrap();
+        let response =
+            ResponseHead::parse(b"HTTP/1.1 201 Created\r\nContent-Type: image/png\r\n\r\n")
+                .unwrap();
+        let mut request_body = inspector.tap();
+        request_body.record(br#"{"name":"x"}"#);
+        let mut response_body = inspector.tap();
+        response_body.record(&[0x89, b'P', b'N', b'G']);
+
+        let exchange = CapturedExchange {
+            started: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            duration: Duration::from_millis(42),
+            request,
+            request_body,
+            response,
+            response_body,
+        };
+        let entry = har_entry(&exchange);
+
+        assert_eq!(entry["startedDateTime"], "2023-11-14T22:13:20.123Z");
+        assert_eq!(
+            entry["request"]["url"],
+            "http://demo.lhr.life/api/items?page=2"
+        );
+        assert_eq!(entry["request"]["queryString"][0]["value"], "2");
+        assert_eq!(entry["request"]["postData"]["text"], r#"{"name":"x"}"#);
+        assert_eq!(entry["response"]["status"], 201);
+        assert_eq!(entry["response"]["content"]["text"], "iVBORw==");
+        assert_eq!(entry["response"]["content"]["encoding"], "base64");
+    }
+}
@@ -0,0 +1,553 @@
+//! Redaction of secrets in recorded traffic
+//!
+//! Webhook payloads and API calls carry tokens, passwords and personal data. The
+//! rules of a [`Redaction`] are applied to an exchange before the inspector keeps
+//! it, so nothing they match reaches its memory, HAR exports or the request logs.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::borrow::Cow;
+
+use super::{RequestHead, ResponseHead};
+
+/// What replaces redacted content
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+/// Rules for removing secrets from recorded traffic
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    /// Headers whose values are redacted, matched case-insensitively
+    /// (e.g. `authorization`, `cookie`, `stripe-signature`)
+    pub headers: Vec<String>,
+    /// Values redacted from JSON bodies
+    pub json_paths: Vec<JsonPath>,
+    /// Matches redacted from request targets, header values and text bodies
+    pub patterns: Vec<RedactionPattern>,
+}
+
+impl Redaction {
+    /// Whether no rules are configured
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.json_paths.is_empty() && self.patterns.is_empty()
+    }
+
+    /// `text` with pattern matches redacted
+    pub(crate) fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut redacted = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Some(replaced) = pattern.replace(&redacted, REDACTED) {
+                redacted = Cow::Owned(replaced);
+            }
+        }
+        redacted
+    }
+
+    fn headers(&self, headers: &mut [(String, String)]) {
+        for (name, value) in headers {
+            if self.headers.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+                *value = REDACTED.to_string();
+            } else if let Cow::Owned(redacted) = self.text(value) {
+                *value = redacted;
+            }
+        }
+    }
+
+    pub(crate) fn request(&self, request: &mut RequestHead) {
+        if let Cow::Owned(target) = self.text(&request.target) {
+            request.target = target;
+        }
+        self.headers(&mut request.headers);
+    }
+
+    pub(crate) fn response(&self, response: &mut ResponseHead) {
+        self.headers(&mut response.headers);
+    }
+
+    /// Redact a recorded body of type `content_type` in place, returning whether
+    /// anything was. A JSON body that can't be parsed, e.g. because it was
+    /// truncated, is replaced as a whole when JSON paths are configured, rather
+    /// than kept with its secrets.
+    pub(crate) fn body(&self, body: &mut Vec<u8>, content_type: &str) -> bool {
+        if body.is_empty() || self.is_empty() {
+            return false;
+        }
+        let mut redacted = false;
+        if !self.json_paths.is_empty() && is_json(content_type) {
+            match serde_json::from_slice::<Value>(body) {
+                Ok(mut value) => {
+                    for path in &self.json_paths {
+                        redacted |= redact_json(&mut value, &path.segments);
+                    }
+                    if redacted {
+                        *body = serde_json::to_vec(&value).expect("JSON values serialize");
+                    }
+                }
+                Err(_) => {
+                    *body = REDACTED.as_bytes().to_vec();
+                    return true;
+                }
+            }
+        }
+        if let Ok(text) = std::str::from_utf8(body) {
+            if let Cow::Owned(text) = self.text(text) {
+                *body = text.into_bytes();
+                redacted = true;
+            }
+        }
+        redacted
+    }
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/json")
+        || essence.to_ascii_lowercase().ends_with("+json")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    /// `*` or `[*]`: every member or element
+    Any,
+    /// `..`: the rest of the path, at any depth
+    Descend,
+}
+
+/// Values selected in a JSON document, as `$.user.token`, `$.items[*].secret`,
+/// `$.data['api-key']` or `$..password` for that key at any depth
+#[derive(Debug, Clone)]
+pub struct JsonPath {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(Self {
+            source: path.to_string(),
+            segments: parse_json_path(path)?,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+fn parse_json_path(path: &str) -> Result<Vec<Segment>> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            segments.push(Segment::Descend);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('.') {
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let (index, after) = after
+                .split_once(']')
+                .with_context(|| format!("Unclosed '[' in JSON path {}", path))?;
+            segments.push(match index.trim_matches(|c| c == '\'' || c == '"') {
+                "*" => Segment::Any,
+                key if index.starts_with(['\'', '"']) => Segment::Key(key.to_string()),
+                _ => {
+                    Segment::Index(index.parse().with_context(|| {
+                        format!("Invalid index [{}] in JSON path {}", index, path)
+                    })?)
+                }
+            });
+            rest = after;
+            continue;
+        } else if segments.is_empty() && path.starts_with('$') {
+            bail!("Invalid JSON path {}", path);
+        }
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        match &rest[..end] {
+            "" => bail!("Empty key in JSON path {}", path),
+            "*" => segments.push(Segment::Any),
+            key => segments.push(Segment::Key(key.to_string())),
+        }
+        rest = &rest[end..];
+    }
+    if segments.is_empty() || segments.last() == Some(&Segment::Descend) {
+        bail!("JSON path {} selects nothing", path);
+    }
+    Ok(segments)
+}
+
+/// Replace the values `path` selects in `value`, returning whether there were any
+fn redact_json(value: &mut Value, path: &[Segment]) -> bool {
+    let Some((segment, rest)) = path.split_first() else {
+        *value = Value::String(REDACTED.to_string());
+        return true;
+    };
+    match (segment, value) {
+        (Segment::Key(key), Value::Object(members)) => members
+            .get_mut(key)
+            .is_some_and(|member| redact_json(member, rest)),
+        (Segment::Index(index), Value::Array(elements)) => elements
+            .get_mut(*index)
+            .is_some_and(|element| redact_json(element, rest)),
+        (Segment::Any, Value::Object(members)) => {
+            members.values_mut().fold(false, |redacted, member| {
+                redact_json(member, rest) | redacted
+            })
+        }
+        (Segment::Any, Value::Array(elements)) => {
+            elements.iter_mut().fold(false, |redacted, element| {
+                redact_json(element, rest) | redacted
+            })
+        }
+        (Segment::Descend, value) => {
+            let mut redacted = redact_json(value, rest);
+            let children: Vec<&mut Value> = match value {
+                Value::Object(members) => members.values_mut().collect(),
+                Value::Array(elements) => elements.iter_mut().collect(),
+                _ => Vec::new(),
+            };
+            for child in children {
+                redacted |= redact_json(child, path);
+            }
+            redacted
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Atom {
+    Char(char),
+    Any,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Atom {
+    fn matches(&self, c: char, ignore_case: bool) -> bool {
+        let fold = |c: char| {
+            if ignore_case {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            }
+        };
+        match self {
+            Atom::Char(expected) => fold(*expected) == fold(c),
+            Atom::Any => c != '\n',
+            Atom::Class { negated, ranges } => {
+                let within = |c: char| ranges.iter().any(|&(low, high)| low <= c && c <= high);
+                let found = within(c)
+                    || ignore_case
+                        && (within(c.to_ascii_lowercase()) || within(c.to_ascii_uppercase()));
+                found != *negated
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Piece {
+    atom: Atom,
+    min: usize,
+    max: usize,
+}
+
+/// A regular expression for [`Redaction::patterns`]
+///
+/// Only a small subset is supported, as the crate doesn't depend on a regex engine:
+/// literals, `.`, classes such as `[A-Za-z0-9_-]` or `[^&]`, the escapes `\d`, `\w`
+/// and `\s`, the quantifiers `*`, `+`, `?` and `{n,m}`, the anchors `^` and `$`, and
+/// a leading `(?i)` for case-insensitive matching. Groups and alternation are not.
+#[derive(Debug, Clone)]
+pub struct RedactionPattern {
+    source: String,
+    pieces: Vec<Piece>,
+    ignore_case: bool,
+    start: bool,
+    end: bool,
+}
+
+const DIGIT: (char, char) = ('0', '9');
+const WORD: [(char, char); 4] = [('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')];
+const SPACE: [(char, char); 4] = [(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')];
+
+impl RedactionPattern {
+    pub fn new(source: &str) -> Result<Self> {
+        let (ignore_case, rest) = match source.strip_prefix("(?i)") {
+            Some(rest) => (true, rest),
+            None => (false, source),
+        };
+        let (start, rest) = match rest.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (end, rest) = match rest.strip_suffix('$') {
+            Some(rest) if !rest.ends_with('\\') => (true, rest),
+            _ => (false, rest),
+        };
+
+        let mut pieces = Vec::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '.' => Atom::Any,
+                '\\' => escape(chars.next(), source)?,
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut ranges = Vec::new();
+                    loop {
+                        let c = chars
+                            .next()
+                            .with_context(|| format!("Unclosed '[' in pattern {}", source))?;
+                        let low = match c {
+                            ']' if !ranges.is_empty() => break,
+                            '\\' => match escape(chars.next(), source)? {
+                                Atom::Char(c) => c,
+                                Atom::Class {
+                                    negated: false,
+                                    ranges: more,
+                                } => {
+                                    ranges.extend(more);
+                                    continue;
+                                }
+                                _ => bail!("Negated escape in a class in pattern {}", source),
+                            },
+                            c => c,
+                        };
+                        let high = if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    ranges.extend([(low, low), ('-', '-')]);
+                                    break;
+                                }
+                                Some('\\') => match escape(chars.next(), source)? {
+                                    Atom::Char(c) => c,
+                                    _ => bail!("Invalid range in pattern {}", source),
+                                },
+                                Some(c) => c,
+                                None => bail!("Unclosed '[' in pattern {}", source),
+                            }
+                        } else {
+                            low
+                        };
+                        if high < low {
+                            bail!("Invalid range {}-{} in pattern {}", low, high, source);
+                        }
+                        ranges.push((low, high));
+                    }
+                    Atom::Class { negated, ranges }
+                }
+                '*' | '+' | '?' | '{' => bail!("Nothing to repeat in pattern {}", source),
+                '(' | ')' | '|' | '^' | '$' => {
+                    bail!(
+                        "Unsupported '{}' in pattern {}, escape it to match it",
+                        c,
+                        source
+                    )
+                }
+                c => Atom::Char(c),
+            };
+            let (min, max) = match chars.peek() {
+                Some('*') => (0, usize::MAX),
+                Some('+') => (1, usize::MAX),
+                Some('?') => (0, 1),
+                Some('{') => {
+                    chars.next();
+                    let bounds: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    let parse = |n: &str| {
+                        n.trim().parse::<usize>().with_context(|| {
+                            format!("Invalid {{{}}} in pattern {}", bounds, source)
+                        })
+                    };
+                    let (min, max) = match bounds.split_once(',') {
+                        Some((min, "")) => (parse(min)?, usize::MAX),
+                        Some((min, max)) => (parse(min)?, parse(max)?),
+                        None => (parse(&bounds)?, parse(&bounds)?),
+                    };
+                    if max < min {
+                        bail!("Invalid {{{}}} in pattern {}", bounds, source);
+                    }
+                    pieces.push(Piece { atom, min, max });
+                    continue;
+                }
+                _ => (1, 1),
+            };
+            if (min, max) != (1, 1) {
+                chars.next();
+            }
+            pieces.push(Piece { atom, min, max });
+        }
+        Ok(Self {
+            source: source.to_string(),
+            pieces,
+            ignore_case,
+            start,
+            end,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern matches anywhere in `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        self.find(&chars, 0).is_some()
+    }
+
+    /// `text` with every match replaced by `with`, borrowed if nothing matched
+    pub fn replace_all<'a>(&self, text: &'a str, with: &str) -> Cow<'a, str> {
+        match self.replace(text, with) {
+            Some(replaced) => Cow::Owned(replaced),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    /// `text` with every non-empty match replaced by `with`, if there were any
+    fn replace(&self, text: &str, with: &str) -> Option<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut replaced = None::<String>;
+        let mut copied = 0;
+        let mut from = 0;
+        while let Some((start, end)) = self.find(&chars, from) {
+            if end == start {
+                from = start + 1;
+                continue;
+            }
+            let replaced = replaced.get_or_insert_with(String::new);
+            replaced.extend(&chars[copied..start]);
+            replaced.push_str(with);
+            copied = end;
+            from = end;
+        }
+        let mut replaced = replaced?;
+        replaced.extend(&chars[copied..]);
+        Some(replaced)
+    }
+
+    /// First match starting at or after `from`, as a range of characters
+    fn find(&self, chars: &[char], from: usize) -> Option<(usize, usize)> {
+        if from > chars.len() || self.start && from > 0 {
+            return None;
+        }
+        let last = if self.start { 0 } else { chars.len() };
+        (from..=last).find_map(|start| {
+            self.match_at(&self.pieces, chars, start)
+                .map(|end| (start, end))
+        })
+    }
+
+    /// End of the longest match of `pieces` at `pos`, backtracking as needed
+    fn match_at(&self, pieces: &[Piece], chars: &[char], pos: usize) -> Option<usize> {
+        let Some((piece, rest)) = pieces.split_first() else {
+            return (!self.end || pos == chars.len()).then_some(pos);
+        };
+        let mut count = 0;
+        while count < piece.max
+            && pos + count < chars.len()
+            && piece.atom.matches(chars[pos + count], self.ignore_case)
+        {
+            count += 1;
+        }
+        (piece.min..=count)
+            .rev()
+            .find_map(|count| self.match_at(rest, chars, pos + count))
+    }
+}
+
+fn escape(c: Option<char>, source: &str) -> Result<Atom> {
+    Ok(match c {
+        Some('d') => Atom::Class {
+            negated: false,
+            ranges: vec![DIGIT],
+        },
+        Some('D') => Atom::Class {
+            negated: true,
+            ranges: vec![DIGIT],
+        },
+        Some('w') => Atom::Class {
+            negated: false,
+            ranges: WORD.to_vec(),
+        },
+        Some('W') => Atom::Class {
+            negated: true,
+            ranges: WORD.to_vec(),
+        },
+        Some('s') => Atom::Class {
+            negated: false,
+            ranges: SPACE.to_vec(),
+        },
+        Some('S') => Atom::Class {
+            negated: true,
+            ranges: SPACE.to_vec(),
+        },
+        Some('n') => Atom::Char('\n'),
+        Some('t') => Atom::Char('\t'),
+        Some(c) if c.is_ascii_alphanumeric() => {
+            bail!("Unsupported escape \\{} in pattern {}", c, source)
+        }
+        Some(c) => Atom::Char(c),
+        None => bail!("Trailing '\\' in pattern {}", source),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        let pattern = RedactionPattern::new(r"(?i)token=[^&]+").unwrap();
+        assert_eq!(
+            pattern.replace_all("/hook?Token=abc123&page=2", REDACTED),
+            "/hook?[REDACTED]&page=2"
+        );
+        let card = RedactionPattern::new(r"\d{4}(-?\d{4}){3}");
+        assert!(card.is_err(), "groups are not supported");
+        let card = RedactionPattern::new(r"\b?\d{4}-\d{4}-\d{4}-\d{4}");
+        assert!(card.is_err(), "unknown escapes are rejected");
+        let card = RedactionPattern::new(r"^\d{4}[- ]?\d{4}$").unwrap();
+        assert!(card.is_match("1234 5678") && !card.is_match("x1234 5678"));
+        assert!(JsonPath::new("$.user.").is_err());
+
+        let redaction = Redaction {
+            headers: vec!["Authorization".to_string()],
+            json_paths: ["$.user.password", "$.cards[*].number", "$..secret"]
+                .into_iter()
+                .map(|path| JsonPath::new(path).unwrap())
+                .collect(),
+            patterns: vec![pattern, RedactionPattern::new(r"sk_live_\w+").unwrap()],
+        };
+        let mut request = RequestHead::parse(
+            b"POST /hook?token=abc HTTP/1.1\r\nauthorization: Bearer xyz\r\nX-Api-Key: sk_live_42\r\n\r\n",
+        )
+        .unwrap();
+        redaction.request(&mut request);
+        assert_eq!(request.target, "/hook?[REDACTED]");
+        assert_eq!(request.header("authorization"), Some(REDACTED));
+        assert_eq!(request.header("x-api-key"), Some(REDACTED));
+
+        let mut body = br#"{"user":{"name":"ann","password":"hunter2"},"cards":[{"number":"4242"}],
+            "data":{"nested":{"secret":1}},"note":"key sk_live_42"}"#
+            .to_vec();
+        assert!(redaction.body(&mut body, "application/json; charset=utf-8"));
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["user"]["name"], "ann");
+        assert_eq!(value["user"]["password"], REDACTED);
+        assert_eq!(value["cards"][0]["number"], REDACTED);
+        assert_eq!(value["data"]["nested"]["secret"], REDACTED);
+        assert_eq!(value["note"], "key [REDACTED]");
+
+        // A truncated JSON body can't be redacted precisely, so none of it is kept
+        let mut truncated = br#"{"user":{"password":"hun"#.to_vec();
+        assert!(redaction.body(&mut truncated, "application/json"));
+        assert_eq!(truncated, REDACTED.as_bytes());
+        let mut plain = b"nothing to see".to_vec();
+        assert!(!redaction.body(&mut plain, "text/plain"));
+    }
+}
@@ -0,0 +1,173 @@
+//! Webhook signature verification for HTTP-aware forwarding
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::RequestHead;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How a webhook sender signs its requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebhookScheme {
+    /// GitHub: `X-Hub-Signature-256: sha256=<hex HMAC-SHA256 of the body>`
+    GitHub,
+    /// Stripe: `Stripe-Signature: t=<unix time>,v1=<hex HMAC-SHA256 of "t.body">`
+    Stripe,
+    /// Any sender putting a hex HMAC-SHA256 of the body in `header`, after an
+    /// optional `prefix` such as `sha256=`
+    Hmac { header: String, prefix: String },
+}
+
+/// Rejects requests whose webhook signature doesn't match the shared secret
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub scheme: WebhookScheme,
+    /// Shared secret configured at the webhook sender
+    pub secret: String,
+    /// Only requests whose path starts with this prefix are checked (all if `None`)
+    pub path_prefix: Option<String>,
+    /// Largest body that is buffered for verification; larger requests are rejected
+    pub max_body_size: u64,
+    /// Maximum age of a signed timestamp (Stripe), guarding against replays
+    pub tolerance: Duration,
+}
+
+impl WebhookConfig {
+    /// Verify requests signed with `scheme` using `secret`
+    pub fn new(scheme: WebhookScheme, secret: impl Into<String>) -> Self {
+        Self {
+            scheme,
+            secret: secret.into(),
+            path_prefix: None,
+            max_body_size: 1024 * 1024,
+            tolerance: Duration::from_secs(300),
+        }
+    }
+
+    /// Whether `request` is subject to verification
+    pub(crate) fn applies_to(&self, request: &RequestHead) -> bool {
+        match &self.path_prefix {
+            Some(prefix) => request.target.starts_with(prefix.as_str()),
+            None => true,
+        }
+    }
+
+    /// Check the signature of `request` over `body`
+    pub(crate) fn verify(&self, request: &RequestHead, body: &[u8]) -> bool {
+        match &self.scheme {
+            WebhookScheme::GitHub => request
+                .header("x-hub-signature-256")
+                .and_then(|value| value.strip_prefix("sha256="))
+                .is_some_and(|signature| self.check(signature, &[body])),
+            WebhookScheme::Stripe => request
+                .header("stripe-signature")
+                .is_some_and(|value| self.verify_stripe(value, body)),
+            WebhookScheme::Hmac { header, prefix } => request
+                .header(header)
+                .and_then(|value| value.strip_prefix(prefix.as_str()))
+                .is_some_and(|signature| self.check(signature, &[body])),
+        }
+    }
+
+    fn verify_stripe(&self, value: &str, body: &[u8]) -> bool {
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for part in value.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", t)) => timestamp = Some(t),
+                Some(("v1", signature)) => signatures.push(signature),
+                _ => {}
+            }
+        }
+        let Some(timestamp) = timestamp else {
+            return false;
+        };
+        let Ok(signed_at) = timestamp.parse::<u64>() else {
+            return false;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(signed_at) > self.tolerance.as_secs() {
+            return false;
+        }
+        signatures
+            .iter()
+            .any(|signature| self.check(signature, &[timestamp.as_bytes(), b".", body]))
+    }
+
+    /// Compare a hex signature with the HMAC of `parts` in constant time
+    fn check(&self, signature: &str, parts: &[&[u8]]) -> bool {
+        let Some(expected) = decode_hex(signature.trim()) else {
+            return false;
+        };
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.verify_slice(&expected).is_ok()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, parts: &[&[u8]]) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn request(header: &str, value: &str) -> RequestHead {
+        let head = format!(
+            "POST /hooks HTTP/1.1\r\nHost: x\r\n{}: {}\r\n\r\n",
+            header, value
+        );
+        RequestHead::parse(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_github_and_stripe_signatures() {
+        let body = br#"{"action":"opened"}"#;
+
+        let github = WebhookConfig::new(WebhookScheme::GitHub, "s3cret");
+        let valid = format!("sha256={}", sign("s3cret", &[body]));
+        assert!(github.verify(&request("X-Hub-Signature-256", &valid), body));
+        let forged = format!("sha256={}", sign("guess", &[body]));
+        assert!(!github.verify(&request("X-Hub-Signature-256", &forged), body));
+        assert!(!github.verify(&request("X-Other", &valid), body));
+
+        let stripe = WebhookConfig::new(WebhookScheme::Stripe, "whsec");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let signature = sign("whsec", &[now.as_bytes(), b".", body]);
+        let valid = format!("t={},v1={}", now, signature);
+        assert!(stripe.verify(&request("Stripe-Signature", &valid), body));
+        let stale = format!("t=1000,v1={}", sign("whsec", &[b"1000.", body]));
+        assert!(!stripe.verify(&request("Stripe-Signature", &stale), body));
+    }
+}
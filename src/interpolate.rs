@@ -0,0 +1,140 @@
+//! `${VAR}` interpolation in config files
+//!
+//! - `${VAR}` is replaced with the value of `VAR`, which must be set
+//! - `${VAR:-default}` falls back to `default` when `VAR` is unset or empty
+//! - `${VAR:?message}` fails with `message` when `VAR` is unset or empty
+//! - `$$` is a literal `$`
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+
+/// Interpolate every string in a parsed config file, naming the offending field on
+/// errors
+pub(crate) fn interpolate_json(
+    value: &mut Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    interpolate_at(value, lookup, &mut Vec::new())
+}
+
+fn interpolate_at(
+    value: &mut Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+    path: &mut Vec<String>,
+) -> Result<()> {
+    match value {
+        Value::String(text) if text.contains('$') => {
+            *text = interpolate(text, lookup).with_context(|| format!("In {}", path.join(".")))?;
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                path.push(i.to_string());
+                interpolate_at(item, lookup, path)?;
+                path.pop();
+            }
+        }
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                path.push(key.clone());
+                interpolate_at(field, lookup, path)?;
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace the `${...}` references in `text`
+pub(crate) fn interpolate(text: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(body) = rest.strip_prefix('{') else {
+            out.push('$');
+            continue;
+        };
+        let end = body
+            .find('}')
+            .with_context(|| format!("Unterminated ${{ in {:?}", text))?;
+        out.push_str(&expand(&body[..end], lookup)?);
+        rest = &body[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn expand(reference: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let (name, fallback) = match reference.split_once(':') {
+        Some((name, fallback)) => (name, Some(fallback)),
+        None => (reference, None),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("Invalid variable reference ${{{}}}", reference);
+    }
+    let value = lookup(name).filter(|value| !value.is_empty() || fallback.is_none());
+    match (value, fallback) {
+        (Some(value), _) => Ok(value),
+        (None, Some(fallback)) => {
+            if let Some(default) = fallback.strip_prefix('-') {
+                Ok(default.to_string())
+            } else if let Some(message) = fallback.strip_prefix('?') {
+                Err(anyhow!("{} is required: {}", name, message))
+            } else {
+                bail!("Invalid variable reference ${{{}}}", reference)
+            }
+        }
+        (None, None) => bail!(
+            "{} is not set (use ${{{}:-default}} to give it a default)",
+            name,
+            name
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |name: &str| match name {
+            "HOST" => Some("tunnel.example.com".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |text: &str| interpolate(text, &lookup);
+
+        assert_eq!(expand("${HOST}").unwrap(), "tunnel.example.com");
+        assert_eq!(
+            expand("ssh://${HOST}:${PORT:-22}").unwrap(),
+            "ssh://tunnel.example.com:22"
+        );
+        assert_eq!(expand("${EMPTY:-fallback}").unwrap(), "fallback");
+        assert_eq!(expand("${EMPTY}").unwrap(), "");
+        assert_eq!(expand("$$HOME and $5").unwrap(), "$HOME and $5");
+
+        let error = expand("${KEY:?set KEY to the deploy key path}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "KEY is required: set KEY to the deploy key path"
+        );
+        assert!(expand("${MISSING}")
+            .unwrap_err()
+            .to_string()
+            .contains("MISSING is not set"));
+        assert!(expand("${HOST").is_err());
+        assert!(expand("${BAD NAME}").is_err());
+
+        let mut value = serde_json::json!({ "tunnels": { "web": { "user": "${MISSING}" } } });
+        let error = interpolate_json(&mut value, &lookup).unwrap_err();
+        assert_eq!(error.to_string(), "In tunnels.web.user");
+    }
+}
@@ -0,0 +1,223 @@
+//! Reaching the SSH server through jump hosts, like `ssh -J`
+//!
+//! Servers inside a private network are often only reachable through a bastion.
+//! With [`ReverseSshConfig::jump_hosts`](crate::ReverseSshConfig::jump_hosts) set,
+//! `connect()` logs in to the first jump host, opens a `direct-tcpip` channel to the
+//! next one and logs in to it over that channel, and so on, until the channel of the
+//! last jump host reaches the SSH server. The tunnel's session then runs over that
+//! channel. Each jump host's session lives as long as the channel through it.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use russh::client::{self, Handle, Msg};
+use russh::ChannelStream;
+use russh_keys::key;
+use tracing::info;
+
+use crate::handle::{normalize_fingerprint, Shared};
+use crate::security::{SecurityEvent, SecurityEventKind};
+use crate::{auth, eyeballs, targets, AuthMethod, ReverseSshConfig};
+
+/// An SSH host to hop through on the way to the server
+#[derive(Debug, Clone)]
+pub struct JumpHost {
+    pub host: String,
+    pub port: u16,
+    /// User to log in as, `username` of the configuration when unset
+    pub username: Option<String>,
+    /// Ways to authenticate, the configuration's credentials when empty
+    pub auth: Vec<AuthMethod>,
+    /// SHA256 fingerprint the jump host's key must have (`SHA256:...`). Any key is
+    /// accepted when unset.
+    pub host_key_fingerprint: Option<String>,
+}
+
+impl JumpHost {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            username: None,
+            auth: Vec::new(),
+            host_key_fingerprint: None,
+        }
+    }
+
+    /// Parse a jump host as `ssh -J` takes it: `[user@]host[:port]`, with IPv6
+    /// addresses in brackets
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (username, address) = match spec.rsplit_once('@') {
+            Some((user, address)) => (Some(user.to_string()), address),
+            None => (None, spec),
+        };
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest
+                    .split_once(']')
+                    .with_context(|| format!("Unclosed bracket in jump host {:?}", spec))?;
+                let port = match rest {
+                    "" => None,
+                    rest => Some(rest.strip_prefix(':').with_context(|| {
+                        format!("Invalid jump host {:?}, expected [user@]host[:port]", spec)
+                    })?),
+                };
+                (host, port)
+            }
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        if host.is_empty() || username.as_deref() == Some("") {
+            bail!("Invalid jump host {:?}, expected [user@]host[:port]", spec);
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("Invalid port in jump host {:?}", spec))?,
+            None => 22,
+        };
+        Ok(Self {
+            username,
+            ..Self::new(host, port)
+        })
+    }
+
+    /// Parse a comma-separated list of jump hosts, in the order they are hopped through
+    pub fn parse_list(spec: &str) -> Result<Vec<Self>> {
+        spec.split(',')
+            .map(|jump| Self::parse(jump.trim()))
+            .collect()
+    }
+}
+
+/// Handler of a jump host's session, which only carries channels to the next hop
+struct Hop {
+    server: String,
+    fingerprint: Option<String>,
+    shared: Arc<Shared>,
+}
+
+#[async_trait::async_trait]
+impl client::Handler for Hop {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        if let Some(expected) = &self.fingerprint {
+            let presented = server_public_key.fingerprint();
+            if &presented != expected {
+                self.shared.events.emit_security(SecurityEvent::now(
+                    SecurityEventKind::HostKeyMismatch {
+                        server: self.server.clone(),
+                        expected: format!("SHA256:{}", expected),
+                        presented: format!("SHA256:{}", presented),
+                    },
+                ));
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Log in to the jump hosts of `config` in turn, returning a channel from the last
+/// one to the SSH server
+pub(crate) async fn open(
+    config: &ReverseSshConfig,
+    shared: &Arc<Shared>,
+    client_config: Arc<client::Config>,
+) -> Result<ChannelStream<Msg>> {
+    let Some(first) = config.jump_hosts.first() else {
+        bail!("No jump hosts configured");
+    };
+    let stream = match &config.proxy {
+        Some(proxy) => {
+            proxy
+                .connect(&*config.network, &first.host, first.port)
+                .await?
+        }
+        None => eyeballs::connect(config, &first.host, first.port)
+            .await
+            .with_context(|| format!("Failed to connect to jump host {}", first.host))?,
+    };
+    let mut session = login(first, stream, config, shared, client_config.clone()).await?;
+    for next in &config.jump_hosts[1..] {
+        let stream = forward(&session, &next.host, next.port).await?;
+        session = login(next, stream, config, shared, client_config.clone()).await?;
+    }
+    forward(&session, &config.server_addr, config.server_port).await
+}
+
+/// Run a session with `jump` over `stream` and authenticate
+async fn login<S>(
+    jump: &JumpHost,
+    stream: S,
+    config: &ReverseSshConfig,
+    shared: &Arc<Shared>,
+    client_config: Arc<client::Config>,
+) -> Result<Handle<Hop>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let server = format!("{}:{}", jump.host, jump.port);
+    let hop = Hop {
+        server: server.clone(),
+        fingerprint: jump
+            .host_key_fingerprint
+            .as_deref()
+            .map(normalize_fingerprint),
+        shared: shared.clone(),
+    };
+    let mut session = client::connect_stream(client_config, stream, hop)
+        .await
+        .with_context(|| format!("Failed to connect to jump host {}", server))?;
+    let user = jump.username.as_deref().unwrap_or(&config.username);
+    let credentials = match jump.auth.is_empty() {
+        true => shared.credentials(),
+        false => jump.auth.clone(),
+    };
+    auth::authenticate(&mut session, user, &credentials)
+        .await
+        .with_context(|| format!("Failed to authenticate to jump host {}", server))?;
+    info!(target: targets::SESSION, "Logged in to jump host {}", server);
+    Ok(session)
+}
+
+/// Open a channel through `session` to `host:port`
+async fn forward(session: &Handle<Hop>, host: &str, port: u16) -> Result<ChannelStream<Msg>> {
+    let channel = session
+        .channel_open_direct_tcpip(host, port as u32, "127.0.0.1", 0)
+        .await
+        .with_context(|| format!("Jump host could not reach {}:{}", host, port))?;
+    Ok(channel.into_stream())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_jump_hosts() {
+        let jumps = JumpHost::parse_list("bastion.corp, ops@[fd00::1]:2222,relay:23").unwrap();
+        let parsed: Vec<_> = jumps
+            .iter()
+            .map(|jump| (jump.username.as_deref(), jump.host.as_str(), jump.port))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                (None, "bastion.corp", 22),
+                (Some("ops"), "fd00::1", 2222),
+                (None, "relay", 23),
+            ]
+        );
+        assert!(JumpHost::parse("@bastion").is_err());
+        assert!(JumpHost::parse("bastion:ssh").is_err());
+        assert!(JumpHost::parse("[fd00::1:22").is_err());
+        assert!(JumpHost::parse_list("bastion,").is_err());
+    }
+}
@@ -0,0 +1,234 @@
+//! What the key exchange settled on, read from the server's offer
+//!
+//! russh doesn't tell which algorithms a session negotiated. The server's first
+//! `SSH_MSG_KEXINIT` goes in the clear, right after its identification line, so the
+//! client reads the server's lists from the connection as they go by, and picks from
+//! them the way the key exchange does (RFC 4253, section 7.1): the first of the
+//! client's preferences that the server supports.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use russh::compression;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Algorithms the client offers with [`compression`](crate::ReverseSshConfig::compression)
+/// on. russh starts compressing once authenticated, as `zlib@openssh.com` expects,
+/// so plain `zlib` isn't offered.
+pub(crate) const COMPRESSION: &[compression::Name] = &[compression::ZLIB_LEGACY, compression::NONE];
+
+/// Bytes read before giving up on finding the server's offer
+const MAX_OFFER: usize = 64 * 1024;
+
+const MSG_KEXINIT: u8 = 20;
+
+/// Name lists of the server's `SSH_MSG_KEXINIT`, in the order it sends them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ServerOffer {
+    lists: Vec<Vec<String>>,
+}
+
+impl ServerOffer {
+    /// The compression of data sent to the server, picked from `preferred`
+    pub(crate) fn compression(&self, preferred: &[compression::Name]) -> Option<&'static str> {
+        // After kex, host key, ciphers and MACs, both ways
+        negotiate(preferred, &self.lists[6])
+    }
+}
+
+fn negotiate(preferred: &[compression::Name], offered: &[String]) -> Option<&'static str> {
+    let name = preferred
+        .iter()
+        .find(|name| offered.iter().any(|offered| offered == name.as_ref()))?;
+    // As the constant of russh's, whose name lives as long as the client
+    compression::ALL_COMPRESSION_ALGORITHMS
+        .iter()
+        .find(|known| **known == name)
+        .map(|&known| -> &'static str { known.as_ref() })
+}
+
+/// A connection to the server passing what it reads to `on_offer` until the
+/// server's offer went by
+pub(crate) struct Watched<S, F> {
+    inner: S,
+    seen: Vec<u8>,
+    on_offer: Option<F>,
+}
+
+/// Watch `stream` for the server's offer, handing it to `on_offer`
+pub(crate) fn watch<S, F>(stream: S, on_offer: F) -> Watched<S, F>
+where
+    F: FnOnce(ServerOffer),
+{
+    Watched {
+        inner: stream,
+        seen: Vec::new(),
+        on_offer: Some(on_offer),
+    }
+}
+
+impl<S: AsyncRead + Unpin, F: FnOnce(ServerOffer) + Unpin> AsyncRead for Watched<S, F> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if this.on_offer.is_some() {
+            this.seen.extend_from_slice(&buf.filled()[before..]);
+            match parse(&this.seen) {
+                Parsed::Incomplete if this.seen.len() < MAX_OFFER => {}
+                Parsed::Offer(offer) => {
+                    (this.on_offer.take().unwrap())(offer);
+                    this.seen = Vec::new();
+                }
+                Parsed::Incomplete | Parsed::Invalid => {
+                    this.on_offer = None;
+                    this.seen = Vec::new();
+                }
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin, F: Unpin> AsyncWrite for Watched<S, F> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+enum Parsed {
+    Incomplete,
+    Offer(ServerOffer),
+    Invalid,
+}
+
+/// Find the server's `SSH_MSG_KEXINIT` in the first bytes it sent
+fn parse(data: &[u8]) -> Parsed {
+    // Servers may send other lines before their identification line
+    let mut rest = data;
+    loop {
+        let Some(end) = rest.iter().position(|&b| b == b'\n') else {
+            return Parsed::Incomplete;
+        };
+        let line = &rest[..end];
+        rest = &rest[end + 1..];
+        if line.starts_with(b"SSH-") {
+            break;
+        }
+    }
+    let Some(length) = rest.get(..4) else {
+        return Parsed::Incomplete;
+    };
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    let Some(packet) = rest.get(4..4 + length) else {
+        return Parsed::Incomplete;
+    };
+    let Some((&padding, packet)) = packet.split_first() else {
+        return Parsed::Invalid;
+    };
+    let payload = &packet[..packet.len().saturating_sub(padding as usize)];
+    // Message number and 16 bytes of cookie, then kex, host key, ciphers, MACs,
+    // compression and languages
+    let Some(mut payload) = payload
+        .strip_prefix(&[MSG_KEXINIT])
+        .and_then(|payload| payload.get(16..))
+    else {
+        return Parsed::Invalid;
+    };
+    let mut lists = Vec::new();
+    for _ in 0..10 {
+        let Some(length) = payload.get(..4) else {
+            return Parsed::Invalid;
+        };
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        let Some(list) = payload.get(4..4 + length) else {
+            return Parsed::Invalid;
+        };
+        payload = &payload[4 + length..];
+        let list = String::from_utf8_lossy(list);
+        lists.push(
+            list.split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect(),
+        );
+    }
+    Parsed::Offer(ServerOffer { lists })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sim, ReverseSshClient, ReverseSshConfig, TunnelEvent, TunnelState};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_compression_is_negotiated() {
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut service = network.listen("127.0.0.1", 8080).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        for (compression, remote_port, negotiated) in
+            [(true, 8000, "zlib@openssh.com"), (false, 8001, "none")]
+        {
+            let config = ReverseSshConfig {
+                server_addr: "ssh.sim".to_string(),
+                remote_port,
+                compression,
+                network: Arc::new(network.clone()),
+                ..Default::default()
+            };
+            let mut client = ReverseSshClient::new(config);
+            let handle = client.handle();
+            let mut events = client.subscribe();
+            tokio::spawn(async move { client.run().await });
+            while !matches!(
+                events.recv().await,
+                Ok(TunnelEvent::StateChanged {
+                    to: TunnelState::Ready,
+                    ..
+                })
+            ) {}
+            assert_eq!(handle.status().compression, Some(negotiated));
+
+            let text = "GET /index.html HTTP/1.1\r\n".repeat(4096);
+            let mut peer = network.dial("ssh.sim", remote_port as u16).await.unwrap();
+            peer.write_all(text.as_bytes()).await.unwrap();
+            let mut reply = vec![0; text.len()];
+            peer.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, text.as_bytes());
+            handle.shutdown().await.unwrap();
+        }
+
+        let offer = |name: &str| ServerOffer {
+            lists: (0..10).map(|_| vec![name.to_string()]).collect(),
+        };
+        assert_eq!(offer("zlib").compression(COMPRESSION), None);
+    }
+}
@@ -0,0 +1,72 @@
+//! Private keys supplied in memory rather than as a file
+
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use russh_keys::key::KeyPair;
+
+/// Private key material for public key authentication, e.g. fetched from a secrets
+/// manager, so it never has to be written to disk
+#[derive(Clone)]
+pub enum PrivateKey {
+    /// An unencrypted key in PEM or OpenSSH format
+    Pem(String),
+    /// An already decoded key
+    KeyPair(Arc<KeyPair>),
+}
+
+impl PrivateKey {
+    /// Read the key text from an environment variable
+    pub fn from_env(name: &str) -> Result<Self> {
+        let pem = std::env::var(name)
+            .with_context(|| format!("Private key variable {} is not set", name))?;
+        Ok(PrivateKey::Pem(pem))
+    }
+
+    pub(crate) fn key_pair(&self) -> Result<Arc<KeyPair>> {
+        match self {
+            PrivateKey::Pem(pem) => Ok(Arc::new(
+                russh_keys::decode_secret_key(pem, None).context("Failed to decode private key")?,
+            )),
+            PrivateKey::KeyPair(key_pair) => Ok(key_pair.clone()),
+        }
+    }
+}
+
+impl From<KeyPair> for PrivateKey {
+    fn from(key_pair: KeyPair) -> Self {
+        PrivateKey::KeyPair(Arc::new(key_pair))
+    }
+}
+
+// Never print key material
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrivateKey::Pem(_) => f.write_str("PrivateKey::Pem(..)"),
+            PrivateKey::KeyPair(key_pair) => write!(f, "PrivateKey::KeyPair({:?})", key_pair),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pem_round_trip() {
+        let key_pair = KeyPair::generate_ed25519().unwrap();
+        let mut pem = Vec::new();
+        russh_keys::encode_pkcs8_pem(&key_pair, &mut pem).unwrap();
+        let key = PrivateKey::Pem(String::from_utf8(pem).unwrap());
+
+        let decoded = key.key_pair().unwrap();
+        assert_eq!(
+            decoded.clone_public_key().unwrap(),
+            key_pair.clone_public_key().unwrap()
+        );
+        assert_eq!(format!("{:?}", key), "PrivateKey::Pem(..)");
+        assert!(PrivateKey::Pem("not a key".into()).key_pair().is_err());
+    }
+}
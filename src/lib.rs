@@ -1,12 +1,149 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use russh::client::{self, Handle, Msg};
 use russh::keys::*;
 use russh::*;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
+
+mod access;
+mod alerts;
+mod algorithms;
+mod auth;
+mod capabilities;
+mod cert;
+mod clock;
+mod conflict;
+mod deadline;
+mod demo;
+mod endpoint;
+mod env;
+mod errorlog;
+mod events;
+mod eyeballs;
+mod fds;
+mod forward;
+mod gate;
+mod handle;
+mod health;
+mod http;
+mod interpolate;
+mod jump;
+mod kexinit;
+mod keys;
+mod manager;
+mod messages;
+mod metrics;
+mod network;
+#[cfg(unix)]
+mod notify;
+mod overhead;
+mod preflight;
+mod preset;
+mod profiles;
+#[cfg(feature = "profiling")]
+mod profiling;
+mod provider;
+mod proxy;
+mod quota;
+mod ratelimit;
+mod reconfig;
+mod reject;
+mod report;
+mod sampling;
+mod security;
+mod service;
+mod session_id;
+mod shaping;
+pub mod sim;
+mod snapshot;
+mod socks;
+mod stats;
+mod status;
+pub mod targets;
+mod tasks;
+mod tee;
+mod timeline;
+mod timeouts;
+mod udp;
+pub mod unstable;
+mod url;
+
+pub use access::{AccessList, IpNet};
+pub use alerts::{Alert, AlertAction, AlertCondition, AlertRule};
+pub use algorithms::Algorithms;
+pub use auth::AuthMethod;
+pub use capabilities::{capabilities, Capabilities};
+pub use cert::{CertificateExpired, CertificateRefresh};
+pub use clock::{Clock, ManualClock, Sleep, SystemClock};
+pub use conflict::{Availability, AvailabilityCheck, ForwardConflict};
+pub use demo::{expose_demo, Demo, DemoServer, DemoService};
+pub use endpoint::{EndpointChecker, EndpointProbe};
+pub use events::{CloseReason, EventStream, TunnelEvent, EVENT_CAPACITY};
+pub use eyeballs::AddressFamily;
+pub use forward::{ConnectionBudget, Forward, StartAfter};
+pub use gate::WireProtocol;
+pub use handle::{ClientHandle, ConnectionInfo, TunnelStats};
+pub use health::{HealthCheck, HealthProbe};
+pub use http::{
+    CacheConfig, HttpConfig, InspectorConfig, JsonPath, Redaction, RedactionPattern, WebhookConfig,
+    WebhookScheme,
+};
+pub use jump::JumpHost;
+pub use keys::PrivateKey;
+pub use manager::{RestartPolicy, TunnelManager};
+pub use messages::MessageHandlerSlot;
+pub use metrics::{HistogramSnapshot, MetricsSnapshot};
+pub use network::{Connecting, Connection, Network, Resolving, TcpNetwork};
+#[cfg(unix)]
+pub use notify::Notification;
+pub use preflight::{PreflightCheck, PreflightReport};
+pub use preset::ProtocolPreset;
+pub use profiles::{Profile, PROFILES_VERSION};
+#[cfg(feature = "profiling")]
+pub use profiling::{AllocationStats, CountingAllocator, ResourceUsage, RuntimeProfile};
+pub use provider::{Provider, ProviderError, TunnelInfo, TunnelProvider};
+pub use proxy::{ProxyConfig, ProxyProtocol};
+pub use quota::{QuotaAction, QuotaUsage, TrafficQuota};
+pub use ratelimit::{RateLimit, RateLimits};
+pub use reconfig::ConfigDiff;
+pub use reject::RejectAction;
+pub use report::{clear_error_hook, on_error, ErrorEvent, ErrorPhase};
+pub use sampling::TraceSampling;
+pub use security::{SecurityEvent, SecurityEventKind};
+pub use service::{ServiceManager, ServiceSpec};
+pub use session_id::SessionId;
+pub use shaping::{Latency, ShapingProfile};
+pub use snapshot::{Snapshot, SnapshotForward};
+pub use stats::OriginatorStats;
+pub use status::{TunnelState, TunnelStatus};
+pub use tee::{TeeDirection, TEE_CAPACITY};
+pub use timeline::{StartupPhase, StartupTimeline};
+pub use timeouts::ConnectTimeout;
+pub use udp::UdpHelper;
+pub use url::{TunnelUrl, UrlDetector};
+
+use alerts::{AlertInputs, AlertMonitor};
+use clock::Interval;
+use deadline::DeadlineWatch;
+use handle::Shared;
+use health::HealthTracker;
+use http::HttpProxy;
+use metrics::Metrics;
+use overhead::{Flow, OnWire};
+use provider::{LineBuffer, TextDecoder};
+use quota::QuotaChange;
+use sampling::Sampler;
+use shaping::Shaped;
+use stats::{Counted, TrafficCounters};
+use tee::Teed;
+use tokio::sync::broadcast;
+use unstable::ForwardedConnection;
 
 /// Configuration for the reverse SSH connection
 #[derive(Debug, Clone)]
@@ -17,22 +154,369 @@ pub struct ReverseSshConfig {
     pub server_port: u16,
     /// Username for SSH authentication
     pub username: String,
+    /// Authentication methods to try in order. When empty, `key`, `key_path` and
+    /// `password` are tried in that order, or the `none` method without any of them.
+    pub auth: Vec<AuthMethod>,
+    /// Private key held in memory, tried before `key_path`
+    pub key: Option<PrivateKey>,
     /// Private key path for authentication
     pub key_path: Option<String>,
     /// Password for authentication (if not using key)
     pub password: Option<String>,
+    /// Mints a fresh certificate before every connect, for
+    /// [`AuthMethod::Certificate`] methods in `auth`
+    pub certificate_refresh: Option<CertificateRefresh>,
+    /// Reach the SSH server through this HTTP or SOCKS5 proxy
+    pub proxy: Option<ProxyConfig>,
+    /// Hop through these SSH hosts in order to reach the server, like `ssh -J`. The
+    /// first one is reached through `proxy` when set.
+    pub jump_hosts: Vec<JumpHost>,
+    /// SHA256 fingerprint the server's host key must have, as printed by
+    /// `ssh-keygen -lf` (`SHA256:...`). Any key is accepted when unset.
+    pub host_key_fingerprint: Option<String>,
     /// Remote port to listen on (on the SSH server)
     pub remote_port: u32,
+    /// Address sent with forward requests. Empty by default, which localhost.run
+    /// requires; serveo and sish take a subdomain to ask for here.
+    pub bind_address: String,
     /// Local address to forward connections to
     pub local_addr: String,
     /// Local port to forward connections to
     pub local_port: u16,
+    /// Unix domain socket to forward connections to instead of `local_addr` and
+    /// `local_port`, e.g. `/var/run/app.sock`
+    pub local_socket: Option<PathBuf>,
+    /// The local service speaks UDP. Its datagrams travel framed over forwarded
+    /// connections, which a [`UdpHelper`] on the server side opens.
+    pub udp: bool,
+    /// Only relay connections whose first bytes start this protocol's handshake,
+    /// closing those of scanners before they reach the database
+    pub wire_gate: Option<WireProtocol>,
+    /// Copy the bytes of connections to `remote_port` untouched even though `http` is
+    /// set, which then only applies to the `forwards` marked for it
+    pub raw: bool,
+    /// What to do with connections a `wire_gate` rejects: close them, tarpit them, or
+    /// record what they send
+    pub reject_action: RejectAction,
+    /// More remote ports to forward on the same session, each to its own local target
+    pub forwards: Vec<Forward>,
+    /// Serve a SOCKS5 proxy on this address while the session is up, making its
+    /// connections from the SSH server like `ssh -D`
+    pub dynamic_forward: Option<SocketAddr>,
+    /// Handle connections to `remote_port` as HTTP/1.x exchanges instead of raw
+    /// bytes. These settings also apply to the `forwards` marked
+    /// [`http`](Forward::http); the others stay raw.
+    pub http: Option<HttpConfig>,
+    /// Throughput cap, latency and jitter applied to connections to `remote_port`, to
+    /// simulate a slow tunnel; `forwards` have their own [`shaping`](Forward::shaping).
+    /// Can be switched at runtime with [`ReverseSshClient::set_shaping`].
+    pub shaping: Option<ShapingProfile>,
+    /// Throughput caps per connection and for all connections, upstream and
+    /// downstream, so a public tunnel can't saturate the uplink. Can be changed at
+    /// runtime with [`ClientHandle::set_rate_limits`].
+    pub rate_limits: RateLimits,
+    /// Networks forwarded connections may come from, by the originator address the
+    /// server reports. Others are rejected per `reject_action`. Can be changed at
+    /// runtime with [`ClientHandle::set_access_list`].
+    pub access_list: AccessList,
+    /// Cap on the traffic relayed by all connections over a period, e.g. a day
+    pub quota: Option<TrafficQuota>,
+    /// Keep quiet connections alive for protocols with long silent periods (IMAP IDLE,
+    /// MQTT): enable TCP keepalives after this long on local connections, and send a
+    /// no-op request on raw forwards' channels once they've been idle this long, so
+    /// NATs and relays with idle timeouts don't drop them
+    pub idle_keepalive: Option<Duration>,
+    /// Size of the buffer raw forwards read from the local service with
+    pub buffer_size: usize,
+    /// Bytes the server may send on a channel before the client acknowledges them.
+    /// Larger windows keep long transfers going over high-latency links.
+    pub window_size: u32,
+    /// Largest SSH packet the client accepts; servers may cap what they send lower
+    pub max_packet_size: u32,
+    /// Key exchanges, host key algorithms, ciphers and MACs to offer, in place of
+    /// russh's defaults
+    pub algorithms: Algorithms,
+    /// Offer `zlib@openssh.com` compression, which helps text-heavy traffic over slow
+    /// links; [`TunnelStatus::compression`] tells whether the server took it
+    pub compression: bool,
+    /// Limits on the connections of every forward, unless the forward sets its own
+    /// in [`Forward::budget`]
+    pub connection_budget: ConnectionBudget,
+    /// Connections of every forward whose log lines are kept, unless the forward
+    /// sets its own in [`Forward::sampling`]
+    pub trace_sampling: TraceSampling,
+    /// Connections relayed at once across all forwards; more wait or are refused
+    /// with [`CloseReason::LimitReached`], as `connection_queue` says
+    pub max_connections: Option<usize>,
+    /// How long a connection over `max_connections` waits for another to close
+    /// before it is refused; without it, it is refused right away
+    pub connection_queue: Option<Duration>,
+    /// Conditions checked while the tunnel runs, with the action taken when one fires
+    pub alerts: Vec<AlertRule>,
+    /// What to run on the session channel whose output carries server messages
+    pub session_channel: SessionChannel,
+    /// Parses the server's output in place of the built-in parsing, set by
+    /// [`for_provider`](Self::for_provider)
+    pub provider: Option<Arc<dyn TunnelProvider>>,
+    /// Domains the tunnel URL is on, for [`TunnelEvent::TunnelUrl`]; see
+    /// [`UrlDetector::with_domains`]. Lines announcing a tunnel are recognised when empty.
+    pub url_domains: Vec<String>,
+    /// Send a `keepalive@openssh.com` request after this long without hearing from
+    /// the server, or never with `None`
+    pub keepalive_interval: Option<Duration>,
+    /// Keepalives left unanswered before the session is considered dead and torn
+    /// down, making `run()` fail so a [`RestartPolicy`] can reconnect
+    pub keepalive_count_max: usize,
+    /// Probe the local target periodically. While it is unhealthy, the tunnel is
+    /// degraded and new connections are answered as in maintenance mode.
+    pub health_check: Option<HealthCheck>,
+    /// Request the public URL once it is known and periodically, reporting a provider
+    /// edge that doesn't route to the tunnel
+    pub endpoint_probe: Option<EndpointProbe>,
+    /// IP versions the server is dialed over when its name has addresses of both,
+    /// IPv6 first by default
+    pub address_family: AddressFamily,
+    /// Time an attempt to connect to one of the server's addresses gets before the
+    /// next address is tried alongside it (RFC 8305's connection attempt delay)
+    pub connection_attempt_delay: Duration,
+    /// How long opening the TCP connection to the server (through the proxy or the
+    /// jump hosts, if any) may take before `connect()` fails with a [`ConnectTimeout`]
+    pub connect_timeout: Option<Duration>,
+    /// How long the SSH handshake, up to the key exchange, may take
+    pub handshake_timeout: Option<Duration>,
+    /// How long authentication may take, every method tried included
+    pub auth_timeout: Option<Duration>,
+    /// Check that the SSH server and the local target are reachable before
+    /// connecting, failing `connect()` with a [`PreflightReport`] if not
+    pub preflight: bool,
+    /// Ask the [`provider`](Self::provider) whether each forward is free before
+    /// requesting it, failing with a [`ForwardConflict`] that suggests alternatives
+    /// if it is taken; see [`TunnelProvider::check_forward`]
+    pub precheck_forwards: bool,
+    /// File keeping the parameters of the last session that came up (server address,
+    /// host key algorithm, assigned ports), preferred by the next connects and
+    /// dropped as they stop working; see [`Snapshot`]
+    pub snapshot_path: Option<PathBuf>,
+    /// Window in which repeats of an error already logged are only counted, and
+    /// summarised once it ends; `None` logs every error
+    pub error_log_window: Option<Duration>,
+    /// Server messages queued for the message handler. When the handler falls behind,
+    /// e.g. with a server flooding the session channel, further messages are dropped
+    /// and counted in [`TunnelEvent::MessagesDropped`]
+    pub message_queue: usize,
+    /// Time source of the client's timers, the [`SystemClock`] unless a test swaps
+    /// in a [`ManualClock`]
+    pub clock: Arc<dyn Clock>,
+    /// Network the connections to the SSH server and local TCP services are opened
+    /// on, the [`TcpNetwork`] unless a test swaps in a [`sim::SimNetwork`]
+    pub network: Arc<dyn Network>,
+}
+
+impl Default for ReverseSshConfig {
+    fn default() -> Self {
+        Self {
+            server_addr: String::new(),
+            server_port: 22,
+            username: String::new(),
+            auth: Vec::new(),
+            key: None,
+            key_path: None,
+            password: None,
+            certificate_refresh: None,
+            proxy: None,
+            jump_hosts: Vec::new(),
+            host_key_fingerprint: None,
+            remote_port: 80,
+            bind_address: String::new(),
+            local_addr: "127.0.0.1".to_string(),
+            local_port: 8080,
+            local_socket: None,
+            udp: false,
+            wire_gate: None,
+            raw: false,
+            reject_action: RejectAction::Close,
+            forwards: Vec::new(),
+            dynamic_forward: None,
+            http: None,
+            shaping: None,
+            rate_limits: RateLimits::default(),
+            access_list: AccessList::default(),
+            quota: None,
+            idle_keepalive: None,
+            buffer_size: 8192,
+            window_size: 2 * 1024 * 1024,
+            max_packet_size: 32 * 1024,
+            algorithms: Algorithms::default(),
+            compression: false,
+            connection_budget: ConnectionBudget::default(),
+            trace_sampling: TraceSampling::default(),
+            max_connections: None,
+            connection_queue: None,
+            alerts: Vec::new(),
+            session_channel: SessionChannel::Shell,
+            provider: None,
+            url_domains: Vec::new(),
+            keepalive_interval: Some(Duration::from_secs(30)),
+            keepalive_count_max: 3,
+            health_check: None,
+            endpoint_probe: None,
+            address_family: AddressFamily::default(),
+            connection_attempt_delay: Duration::from_millis(250),
+            connect_timeout: Some(Duration::from_secs(10)),
+            handshake_timeout: Some(Duration::from_secs(15)),
+            auth_timeout: Some(Duration::from_secs(30)),
+            preflight: false,
+            precheck_forwards: false,
+            snapshot_path: None,
+            error_log_window: Some(Duration::from_secs(30)),
+            message_queue: 256,
+            clock: clock::system(),
+            network: network::tcp(),
+        }
+    }
+}
+
+impl ReverseSshConfig {
+    /// A configuration following the conventions of `provider`: its server, user
+    /// name, bind address, remote port, session channel and credentials
+    ///
+    /// ```
+    /// use reverse_ssh::{Provider, ReverseSshConfig};
+    ///
+    /// let config = ReverseSshConfig {
+    ///     local_port: 3000,
+    ///     ..ReverseSshConfig::for_provider(Provider::Serveo)
+    /// };
+    /// assert_eq!(config.server_addr, "serveo.net");
+    /// ```
+    pub fn for_provider(provider: impl TunnelProvider + 'static) -> Self {
+        let (server_addr, server_port) = provider.server();
+        Self {
+            server_addr,
+            server_port,
+            username: provider.username(),
+            auth: provider.auth(),
+            remote_port: provider.remote_port(),
+            bind_address: provider.bind_address(),
+            session_channel: provider.session_channel(),
+            keepalive_interval: provider.keepalive_interval(),
+            url_domains: provider.url_domains(),
+            provider: Some(Arc::new(provider)),
+            ..Default::default()
+        }
+    }
+
+    /// Read the configuration of the only tunnel in a profiles file, JSON or TOML
+    /// (see [`Profile::load_all`]). Files with several tunnels are read with
+    /// [`Profile::load`] or [`TunnelManager::from_file`] instead.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut profiles = Profile::load_all(path)?;
+        match profiles.len() {
+            1 => Ok(profiles.remove(0).config),
+            0 => bail!("No tunnel in {}", path.display()),
+            n => bail!(
+                "{} has {} tunnels, pick one with Profile::load",
+                path.display(),
+                n
+            ),
+        }
+    }
+
+    /// Read the configuration of a tunnel from `RRP_*` environment variables:
+    /// `RRP_SERVER`, `RRP_USER`, `RRP_KEY`, `RRP_REMOTE_PORT`, `RRP_LOCAL_PORT` and the
+    /// others listed in the README, which mirror the fields of a profiles file.
+    /// Invalid values and unknown `RRP_` variables are errors.
+    pub fn from_env() -> Result<Self> {
+        env::config_from_vars(std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
+
+    /// The forward of `remote_port` to the local target
+    pub(crate) fn local_forward(&self) -> Forward {
+        Forward {
+            remote_port: self.remote_port,
+            bind_address: self.bind_address.clone(),
+            local_addr: self.local_addr.clone(),
+            local_port: self.local_port,
+            local_socket: self.local_socket.clone(),
+            wire_gate: self.wire_gate,
+            http: self.http.is_some(),
+            raw: self.raw,
+            udp: self.udp,
+            budget: ConnectionBudget::default(),
+            idle_keepalive: None,
+            shaping: self.shaping,
+            sampling: None,
+            optional: false,
+            after: None,
+        }
+    }
+
+    /// The authentication methods `connect()` tries, in order, until replaced with
+    /// [`ClientHandle::set_credentials`]
+    pub fn auth_methods(&self) -> Vec<AuthMethod> {
+        if !self.auth.is_empty() {
+            return self.auth.clone();
+        }
+        let key = self.key.clone().map(AuthMethod::Key);
+        let key_file = self.key_path.clone().map(AuthMethod::KeyFile);
+        let password = self.password.clone().map(AuthMethod::Password);
+        let methods: Vec<_> = key.into_iter().chain(key_file).chain(password).collect();
+        if methods.is_empty() {
+            return vec![AuthMethod::None];
+        }
+        methods
+    }
+}
+
+/// What to run on the session channel opened next to the forward. Its output is
+/// delivered to the message handler, like a banner printed by `ssh -R`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SessionChannel {
+    /// Don't open a session channel
+    None,
+    /// Request a shell, which is what most relays expect
+    #[default]
+    Shell,
+    /// Run a command, for relays that take options this way
+    Exec(String),
+}
+
+impl SessionChannel {
+    /// Ask localhost.run for machine-readable output, parsed into [`TunnelInfo`]
+    pub fn localhost_run_json() -> Self {
+        SessionChannel::Exec("--output json".to_string())
+    }
 }
 
+/// How often usage is compared with the traffic quota
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often alert rules are evaluated
+const ALERT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long forwards waiting for the tunnel's URL wait for the provider to announce it
+const URL_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// SSH client handler
 struct Client {
-    tx: mpsc::UnboundedSender<(Channel<Msg>, String, u32)>,
-    message_tx: mpsc::UnboundedSender<String>,
+    tx: mpsc::UnboundedSender<ForwardedConnection>,
+    message_tx: mpsc::Sender<String>,
+    /// Server messages dropped since the queue was last found full
+    dropped_messages: u64,
+    shared: Arc<Shared>,
+    /// Lines of server output being reassembled, per stream, for parsing
+    stdout: LineBuffer,
+    stderr: LineBuffer,
+    /// Server output being decoded, per stream, for the message pipeline
+    stdout_text: TextDecoder,
+    stderr_text: TextDecoder,
+    urls: UrlDetector,
+    provider: Option<Arc<dyn TunnelProvider>>,
+    /// Requested with the forward, which may be a custom domain
+    bind_address: String,
 }
 
 #[async_trait::async_trait]
@@ -41,10 +525,28 @@ impl client::Handler for Client {
 
     async fn check_server_key(
         &mut self,
-        _server_public_key: &key::PublicKey,
+        server_public_key: &key::PublicKey,
     ) -> Result<bool, Self::Error> {
-        // In production, you should verify the server's public key
-        // For now, we accept any key
+        // Without a pinned fingerprint, any key is accepted
+        if let Some(expected) = self.shared.host_key_fingerprint() {
+            let presented = server_public_key.fingerprint();
+            if presented != expected {
+                self.shared.events.emit_security(SecurityEvent::now(
+                    SecurityEventKind::HostKeyMismatch {
+                        server: self.shared.server(),
+                        expected: format!("SHA256:{}", expected),
+                        presented: format!("SHA256:{}", presented),
+                    },
+                ));
+                return Ok(false);
+            }
+        }
+        self.shared
+            .snapshot
+            .observe_host_key_algorithm(server_public_key.name());
+        self.shared
+            .startup
+            .mark(StartupPhase::KeyExchange, &self.shared.events);
         Ok(true)
     }
 
@@ -57,15 +559,39 @@ impl client::Handler for Client {
         originator_port: u32,
         _session: &mut client::Session,
     ) -> Result<(), Self::Error> {
-        debug!(
+        debug!(target: targets::PROXY,
             "Forwarded channel: {}:{} -> {}:{}",
             originator_address, originator_port, connected_address, connected_port
         );
 
+        if let Some(reason) = self.shared.refuses(originator_address) {
+            let originator = format!("{}:{}", originator_address, originator_port);
+            info!(target: targets::SECURITY, "Refused connection from {}: {}", originator, reason);
+            self.shared.metrics.increment("connections_denied_total", 1);
+            self.shared.events.emit_security(SecurityEvent::now(
+                SecurityEventKind::ConnectionRejected {
+                    originator: originator.clone(),
+                    reason: reason.to_string(),
+                },
+            ));
+            let shared = self.shared.clone();
+            tasks::spawn("refused connection", async move {
+                let mut channel = channel;
+                reject::handle_rejected(&mut channel, Vec::new(), &originator, reason, &shared)
+                    .await;
+                let _ = channel.close().await;
+            });
+            return Ok(());
+        }
+
         // Send the channel to be handled
-        let _ = self
-            .tx
-            .send((channel, connected_address.to_string(), connected_port));
+        let _ = self.tx.send(ForwardedConnection {
+            channel,
+            connected_address: connected_address.to_string(),
+            connected_port,
+            originator_address: originator_address.to_string(),
+            originator_port,
+        });
 
         Ok(())
     }
@@ -76,18 +602,15 @@ impl client::Handler for Client {
         data: &[u8],
         _session: &mut client::Session,
     ) -> Result<(), Self::Error> {
+        for line in self.stdout.push(data) {
+            self.on_line(&line);
+        }
         // Convert data to string and send it for processing
         // Don't filter out partial messages - send everything
-        if let Ok(message) = String::from_utf8(data.to_vec()) {
-            debug!("Received data ({} bytes): {}", data.len(), message);
-            let _ = self.message_tx.send(message);
-        } else {
-            // Log if we received non-UTF8 data
-            debug!(
-                "Received {} bytes of non-UTF8 data on channel {:?}",
-                data.len(),
-                _channel
-            );
+        let message = self.stdout_text.push(data);
+        debug!(target: targets::PROVIDER, "Received data ({} bytes): {}", data.len(), message);
+        if !message.is_empty() {
+            self.queue_message(message);
         }
         Ok(())
     }
@@ -101,11 +624,15 @@ impl client::Handler for Client {
     ) -> Result<(), Self::Error> {
         // Extended data includes stderr (ext == 1)
         // localhost.run sends URL info through stderr
-        if let Ok(message) = String::from_utf8(data.to_vec()) {
-            info!("Received extended data (type {}): {}", ext, message);
-            let _ = self.message_tx.send(message);
+        for line in self.stderr.push(data) {
+            self.on_line(&line);
+        }
+        let message = self.stderr_text.push(data);
+        if !message.is_empty() {
+            info!(target: targets::PROVIDER, "Received extended data (type {}): {}", ext, message);
+            self.queue_message(message);
         }
-        debug!(
+        debug!(target: targets::PROVIDER,
             "Received {} bytes of extended data (type {}) on channel {:?}",
             data.len(),
             ext,
@@ -113,139 +640,719 @@ impl client::Handler for Client {
         );
         Ok(())
     }
+
+    async fn disconnected(
+        &mut self,
+        reason: client::DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        match reason {
+            client::DisconnectReason::ReceivedDisconnect(info) => {
+                debug!(target: targets::RECONNECT, "Server disconnected: {:?}", info);
+                Ok(())
+            }
+            client::DisconnectReason::Error(e) => {
+                self.on_session_error(&e);
+                Err(e)
+            }
+        }
+    }
 }
 
 impl Client {
     fn new(
-        tx: mpsc::UnboundedSender<(Channel<Msg>, String, u32)>,
-        message_tx: mpsc::UnboundedSender<String>,
+        tx: mpsc::UnboundedSender<ForwardedConnection>,
+        message_tx: mpsc::Sender<String>,
+        shared: Arc<Shared>,
+        config: &ReverseSshConfig,
     ) -> Self {
-        Self { tx, message_tx }
+        *shared.message_queue.lock().unwrap() = Some(message_tx.downgrade());
+        Self {
+            tx,
+            message_tx,
+            dropped_messages: 0,
+            shared,
+            stdout: LineBuffer::default(),
+            stderr: LineBuffer::default(),
+            stdout_text: TextDecoder::default(),
+            stderr_text: TextDecoder::default(),
+            urls: UrlDetector::with_domains(config.url_domains.iter().cloned()),
+            provider: config.provider.clone(),
+            bind_address: config.bind_address.clone(),
+        }
+    }
+
+    /// Look for tunnel details or a refusal in a complete line of server output
+    fn on_line(&mut self, line: &str) {
+        let refusal = match &self.provider {
+            Some(provider) => provider.classify(line),
+            None => provider::classify(line),
+        };
+        if let Some(error) = refusal {
+            self.on_provider_error(error);
+            return;
+        }
+        let console = self.provider.as_ref().and_then(|p| p.console_url(line));
+        if let Some(url) = console {
+            // The URL carries the console's access token
+            info!(target: targets::PROVIDER, "Tunnel console available");
+            self.shared.events.emit(TunnelEvent::ConsoleUrl { url });
+            return;
+        }
+        if self.urls.detect(line) {
+            if let Some(url) = self.urls.url() {
+                self.shared.events.emit(TunnelEvent::TunnelUrl(url.clone()));
+            }
+        }
+        let info = match &self.provider {
+            Some(provider) => provider.parse_line(line),
+            None => provider::parse_line(line),
+        };
+        let Some(info) = info else {
+            return;
+        };
+        self.shared
+            .startup
+            .mark(StartupPhase::FirstUrl, &self.shared.events);
+        let Some(previous) = self.shared.status.set_info(info.clone()) else {
+            return;
+        };
+        info!(target: targets::PROVIDER, "Tunnel URL: {}", info.url);
+        self.shared
+            .events
+            .emit(TunnelEvent::TunnelInfo(info.clone()));
+        let old_domain = previous.as_ref().and_then(|old| old.domain.as_ref());
+        if let Some(domain) = info
+            .domain
+            .as_ref()
+            .filter(|&domain| Some(domain) != old_domain)
+        {
+            info!(target: targets::PROVIDER, "Tunnel domain: {}", domain);
+            self.shared.events.emit(TunnelEvent::DomainAssigned {
+                domain: domain.clone(),
+                custom: domain.eq_ignore_ascii_case(&self.bind_address),
+            });
+        }
+        if previous.as_ref().map(|old| &old.url) != Some(&info.url) {
+            self.shared.events.emit(TunnelEvent::UrlReceived {
+                url: info.url.clone(),
+            });
+        }
+        if let Some(old) =
+            previous.filter(|old| provider::host(&old.url) != provider::host(&info.url))
+        {
+            info!(target: targets::PROVIDER, "Tunnel URL changed from {} to {}", old.url, info.url);
+            self.shared.events.emit(TunnelEvent::UrlChanged {
+                old: old.url,
+                new: info.url,
+            });
+        }
+    }
+
+    /// Record a refusal and stop handling connections; the session is closed once
+    /// `proxy_connections` sees it
+    fn on_provider_error(&self, error: ProviderError) {
+        error!(target: targets::PROVIDER, "Provider refused the tunnel: {}", error);
+        self.shared.report(ErrorEvent::new(
+            ErrorPhase::Tunnel,
+            &anyhow::Error::new(error.clone()),
+        ));
+        self.shared
+            .events
+            .emit(TunnelEvent::ProviderError(error.clone()));
+        *self.shared.provider_error.lock().unwrap() = Some(error);
+        self.shared.shutdown.send_replace(true);
+    }
+
+    /// Record why the session died; `proxy_connections` returns it once the
+    /// forwarded connections stop
+    fn on_session_error(&self, error: &russh::Error) {
+        let error = match error {
+            russh::Error::KeepaliveTimeout => {
+                anyhow::anyhow!("Server stopped answering keepalives, closed the dead session")
+            }
+            russh::Error::InactivityTimeout => {
+                anyhow::anyhow!("Nothing received from the server, closed the idle session")
+            }
+            other => anyhow::anyhow!("SSH session failed: {}", other),
+        };
+        error!(target: targets::RECONNECT, "{}", error);
+        self.shared
+            .report(ErrorEvent::new(ErrorPhase::Session, &error));
+        *self.shared.session_error.lock().unwrap() = Some(error);
+    }
+
+    /// Queue a server message for the handler. The handler must not block the
+    /// session, so when it falls behind and the queue is full the message is dropped.
+    fn queue_message(&mut self, message: String) {
+        match self.message_tx.try_reserve() {
+            Ok(permit) => {
+                report_dropped_messages(&mut self.dropped_messages, &self.shared);
+                permit.send(message);
+            }
+            Err(mpsc::error::TrySendError::Full(())) => {
+                if self.dropped_messages == 0 {
+                    warn!(target: targets::PROVIDER,
+                        "Server messages arrive faster than they are handled, dropping them");
+                }
+                self.dropped_messages += 1;
+                self.shared.metrics.increment("messages_dropped_total", 1);
+            }
+            Err(mpsc::error::TrySendError::Closed(())) => {}
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // The session ended while messages were being dropped
+        report_dropped_messages(&mut self.dropped_messages, &self.shared);
+    }
+}
+
+/// Tell how many server messages were dropped, once the queue has room again
+fn report_dropped_messages(dropped: &mut u64, shared: &Shared) {
+    if *dropped > 0 {
+        shared
+            .events
+            .emit(TunnelEvent::MessagesDropped(std::mem::take(dropped)));
     }
 }
 
 /// Reverse SSH client that establishes a reverse tunnel
+///
+/// The client, its [`ClientHandle`]s and the futures returned by its methods are
+/// `Send`, so it can run on a multi-threaded Tokio runtime or be spawned from an
+/// actix/axum handler. The only exception is
+/// [`run_with_scoped_handler`](Self::run_with_scoped_handler), whose future is `Send`
+/// only when the handler is.
 pub struct ReverseSshClient {
     config: ReverseSshConfig,
-    handle: Option<Handle<Client>>,
+    shared: Arc<Shared>,
 }
 
 impl ReverseSshClient {
     /// Create a new reverse SSH client with the given configuration
     pub fn new(config: ReverseSshConfig) -> Self {
-        Self {
-            config,
-            handle: None,
+        let shared = Arc::new(Shared::new(&config));
+        Self { config, shared }
+    }
+
+    /// A client for `provider`, see [`ReverseSshConfig::for_provider`]
+    pub fn for_provider(provider: impl TunnelProvider + 'static) -> Self {
+        Self::new(ReverseSshConfig::for_provider(provider))
+    }
+
+    /// A clonable handle to control the client and inspect its traffic, including
+    /// while [`run`](Self::run) is in progress
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            shared: self.shared.clone(),
         }
     }
 
+    /// Snapshot of the metrics recorded so far (see [`MetricsSnapshot`])
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.handle().metrics()
+    }
+
+    /// Traffic per originator address over the last `window` (up to an hour),
+    /// busiest first. Counts connections that finished within the window.
+    pub fn originator_stats(&self, window: Duration) -> Vec<OriginatorStats> {
+        self.handle().originator_stats(window)
+    }
+
+    /// Traffic recorded by the HTTP inspector as a HAR document, or `None` if
+    /// [`HttpConfig::inspector`] isn't enabled
+    pub fn har(&self) -> Option<String> {
+        self.handle().har()
+    }
+
+    /// Write the traffic recorded by the HTTP inspector to a HAR file
+    pub async fn export_har(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.handle().export_har(path).await
+    }
+
+    /// Turn maintenance mode on or off (see [`ClientHandle::set_maintenance`])
+    pub fn set_maintenance(&self, enabled: bool) {
+        self.handle().set_maintenance(enabled)
+    }
+
+    /// Replace the keys or passwords used to authenticate from the next reconnect on,
+    /// see [`ClientHandle::set_credentials`]
+    pub fn set_credentials(&self, methods: Vec<AuthMethod>) {
+        self.handle().set_credentials(methods)
+    }
+
+    /// Switch the traffic shaping profile of the forward of `port`, or turn its
+    /// shaping off with `None`, see [`ClientHandle::set_shaping`]
+    pub fn set_shaping(&self, port: u32, profile: Option<ShapingProfile>) {
+        self.handle().set_shaping(port, profile)
+    }
+
+    /// The traffic shaping profile currently applied to the forward of `port`
+    pub fn shaping(&self, port: u32) -> Option<ShapingProfile> {
+        self.handle().shaping(port)
+    }
+
+    /// Replace the bandwidth limits, see [`ClientHandle::set_rate_limits`]
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.handle().set_rate_limits(limits)
+    }
+
+    /// The slot holding the server message handler. Keep a clone to replace or remove
+    /// the handler while [`run`](Self::run) or
+    /// [`run_with_message_handler`](Self::run_with_message_handler) is running.
+    pub fn message_handler(&self) -> MessageHandlerSlot {
+        self.handle().message_handler()
+    }
+
+    /// Whether maintenance mode is on
+    pub fn is_maintenance(&self) -> bool {
+        self.handle().is_maintenance()
+    }
+
+    /// How long each phase of the latest connection attempt took
+    pub fn startup_timeline(&self) -> StartupTimeline {
+        self.handle().startup_timeline()
+    }
+
+    /// Current state, uptime, reconnect count, last error, URL and forwards
+    pub fn status(&self) -> TunnelStatus {
+        self.handle().status()
+    }
+
+    /// The public URL of the tunnel, as last announced by the provider
+    pub fn url(&self) -> Option<String> {
+        self.handle().url()
+    }
+
+    /// The latest tunnel details announced by the provider
+    pub fn tunnel_info(&self) -> Option<TunnelInfo> {
+        self.handle().tunnel_info()
+    }
+
+    /// Receive [`TunnelEvent`]s from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.handle().subscribe()
+    }
+
+    /// [`TunnelEvent`]s from now on, as a stream
+    pub fn events(&self) -> EventStream {
+        self.handle().events()
+    }
+
+    /// [`SecurityEvent`]s from now on, as a stream
+    pub fn security_events(&self) -> EventStream<SecurityEvent> {
+        self.handle().security_events()
+    }
+
+    /// Check that the SSH server is reachable and speaks SSH, and that the local
+    /// target accepts connections, without setting anything up
+    pub async fn preflight(&self) -> PreflightReport {
+        preflight::run(&self.config).await
+    }
+
     /// Connect to the SSH server and authenticate
-    pub async fn connect(
+    pub(crate) async fn connect_session(
         &mut self,
-        tx: mpsc::UnboundedSender<(Channel<Msg>, String, u32)>,
-        message_tx: mpsc::UnboundedSender<String>,
+        tx: mpsc::UnboundedSender<ForwardedConnection>,
+        message_tx: mpsc::Sender<String>,
     ) -> Result<()> {
-        info!(
-            "Connecting to SSH server {}:{}",
-            self.config.server_addr, self.config.server_port
+        let session = SessionId::new();
+        tracing::Span::current().record("id", tracing::field::display(session));
+        info!(target: targets::SESSION,
+            "Connecting to SSH server {}:{} (session {})",
+            self.config.server_addr, self.config.server_port, session
+        );
+        self.shared.connects.record();
+        self.shared.startup.begin();
+        self.shared.shutdown.send_replace(false);
+        self.shared.set_state(match self.shared.status.state() {
+            TunnelState::Idle => TunnelState::Connecting,
+            _ => TunnelState::Reconnecting,
+        });
+        self.shared.events.begin_session(
+            session,
+            format!("{}:{}", self.config.server_addr, self.config.server_port),
         );
 
+        let primary = self.config.local_forward();
+        std::iter::once(&primary)
+            .chain(&self.config.forwards)
+            .try_for_each(Forward::check)
+            .inspect_err(|e| self.setup_failed(ErrorPhase::Connect, e))?;
+        let snapshot = self.shared.snapshot.begin(&self.shared.server()).await;
+        let algorithms = self
+            .config
+            .algorithms
+            .preferred()
+            .inspect_err(|e| self.setup_failed(ErrorPhase::Connect, e))?;
+        let mut preferred = snapshot::preferred(algorithms, snapshot.as_ref());
+        if self.config.compression {
+            preferred.compression = kexinit::COMPRESSION.into();
+        }
         let client_config = client::Config {
             inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+            keepalive_interval: self.config.keepalive_interval,
+            keepalive_max: self.config.keepalive_count_max,
+            window_size: self.config.window_size,
+            maximum_packet_size: self.config.max_packet_size,
+            preferred,
             ..<_>::default()
         };
+        self.shared.session_error.lock().unwrap().take();
+
+        if self.config.preflight {
+            let report = preflight::check(self.preflight().await)
+                .inspect_err(|e| self.setup_failed(ErrorPhase::Connect, e))?;
+            info!(target: targets::SESSION, "{}", report);
+        }
+
+        cert::prepare(&self.shared, self.config.certificate_refresh.as_ref())
+            .await
+            .inspect_err(|e| self.setup_failed(ErrorPhase::Authenticate, e))?;
+
+        let client_handler = Client::new(tx, message_tx, self.shared.clone(), &self.config);
+        let client_config = Arc::new(client_config);
 
-        let client_handler = Client::new(tx, message_tx);
+        let clock = &*self.shared.clock;
+        let mut session = async {
+            let (host, port) = (self.config.server_addr.as_str(), self.config.server_port);
+            let handshake = self.config.handshake_timeout;
+            if !self.config.jump_hosts.is_empty() {
+                // Through the jump hosts, all of it counts as connecting
+                let open = jump::open(&self.config, &self.shared, client_config.clone());
+                let stream = timeouts::within(
+                    clock,
+                    StartupPhase::TcpConnect,
+                    self.config.connect_timeout,
+                    open,
+                )
+                .await??;
+                let stream = self.watch_negotiation(stream, &client_config.preferred);
+                self.mark_startup(StartupPhase::TcpConnect);
+                let connected = client::connect_stream(client_config, stream, client_handler);
+                return anyhow::Ok(
+                    timeouts::within(clock, StartupPhase::KeyExchange, handshake, connected)
+                        .await??,
+                );
+            }
+            let open = async {
+                match &self.config.proxy {
+                    Some(proxy) => proxy.connect(&*self.config.network, host, port).await,
+                    None => {
+                        let known = snapshot.as_ref().and_then(|snapshot| snapshot.address);
+                        let stream = self.dial(host, port, known).await?;
+                        self.shared.snapshot.observe_address(stream.peer_addr());
+                        Ok(stream)
+                    }
+                }
+            };
+            let stream = timeouts::within(
+                clock,
+                StartupPhase::TcpConnect,
+                self.config.connect_timeout,
+                open,
+            )
+            .await??;
+            let stream = self.watch_negotiation(stream, &client_config.preferred);
+            self.mark_startup(StartupPhase::TcpConnect);
+            let connected = client::connect_stream(client_config, stream, client_handler);
+            anyhow::Ok(
+                timeouts::within(clock, StartupPhase::KeyExchange, handshake, connected).await??,
+            )
+        }
+        .await
+        .context("Failed to connect to SSH server")
+        .inspect_err(|e| self.setup_failed(ErrorPhase::Connect, e))?;
 
-        let mut session = client::connect(
-            Arc::new(client_config),
-            (self.config.server_addr.as_str(), self.config.server_port),
-            client_handler,
-        )
+        self.shared.set_state(TunnelState::Authenticating);
+        let authenticate = self.authenticate(&mut session);
+        async {
+            timeouts::within(
+                clock,
+                StartupPhase::Authentication,
+                self.config.auth_timeout,
+                authenticate,
+            )
+            .await?
+        }
         .await
-        .context("Failed to connect to SSH server")?;
-
-        // Authenticate
-        let auth_result = if let Some(key_path) = &self.config.key_path {
-            info!("Authenticating with private key: {}", key_path);
-            let key_pair = russh_keys::load_secret_key(key_path, None)
-                .context("Failed to load private key")?;
-            session
-                .authenticate_publickey(&self.config.username, Arc::new(key_pair))
-                .await
-        } else if let Some(password) = &self.config.password {
-            info!("Authenticating with password");
-            session
-                .authenticate_password(&self.config.username, password)
-                .await
-        } else {
-            anyhow::bail!("No authentication method provided (need key_path or password)");
-        };
+        .inspect_err(|e| self.setup_failed(ErrorPhase::Authenticate, e))?;
+        self.mark_startup(StartupPhase::Authentication);
+
+        info!(target: targets::SESSION, "Successfully authenticated to SSH server");
+        self.shared.reset_forwards();
+        *self.shared.session.lock().await = Some(session);
+        Ok(())
+    }
+
+    /// Record in the status what the key exchange on `stream` settles on
+    fn watch_negotiation<S>(
+        &self,
+        stream: S,
+        preferred: &Preferred,
+    ) -> kexinit::Watched<S, impl FnOnce(kexinit::ServerOffer) + Unpin> {
+        let shared = self.shared.clone();
+        let offered = preferred.compression.clone();
+        let wanted = self.config.compression;
+        kexinit::watch(stream, move |offer| {
+            let compression = offer.compression(&offered);
+            if wanted && compression != Some("zlib@openssh.com") {
+                warn!(target: targets::SESSION,
+                    "Server doesn't support zlib@openssh.com, going on without compression"
+                );
+            } else if wanted {
+                info!(target: targets::SESSION, "Negotiated zlib@openssh.com compression");
+            }
+            shared.status.set_compression(compression);
+        })
+    }
 
-        if !auth_result.context("Authentication failed")? {
-            anyhow::bail!("Authentication rejected by server");
+    /// Open a direct connection to the server, at its address in the snapshot if
+    /// there is one, skipping the DNS lookup
+    async fn dial(
+        &self,
+        host: &str,
+        port: u16,
+        known: Option<SocketAddr>,
+    ) -> std::io::Result<Box<dyn Connection>> {
+        let known = known.filter(|address| self.config.address_family.allows(address.ip()));
+        if let Some(address) = known {
+            let ip = address.ip().to_string();
+            match self.config.network.connect(&ip, address.port()).await {
+                Ok(stream) => {
+                    debug!(target: targets::SESSION, "Connected to {} from the snapshot", address);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!(target: targets::SESSION, "Failed to connect to {}: {}", address, e);
+                    self.shared
+                        .snapshot
+                        .forget_address(&self.shared.metrics)
+                        .await;
+                }
+            }
         }
+        eyeballs::connect(&self.config, host, port).await
+    }
+
+    fn mark_startup(&self, phase: StartupPhase) {
+        self.shared.startup.mark(phase, &self.shared.events);
+    }
+
+    /// Report an error that keeps the tunnel from coming up
+    fn setup_failed(&self, phase: ErrorPhase, error: &anyhow::Error) {
+        self.shared.report(ErrorEvent::new(phase, error));
+        self.shared.set_state(TunnelState::Stopped);
+    }
 
-        info!("Successfully authenticated to SSH server");
-        self.handle = Some(session);
+    async fn authenticate(&self, session: &mut Handle<Client>) -> Result<()> {
+        let method = auth::authenticate(session, &self.config.username, &self.shared.credentials())
+            .await
+            .inspect_err(|e| {
+                self.shared.events.emit_security(SecurityEvent::now(
+                    SecurityEventKind::AuthenticationFailed {
+                        server: self.shared.server(),
+                        // A method was accepted by an earlier session
+                        reconnect: self.shared.status.auth_method().is_some(),
+                        error: format!("{:#}", e),
+                    },
+                ));
+            })?;
+        self.shared.status.set_auth_method(method);
+        self.shared
+            .events
+            .emit(TunnelEvent::Authenticated { method });
         Ok(())
     }
 
     /// Set up a reverse port forward (remote port forwarding)
-    /// This makes the SSH server listen on a port and forward connections back to us
-    pub async fn setup_reverse_tunnel(&mut self) -> Result<()> {
-        let handle = self
-            .handle
-            .as_mut()
-            .context("Not connected - call connect() first")?;
-
-        info!(
-            "Setting up reverse tunnel: server port {} -> local {}:{}",
-            self.config.remote_port, self.config.local_addr, self.config.local_port
+    /// This makes the SSH server listen on a port and forward connections back to us.
+    /// Returns the port the server listens on, which it picks when `remote_port` is 0.
+    pub(crate) async fn establish_tunnel(&mut self) -> Result<u32> {
+        info!(target: targets::SESSION,
+            "Setting up reverse tunnel: server port {} -> local {}",
+            self.config.remote_port, self.config.local_forward().target()
         );
 
         // Request remote port forwarding
-        // Use empty string "" instead of "0.0.0.0" - this lets the SSH server choose
-        // the bind address. localhost.run requires this format.
-        handle
-            .tcpip_forward("", self.config.remote_port)
+        self.shared.set_state(TunnelState::Establishing);
+        let handle = self.handle();
+        let port = handle
+            .add_forward(self.config.remote_port)
             .await
-            .context("Failed to set up remote port forwarding")?;
+            .inspect_err(|e| self.setup_failed(ErrorPhase::Tunnel, e))?;
+        let mut acked = vec![self.config.remote_port];
+        let pending = self
+            .request_ready(self.config.forwards.clone(), &mut acked, false)
+            .await?;
+        self.mark_startup(StartupPhase::ForwardAck);
+        self.shared.snapshot.save().await;
+        self.shared.set_state(TunnelState::Ready);
 
-        info!("Reverse tunnel established successfully");
+        info!(target: targets::SESSION, "Reverse tunnel established successfully");
 
-        // Open a shell session to receive server messages (like the URL from localhost.run)
-        // This is important for services that send connection info via shell
+        // Forwards waiting for the URL go once the session channel announced it
+        let events = self.shared.events.subscribe();
+        self.open_session_channel().await?;
+        if pending.is_empty() {
+            return Ok(port);
+        }
+        let url_known = !pending
+            .iter()
+            .any(|f| f.after.is_some_and(|after| after.url))
+            || self.wait_for_url(events).await;
+        for forward in self.request_ready(pending, &mut acked, url_known).await? {
+            let error = match forward.after {
+                Some(after) if after.url && !url_known => anyhow::anyhow!(
+                    "Forward of port {} waits for the tunnel URL, which wasn't announced within {:?}",
+                    forward.remote_port,
+                    URL_TIMEOUT
+                ),
+                after => anyhow::anyhow!(
+                    "Forward of port {} waits for the forward of port {}, which wasn't set up",
+                    forward.remote_port,
+                    after.map_or(0, |after| after.remote_port)
+                ),
+            };
+            self.forward_failed(&forward, error)?;
+        }
+        self.shared.snapshot.save().await;
+        Ok(port)
+    }
+
+    /// Request those of `forwards` whose [`StartAfter`] is met, in order, and return
+    /// the others. `acked` holds the remote ports of the forwards set up so far.
+    async fn request_ready(
+        &self,
+        mut forwards: Vec<Forward>,
+        acked: &mut Vec<u32>,
+        url_known: bool,
+    ) -> Result<Vec<Forward>> {
+        let handle = self.handle();
+        while let Some(index) = forwards.iter().position(|forward| match forward.after {
+            Some(after) => acked.contains(&after.remote_port) && (url_known || !after.url),
+            None => true,
+        }) {
+            let forward = forwards.remove(index);
+            match handle.add_forward_to(forward.clone()).await {
+                Ok(_) => acked.push(forward.remote_port),
+                Err(e) => self.forward_failed(&forward, e)?,
+            }
+        }
+        Ok(forwards)
+    }
+
+    /// Go on without `forward` if it is optional, or fail the setup with `error`
+    fn forward_failed(&self, forward: &Forward, error: anyhow::Error) -> Result<()> {
+        if !forward.optional {
+            self.setup_failed(ErrorPhase::Tunnel, &error);
+            return Err(error);
+        }
+        warn!(target: targets::SESSION,
+            "Going on without optional forward of port {}: {:#}",
+            forward.remote_port, error
+        );
+        self.shared.forward_refused(forward, &error);
+        Ok(())
+    }
+
+    /// Wait up to [`URL_TIMEOUT`] for the provider to announce the tunnel's URL in
+    /// this session, watching `events` subscribed before the session channel opened
+    async fn wait_for_url(&self, mut events: broadcast::Receiver<TunnelEvent>) -> bool {
+        let announced = || self.shared.startup.timeline().first_url.is_some();
+        if announced() {
+            return true;
+        }
+        if self.config.session_channel == SessionChannel::None {
+            return false;
+        }
+        let wait = async {
+            loop {
+                match events.recv().await {
+                    Ok(TunnelEvent::StartupPhase {
+                        phase: StartupPhase::FirstUrl,
+                        ..
+                    }) => return true,
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) if announced() => return true,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return false,
+                }
+            }
+        };
+        tokio::select! {
+            announced = wait => announced,
+            _ = self.shared.clock.sleep(URL_TIMEOUT) => false,
+        }
+    }
+
+    /// Open a session channel to receive server messages (like the URL from
+    /// localhost.run), unless the configuration turns it off
+    async fn open_session_channel(&self) -> Result<()> {
+        let session = self.shared.session.lock().await;
+        let handle = session
+            .as_ref()
+            .context("Not connected - call connect() first")?;
+
+        // This is important for services that send connection info via shell.
+        // Failing to do so doesn't affect the forward, so it is reported but not fatal.
+        if self.config.session_channel == SessionChannel::None {
+            return Ok(());
+        }
         match handle.channel_open_session().await {
             Ok(channel) => {
-                info!("Opened shell session to receive server messages");
-                // Request a shell - this triggers the server to send welcome messages
-                if let Err(e) = channel.request_shell(false).await {
-                    warn!("Failed to request shell: {}", e);
+                info!(target: targets::PROVIDER, "Opened session channel to receive server messages");
+                // Request a shell or run the command - this triggers the server to send
+                // welcome messages
+                let request = match &self.config.session_channel {
+                    SessionChannel::Exec(command) => {
+                        debug!(target: targets::PROVIDER, "Running {:?} on the session channel", command);
+                        channel.exec(false, command.as_str()).await
+                    }
+                    _ => channel.request_shell(false).await,
+                };
+                if let Err(e) = request {
+                    warn!(target: targets::PROVIDER, "Failed to start session channel: {}", e);
+                    self.shared.report(ErrorEvent::new(
+                        ErrorPhase::Shell,
+                        &anyhow::Error::new(e).context("Failed to start session channel"),
+                    ));
                 } else {
-                    debug!("Shell requested successfully");
+                    debug!(target: targets::PROVIDER, "Session channel started successfully");
+                }
+                // Keep the channel open to receive messages, replacing the one of a
+                // previous session
+                let watcher = tasks::spawn(
+                    "session channel",
+                    watch_session_channel(channel, self.shared.clone()),
+                );
+                if let Some(previous) = self.shared.session_channel.lock().unwrap().replace(watcher)
+                {
+                    previous.abort();
                 }
-                // Don't close the channel - keep it open to receive messages
-                // The channel will be kept alive by the handler
             }
             Err(e) => {
-                warn!(
-                    "Could not open shell session: {} (this may be normal for some servers)",
+                warn!(target: targets::SESSION,
+                    "Could not open session channel: {} (this may be normal for some servers)",
                     e
                 );
+                self.shared.report(ErrorEvent::new(
+                    ErrorPhase::Shell,
+                    &anyhow::Error::new(e).context("Could not open session channel"),
+                ));
             }
         }
-
         Ok(())
     }
 
     /// Read server messages (useful for services like localhost.run that send URL info)
     /// This opens a session channel and attempts to read any messages from the server
-    #[allow(dead_code)]
-    pub async fn read_server_messages(&mut self) -> Result<Vec<String>> {
-        let handle = self
-            .handle
-            .as_mut()
+    pub(crate) async fn probe_server_messages(&mut self) -> Result<Vec<String>> {
+        let session = self.shared.session.lock().await;
+        let handle = session
+            .as_ref()
             .context("Not connected - call connect() first")?;
 
         let mut messages = Vec::new();
@@ -257,7 +1364,7 @@ impl ReverseSshClient {
                 let _ = channel.request_shell(false).await;
 
                 // Wait a bit for messages to arrive
-                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+                self.shared.clock.sleep(Duration::from_millis(500)).await;
 
                 // Try to read data from the channel
                 // Note: This is a simplified approach - in practice, we'd need to
@@ -270,109 +1377,780 @@ impl ReverseSshClient {
                 messages.push("Check SSH session output for connection URL".to_string());
             }
             Err(e) => {
-                warn!("Could not open session channel: {}", e);
+                warn!(target: targets::PROVIDER, "Could not open session channel: {}", e);
             }
         }
 
         Ok(messages)
     }
 
-    /// Handle forwarded connections from the SSH server
-    pub async fn handle_forwarded_connections(
+    /// Handle forwarded connections from the SSH server, until the session ends or
+    /// [`ClientHandle::shutdown`] is called. Fails with a [`ProviderError`] if the
+    /// provider refused the tunnel.
+    pub(crate) async fn proxy_connections(
         &mut self,
-        mut rx: mpsc::UnboundedReceiver<(Channel<Msg>, String, u32)>,
+        mut rx: mpsc::UnboundedReceiver<ForwardedConnection>,
     ) -> Result<()> {
-        info!("Waiting for forwarded connections...");
+        info!(target: targets::SESSION, "Waiting for forwarded connections...");
+        let socks = self
+            .spawn_socks_proxy()
+            .await
+            .inspect_err(|e| self.setup_failed(ErrorPhase::Tunnel, e))?;
+        let monitor = self.spawn_alert_monitor();
+        let health_monitor = self.spawn_health_monitor();
+        let endpoint_monitor = self.spawn_endpoint_monitor();
+        let quota_monitor = self.spawn_quota_monitor();
+        let error_summary = self.spawn_error_summary();
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let sampler = Sampler::new();
 
-        while let Some((channel, _remote_addr, _remote_port)) = rx.recv().await {
-            info!("New forwarded connection received");
+        loop {
+            let forwarded = tokio::select! {
+                forwarded = rx.recv() => match forwarded {
+                    Some(forwarded) => forwarded,
+                    None => {
+                        warn!(target: targets::SESSION, "Connection closed by server");
+                        break;
+                    }
+                },
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    info!(target: targets::SESSION, "Stopped handling forwarded connections");
+                    break;
+                }
+            };
+            let forward = self.shared.route(forwarded.connected_port);
+            // Until the next connection, including in the tasks spawned for this one
+            let _sampled = sampler.enter(self.shared.sampling(&forward));
+            let (connection_id, counters, deadline) = self.shared.register(&forwarded);
+            self.mark_startup(StartupPhase::FirstConnection);
+            self.shared.events.emit(TunnelEvent::ConnectionOpened {
+                id: connection_id,
+                originator: format!(
+                    "{}:{}",
+                    forwarded.originator_address, forwarded.originator_port
+                ),
+            });
+            info!(target: targets::PROXY,
+                "New forwarded connection #{} received from {}:{}",
+                connection_id, forwarded.originator_address, forwarded.originator_port
+            );
+            let channel = forwarded.channel;
+            let originator = forwarded.originator_address;
 
             // Spawn a task to handle this connection
-            let local_addr = self.config.local_addr.clone();
-            let local_port = self.config.local_port;
+            let budget = self.shared.budget(&forward);
+            let task_name = format!("connection #{} to {}", connection_id, forward.target());
+            let shared = self.shared.clone();
+            if let Some(lifetime) = budget.max_lifetime {
+                self.handle()
+                    .set_connection_deadline(connection_id, Some(lifetime));
+            }
 
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(channel, &local_addr, local_port).await {
-                    error!("Error handling connection: {}", e);
-                }
-            });
+            if self.shared.quota_exhausted() {
+                info!(target: targets::PROXY,
+                    "Traffic quota exhausted, refusing connection #{}",
+                    connection_id
+                );
+                self.shared.spawn_connection_task(&task_name, async move {
+                    let _ = channel.close().await;
+                    shared.unregister(connection_id, CloseReason::QuotaExhausted);
+                });
+                continue;
+            }
+
+            if self.shared.fd_pressure.shedding() {
+                debug!(target: targets::PROXY,
+                    "Out of file descriptors, shedding connection #{}",
+                    connection_id
+                );
+                let mut channel = channel;
+                self.shared.spawn_connection_task(&task_name, async move {
+                    let retry_after = shared.fd_pressure.remaining();
+                    fds::shed(&mut channel, forward.parses_http(), retry_after).await;
+                    shared.metrics.increment("connections_shed_total", 1);
+                    shared.unregister(connection_id, CloseReason::Overloaded);
+                });
+                continue;
+            }
+
+            let port = forwarded.connected_port;
+            if let Some(max) = budget
+                .max_concurrent
+                .filter(|&max| self.shared.open_on(port) > max)
+            {
+                info!(target: targets::PROXY,
+                    "Port {} is at its limit of {} connections, refusing connection #{}",
+                    port, max, connection_id
+                );
+                self.shared.spawn_connection_task(&task_name, async move {
+                    let _ = channel.close().await;
+                    shared.metrics.increment("connections_limited_total", 1);
+                    shared.unregister(connection_id, CloseReason::LimitReached);
+                });
+                continue;
+            }
+
+            let unhealthy = self.shared.status.target_healthy() == Some(false);
+            if unhealthy {
+                info!(target: targets::PROXY,
+                    "Local target is unhealthy, answering connection #{} without it",
+                    connection_id
+                );
+            }
+            if self.is_maintenance() || unhealthy {
+                self.shared.spawn_connection_task(
+                    &task_name,
+                    async move {
+                        let http = forward.parses_http().then_some(&shared.http);
+                        let result = serve_maintenance(channel, http, &shared.metrics).await;
+                        let reason = match result {
+                            Ok(()) => CloseReason::Completed,
+                            Err(e) => {
+                                if shared.should_log(&format!("Error handling connection: {}", e)) {
+                                    error!(target: targets::PROXY, "Error handling connection #{}: {}", connection_id, e);
+                                }
+                                shared.report(
+                                    ErrorEvent::new(ErrorPhase::Forward, &e)
+                                        .with_connection(connection_id, &originator),
+                                );
+                                CloseReason::Failed
+                            }
+                        };
+                        shared.unregister(connection_id, reason);
+                    },
+                );
+                continue;
+            }
+
+            self.shared.spawn_connection_task(
+                &task_name,
+                async move {
+                    let Some(_slot) = shared.connection_slot(connection_id).await else {
+                        let _ = channel.close().await;
+                        shared.unregister(connection_id, CloseReason::LimitReached);
+                        return;
+                    };
+                    let result = handle_connection(
+                        channel,
+                        &forward,
+                        &originator,
+                        &shared,
+                        &counters,
+                        deadline,
+                    )
+                    .await;
+                    shared
+                        .originators
+                        .record(&originator, &counters, result.is_err());
+                    shared.set_healthy(result.is_ok());
+                    let reason = match result {
+                        Ok(reason) => reason,
+                        Err(e) => {
+                            if shared.should_log(&format!("Error handling connection: {}", e)) {
+                                error!(target: targets::PROXY, "Error handling connection #{}: {}", connection_id, e);
+                            }
+                            shared.report(
+                                ErrorEvent::new(ErrorPhase::Forward, &e)
+                                    .with_connection(connection_id, &originator),
+                            );
+                            CloseReason::Failed
+                        }
+                    };
+                    let reason = match reason {
+                        CloseReason::DeadlineExceeded if shared.quota_exhausted() => {
+                            CloseReason::QuotaExhausted
+                        }
+                        reason => reason,
+                    };
+                    if reason == CloseReason::DeadlineExceeded {
+                        info!(target: targets::PROXY, "Connection #{} closed: deadline exceeded", connection_id);
+                        shared
+                            .metrics
+                            .increment("connections_deadline_exceeded_total", 1);
+                    }
+                    if reason == CloseReason::IdleTimeout {
+                        info!(target: targets::PROXY, "Connection #{} closed: idle timeout", connection_id);
+                        shared.metrics.increment("connections_idle_timeout_total", 1);
+                    }
+                    shared.unregister(connection_id, reason);
+                },
+            );
         }
 
-        warn!("Connection closed by server");
-        Ok(())
+        for monitor in [
+            socks,
+            monitor,
+            health_monitor,
+            endpoint_monitor,
+            quota_monitor,
+            error_summary,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            monitor.abort();
+        }
+        let provider_error = self.shared.provider_error.lock().unwrap().take();
+        if provider_error.is_some() {
+            if let Err(e) = self.handle().shutdown().await {
+                debug!(target: targets::SESSION, "Could not close refused session: {}", e);
+            }
+        }
+        self.shared.set_state(TunnelState::Stopped);
+        let result = match provider_error {
+            Some(error) => Err(error.into()),
+            None => match self.shared.session_error.lock().unwrap().take() {
+                Some(error) => Err(error),
+                None => Ok(()),
+            },
+        };
+        self.shared.events.emit(TunnelEvent::Disconnected {
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+        result
+    }
+
+    /// Start evaluating the configured alert rules in the background
+    fn spawn_alert_monitor(&self) -> Option<tokio::task::JoinHandle<()>> {
+        if self.config.alerts.is_empty() {
+            return None;
+        }
+        let mut monitor = AlertMonitor::new(self.config.alerts.clone(), self.shared.clock.clone());
+        let shared = self.shared.clone();
+        Some(tasks::spawn("alerts", async move {
+            let mut interval = Interval::new(shared.clock.clone(), ALERT_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                monitor.check(&AlertInputs {
+                    connects: &shared.connects,
+                    originators: &shared.originators,
+                    traffic: &shared.traffic,
+                });
+            }
+        }))
+    }
+
+    /// Start serving the SOCKS5 proxy, if a dynamic forward is configured
+    async fn spawn_socks_proxy(&self) -> Result<Option<tokio::task::JoinHandle<()>>> {
+        let Some(addr) = self.config.dynamic_forward else {
+            return Ok(None);
+        };
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen for SOCKS clients on {}", addr))?;
+        info!(target: targets::PROXY, "SOCKS5 proxy listening on {}", addr);
+        Ok(Some(tasks::spawn(
+            "socks proxy",
+            socks::serve(listener, self.shared.clone()),
+        )))
+    }
+
+    /// Start summarising repeated errors in the background, once their window ends
+    fn spawn_error_summary(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let window = self.shared.errors.window()?;
+        let shared = self.shared.clone();
+        Some(tasks::spawn("error-summary", async move {
+            let mut interval = Interval::new(shared.clock.clone(), window);
+            loop {
+                interval.tick().await;
+                shared.errors.flush();
+            }
+        }))
+    }
+
+    /// Start comparing the traffic with the quota in the background, if there is one
+    fn spawn_quota_monitor(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let quota = self.config.quota?;
+        let handle = self.handle();
+        Some(tasks::spawn("quota", async move {
+            let mut interval = Interval::new(handle.shared.clock.clone(), QUOTA_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                match handle.shared.check_quota() {
+                    Some(QuotaChange::Exhausted { used }) => {
+                        warn!(target: targets::SESSION,
+                            "Traffic quota exhausted: {} of {} bytes relayed, {}",
+                            used,
+                            quota.limit,
+                            match quota.action {
+                                QuotaAction::Pause => "refusing connections until the period ends",
+                                QuotaAction::Shutdown => "shutting down",
+                            }
+                        );
+                        handle.shared.events.emit(TunnelEvent::QuotaExhausted {
+                            used,
+                            limit: quota.limit,
+                        });
+                        match quota.action {
+                            QuotaAction::Pause => handle.shared.close_connections(),
+                            // On its own task, as this one is stopped with the session
+                            QuotaAction::Shutdown => {
+                                let handle = handle.clone();
+                                tokio::spawn(async move {
+                                    if let Err(e) = handle.shutdown().await {
+                                        debug!(target: targets::SESSION, "Could not disconnect: {}", e);
+                                    }
+                                });
+                            }
+                        }
+                    }
+                    Some(QuotaChange::Reset) => {
+                        info!(target: targets::SESSION, "New traffic quota period, accepting connections again");
+                        handle.shared.events.emit(TunnelEvent::QuotaReset);
+                    }
+                    None => {}
+                }
+            }
+        }))
+    }
+
+    /// Start probing the local target in the background, if health checks are configured
+    fn spawn_health_monitor(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let check = self.config.health_check.clone()?;
+        let target = self.config.local_forward().target();
+        let network = self.config.network.clone();
+        let shared = self.shared.clone();
+        shared.status.set_target_healthy(None);
+        Some(tasks::spawn("health checks", async move {
+            let mut tracker = HealthTracker::new(&check);
+            let mut interval = Interval::new(shared.clock.clone(), check.interval);
+            loop {
+                interval.tick().await;
+                let result = check.probe(&*network, &target, &*shared.clock).await;
+                if let Err(e) = &result {
+                    debug!(target: targets::HEALTH, "Health check failed: {:#}", e);
+                }
+                if let Some(healthy) = tracker.record(result.is_ok()) {
+                    shared.set_target_health(healthy, result.err());
+                }
+            }
+        }))
+    }
+
+    /// Start probing the public URL in the background, if an endpoint probe is
+    /// configured. Probes start as soon as the provider announces the URL.
+    fn spawn_endpoint_monitor(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let probe = self.config.endpoint_probe.clone()?;
+        let shared = self.shared.clone();
+        let mut events = shared.events.subscribe();
+        Some(tasks::spawn("endpoint probe", async move {
+            let mut failures = 0;
+            let mut interval = Interval::new(shared.clock.clone(), probe.interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    event = events.recv() => match event {
+                        Ok(TunnelEvent::UrlReceived { .. }) => {
+                            interval.reset();
+                            failures = 0;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                        _ => continue,
+                    },
+                }
+                let Some(url) = shared.status.info().map(|info| info.url) else {
+                    continue;
+                };
+                match probe.probe(&url, &*shared.clock).await {
+                    Ok(()) => {
+                        if failures >= probe.unreachable_threshold.max(1) {
+                            info!(target: targets::HEALTH, "Public URL {} is reachable again", url);
+                            shared.events.emit(TunnelEvent::EndpointReachable { url });
+                        }
+                        failures = 0;
+                    }
+                    Err(e) => {
+                        debug!(target: targets::HEALTH, "Endpoint probe of {} failed: {:#}", url, e);
+                        shared.metrics.increment("endpoint_probe_failures_total", 1);
+                        failures += 1;
+                        if failures == probe.unreachable_threshold.max(1) {
+                            warn!(target: targets::HEALTH, "Public URL {} is unreachable: {:#}", url, e);
+                            shared.events.emit(TunnelEvent::EndpointUnreachable {
+                                url,
+                                error: format!("{:#}", e),
+                            });
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Connect to the SSH server and authenticate
+    #[deprecated(since = "0.2.0", note = "use `unstable::RawSession::connect`")]
+    pub async fn connect(
+        &mut self,
+        tx: mpsc::UnboundedSender<ForwardedConnection>,
+        message_tx: mpsc::Sender<String>,
+    ) -> Result<()> {
+        self.connect_session(tx, message_tx).await
+    }
+
+    /// Request the configured forward and open the session channel, returning the
+    /// port the server listens on
+    #[deprecated(
+        since = "0.2.0",
+        note = "use `unstable::RawSession::setup_reverse_tunnel`"
+    )]
+    pub async fn setup_reverse_tunnel(&mut self) -> Result<u32> {
+        self.establish_tunnel().await
+    }
+
+    /// Open a throwaway shell channel to prompt the server for messages
+    #[deprecated(
+        since = "0.2.0",
+        note = "use `unstable::RawSession::read_server_messages`"
+    )]
+    pub async fn read_server_messages(&mut self) -> Result<Vec<String>> {
+        self.probe_server_messages().await
+    }
+
+    /// Proxy the connections received on `rx` until the session ends
+    #[deprecated(
+        since = "0.2.0",
+        note = "use `unstable::RawSession::handle_forwarded_connections`"
+    )]
+    pub async fn handle_forwarded_connections(
+        &mut self,
+        rx: mpsc::UnboundedReceiver<ForwardedConnection>,
+    ) -> Result<()> {
+        self.proxy_connections(rx).await
     }
 
     /// Run the reverse SSH client (connect, setup tunnel, and handle connections)
     #[allow(dead_code)]
     pub async fn run(&mut self) -> Result<()> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        let (message_tx, mut message_rx) = mpsc::unbounded_channel();
+        async {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let (message_tx, mut message_rx) = mpsc::channel(self.config.message_queue.max(1));
 
-        self.connect(tx, message_tx).await?;
-        self.setup_reverse_tunnel().await?;
+            self.connect_session(tx, message_tx).await?;
+            self.establish_tunnel().await?;
 
-        // Spawn a task to print server messages
-        tokio::spawn(async move {
-            while let Some(message) = message_rx.recv().await {
-                // Print server messages, which may include URLs
-                if !message.trim().is_empty() {
-                    println!("[Server] {}", message.trim());
+            // Spawn a task to print server messages, unless a handler has been installed
+            let message_handler = self.message_handler();
+            tasks::spawn("server messages", async move {
+                while let Some(message) = message_rx.recv().await {
+                    if message_handler.dispatch(message.clone()) {
+                        continue;
+                    }
+                    // Print server messages, which may include URLs
+                    if !message.trim().is_empty() {
+                        println!("[Server] {}", message.trim());
+                    }
                 }
-            }
-        });
-
-        self.handle_forwarded_connections(rx).await?;
+            });
 
-        Ok(())
+            self.proxy_connections(rx).await
+        }
+        .instrument(session_span())
+        .await
     }
 
-    /// Run the client with custom message handling
-    pub async fn run_with_message_handler<F>(&mut self, mut message_handler: F) -> Result<()>
+    /// Run the client with custom message handling. The handler can be replaced or
+    /// removed while running through [`message_handler`](Self::message_handler);
+    /// messages arriving while no handler is installed are dropped.
+    pub async fn run_with_message_handler<F>(&mut self, message_handler: F) -> Result<()>
     where
         F: FnMut(String) + Send + 'static,
     {
-        let (tx, rx) = mpsc::unbounded_channel();
-        let (message_tx, mut message_rx) = mpsc::unbounded_channel();
+        self.shared.message_handler.replace(message_handler);
+        async {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let (message_tx, mut message_rx) = mpsc::channel(self.config.message_queue.max(1));
 
-        self.connect(tx, message_tx).await?;
-        self.setup_reverse_tunnel().await?;
+            self.connect_session(tx, message_tx).await?;
+            self.establish_tunnel().await?;
 
-        // Spawn a task to handle server messages with custom handler
-        tokio::spawn(async move {
-            while let Some(message) = message_rx.recv().await {
-                message_handler(message);
+            // Spawn a task to handle server messages with the current handler
+            let message_handler = self.message_handler();
+            tasks::spawn("server messages", async move {
+                while let Some(message) = message_rx.recv().await {
+                    if !message_handler.dispatch(message) {
+                        debug!(target: targets::PROVIDER,
+                            "No message handler installed, dropping server message");
+                    }
+                }
+            });
+
+            self.proxy_connections(rx).await
+        }
+        .instrument(session_span())
+        .await
+    }
+
+    /// Run the client, passing server messages to a handler that is called on the
+    /// current task. Unlike [`run_with_message_handler`](Self::run_with_message_handler),
+    /// the handler doesn't need to be `Send` or `'static`, so it can borrow application
+    /// state. The [`message_handler`](Self::message_handler) slot is not consulted.
+    ///
+    /// ```no_run
+    /// # async fn example(mut client: reverse_ssh::ReverseSshClient) -> anyhow::Result<()> {
+    /// let mut urls = Vec::new();
+    /// client
+    ///     .run_with_scoped_handler(|message| {
+    ///         if message.contains("https://") {
+    ///             urls.push(message);
+    ///         }
+    ///     })
+    ///     .await?;
+    /// println!("Tunnel served {} URLs", urls.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run_with_scoped_handler<F>(&mut self, mut message_handler: F) -> Result<()>
+    where
+        F: FnMut(String),
+    {
+        async {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let (message_tx, mut message_rx) = mpsc::channel(self.config.message_queue.max(1));
+
+            self.connect_session(tx, message_tx).await?;
+            self.establish_tunnel().await?;
+
+            let forwards = self.proxy_connections(rx);
+            tokio::pin!(forwards);
+            let mut messages_open = true;
+            loop {
+                tokio::select! {
+                    result = &mut forwards => return result,
+                    message = message_rx.recv(), if messages_open => match message {
+                        Some(message) => message_handler(message),
+                        None => messages_open = false,
+                    },
+                }
             }
-        });
+        }
+        .instrument(session_span())
+        .await
+    }
+}
+
+/// Span around the log lines of a session; `connect()` records its [`SessionId`]
+fn session_span() -> tracing::Span {
+    tracing::info_span!(target: "rrp", "session", id = tracing::field::Empty)
+}
 
-        self.handle_forwarded_connections(rx).await?;
+/// Own the session channel for the lifetime of the session: drain it (its output
+/// reaches the message pipeline through the handler), report it if the server closes
+/// it, and close it on shutdown
+async fn watch_session_channel(mut channel: Channel<Msg>, shared: Arc<Shared>) {
+    let mut shutdown = shared.shutdown.subscribe();
+    let mut eof = false;
+    let mut exit_status = None;
+    loop {
+        tokio::select! {
+            msg = channel.wait() => match msg {
+                // russh drops a channel the server closes without passing the close on.
+                // Servers send EOF or an exit status first, which tells a closed channel
+                // from a session that is gone, which is reported on its own.
+                Some(ChannelMsg::Close) | None => {
+                    let error = match exit_status {
+                        Some(status) => anyhow::anyhow!(
+                            "Session channel command exited with status {}",
+                            status
+                        ),
+                        None => anyhow::anyhow!("Session channel closed by server"),
+                    };
+                    if eof || exit_status.is_some() {
+                        warn!(target: targets::PROVIDER, "{}", error);
+                        shared.report(ErrorEvent::new(ErrorPhase::Shell, &error));
+                    }
+                    break;
+                }
+                Some(ChannelMsg::Eof) => eof = true,
+                Some(ChannelMsg::ExitStatus { exit_status: status }) => {
+                    debug!(target: targets::PROVIDER, "Session channel command exited with status {}", status);
+                    exit_status = Some(status);
+                }
+                Some(_) => {}
+            },
+            _ = async { shutdown.wait_for(|stop| *stop).await.map(|_| ()) } => {
+                debug!(target: targets::PROVIDER, "Closing session channel");
+                let _ = channel.eof().await;
+                let _ = channel.close().await;
+                break;
+            }
+        }
+    }
+}
 
-        Ok(())
+/// Answer a forwarded connection while in maintenance mode, without touching the local service
+async fn serve_maintenance(
+    mut channel: Channel<Msg>,
+    http: Option<&HttpProxy>,
+    metrics: &Metrics,
+) -> Result<()> {
+    match http {
+        Some(http) => {
+            info!(target: targets::PROXY, "Maintenance mode: serving maintenance page");
+            let channel_tx = channel.make_writer();
+            let result = http
+                .serve_maintenance(channel.make_reader(), channel_tx, metrics)
+                .await;
+            let _ = channel.eof().await;
+            let _ = channel.close().await;
+            result
+        }
+        None => {
+            info!(target: targets::PROXY, "Maintenance mode: rejecting connection");
+            let _ = channel.close().await;
+            Ok(())
+        }
     }
 }
 
-/// Handle a single forwarded connection by proxying data between SSH channel and local service
+/// Handle a single forwarded connection by proxying data between SSH channel and local
+/// service, until either side closes it, its deadline passes or it sits idle too long
 async fn handle_connection(
     mut channel: Channel<Msg>,
-    local_addr: &str,
-    local_port: u16,
-) -> Result<()> {
+    forward: &Forward,
+    originator: &str,
+    shared: &Shared,
+    counters: &TrafficCounters,
+    mut deadline: DeadlineWatch,
+) -> Result<CloseReason> {
+    let result = tokio::select! {
+        result = relay(&mut channel, forward, originator, shared, counters) => result,
+        _ = deadline.expired() => Ok(CloseReason::DeadlineExceeded),
+        _ = idle_timeout(shared, counters, shared.budget(forward).max_idle) => {
+            Ok(CloseReason::IdleTimeout)
+        }
+    };
+
+    // Close the channel gracefully
+    let _ = channel.eof().await;
+    let _ = channel.close().await;
+
+    info!(target: targets::PROXY, "Connection proxy closed");
+
+    result
+}
+
+/// Complete once a connection has moved no bytes either way for `max_idle`, never
+/// without it. Activity is sampled a few times per period, so an idle connection
+/// is closed at most a quarter of `max_idle` late.
+async fn idle_timeout(shared: &Shared, counters: &TrafficCounters, max_idle: Option<Duration>) {
+    let Some(max_idle) = max_idle else {
+        return std::future::pending().await;
+    };
+    let moved =
+        || counters.received.load(Ordering::Relaxed) + counters.sent.load(Ordering::Relaxed);
+    let mut seen = moved();
+    let mut active = shared.clock.now();
+    loop {
+        shared
+            .clock
+            .sleep((max_idle / 4).max(Duration::from_millis(1)))
+            .await;
+        let now = shared.clock.now();
+        if moved() != seen {
+            seen = moved();
+            active = now;
+        } else if now.saturating_duration_since(active) >= max_idle {
+            return;
+        }
+    }
+}
+
+/// Connect to the local service and relay data both ways
+async fn relay(
+    channel: &mut Channel<Msg>,
+    forward: &Forward,
+    originator: &str,
+    shared: &Shared,
+    counters: &TrafficCounters,
+) -> Result<CloseReason> {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    info!("Connecting to local service {}:{}", local_addr, local_port);
+    let gate = forward.wire_gate;
+    let mut handshake = Vec::new();
+    if let Some(protocol) = gate.filter(|protocol| !protocol.server_first()) {
+        match gate::read_handshake(channel, protocol, &*shared.clock).await {
+            Ok(bytes) => handshake = bytes,
+            Err(bytes) => return Ok(reject(channel, protocol, bytes, originator, shared).await),
+        }
+    }
+
+    let target = forward.target();
+    let keepalive = forward.idle_keepalive.or(shared.idle_keepalive);
+    info!(target: targets::PROXY, "Connecting to local service {}", target);
 
-    // Connect to the local service
-    let local_socket_addr: SocketAddr = format!("{}:{}", local_addr, local_port)
-        .parse()
-        .context("Invalid local address")?;
+    let (mut local_rx, local_tx) = match target.connect(&*shared.network, keepalive).await {
+        Ok(halves) => halves,
+        Err(e) if fds::caused_by_exhaustion(&e) => {
+            shared.fd_exhausted();
+            fds::shed(
+                channel,
+                forward.parses_http(),
+                shared.fd_pressure.remaining(),
+            )
+            .await;
+            shared.metrics.increment("connections_shed_total", 1);
+            return Ok(CloseReason::Overloaded);
+        }
+        Err(e) => return Err(e.context("Failed to connect to local service")),
+    };
+    shared.fd_pressure.recovered();
+    let shaper = shared.shaper(forward);
+    let local_tx = Shaped::new(local_tx, shaper.clone());
+    let local_tx = shared.rate_limiter.limit(local_tx, Flow::Received);
+    let local_tx = Counted::new(local_tx, counters.received.clone());
+    let local_tx = OnWire::new(local_tx, shared.wire.clone(), Flow::Received);
+    let local_tx = Counted::new(local_tx, counters.total.clone());
+    let inbound = shared.tees.sink(forward.remote_port, TeeDirection::Inbound);
+    let mut local_tx = Teed::new(local_tx, inbound);
+    let channel_tx = Shaped::new(channel.make_writer(), shaper);
+    let channel_tx = shared.rate_limiter.limit(channel_tx, Flow::Sent);
+    let channel_tx = OnWire::new(channel_tx, shared.wire.clone(), Flow::Sent);
+    let channel_tx = Counted::new(channel_tx, counters.sent.clone());
+    let channel_tx = Counted::new(channel_tx, counters.total.clone());
+    let outbound = shared
+        .tees
+        .sink(forward.remote_port, TeeDirection::Outbound);
+    let mut channel_tx = Teed::new(channel_tx, outbound);
 
-    let mut local_stream = TcpStream::connect(local_socket_addr)
-        .await
-        .context("Failed to connect to local service")?;
+    if let Some(protocol) = gate.filter(|protocol| protocol.server_first()) {
+        gate::relay_greeting(&mut local_rx, &mut channel_tx)
+            .await
+            .context("Failed to read the local service's greeting")?;
+        match gate::read_handshake(channel, protocol, &*shared.clock).await {
+            Ok(bytes) => handshake = bytes,
+            Err(bytes) => {
+                // The local service has no business with what comes next
+                drop((local_rx, local_tx));
+                return Ok(reject(channel, protocol, bytes, originator, shared).await);
+            }
+        }
+    }
+    local_tx.write_all(&handshake).await?;
+
+    if forward.parses_http() {
+        info!(target: targets::PROXY, "Connected to local service, starting HTTP-aware proxy");
+        return shared
+            .http
+            .proxy(
+                forward.remote_port,
+                channel.make_reader(),
+                channel_tx,
+                local_rx,
+                local_tx,
+                &shared.metrics,
+            )
+            .await
+            .map(|()| CloseReason::Completed);
+    }
 
-    info!("Connected to local service, starting bidirectional proxy");
+    info!(target: targets::PROXY, "Connected to local service, starting bidirectional proxy");
 
     // Bidirectional proxy using tokio::select!
-    let mut local_buf = vec![0u8; 8192];
+    let buffer_size = shared.budget(forward).max_buffered.unwrap_or_default();
+    let mut local_buf = vec![0u8; buffer_size.max(1)];
+    // Moving data only records the time; the sleep is renewed once it completes
+    let keepalive_after = keepalive.unwrap_or_default();
+    let mut active = shared.clock.now();
+    let mut idle = shared.clock.sleep(keepalive_after);
 
     // Read from local and forward to SSH
     loop {
@@ -381,61 +2159,104 @@ async fn handle_connection(
             msg = channel.wait() => {
                 match msg {
                     Some(russh::ChannelMsg::Data { data }) => {
-                        debug!("Received {} bytes from SSH channel", data.len());
-                        if let Err(e) = local_stream.write_all(&data).await {
-                            error!("Failed to write to local service: {}", e);
+                        debug!(target: targets::PROXY, "Received {} bytes from SSH channel", data.len());
+                        if let Err(e) = local_tx.write_all(&data).await {
+                            let message = format!("Failed to write to local service: {}", e);
+                            if shared.should_log(&message) {
+                                error!(target: targets::PROXY, "{}", message);
+                            }
                             break;
                         }
+                        active = shared.clock.now();
                     }
                     Some(russh::ChannelMsg::Eof) => {
-                        debug!("Received EOF from SSH channel");
-                        let _ = local_stream.shutdown().await;
+                        debug!(target: targets::PROXY, "Received EOF from SSH channel");
+                        let _ = local_tx.shutdown().await;
                         break;
                     }
                     Some(russh::ChannelMsg::Close) => {
-                        debug!("SSH channel closed");
+                        debug!(target: targets::PROXY, "SSH channel closed");
                         break;
                     }
                     Some(other) => {
-                        debug!("Received other channel message: {:?}", other);
+                        debug!(target: targets::PROXY, "Received other channel message: {:?}", other);
                     }
                     None => {
-                        debug!("SSH channel receiver closed");
+                        debug!(target: targets::PROXY, "SSH channel receiver closed");
                         break;
                     }
                 }
             }
 
             // Read from local service and write to SSH channel
-            result = local_stream.read(&mut local_buf) => {
+            result = local_rx.read(&mut local_buf) => {
                 match result {
                     Ok(0) => {
-                        debug!("Local connection closed");
+                        debug!(target: targets::PROXY, "Local connection closed");
                         break;
                     }
                     Ok(n) => {
-                        debug!("Read {} bytes from local service", n);
-                        if let Err(e) = channel.data(&local_buf[..n]).await {
-                            error!("Failed to send data to SSH channel: {}", e);
+                        debug!(target: targets::PROXY, "Read {} bytes from local service", n);
+                        if let Err(e) = channel_tx.write_all(&local_buf[..n]).await {
+                            let message = format!("Failed to send data to SSH channel: {}", e);
+                            if shared.should_log(&message) {
+                                error!(target: targets::PROXY, "{}", message);
+                            }
                             break;
                         }
+                        active = shared.clock.now();
                     }
                     Err(e) => {
-                        error!("Error reading from local service: {}", e);
+                        let message = format!("Error reading from local service: {}", e);
+                        if shared.should_log(&message) {
+                            error!(target: targets::PROXY, "{}", message);
+                        }
                         break;
                     }
                 }
             }
+
+            // Nothing moved for a while: a `window-change` request without a reply is
+            // ignored by servers on forwarded channels, but refreshes idle timers on
+            // the way
+            _ = &mut idle, if keepalive.is_some() => {
+                let due = active + keepalive_after;
+                if shared.clock.now() < due {
+                    idle = shared.clock.sleep_until(due);
+                    continue;
+                }
+                debug!(target: targets::PROXY, "Connection idle, sending a channel keepalive");
+                if let Err(e) = channel.window_change(0, 0, 0, 0).await {
+                    debug!(target: targets::PROXY, "Failed to send channel keepalive: {}", e);
+                    break;
+                }
+                active = shared.clock.now();
+                idle = shared.clock.sleep(keepalive_after);
+            }
         }
     }
 
-    // Close the channel gracefully
-    let _ = channel.eof().await;
-    let _ = channel.close().await;
-
-    info!("Connection proxy closed");
+    Ok(CloseReason::Completed)
+}
 
-    Ok(())
+/// Handle a connection rejected by a wire gate, which sent `received` so far
+async fn reject(
+    channel: &mut Channel<Msg>,
+    protocol: WireProtocol,
+    received: Vec<u8>,
+    originator: &str,
+    shared: &Shared,
+) -> CloseReason {
+    shared.metrics.increment("wire_gate_rejected_total", 1);
+    let reason = format!("not a {} handshake", protocol);
+    shared
+        .events
+        .emit_security(SecurityEvent::now(SecurityEventKind::ConnectionRejected {
+            originator: originator.to_string(),
+            reason: reason.clone(),
+        }));
+    reject::handle_rejected(channel, received, originator, &reason, shared).await;
+    CloseReason::Rejected
 }
 
 #[cfg(test)]
@@ -453,9 +2274,489 @@ mod tests {
             remote_port: 8080,
             local_addr: "127.0.0.1".to_string(),
             local_port: 3000,
+            ..Default::default()
         };
 
         assert_eq!(config.server_addr, "example.com");
         assert_eq!(config.remote_port, 8080);
+        let methods: Vec<_> = config.auth_methods().iter().map(AuthMethod::name).collect();
+        assert_eq!(methods, ["key-file"]);
+    }
+
+    fn assert_send<T: Send>(_: &T) {}
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    /// Compile-time check that the client can be driven from multi-threaded runtimes
+    /// and shared with web framework handlers
+    #[test]
+    fn test_public_types_are_thread_safe() {
+        assert_send_sync::<ReverseSshConfig>();
+        assert_send_sync::<ReverseSshClient>();
+        assert_send_sync::<ClientHandle>();
+        assert_send_sync::<MessageHandlerSlot>();
+        assert_send_sync::<ErrorEvent>();
+        assert_send_sync::<Alert>();
+        assert_send_sync::<TunnelStats>();
+        assert_send_sync::<ConnectionInfo>();
+        assert_send_sync::<MetricsSnapshot>();
+        assert_send_sync::<ForwardedConnection>();
+
+        let mut client = ReverseSshClient::new(ReverseSshConfig::default());
+        let handle = client.handle();
+        assert_send(&handle.add_forward(0));
+        assert_send(&handle.shutdown());
+        assert_send(&handle.export_har("har.json"));
+        assert_send(&client.run());
+        assert_send(&client.run_with_message_handler(|_| {}));
+        assert_send(&client.run_with_scoped_handler(|_| {}));
+    }
+
+    #[tokio::test]
+    async fn test_message_flood_is_dropped() {
+        let client = ReverseSshClient::new(ReverseSshConfig {
+            message_queue: 4,
+            ..Default::default()
+        });
+        let handle = client.handle();
+        let mut events = handle.subscribe();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (message_tx, mut messages) = mpsc::channel(client.config.message_queue);
+        let mut handler = Client::new(tx, message_tx, client.shared.clone(), &client.config);
+
+        // A server spamming the session channel while the handler is stuck
+        for i in 0..100 {
+            handler.queue_message(format!("spam {}", i));
+        }
+        assert_eq!(handle.stats().queued_messages, 4);
+        assert_eq!(handle.metrics().counter("messages_dropped_total"), 96);
+        assert!(events.try_recv().is_err());
+
+        // The handler catches up: the drops are reported before the next message
+        assert_eq!(messages.recv().await.unwrap(), "spam 0");
+        handler.queue_message("https://abc.lhr.life".to_string());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(TunnelEvent::MessagesDropped(96))
+        ));
+        drop(handler);
+        let mut rest = Vec::new();
+        while let Some(message) = messages.recv().await {
+            rest.push(message);
+        }
+        assert_eq!(rest, ["spam 1", "spam 2", "spam 3", "https://abc.lhr.life"]);
+        assert_eq!(handle.stats().queued_messages, 0);
+    }
+
+    #[tokio::test]
+    async fn test_forwards_wait_for_their_dependency() {
+        let client = ReverseSshClient::new(ReverseSshConfig::default());
+        let mut events = client.subscribe();
+        // Without a session every request fails, so nothing depending on 2222 goes
+        let ssh = Forward::new(2222, "127.0.0.1", 22).optional();
+        let web = Forward::new(3000, "127.0.0.1", 3000).after(2222).optional();
+        let api = Forward::new(3001, "127.0.0.1", 3001).after_url(80);
+        let mut acked = vec![80];
+        let pending = client
+            .request_ready(vec![api.clone(), web.clone(), ssh], &mut acked, false)
+            .await
+            .unwrap();
+        assert_eq!(pending, [api.clone(), web.clone()]);
+        assert_eq!(acked, [80]);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(TunnelEvent::ForwardRefused { port: 2222, .. })
+        ));
+        assert!(events.try_recv().is_err());
+
+        // Once the URL is known the next one is requested, and a required one failing
+        // fails the setup
+        assert!(client
+            .request_ready(pending, &mut acked, true)
+            .await
+            .is_err());
+        assert!(client
+            .forward_failed(&web, anyhow::anyhow!("refused"))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_the_limit_queue() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut service = network.listen("127.0.0.1", 8080).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        let clock = Arc::new(ManualClock::new());
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 80,
+            local_port: 8080,
+            max_connections: Some(1),
+            connection_queue: Some(Duration::from_secs(5)),
+            connection_budget: ConnectionBudget {
+                max_idle: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+            clock: clock.clone(),
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::ForwardEstablished { .. })
+        ) {}
+
+        async fn echoes(peer: &mut tokio::io::DuplexStream) -> bool {
+            let mut reply = [0u8; 4];
+            peer.write_all(b"ping").await.unwrap();
+            let read = peer.read_exact(&mut reply);
+            tokio::time::timeout(Duration::from_millis(100), read)
+                .await
+                .is_ok()
+        }
+        let mut first = network.dial("ssh.sim", 80).await.unwrap();
+        assert!(echoes(&mut first).await);
+        // The second waits for the first to close
+        let mut second = network.dial("ssh.sim", 80).await.unwrap();
+        assert!(!echoes(&mut second).await);
+        drop(first);
+        let mut reply = [0u8; 4];
+        second.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+
+        // The third gives up once it waited for the queue timeout
+        let mut third = network.dial("ssh.sim", 80).await.unwrap();
+        assert!(!echoes(&mut third).await);
+        clock.advance(Duration::from_secs(5));
+        let mut rest = Vec::new();
+        third.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        let counters = handle.metrics().counters;
+        assert_eq!(counters["connections_queued_total"], 2);
+        assert_eq!(counters["connections_limited_total"], 1);
+
+        // The second is closed once it has been quiet for `max_idle`
+        let mut idle = Duration::ZERO;
+        loop {
+            clock.advance(Duration::from_secs(15));
+            idle += Duration::from_secs(15);
+            let read = tokio::time::timeout(Duration::from_millis(50), second.read(&mut reply));
+            if let Ok(read) = read.await {
+                assert_eq!(read.unwrap(), 0);
+                break;
+            }
+            assert!(
+                idle < Duration::from_secs(90),
+                "still open after {:?}",
+                idle
+            );
+        }
+        assert!(idle >= Duration::from_secs(60));
+        let counters = handle.metrics().counters;
+        assert_eq!(counters["connections_idle_timeout_total"], 1);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_applies_only_to_its_forwards() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut web = network.listen("127.0.0.1", 8080).unwrap();
+        tokio::spawn(async move {
+            while let Some((mut stream, _)) = web.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0u8];
+                        if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        head.push(byte[0]);
+                    }
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                    let _ = stream.write_all(response).await;
+                });
+            }
+        });
+        let mut db = network.listen("127.0.0.1", 5433).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = db.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        let config = ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 80,
+            local_port: 8080,
+            http: Some(HttpConfig::default()),
+            forwards: vec![
+                Forward::new(5432, "127.0.0.1", 5433),
+                Forward::new(5434, "127.0.0.1", 5433).gated(WireProtocol::Postgres),
+            ],
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        };
+        let mut client = ReverseSshClient::new(config.clone());
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::StateChanged {
+                to: TunnelState::Ready,
+                ..
+            })
+        ) {}
+
+        let request = b"GET / HTTP/1.1\r\nHost: sim\r\n\r\n";
+        let mut browser = network.dial("ssh.sim", 80).await.unwrap();
+        browser.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"ok") {
+            let mut chunk = [0u8; 256];
+            let n = browser.read(&mut chunk).await.unwrap();
+            assert!(
+                n > 0,
+                "closed after {:?}",
+                String::from_utf8_lossy(&response)
+            );
+            response.extend_from_slice(&chunk[..n]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // The other forwards relay bytes as they are, and keep their gates
+        let mut raw = network.dial("ssh.sim", 5432).await.unwrap();
+        raw.write_all(request).await.unwrap();
+        let mut echo = vec![0u8; request.len()];
+        raw.read_exact(&mut echo).await.unwrap();
+        assert_eq!(echo, request);
+        let mut gated = network.dial("ssh.sim", 5434).await.unwrap();
+        let _ = gated.write_all(request).await;
+        let mut reply = Vec::new();
+        let _ = gated.read_to_end(&mut reply).await;
+        assert!(reply.is_empty());
+        assert_eq!(handle.metrics().counter("wire_gate_rejected_total"), 1);
+        handle.shutdown().await.unwrap();
+
+        // A gate on an HTTP forward would never see the handshake it checks
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            wire_gate: Some(WireProtocol::Postgres),
+            ..config
+        });
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (message_tx, _messages) = mpsc::channel(16);
+        let error = client.connect_session(tx, message_tx).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The forward of port 80 sets both http and wire_gate; wire gates only apply to raw forwards"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raw_forwards_skip_http_handling() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut grpc = network.listen("127.0.0.1", 50051).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = grpc.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 80,
+            local_port: 50051,
+            http: Some(HttpConfig::default()),
+            raw: true,
+            forwards: vec![Forward::new(81, "127.0.0.1", 50051).http().raw()],
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::StateChanged {
+                to: TunnelState::Ready,
+                ..
+            })
+        ) {}
+
+        // An HTTP-aware forward would add forwarding headers on the way
+        let request = b"POST /helloworld.Greeter/SayHello HTTP/1.1\r\nHost: sim\r\n\r\n";
+        for port in [80, 81] {
+            let mut peer = network.dial("ssh.sim", port).await.unwrap();
+            peer.write_all(request).await.unwrap();
+            let mut echo = vec![0u8; request.len()];
+            peer.read_exact(&mut echo).await.unwrap();
+            assert_eq!(echo, request, "port {}", port);
+        }
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_forwards_get_channel_keepalives() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let network = sim::SimNetwork::new();
+        let server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut service = network.listen("127.0.0.1", 8080).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        let clock = Arc::new(ManualClock::new());
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            idle_keepalive: Some(Duration::from_secs(30)),
+            clock: clock.clone(),
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::StateChanged {
+                to: TunnelState::Ready,
+                ..
+            })
+        ) {}
+
+        let mut peer = network.dial("ssh.sim", 80).await.unwrap();
+        let mut reply = [0u8; 4];
+        for _ in 0..2 {
+            peer.write_all(b"ping").await.unwrap();
+            peer.read_exact(&mut reply).await.unwrap();
+            clock.advance(Duration::from_secs(20));
+        }
+        // Traffic 20 s ago put off the keepalive due 30 s after the connection opened
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.window_changes(), 0);
+        clock.advance(Duration::from_secs(10));
+        wait_until(|| server.window_changes() == 1).await;
+        clock.advance(Duration::from_secs(30));
+        wait_until(|| server.window_changes() == 2).await;
+        handle.shutdown().await.unwrap();
+    }
+
+    /// Wait for the error the session channel watcher reports
+    async fn shell_error(client: &ReverseSshClient) -> String {
+        let mut message = None;
+        wait_until(|| {
+            let error = client.status().last_error;
+            message = error.filter(|error| error.phase == ErrorPhase::Shell);
+            message.is_some()
+        })
+        .await;
+        message.unwrap().message
+    }
+
+    /// Wait for `done`, which the simulated server makes true in its own time
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_channel_reopened_and_closed() {
+        let network = sim::SimNetwork::new();
+        let server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            session_channel: SessionChannel::Shell,
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (message_tx, _messages) = mpsc::channel(16);
+        client.connect_session(tx, message_tx).await.unwrap();
+        client.establish_tunnel().await.unwrap();
+        wait_until(|| server.session_requests().len() == 1).await;
+        assert_eq!(server.session_channels(), 1);
+
+        // The provider ends the shell, but not the session
+        server.close_session_channels().await;
+        assert_eq!(
+            shell_error(&client).await,
+            "Session channel closed by server"
+        );
+
+        // The next session opens a shell of its own
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (message_tx, _messages) = mpsc::channel(16);
+        client.connect_session(tx, message_tx).await.unwrap();
+        client.establish_tunnel().await.unwrap();
+        wait_until(|| server.session_requests().len() == 2).await;
+        assert_eq!(server.session_requests(), [None, None]);
+        assert_eq!(server.session_channels(), 1);
+
+        client.handle().shutdown().await.unwrap();
+        wait_until(|| server.session_channels() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_exec_channel_output_and_exit_status() {
+        let network = sim::SimNetwork::new();
+        let server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        server.set_banner("Connect to https://abc.lhr.life\r\n");
+        server.set_exit_status(Some(2));
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            session_channel: SessionChannel::localhost_run_json(),
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (message_tx, mut messages) = mpsc::channel(16);
+        client.connect_session(tx, message_tx).await.unwrap();
+        client.establish_tunnel().await.unwrap();
+
+        assert!(messages
+            .recv()
+            .await
+            .unwrap()
+            .contains("https://abc.lhr.life"));
+        assert_eq!(
+            server.session_requests(),
+            [Some("--output json".to_string())]
+        );
+        assert_eq!(
+            shell_error(&client).await,
+            "Session channel command exited with status 2"
+        );
+        client.handle().shutdown().await.unwrap();
     }
 }
@@ -0,0 +1,353 @@
+//! Running several tunnels from one process
+//!
+//! [`TunnelManager`] runs each tunnel on its own task, restarts it according to its
+//! [`RestartPolicy`], and merges their events and status. Logs from each tunnel carry
+//! a `tunnel{name=..}` span, so they can be told apart.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::reconfig::{self, ConfigDiff};
+use crate::{
+    targets, ClientHandle, Profile, ReverseSshClient, ReverseSshConfig, TunnelEvent, TunnelStatus,
+    EVENT_CAPACITY,
+};
+
+/// What to do when a tunnel's `run()` returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+    /// Leave it stopped
+    #[default]
+    Never,
+    /// Run it again after `delay` if it failed
+    OnFailure { delay: Duration },
+    /// Run it again after `delay`, whether it failed or the server closed the session
+    Always { delay: Duration },
+}
+
+impl RestartPolicy {
+    fn delay_after(&self, result: &Result<()>) -> Option<Duration> {
+        match (self, result) {
+            (RestartPolicy::OnFailure { delay }, Err(_)) | (RestartPolicy::Always { delay }, _) => {
+                Some(*delay)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Managed {
+    name: String,
+    handle: ClientHandle,
+    /// The configuration the tunnel runs with, picked up before each `run()`
+    config: watch::Sender<ReverseSshConfig>,
+    task: JoinHandle<Result<()>>,
+    forwarder: JoinHandle<()>,
+}
+
+/// Runs named tunnels side by side, like `docker compose` for tunnels
+pub struct TunnelManager {
+    tunnels: Vec<Managed>,
+    events: broadcast::Sender<(String, TunnelEvent)>,
+    stopping: watch::Sender<bool>,
+}
+
+impl Default for TunnelManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TunnelManager {
+    pub fn new() -> Self {
+        Self {
+            tunnels: Vec::new(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            stopping: watch::channel(false).0,
+        }
+    }
+
+    /// Start every tunnel of a profiles file, JSON or TOML (see [`Profile::load_all`]),
+    /// with its restart policy
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let mut manager = Self::new();
+        for profile in Profile::load_all(path)? {
+            manager.add(profile.name, profile.config, profile.restart);
+        }
+        Ok(manager)
+    }
+
+    /// Start running a tunnel. Server messages are logged line by line under
+    /// [`targets::SERVER`], inside the tunnel's span.
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        config: ReverseSshConfig,
+        restart: RestartPolicy,
+    ) -> ClientHandle {
+        let name = name.into();
+        let (config, config_rx) = watch::channel(config);
+        let client = ReverseSshClient::new(config.borrow().clone());
+        let handle = client.handle();
+
+        client.message_handler().replace(|message| {
+            for line in message.lines().filter(|line| !line.trim().is_empty()) {
+                info!(target: targets::SERVER, "{}", line.trim_end());
+            }
+        });
+
+        let mut events = handle.subscribe();
+        let combined = self.events.clone();
+        let tunnel = name.clone();
+        let forwarder = tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let _ = combined.send((tunnel.clone(), event));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let span = info_span!(target: "rrp", "tunnel", name = %name);
+        let task = tokio::spawn(
+            supervise(client, restart, config_rx, self.stopping.subscribe()).instrument(span),
+        );
+        self.tunnels.push(Managed {
+            name,
+            handle: handle.clone(),
+            config,
+            task,
+            forwarder,
+        });
+        handle
+    }
+
+    /// The handle of the tunnel called `name`
+    pub fn handle(&self, name: &str) -> Option<ClientHandle> {
+        self.tunnels
+            .iter()
+            .find(|tunnel| tunnel.name == name)
+            .map(|tunnel| tunnel.handle.clone())
+    }
+
+    /// Switch the tunnel called `name` to `config`. Changes that can be are applied
+    /// to the running tunnel; the session is only rebuilt when one of them needs it.
+    /// Fails without changing anything if a setting fixed at creation differs.
+    pub async fn reconfigure(&self, name: &str, config: ReverseSshConfig) -> Result<ConfigDiff> {
+        let tunnel = self
+            .tunnels
+            .iter()
+            .find(|tunnel| tunnel.name == name)
+            .with_context(|| format!("No tunnel called {}", name))?;
+        let old = tunnel.config.borrow().clone();
+        let diff = ConfigDiff::between(&old, &config);
+        if !diff.fixed.is_empty() {
+            bail!(
+                "{} can't be changed on a running tunnel",
+                diff.fixed.join(", ")
+            );
+        }
+        if diff.is_empty() {
+            return Ok(diff);
+        }
+        reconfig::apply(&tunnel.handle, &old, &config).await;
+        tunnel.config.send_replace(config);
+        if diff.requires_reconnect() {
+            info!(target: targets::CONFIG,
+                "Reconnecting tunnel {} for changes to {}",
+                name, diff.reconnect.join(", ")
+            );
+        } else {
+            info!(target: targets::CONFIG,
+                "Applied changes to {} to tunnel {} in place",
+                diff.in_place.join(", "), name
+            );
+        }
+        tunnel
+            .handle
+            .shared
+            .events
+            .emit(TunnelEvent::Reconfigured { diff: diff.clone() });
+        if diff.requires_reconnect() {
+            tunnel.handle.shutdown().await?;
+        }
+        Ok(diff)
+    }
+
+    /// Status of every tunnel, in the order they were added
+    pub fn status(&self) -> Vec<(String, TunnelStatus)> {
+        self.tunnels
+            .iter()
+            .map(|tunnel| (tunnel.name.clone(), tunnel.handle.status()))
+            .collect()
+    }
+
+    /// Whether every tunnel has stopped for good
+    pub fn is_finished(&self) -> bool {
+        self.tunnels.iter().all(|tunnel| tunnel.task.is_finished())
+    }
+
+    /// Events of all tunnels, tagged with the tunnel's name
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, TunnelEvent)> {
+        self.events.subscribe()
+    }
+
+    /// Stop restarting tunnels, give their open connections up to `drain` to finish,
+    /// then disconnect them all
+    pub async fn shutdown(&self, drain: Duration) {
+        self.stopping.send_replace(true);
+        let stops: Vec<_> = self
+            .tunnels
+            .iter()
+            .map(|tunnel| {
+                let (name, handle) = (tunnel.name.clone(), tunnel.handle.clone());
+                tokio::spawn(async move {
+                    if !handle.drain(drain).await {
+                        warn!(target: targets::RECONNECT,
+                            "Tunnel {} still had connections open after {:?}",
+                            name, drain
+                        );
+                    }
+                    if let Err(e) = handle.shutdown().await {
+                        warn!(target: targets::RECONNECT, "Could not shut down tunnel {}: {:#}", name, e);
+                    }
+                })
+            })
+            .collect();
+        for stop in stops {
+            let _ = stop.await;
+        }
+    }
+
+    /// Wait until every tunnel has stopped for good, returning how each one ended
+    pub async fn wait(self) -> Vec<(String, Result<()>)> {
+        let mut results = Vec::new();
+        for tunnel in self.tunnels {
+            let result = match tunnel.task.await {
+                Ok(result) => result,
+                Err(e) => Err(e.into()),
+            };
+            tunnel.forwarder.abort();
+            results.push((tunnel.name, result));
+        }
+        results
+    }
+}
+
+/// Run `client` until its restart policy says to stop or the manager shuts down,
+/// right away again when a new configuration needs a new session
+async fn supervise(
+    mut client: ReverseSshClient,
+    restart: RestartPolicy,
+    mut config: watch::Receiver<ReverseSshConfig>,
+    mut stopping: watch::Receiver<bool>,
+) -> Result<()> {
+    loop {
+        if *stopping.borrow() {
+            return Ok(());
+        }
+        if config.has_changed().unwrap_or(false) {
+            client.config = config.borrow_and_update().clone();
+        }
+        let result = client.run().await;
+        if *stopping.borrow() {
+            return result;
+        }
+        if config.has_changed().unwrap_or(false)
+            && ConfigDiff::between(&client.config, &config.borrow()).requires_reconnect()
+        {
+            continue;
+        }
+        let Some(delay) = restart.delay_after(&result) else {
+            return result;
+        };
+        client
+            .handle()
+            .shared
+            .events
+            .emit(TunnelEvent::Reconnecting { delay });
+        match &result {
+            Err(e) => {
+                warn!(target: targets::RECONNECT, "Tunnel failed: {:#}, restarting in {:?}", e, delay)
+            }
+            Ok(()) => info!(target: targets::RECONNECT, "Tunnel closed, restarting in {:?}", delay),
+        }
+        tokio::select! {
+            _ = client.config.clock.sleep(delay) => {}
+            _ = stopping.wait_for(|stop| *stop) => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_restart_policy() {
+        let delay = Duration::from_secs(5);
+        let failed: Result<()> = Err(anyhow::anyhow!("refused"));
+        assert_eq!(RestartPolicy::Never.delay_after(&failed), None);
+        assert_eq!(
+            RestartPolicy::OnFailure { delay }.delay_after(&failed),
+            Some(delay)
+        );
+        assert_eq!(
+            RestartPolicy::OnFailure { delay }.delay_after(&Ok(())),
+            None
+        );
+        assert_eq!(
+            RestartPolicy::Always { delay }.delay_after(&Ok(())),
+            Some(delay)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_waits_on_the_clock() {
+        let clock = Arc::new(crate::ManualClock::new());
+        let config = ReverseSshConfig {
+            server_addr: "127.0.0.1".to_string(),
+            server_port: 1,
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let mut manager = TunnelManager::new();
+        let mut events = manager.subscribe();
+        let delay = Duration::from_secs(3600);
+        manager.add("refused", config, RestartPolicy::OnFailure { delay });
+
+        async fn next(events: &mut broadcast::Receiver<(String, TunnelEvent)>) -> TunnelEvent {
+            loop {
+                match events.recv().await.unwrap().1 {
+                    event @ (TunnelEvent::Connecting { .. } | TunnelEvent::Reconnecting { .. }) => {
+                        return event
+                    }
+                    _ => continue,
+                }
+            }
+        }
+        assert!(matches!(
+            next(&mut events).await,
+            TunnelEvent::Connecting { .. }
+        ));
+        assert_eq!(next(&mut events).await, TunnelEvent::Reconnecting { delay });
+        // An hour of backoff passes in an instant, and not before the clock says so
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(events.is_empty());
+        clock.advance(delay);
+        assert!(matches!(
+            next(&mut events).await,
+            TunnelEvent::Connecting { .. }
+        ));
+        manager.shutdown(Duration::ZERO).await;
+    }
+}
@@ -0,0 +1,136 @@
+//! Handling of messages sent by the SSH server (e.g. the URL of a localhost.run tunnel)
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Messages kept for the first handler, beyond which the oldest are dropped
+const MAX_BACKLOG: usize = 64;
+
+type BoxedHandler = Box<dyn FnMut(String) + Send>;
+
+#[derive(Default)]
+struct Slot {
+    handler: Option<BoxedHandler>,
+    /// Bumped on every replacement, so a handler that is running while it gets
+    /// replaced isn't put back afterwards
+    generation: u64,
+    /// Messages that arrived before any handler was installed
+    backlog: VecDeque<String>,
+    subscribed: bool,
+}
+
+/// Shared slot holding the server message handler.
+///
+/// Obtained with [`ReverseSshClient::message_handler`](crate::ReverseSshClient::message_handler)
+/// before the client starts running; cloning it gives another reference to the same
+/// slot, so the handler can be replaced or removed from anywhere while the client runs.
+/// Handlers may also replace or remove themselves.
+///
+/// Messages that arrive before the first handler is installed (e.g. the URL sent
+/// right after the forward is set up) are kept and delivered to that handler when it
+/// is installed, so they can't be missed.
+#[derive(Clone, Default)]
+pub struct MessageHandlerSlot {
+    slot: Arc<Mutex<Slot>>,
+}
+
+impl MessageHandlerSlot {
+    /// Install `handler`, replacing the current one. Takes effect from the next message;
+    /// the first handler installed is also called with any messages received earlier.
+    pub fn replace<F>(&self, handler: F)
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let backlog = {
+            let mut slot = self.slot.lock().unwrap();
+            slot.handler = Some(Box::new(handler));
+            slot.generation += 1;
+            slot.subscribed = true;
+            std::mem::take(&mut slot.backlog)
+        };
+        for message in backlog {
+            self.dispatch(message);
+        }
+    }
+
+    /// Remove the current handler; later messages are dropped. Returns whether a
+    /// handler was installed.
+    pub fn remove(&self) -> bool {
+        let mut slot = self.slot.lock().unwrap();
+        slot.generation += 1;
+        slot.handler.take().is_some()
+    }
+
+    /// Whether a handler is installed
+    pub fn is_set(&self) -> bool {
+        self.slot.lock().unwrap().handler.is_some()
+    }
+
+    /// Pass `message` to the current handler. Returns `false` if there is none, in
+    /// which case the message is kept for the first handler if none was installed yet.
+    pub(crate) fn dispatch(&self, message: String) -> bool {
+        let (mut handler, generation) = {
+            let mut slot = self.slot.lock().unwrap();
+            match slot.handler.take() {
+                Some(handler) => (handler, slot.generation),
+                None => {
+                    if !slot.subscribed {
+                        if slot.backlog.len() == MAX_BACKLOG {
+                            slot.backlog.pop_front();
+                        }
+                        slot.backlog.push_back(message);
+                    }
+                    return false;
+                }
+            }
+        };
+        handler(message);
+        let mut slot = self.slot.lock().unwrap();
+        if slot.generation == generation {
+            slot.handler = Some(handler);
+        }
+        true
+    }
+}
+
+impl fmt::Debug for MessageHandlerSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MessageHandlerSlot")
+            .field("is_set", &self.is_set())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_and_remove_handler() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let slot = MessageHandlerSlot::default();
+        assert!(!slot.dispatch("early".into()));
+
+        // The first handler receives messages that arrived before it was installed
+        let log = seen.clone();
+        slot.replace(move |message| log.lock().unwrap().push(format!("a:{}", message)));
+        assert!(slot.dispatch("one".into()));
+
+        // A handler that deregisters itself after the first message
+        let log = seen.clone();
+        let own_slot = slot.clone();
+        slot.replace(move |message| {
+            log.lock().unwrap().push(format!("b:{}", message));
+            own_slot.remove();
+        });
+        assert!(slot.dispatch("two".into()));
+        assert!(!slot.dispatch("three".into()));
+        assert!(!slot.is_set());
+
+        assert!(!slot.dispatch("dropped".into()));
+        slot.replace(|_| {});
+
+        assert_eq!(*seen.lock().unwrap(), vec!["a:early", "a:one", "b:two"]);
+    }
+}
@@ -0,0 +1,201 @@
+//! In-process metrics
+//!
+//! The client records counters and histograms while it proxies traffic. A point-in-time
+//! copy is available through [`ReverseSshClient::metrics`](crate::ReverseSshClient::metrics).
+//!
+//! Metrics recorded for every forward:
+//!
+//! - `connections_deadline_exceeded_total` (counter): connections closed by their deadline
+//! - `connections_idle_timeout_total` (counter): connections closed after `max_idle`
+//!   without data
+//! - `connections_limited_total` / `connections_queued_total` (counters): connections
+//!   refused over a connection limit, and those that waited for a place under
+//!   [`max_connections`](crate::ReverseSshConfig::max_connections)
+//! - `ssh_payload_received_bytes_total` / `ssh_payload_sent_bytes_total` (counters): data
+//!   relayed from and to the SSH server
+//! - `ssh_wire_received_bytes_total` / `ssh_wire_sent_bytes_total` (counters): estimated
+//!   bytes the SSH connection carried for it, see [`TunnelStats::wire_bytes`](crate::TunnelStats::wire_bytes)
+//! - `errors_suppressed_total` (counter): repeats of an error already logged in the
+//!   [`error_log_window`](crate::ReverseSshConfig::error_log_window), left out of the logs
+//! - `forward_conflicts_total` (counter): forwards the provider reported taken
+//!   when [`precheck_forwards`](crate::ReverseSshConfig::precheck_forwards) checked them
+//! - `messages_dropped_total` (counter): server messages dropped because the
+//!   [`message_queue`](crate::ReverseSshConfig::message_queue) was full
+//! - `snapshot_invalidations_total` (counter): parameters of the
+//!   [`Snapshot`](crate::Snapshot) dropped because they stopped working
+//! - `socks_connections_total` (counter): connections made through the
+//!   [`dynamic_forward`](crate::ReverseSshConfig::dynamic_forward) SOCKS5 proxy
+//! - `wire_gate_rejected_total` (counter): connections closed by a
+//!   [`wire_gate`](crate::ReverseSshConfig::wire_gate) for not starting its handshake
+//! - `connections_denied_total` (counter): connections rejected by the
+//!   [`access_list`](crate::ReverseSshConfig::access_list)
+//! - `connections_tarpitted_total` / `connections_quarantined_total` (counters): rejected
+//!   connections handled by the [`reject_action`](crate::ReverseSshConfig::reject_action)
+//! - `tee_dropped_bytes_total` (counter): bytes not copied to a
+//!   [tee](crate::ClientHandle::tee) sink because it fell behind
+//!
+//! Metrics recorded in HTTP-aware mode:
+//!
+//! - `http_requests_total` (counter): completed requests
+//! - `http_responses_too_large_total` (counter): responses cut off by `max_response_size`
+//! - `http_cache_hits_total` / `http_cache_misses_total` (counters): cacheable requests
+//!   answered from the response cache, or forwarded to the local service
+//! - `http_maintenance_responses_total` (counter): requests answered with the maintenance page
+//! - `http_webhooks_rejected_total` (counter): requests with an invalid webhook signature
+//! - `http_request_duration_seconds` (histogram): time from request head to end of response
+//! - `http_request_size_bytes` (histogram): request body sizes
+//! - `http_response_size_bytes` (histogram): response body sizes
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::session_id::SessionId;
+
+/// Bucket upper bounds for durations in seconds
+pub(crate) const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Bucket upper bounds for sizes in bytes
+pub(crate) const SIZE_BUCKETS: &[f64] = &[
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+];
+
+/// Registry of counters and histograms shared by all connection tasks
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    inner: Mutex<MetricsSnapshot>,
+}
+
+impl Metrics {
+    /// Add `value` to a counter
+    pub(crate) fn increment(&self, name: &str, value: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.counters.entry(name.to_string()).or_default() += value;
+    }
+
+    /// Record an observation in a histogram, creating it with `buckets` if needed
+    pub(crate) fn observe(&self, name: &str, buckets: &[f64], value: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .histograms
+            .entry(name.to_string())
+            .or_insert_with(|| HistogramSnapshot::new(buckets))
+            .observe(value);
+    }
+
+    /// Copy the current values
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+/// Point-in-time copy of all recorded metrics
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    /// Session the snapshot was taken in, to label exported metrics with; the values
+    /// add up over all sessions of the client
+    pub session: Option<SessionId>,
+    pub counters: BTreeMap<String, u64>,
+    pub histograms: BTreeMap<String, HistogramSnapshot>,
+}
+
+impl MetricsSnapshot {
+    /// Value of a counter, 0 if it was never incremented
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters.get(name).copied().unwrap_or(0)
+    }
+
+    /// A histogram by name, if anything was recorded in it
+    pub fn histogram(&self, name: &str) -> Option<&HistogramSnapshot> {
+        self.histograms.get(name)
+    }
+}
+
+/// Cumulative distribution of observed values over fixed buckets
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Upper bounds of the buckets; an implicit final bucket holds everything larger
+    pub bounds: Vec<f64>,
+    /// Number of observations per bucket (one more entry than `bounds`)
+    pub counts: Vec<u64>,
+    /// Total number of observations
+    pub count: u64,
+    /// Sum of all observed values
+    pub sum: f64,
+}
+
+impl HistogramSnapshot {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Mean of all observations
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// Estimate the `q` quantile (0.0..=1.0) by interpolating within the bucket it
+    /// falls into. Values beyond the last bound are reported as that bound.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * self.count as f64;
+        let mut seen = 0u64;
+        for (i, &count) in self.counts.iter().enumerate() {
+            if count > 0 && (seen + count) as f64 >= rank {
+                let Some(&upper) = self.bounds.get(i) else {
+                    return self.bounds.last().copied();
+                };
+                let lower = if i == 0 { 0.0 } else { self.bounds[i - 1] };
+                let fraction = (rank - seen as f64) / count as f64;
+                return Some(lower + (upper - lower) * fraction);
+            }
+            seen += count;
+        }
+        self.bounds.last().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_quantile() {
+        let metrics = Metrics::default();
+        for _ in 0..90 {
+            metrics.observe("latency", &[0.1, 1.0, 10.0], 0.05);
+        }
+        for _ in 0..10 {
+            metrics.observe("latency", &[0.1, 1.0, 10.0], 5.0);
+        }
+        metrics.increment("requests", 100);
+
+        let snapshot = metrics.snapshot();
+        let latency = snapshot.histogram("latency").unwrap();
+        assert_eq!(latency.count, 100);
+        assert_eq!(latency.counts, vec![90, 0, 10, 0]);
+        assert!(latency.quantile(0.5).unwrap() <= 0.1);
+        assert!(latency.quantile(0.95).unwrap() > 1.0);
+        assert_eq!(snapshot.counter("requests"), 100);
+        assert_eq!(snapshot.counter("missing"), 0);
+    }
+}
@@ -0,0 +1,104 @@
+//! The network the client opens its connections on
+//!
+//! Connections to the SSH server (or the first jump host, or the proxy) and to local
+//! TCP services are opened through the [`Network`] of the configuration rather than
+//! with `TcpStream::connect` directly. [`TcpNetwork`] is the real network;
+//! [`SimNetwork`](crate::sim::SimNetwork) connects the client to in-memory peers,
+//! so whole tunnels can be tested without sockets. UDP and Unix socket targets, the
+//! SOCKS proxy and the endpoint probe always use the real network.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+
+/// A connection opened by a [`Network`]
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Send TCP keepalives once the connection is idle for `idle`, where the
+    /// connection has them
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        let _ = idle;
+        Ok(())
+    }
+
+    /// The address the connection reached, where it has one
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+        socket2::SockRef::from(self).set_tcp_keepalive(&keepalive)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl Connection for DuplexStream {}
+
+/// A connection being opened by a [`Network`]
+pub type Connecting = Pin<Box<dyn Future<Output = io::Result<Box<dyn Connection>>> + Send>>;
+
+/// Addresses being looked up by a [`Network`]
+pub type Resolving = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>>;
+
+/// Where the client's connections go
+pub trait Network: Debug + Send + Sync {
+    /// Open a connection to `host:port`
+    fn connect(&self, host: &str, port: u16) -> Connecting;
+
+    /// Look up the addresses of `host`, so the SSH server's can be raced (see
+    /// [`AddressFamily`](crate::AddressFamily)). No addresses means the network
+    /// resolves names itself in [`connect`](Self::connect), as it does by default.
+    fn resolve(&self, host: &str, port: u16) -> Resolving {
+        let _ = (host, port);
+        Box::pin(std::future::ready(Ok(Vec::new())))
+    }
+}
+
+/// The machine's network, through tokio's sockets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpNetwork;
+
+impl Network for TcpNetwork {
+    fn connect(&self, host: &str, port: u16) -> Connecting {
+        let resolving = self.resolve(host, port);
+        Box::pin(async move {
+            let addrs = resolving.await?;
+            let stream = TcpStream::connect(&addrs[..]).await?;
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        })
+    }
+
+    fn resolve(&self, host: &str, port: u16) -> Resolving {
+        let host = host.to_string();
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Could not resolve {}: {}", host, e),
+                    )
+                })?;
+            Ok(addrs.collect())
+        })
+    }
+}
+
+/// The [`TcpNetwork`] configurations start with, shared so that configurations
+/// built separately compare as having the same network
+pub(crate) fn tcp() -> Arc<dyn Network> {
+    static TCP: OnceLock<Arc<dyn Network>> = OnceLock::new();
+    TCP.get_or_init(|| Arc::new(TcpNetwork)).clone()
+}
@@ -0,0 +1,120 @@
+//! Notifying embedders through a file descriptor
+//!
+//! Hosts written in C, or daemons with their own event loop, can't easily take
+//! callbacks across the FFI boundary. They hand the client an `eventfd` or the write
+//! end of a pipe instead, with
+//! [`ClientHandle::notify_fd`](crate::ClientHandle::notify_fd), and poll the other
+//! end alongside their own descriptors. Every time the [`Notification`] occurs, the
+//! client writes the 8 bytes of the number 1 (native byte order) to the descriptor:
+//! an `eventfd` counts the occurrences, a pipe yields one 8-byte record each. The
+//! host then reads the details, such as the URL, through the regular API.
+//!
+//! The client writes to a duplicate of the descriptor, which it closes when the
+//! client is dropped. Writes that would block, because the host isn't reading a
+//! non-blocking descriptor, are dropped. Hosts that don't ignore `SIGPIPE` should
+//! prefer an `eventfd`, or keep the read end of their pipe open.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::AsFd;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::{targets, tasks, TunnelEvent};
+
+/// A condition an embedder is notified of through a file descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notification {
+    /// The provider announced the tunnel's public URL, or a new one
+    UrlReady,
+    /// The session failed; `run()` returns the error, or a
+    /// [`RestartPolicy`](crate::RestartPolicy) starts another session
+    FatalError,
+}
+
+impl Notification {
+    fn matches(self, event: &TunnelEvent) -> bool {
+        match self {
+            Notification::UrlReady => matches!(event, TunnelEvent::UrlReceived { .. }),
+            Notification::FatalError => {
+                matches!(event, TunnelEvent::Disconnected { error: Some(_) })
+            }
+        }
+    }
+}
+
+/// Write to a duplicate of `fd` whenever `notification` occurs among `events`
+pub(crate) fn spawn(
+    mut events: broadcast::Receiver<TunnelEvent>,
+    notification: Notification,
+    fd: impl AsFd,
+) -> Result<JoinHandle<()>> {
+    let fd = fd
+        .as_fd()
+        .try_clone_to_owned()
+        .context("Failed to duplicate the notification descriptor")?;
+    let file = Arc::new(File::from(fd));
+    let name = format!("notify {:?}", notification);
+    Ok(tasks::spawn(&name, async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if notification.matches(&event) => {}
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+            // A blocking descriptor the host doesn't read must not stall the runtime
+            let file = file.clone();
+            let write = move || file.as_ref().write_all(&1u64.to_ne_bytes());
+            let written = tokio::task::spawn_blocking(write)
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            match written {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    debug!(target: targets::SESSION,
+                        "Stopped notifying {:?} through descriptor: {}", notification, e);
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ReverseSshClient, ReverseSshConfig};
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    #[tokio::test]
+    async fn test_notify_fd() {
+        let handle = ReverseSshClient::new(ReverseSshConfig::default()).handle();
+        let (mut host, fd) = UnixStream::pair().unwrap();
+        handle.notify_fd(Notification::FatalError, &fd).unwrap();
+        // The client writes to its own duplicate
+        drop(fd);
+
+        let events = &handle.shared.events;
+        events.emit(TunnelEvent::UrlReceived {
+            url: "https://8d3c1a.lhr.life".into(),
+        });
+        events.emit(TunnelEvent::Disconnected { error: None });
+        events.emit(TunnelEvent::Disconnected {
+            error: Some("Connection reset".into()),
+        });
+        let record = tokio::task::spawn_blocking(move || {
+            let mut record = [0u8; 8];
+            host.read_exact(&mut record).map(|_| record)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(u64::from_ne_bytes(record), 1);
+    }
+}
@@ -0,0 +1,231 @@
+//! Estimated SSH overhead of the relayed traffic
+//!
+//! Payload is what the local service and the remote peers exchange. On the wire,
+//! the SSH session wraps it in `SSH_MSG_CHANNEL_DATA` packets with a length, padding
+//! and a MAC, opens and closes a channel per connection, and rekeys after every
+//! gigabyte. The estimate assumes the AEAD ciphers russh prefers (16 byte blocks and
+//! tags) and the default 32 KiB maximum packet size; TCP/IP headers are not included.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+
+/// Cipher block size packets are padded to
+const BLOCK_SIZE: u64 = 16;
+/// Authentication tag appended to every packet
+const MAC_SIZE: u64 = 16;
+/// Largest data payload of one `SSH_MSG_CHANNEL_DATA` packet
+const MAX_PACKET_DATA: u64 = 32768;
+/// Message type, recipient channel and data length of `SSH_MSG_CHANNEL_DATA`
+const CHANNEL_DATA_HEADER: u64 = 9;
+/// Bytes written in one direction before russh rekeys
+const REKEY_LIMIT: u64 = 1 << 30;
+/// Bytes one direction of a curve25519 key exchange takes (KEXINIT, ECDH, NEWKEYS)
+const REKEY_BYTES: u64 = 1200;
+
+/// Bytes on the wire of a packet carrying `payload` bytes
+fn packet_size(payload: u64) -> u64 {
+    let unpadded = 5 + payload;
+    let mut padding = BLOCK_SIZE - unpadded % BLOCK_SIZE;
+    if padding < 4 {
+        padding += BLOCK_SIZE;
+    }
+    unpadded + padding + MAC_SIZE
+}
+
+/// Bytes on the wire of `len` bytes of channel data
+fn channel_data_size(len: u64) -> u64 {
+    let full = len / MAX_PACKET_DATA;
+    let rest = len % MAX_PACKET_DATA;
+    let mut size = full * packet_size(CHANNEL_DATA_HEADER + MAX_PACKET_DATA);
+    if rest > 0 {
+        size += packet_size(CHANNEL_DATA_HEADER + rest);
+    }
+    size
+}
+
+/// SSH string: length prefix and contents
+fn string_size(s: &str) -> u64 {
+    4 + s.len() as u64
+}
+
+/// Payload and estimated wire bytes in one direction
+#[derive(Debug, Default)]
+struct Direction {
+    payload: AtomicU64,
+    wire: AtomicU64,
+}
+
+impl Direction {
+    fn add(&self, payload: u64, wire: u64) {
+        self.payload.fetch_add(payload, Ordering::Relaxed);
+        let before = self.wire.fetch_add(wire, Ordering::Relaxed);
+        let rekeys = (before + wire) / REKEY_LIMIT - before / REKEY_LIMIT;
+        if rekeys > 0 {
+            self.wire.fetch_add(rekeys * REKEY_BYTES, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Payload and estimated wire bytes of all connections of a client
+#[derive(Debug, Default)]
+pub(crate) struct WireAccounting {
+    /// From the server: data for the local service
+    received: Direction,
+    /// To the server: data from the local service
+    sent: Direction,
+}
+
+impl WireAccounting {
+    /// Account for opening and closing the channel of a forwarded connection
+    pub(crate) fn record_channel(&self, connected_address: &str, originator_address: &str) {
+        // SSH_MSG_CHANNEL_OPEN "forwarded-tcpip" from the server
+        let open = 1
+            + string_size("forwarded-tcpip")
+            + 12
+            + string_size(connected_address)
+            + 4
+            + string_size(originator_address)
+            + 4;
+        // SSH_MSG_CHANNEL_OPEN_CONFIRMATION, then SSH_MSG_CHANNEL_EOF and
+        // SSH_MSG_CHANNEL_CLOSE both ways
+        let confirmation = 17;
+        let eof_or_close = 5;
+        self.received
+            .add(0, packet_size(open) + 2 * packet_size(eof_or_close));
+        self.sent
+            .add(0, packet_size(confirmation) + 2 * packet_size(eof_or_close));
+    }
+
+    /// Account for `len` bytes of data received from the server
+    pub(crate) fn record_received(&self, len: u64) {
+        self.received.add(len, channel_data_size(len));
+    }
+
+    /// Account for `len` bytes of data sent to the server
+    pub(crate) fn record_sent(&self, len: u64) {
+        self.sent.add(len, channel_data_size(len));
+    }
+
+    /// Payload received from and sent to the server
+    pub(crate) fn payload(&self) -> (u64, u64) {
+        (
+            self.received.payload.load(Ordering::Relaxed),
+            self.sent.payload.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Estimated bytes on the wire in both directions
+    pub(crate) fn wire_bytes(&self) -> u64 {
+        self.received.wire.load(Ordering::Relaxed) + self.sent.wire.load(Ordering::Relaxed)
+    }
+
+    /// Values for the `ssh_*_bytes_total` counters
+    pub(crate) fn counters(&self) -> [(&'static str, u64); 4] {
+        [
+            (
+                "ssh_payload_received_bytes_total",
+                self.received.payload.load(Ordering::Relaxed),
+            ),
+            (
+                "ssh_payload_sent_bytes_total",
+                self.sent.payload.load(Ordering::Relaxed),
+            ),
+            (
+                "ssh_wire_received_bytes_total",
+                self.received.wire.load(Ordering::Relaxed),
+            ),
+            (
+                "ssh_wire_sent_bytes_total",
+                self.sent.wire.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+/// Which way a [`OnWire`] writer moves data
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Flow {
+    /// Writes to the local service of data that came from the server
+    Received,
+    /// Writes to the SSH channel
+    Sent,
+}
+
+/// Writer that accounts for what its writes cost on the SSH connection
+pub(crate) struct OnWire<W> {
+    inner: W,
+    accounting: Arc<WireAccounting>,
+    flow: Flow,
+}
+
+impl<W> OnWire<W> {
+    pub(crate) fn new(inner: W, accounting: Arc<WireAccounting>, flow: Flow) -> Self {
+        Self {
+            inner,
+            accounting,
+            flow,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for OnWire<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        match self.flow {
+            Flow::Received => self.accounting.record_received(written as u64),
+            Flow::Sent => self.accounting.record_sent(written as u64),
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_wire_estimate() {
+        // 5 + 9 + 1 = 15 bytes pads to 32, plus the tag
+        assert_eq!(packet_size(CHANNEL_DATA_HEADER + 1), 48);
+        assert_eq!(packet_size(0) % BLOCK_SIZE, 0);
+        assert_eq!(
+            channel_data_size(MAX_PACKET_DATA + 1),
+            packet_size(CHANNEL_DATA_HEADER + MAX_PACKET_DATA) + 48
+        );
+
+        let accounting = Arc::new(WireAccounting::default());
+        let mut writer = OnWire::new(Vec::new(), accounting.clone(), Flow::Sent);
+        writer.write_all(b"x").await.unwrap();
+        accounting.record_received(REKEY_LIMIT);
+        let counters = accounting.counters();
+        assert_eq!(
+            counters[0],
+            ("ssh_payload_received_bytes_total", REKEY_LIMIT)
+        );
+        assert_eq!(counters[1], ("ssh_payload_sent_bytes_total", 1));
+        assert_eq!(counters[3].1, 48);
+        // Framing plus one rekey
+        assert_eq!(counters[2].1, channel_data_size(REKEY_LIMIT) + REKEY_BYTES);
+        assert_eq!(accounting.wire_bytes(), counters[2].1 + 48);
+
+        accounting.record_channel("localhost", "203.0.113.7");
+        assert!(accounting.wire_bytes() > counters[2].1 + 48 + 6 * 32);
+    }
+}
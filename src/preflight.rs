@@ -0,0 +1,266 @@
+//! Connectivity checks run before a tunnel is set up
+//!
+//! A [`PreflightReport`] says whether the SSH server is reachable and speaks SSH, and
+//! whether the local target accepts connections, with a hint for each failure. With
+//! [`ReverseSshConfig::preflight`](crate::ReverseSshConfig::preflight) set, `connect()`
+//! fails with the report instead of establishing a forward to nowhere.
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use tokio::io::AsyncReadExt;
+
+use crate::clock::{self, Clock};
+use crate::network::{Connection, Network};
+use crate::ReverseSshConfig;
+
+/// Time each check may take
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of one preflight check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightCheck {
+    /// `host:port` that was checked
+    pub address: String,
+    /// Time the check took
+    pub elapsed: Duration,
+    /// Why the check failed
+    pub error: Option<String>,
+    /// What to do about the failure
+    pub hint: Option<String>,
+}
+
+impl PreflightCheck {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Reachability of both ends of a tunnel. Also the error `connect()` fails with when
+/// a preflight check fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightReport {
+    /// The SSH server accepts TCP connections and sends an SSH banner
+    pub server: PreflightCheck,
+    /// The local target accepts connections (or passes its health check)
+    pub local_target: PreflightCheck,
+}
+
+impl PreflightReport {
+    pub fn is_ok(&self) -> bool {
+        self.server.is_ok() && self.local_target.is_ok()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            f.write_str("Preflight checks passed")?;
+        } else {
+            f.write_str("Preflight checks failed")?;
+        }
+        for (name, check) in [
+            ("ssh server", &self.server),
+            ("local target", &self.local_target),
+        ] {
+            let verdict = if check.is_ok() { "ok" } else { "FAIL" };
+            write!(
+                f,
+                "\n  {:<4} {} {} ({:?})",
+                verdict, name, check.address, check.elapsed
+            )?;
+            if let Some(error) = &check.error {
+                write!(f, ": {}", error)?;
+            }
+            if let Some(hint) = &check.hint {
+                write!(f, "\n       hint: {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PreflightReport {}
+
+/// Check both ends of the tunnel described by `config`, concurrently
+pub(crate) async fn run(config: &ReverseSshConfig) -> PreflightReport {
+    let (server, local_target) = tokio::join!(check_server(config), check_local_target(config));
+    PreflightReport {
+        server,
+        local_target,
+    }
+}
+
+async fn check_server(config: &ReverseSshConfig) -> PreflightCheck {
+    // Through jump hosts, the first one is all that can be checked without logging in
+    let (host, port) = match config.jump_hosts.first() {
+        Some(jump) => (jump.host.as_str(), jump.port),
+        None => (config.server_addr.as_str(), config.server_port),
+    };
+    timed(format!("{}:{}", host, port), &*config.clock, async {
+        let mut stream = match &config.proxy {
+            Some(proxy) => proxy
+                .connect(&*config.network, host, port)
+                .await
+                .map_err(|e| {
+                    failure(
+                        e,
+                        "check the proxy address and credentials, and that it allows CONNECT to \
+                     server_port",
+                    )
+                })?,
+            None => connect_direct(&*config.network, host, port).await?,
+        };
+        let mut banner = [0u8; 8];
+        stream.read_exact(&mut banner).await.map_err(|e| {
+            failure(
+                anyhow!(e).context("The server closed the connection without an SSH banner"),
+                "check server_port, it may belong to another service",
+            )
+        })?;
+        if !banner.starts_with(b"SSH-") {
+            return Err(failure(
+                anyhow!(
+                    "The server does not speak SSH (it sent {:?})",
+                    String::from_utf8_lossy(&banner)
+                ),
+                "check server_port, it may belong to another service",
+            ));
+        }
+        Ok(())
+    })
+    .await
+}
+
+async fn connect_direct(
+    network: &dyn Network,
+    host: &str,
+    port: u16,
+) -> Result<Box<dyn Connection>, (anyhow::Error, String)> {
+    network.connect(host, port).await.map_err(|e| {
+        let hint = match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                "check server_addr for typos, and that DNS works on this machine".to_string()
+            }
+            std::io::ErrorKind::ConnectionRefused => {
+                "nothing is listening there, check server_addr and server_port".to_string()
+            }
+            _ => format!(
+                "outbound connections to port {} may be blocked by a firewall or proxy, \
+                 see the proxy setting",
+                port
+            ),
+        };
+        failure(anyhow!(e), hint)
+    })
+}
+
+async fn check_local_target(config: &ReverseSshConfig) -> PreflightCheck {
+    let target = config.local_forward().target();
+    timed(target.to_string(), &*config.clock, async {
+        let result = match &config.health_check {
+            Some(check) => check.probe(&*config.network, &target, &*config.clock).await,
+            None => target.connect(&*config.network, None).await.map(drop),
+        };
+        let hint = match config.local_socket {
+            Some(_) => "start the local service, or point local_socket at it",
+            None => "start the local service, or point local_addr and local_port at it",
+        };
+        result.map_err(|e| failure(e, hint))
+    })
+    .await
+}
+
+fn failure(error: anyhow::Error, hint: impl Into<String>) -> (anyhow::Error, String) {
+    (error, hint.into())
+}
+
+/// Run a check with [`PREFLIGHT_TIMEOUT`], recording how long it took
+async fn timed(
+    address: String,
+    clock: &dyn Clock,
+    check: impl std::future::Future<Output = Result<(), (anyhow::Error, String)>>,
+) -> PreflightCheck {
+    let started = clock.now();
+    let result = match clock::timeout(clock, PREFLIGHT_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(failure(
+            anyhow!("Timed out after {:?}", PREFLIGHT_TIMEOUT),
+            "the host may be down, or a firewall may be dropping the connection",
+        )),
+    };
+    let (error, hint) = match result {
+        Ok(()) => (None, None),
+        Err((error, hint)) => (Some(format!("{:#}", error)), Some(hint)),
+    };
+    PreflightCheck {
+        address,
+        elapsed: clock.now() - started,
+        error,
+        hint,
+    }
+}
+
+/// Fail unless `report` passed
+pub(crate) fn check(report: PreflightReport) -> Result<PreflightReport> {
+    if !report.is_ok() {
+        bail!(report);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_preflight_report() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for banner in ["SSH-2.0-OpenSSH_9.6\r\n", "HTTP/1.1 400 Bad Request\r\n"] {
+                let (mut stream, _) = server.accept().await.unwrap();
+                stream.write_all(banner.as_bytes()).await.unwrap();
+            }
+        });
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = ReverseSshConfig {
+            server_addr: "127.0.0.1".to_string(),
+            server_port,
+            local_port: local.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+
+        let report = run(&config).await;
+        assert!(report.is_ok(), "{}", report);
+
+        drop(local);
+        let report = run(&config).await;
+        assert!(!report.server.is_ok());
+        assert!(report
+            .server
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("does not speak SSH"));
+        assert!(!report.local_target.is_ok());
+        assert!(report
+            .local_target
+            .hint
+            .as_ref()
+            .unwrap()
+            .contains("local_port"));
+        let error = check(report).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Preflight checks failed\n  FAIL ssh server"));
+        assert!(error.downcast_ref::<PreflightReport>().is_some());
+
+        config.server_addr = "nonexistent.invalid".to_string();
+        let report = run(&config).await;
+        assert!(report.server.error.unwrap().contains("Could not resolve"));
+    }
+}
@@ -0,0 +1,120 @@
+//! Raw forward settings tuned for common protocols
+//!
+//! Long-lived, mostly idle connections are what trips up tunnels carrying MQTT or
+//! database traffic: an HTTP-aware forward would mangle them, and NATs drop them
+//! between two messages. A [`ProtocolPreset`] sets up a forward for one protocol in
+//! one go, by name in profiles files (`"preset": "postgres"`).
+
+use std::time::Duration;
+
+use crate::Forward;
+
+/// Forward settings for a protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProtocolPreset {
+    /// MQTT brokers: small messages and clients that sit idle between pings
+    Mqtt,
+    /// PostgreSQL: pooled connections idle for minutes, then bulk result sets
+    Postgres,
+    /// Redis: pipelined commands, pub/sub connections idle indefinitely
+    Redis,
+    /// SSH to a host behind the tunnel: interactive sessions left open for hours
+    Ssh,
+}
+
+impl ProtocolPreset {
+    pub const ALL: [Self; 4] = [Self::Mqtt, Self::Postgres, Self::Redis, Self::Ssh];
+
+    /// Look up a preset by name: `mqtt`, `postgres`, `redis` or `ssh` (case-insensitive)
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "mqtt" => Some(Self::Mqtt),
+            "postgres" | "postgresql" => Some(Self::Postgres),
+            "redis" => Some(Self::Redis),
+            "ssh" => Some(Self::Ssh),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mqtt => "mqtt",
+            Self::Postgres => "postgres",
+            Self::Redis => "redis",
+            Self::Ssh => "ssh",
+        }
+    }
+
+    /// Port the protocol's servers listen on by default
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::Mqtt => 1883,
+            Self::Postgres => 5432,
+            Self::Redis => 6379,
+            Self::Ssh => 22,
+        }
+    }
+
+    /// Silence after which connections are kept alive, well below the shortest
+    /// common NAT timeouts
+    pub fn idle_keepalive(&self) -> Duration {
+        match self {
+            Self::Mqtt | Self::Ssh => Duration::from_secs(30),
+            Self::Postgres | Self::Redis => Duration::from_secs(60),
+        }
+    }
+
+    /// Size of the buffer raw forwards relay with
+    pub fn buffer_size(&self) -> usize {
+        match self {
+            Self::Mqtt => 4 * 1024,
+            Self::Redis => 16 * 1024,
+            // One full SSH packet
+            Self::Ssh => 32 * 1024,
+            Self::Postgres => 64 * 1024,
+        }
+    }
+
+    /// Turn `forward` into a raw forward for this protocol. Ports are left alone.
+    pub fn apply(&self, forward: &mut Forward) {
+        forward.http = false;
+        forward.idle_keepalive = Some(self.idle_keepalive());
+        forward.budget.max_buffered = Some(self.buffer_size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConnectionBudget, HttpConfig, ReverseSshConfig};
+
+    #[test]
+    fn test_presets() {
+        for preset in ProtocolPreset::ALL {
+            assert_eq!(ProtocolPreset::named(preset.name()), Some(preset));
+        }
+        assert_eq!(
+            ProtocolPreset::named("PostgreSQL"),
+            Some(ProtocolPreset::Postgres)
+        );
+        assert_eq!(ProtocolPreset::named("http"), None);
+
+        let config = ReverseSshConfig {
+            http: Some(HttpConfig::default()),
+            forwards: vec![Forward::new(1883, "127.0.0.1", 1883)
+                .http()
+                .with_preset(ProtocolPreset::Mqtt)],
+            ..Default::default()
+        };
+        let mqtt = &config.forwards[0];
+        assert!(!mqtt.http);
+        assert_eq!(mqtt.idle_keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(mqtt.budget.max_buffered, Some(4096));
+        assert_eq!(mqtt.local_port, 1883);
+        // The configuration and its other forwards are left alone
+        assert!(config.local_forward().http);
+        assert_eq!(config.idle_keepalive, None);
+        assert_eq!(config.local_forward().budget, ConnectionBudget::default());
+    }
+}
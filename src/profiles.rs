@@ -0,0 +1,676 @@
+//! Named tunnels read from a profiles file
+//!
+//! ```json
+//! {
+//!   "version": 2,
+//!   "tunnels": {
+//!     "web": { "local_port": 8080, "restart": "always" },
+//!     "db": { "server": "tunnel.example.com", "user": "deploy", "key_file": "~/.ssh/id_ed25519",
+//!             "remote_port": 5432, "local_port": 5432, "restart": "on-failure" }
+//!   }
+//! }
+//! ```
+//!
+//! The same schema can be written in TOML, in a file ending with `.toml`:
+//!
+//! ```toml
+//! version = 2
+//!
+//! [tunnels.web]
+//! provider = "serveo"
+//! domain = "myapp"
+//! local_port = 8080
+//! restart = "always"
+//!
+//! [tunnels.db]
+//! server = "tunnel.example.com"
+//! user = "deploy"
+//! agent = true
+//! key_file = "~/.ssh/id_ed25519"
+//! remote_port = 5432
+//! local_port = 5432
+//! restart = "on-failure"
+//! ```
+//!
+//! A `provider` (`localhost.run`, `serveo`, `pinggy` or `sish`) sets up its conventions,
+//! as [`ReverseSshConfig::for_provider`] does, with `domain` requesting a custom
+//! domain, subdomain or sish alias; the other settings of the entry override them.
+//!
+//! Files without a `version` are version 1 and are migrated when read; files from a
+//! newer release are refused rather than misread. Relative key paths are resolved
+//! against the directory of the file. A `preset` names a
+//! [`ProtocolPreset`](crate::ProtocolPreset) and stands in for `local_port`. Strings may
+//! refer to environment variables as `${VAR}`, `${VAR:-default}` or `${VAR:?message}`,
+//! and numbers may be given as such strings, e.g. `"port": "${SSH_PORT:-22}"`.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+use tracing::info;
+
+use crate::interpolate::interpolate_json;
+use crate::manager::RestartPolicy;
+use crate::{
+    targets, AccessList, AddressFamily, Algorithms, AuthMethod, PrivateKey, ProtocolPreset,
+    Provider, ProxyConfig, ReverseSshConfig, SessionChannel, WireProtocol,
+};
+
+/// Version of the profiles file format understood by this release
+pub const PROFILES_VERSION: u64 = 2;
+
+/// Upgrades of each tunnel entry from version `n` to `n + 1`, at index `n - 1`
+const MIGRATIONS: &[fn(&mut serde_json::Map<String, Value>)] = &[
+    // 2: `key` became `key_file`, to tell it apart from in-memory keys
+    |entry| {
+        if let Some(path) = entry.remove("key") {
+            entry.insert("key_file".to_string(), path);
+        }
+    },
+];
+
+/// A named tunnel and how to restart it
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub config: ReverseSshConfig,
+    pub restart: RestartPolicy,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    tunnels: BTreeMap<String, Entry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Entry {
+    /// Defaults to the provider's server, or localhost.run
+    server: Option<String>,
+    #[serde(default, deserialize_with = "optional_number")]
+    port: Option<u16>,
+    user: Option<String>,
+    /// Tunnel service whose conventions to follow
+    provider: Option<String>,
+    /// Custom domain, subdomain or alias to ask the provider for
+    domain: Option<String>,
+    key_file: Option<String>,
+    /// Environment variable holding the private key
+    key_env: Option<String>,
+    password: Option<String>,
+    /// Try the keys of the SSH agent first
+    #[serde(default)]
+    agent: bool,
+    /// HTTP proxy URL, `http://[user:password@]host:port`
+    proxy: Option<String>,
+    /// `SHA256:...` fingerprint the server's host key must have
+    host_key_fingerprint: Option<String>,
+    /// Defaults to the provider's, or 80
+    #[serde(default, deserialize_with = "optional_number")]
+    remote_port: Option<u32>,
+    local_addr: Option<String>,
+    /// Defaults to the preset's port
+    #[serde(default, deserialize_with = "optional_number")]
+    local_port: Option<u16>,
+    /// Unix domain socket forwarded to instead of `local_addr` and `local_port`
+    local_socket: Option<String>,
+    /// The local service speaks UDP
+    #[serde(default)]
+    udp: bool,
+    /// Name of a [`ProtocolPreset`]
+    preset: Option<String>,
+    /// Name of a [`WireProtocol`] connections must start with
+    wire_gate: Option<String>,
+    /// Ask localhost.run for JSON output
+    #[serde(default)]
+    json: bool,
+    restart: Option<String>,
+    /// Seconds to wait before restarting
+    #[serde(default = "default_restart_delay", deserialize_with = "number")]
+    restart_delay: u64,
+    /// Seconds of silence before a keepalive is sent, 0 to turn them off
+    #[serde(default, deserialize_with = "optional_number")]
+    keepalive_interval: Option<u64>,
+    #[serde(default, deserialize_with = "optional_number")]
+    keepalive_count_max: Option<usize>,
+    /// Seconds a forwarded connection may stay silent before it is kept alive
+    #[serde(default, deserialize_with = "optional_number")]
+    idle_keepalive: Option<u64>,
+    /// Connections relayed at once
+    #[serde(default, deserialize_with = "optional_number")]
+    max_connections: Option<usize>,
+    /// Seconds a connection over `max_connections` waits for a place
+    #[serde(default, deserialize_with = "optional_number")]
+    connection_queue: Option<u64>,
+    /// File keeping the parameters of the last session that came up
+    snapshot_path: Option<String>,
+    /// Seconds in which repeats of a logged error are only counted, 0 to log them all
+    #[serde(default, deserialize_with = "optional_number")]
+    error_log_window: Option<u64>,
+    /// Name of an [`AddressFamily`]
+    address_family: Option<String>,
+    /// Server messages queued for the handler
+    #[serde(default, deserialize_with = "optional_number")]
+    message_queue: Option<usize>,
+    /// Seconds the TCP connect, the SSH handshake and authentication may each
+    /// take, 0 to wait as long as they do
+    #[serde(default, deserialize_with = "optional_number")]
+    connect_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "optional_number")]
+    handshake_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "optional_number")]
+    auth_timeout: Option<u64>,
+    /// Bytes read from the local service at a time, overriding the preset's
+    #[serde(default, deserialize_with = "optional_number")]
+    buffer_size: Option<usize>,
+    /// SSH channel window, in bytes
+    #[serde(default, deserialize_with = "optional_number")]
+    window_size: Option<u32>,
+    /// Largest SSH packet accepted, in bytes
+    #[serde(default, deserialize_with = "optional_number")]
+    max_packet_size: Option<u32>,
+    /// Offer `zlib@openssh.com` compression
+    #[serde(default)]
+    compression: bool,
+    /// Ask the provider whether forwards are free before requesting them
+    #[serde(default)]
+    precheck_forwards: bool,
+    /// Algorithms to offer, most preferred first, as in OpenSSH's `KexAlgorithms`,
+    /// `HostKeyAlgorithms`, `Ciphers` and `MACs`
+    #[serde(default)]
+    kex_algorithms: Vec<String>,
+    #[serde(default)]
+    host_key_algorithms: Vec<String>,
+    #[serde(default)]
+    ciphers: Vec<String>,
+    #[serde(default)]
+    macs: Vec<String>,
+    /// Networks forwarded connections may, or may not, come from
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+/// A number, or a string holding one (e.g. the result of `${VAR}`)
+fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString<T> {
+        Number(T),
+        String(String),
+    }
+    match NumberOrString::<T>::deserialize(deserializer)? {
+        NumberOrString::Number(number) => Ok(number),
+        NumberOrString::String(text) => text
+            .trim()
+            .parse()
+            .map_err(|e| serde::de::Error::custom(format!("invalid number {:?}: {}", text, e))),
+    }
+}
+
+fn optional_number<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: Display,
+{
+    number(deserializer).map(Some)
+}
+
+fn default_restart_delay() -> u64 {
+    5
+}
+
+impl Profile {
+    /// Read every tunnel in the profiles file at `path`, sorted by name. Files
+    /// ending with `.toml` are read as TOML, others as JSON.
+    pub fn load_all(path: impl AsRef<Path>) -> Result<Vec<Profile>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        let profiles = match path.extension().is_some_and(|ext| ext == "toml") {
+            true => Self::parse_toml(&text, base),
+            false => Self::parse(&text, base),
+        };
+        profiles.with_context(|| format!("Invalid profiles in {}", path.display()))
+    }
+
+    /// Read the tunnel called `name` from the profiles file at `path`
+    pub fn load(path: impl AsRef<Path>, name: &str) -> Result<Profile> {
+        Self::load_all(&path)?
+            .into_iter()
+            .find(|profile| profile.name == name)
+            .with_context(|| format!("No tunnel {} in {}", name, path.as_ref().display()))
+    }
+
+    fn parse(text: &str, base: &Path) -> Result<Vec<Profile>> {
+        Self::parse_with(text, base, &|name| std::env::var(name).ok())
+    }
+
+    fn parse_toml(text: &str, base: &Path) -> Result<Vec<Profile>> {
+        Self::parse_value(toml::from_str(text)?, base, &|name| {
+            std::env::var(name).ok()
+        })
+    }
+
+    fn parse_with(
+        text: &str,
+        base: &Path,
+        env: &impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<Profile>> {
+        Self::parse_value(serde_json::from_str(text)?, base, env)
+    }
+
+    fn parse_value(
+        mut value: Value,
+        base: &Path,
+        env: &impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<Profile>> {
+        migrate(&mut value)?;
+        interpolate_json(&mut value, env)?;
+        let file: ProfilesFile = serde_json::from_value(value)?;
+        file.tunnels
+            .into_iter()
+            .map(|(name, entry)| {
+                let profile = entry.into_profile(&name, base);
+                profile.with_context(|| format!("Tunnel {}", name))
+            })
+            .collect()
+    }
+}
+
+impl Entry {
+    pub(crate) fn into_profile(self, name: &str, base: &Path) -> Result<Profile> {
+        let delay = Duration::from_secs(self.restart_delay);
+        let restart = match self.restart.as_deref() {
+            None | Some("never") => RestartPolicy::Never,
+            Some("on-failure") => RestartPolicy::OnFailure { delay },
+            Some("always") => RestartPolicy::Always { delay },
+            Some(other) => bail!(
+                "Unknown restart policy {} (expected never, on-failure or always)",
+                other
+            ),
+        };
+        let key = self
+            .key_env
+            .as_deref()
+            .map(PrivateKey::from_env)
+            .transpose()?;
+        let preset = self
+            .preset
+            .as_deref()
+            .map(|name| {
+                ProtocolPreset::named(name).with_context(|| {
+                    format!(
+                        "Unknown preset {} (expected mqtt, postgres, redis or ssh)",
+                        name
+                    )
+                })
+            })
+            .transpose()?;
+        let address_family = self
+            .address_family
+            .as_deref()
+            .map(|name| {
+                AddressFamily::named(name).with_context(|| {
+                    format!(
+                        "Unknown address_family {} (expected prefer-ipv6, prefer-ipv4, ipv6 or ipv4)",
+                        name
+                    )
+                })
+            })
+            .transpose()?;
+        let access_list = AccessList {
+            allow: AccessList::allow(&self.allow)
+                .context("Invalid allow")?
+                .allow,
+            deny: AccessList::deny(&self.deny).context("Invalid deny")?.deny,
+        };
+        let wire_gate = self
+            .wire_gate
+            .as_deref()
+            .map(|name| {
+                WireProtocol::named(name).with_context(|| {
+                    format!("Unknown wire_gate {} (expected postgres or mysql)", name)
+                })
+            })
+            .transpose()?;
+        let local_port = match (self.local_port, preset, &self.local_socket) {
+            (Some(port), _, _) => port,
+            (None, Some(preset), _) => preset.default_port(),
+            (None, None, Some(_)) => 0,
+            (None, None, None) => bail!("Missing local_port"),
+        };
+        let key_path = self.key_file.map(|key| resolve(&key, base));
+        let mut config = match self.provider.as_deref() {
+            Some(name) => ReverseSshConfig::for_provider(Provider::named(
+                name,
+                self.domain,
+                self.server.as_deref(),
+                self.port,
+                key_path.as_deref(),
+            )?),
+            None if self.domain.is_some() => bail!("domain needs a provider"),
+            None => ReverseSshConfig {
+                server_addr: "localhost.run".to_string(),
+                username: "nokey".to_string(),
+                ..Default::default()
+            },
+        };
+        if let Some(server) = self.server {
+            config.server_addr = server;
+        }
+        if let Some(port) = self.port {
+            config.server_port = port;
+        }
+        if let Some(user) = self.user {
+            config.username = user;
+        }
+        // The entry's credentials replace the provider's, and the fields only hold one
+        // of each kind
+        let mut auth: Vec<_> = self
+            .agent
+            .then_some(AuthMethod::Agent)
+            .into_iter()
+            .collect();
+        auth.extend(key.clone().map(AuthMethod::Key));
+        auth.extend(key_path.clone().map(AuthMethod::KeyFile));
+        auth.extend(self.password.clone().map(AuthMethod::Password));
+        if self.agent || (!config.auth.is_empty() && !auth.is_empty()) {
+            config.auth = auth;
+        }
+        config.key = key;
+        config.key_path = key_path;
+        config.password = self.password;
+        config.proxy = self.proxy.as_deref().map(ProxyConfig::parse).transpose()?;
+        config.host_key_fingerprint = self.host_key_fingerprint;
+        if let Some(port) = self.remote_port {
+            config.remote_port = port;
+        }
+        config.local_port = local_port;
+        config.local_socket = self.local_socket.map(|path| resolve(&path, base).into());
+        config.udp = self.udp;
+        config.wire_gate = wire_gate;
+        config.access_list = access_list;
+        // A profile forwards one port, so the preset's settings are the tunnel's
+        if let Some(preset) = preset {
+            config.idle_keepalive = Some(preset.idle_keepalive());
+            config.buffer_size = preset.buffer_size();
+        }
+        if let Some(local_addr) = self.local_addr {
+            config.local_addr = local_addr;
+        }
+        if self.json {
+            config.session_channel = SessionChannel::localhost_run_json();
+        }
+        if let Some(secs) = self.keepalive_interval {
+            config.keepalive_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(count) = self.keepalive_count_max {
+            config.keepalive_count_max = count;
+        }
+        if let Some(secs) = self.idle_keepalive {
+            config.idle_keepalive = Some(Duration::from_secs(secs));
+        }
+        config.max_connections = self.max_connections;
+        config.connection_queue = self.connection_queue.map(Duration::from_secs);
+        config.snapshot_path = self.snapshot_path.map(|path| resolve(&path, base).into());
+        if let Some(secs) = self.error_log_window {
+            config.error_log_window = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(family) = address_family {
+            config.address_family = family;
+        }
+        if let Some(size) = self.message_queue {
+            config.message_queue = size;
+        }
+        let timeout = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        if let Some(secs) = self.connect_timeout {
+            config.connect_timeout = timeout(secs);
+        }
+        if let Some(secs) = self.handshake_timeout {
+            config.handshake_timeout = timeout(secs);
+        }
+        if let Some(secs) = self.auth_timeout {
+            config.auth_timeout = timeout(secs);
+        }
+        if let Some(size) = self.buffer_size {
+            config.buffer_size = size;
+        }
+        if let Some(size) = self.window_size {
+            config.window_size = size;
+        }
+        if let Some(size) = self.max_packet_size {
+            config.max_packet_size = size;
+        }
+        config.compression = self.compression;
+        config.precheck_forwards = self.precheck_forwards;
+        config.algorithms = Algorithms {
+            kex: self.kex_algorithms,
+            host_key: self.host_key_algorithms,
+            cipher: self.ciphers,
+            mac: self.macs,
+        };
+        Ok(Profile {
+            name: name.to_string(),
+            config,
+            restart,
+        })
+    }
+}
+
+/// Upgrade a parsed profiles file to [`PROFILES_VERSION`], removing its `version`
+fn migrate(value: &mut Value) -> Result<()> {
+    let Some(file) = value.as_object_mut() else {
+        bail!("Expected a JSON object");
+    };
+    let version = match file.remove("version") {
+        None => 1,
+        Some(version) => match version.as_u64() {
+            Some(version) if version >= 1 => version,
+            _ => bail!("Invalid version {} (expected a positive integer)", version),
+        },
+    };
+    if version > PROFILES_VERSION {
+        bail!(
+            "Profiles file version {} is newer than this release understands (up to {}), \
+             upgrade rrp to read it",
+            version,
+            PROFILES_VERSION
+        );
+    }
+    if version == PROFILES_VERSION {
+        return Ok(());
+    }
+    if let Some(tunnels) = file.get_mut("tunnels").and_then(Value::as_object_mut) {
+        for entry in tunnels.values_mut().filter_map(Value::as_object_mut) {
+            for migration in &MIGRATIONS[version as usize - 1..] {
+                migration(entry);
+            }
+        }
+    }
+    info!(target: targets::CONFIG,
+        "Migrated profiles file from version {} to {}",
+        version, PROFILES_VERSION
+    );
+    Ok(())
+}
+
+/// Expand `~/` and resolve relative paths against `base`
+fn resolve(path: &str, base: &Path) -> String {
+    let path = match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    };
+    base.join(path).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profiles() {
+        let text = r#"{
+            "version": 2,
+            "tunnels": {
+                "web": { "local_port": 8080, "restart": "always", "json": true,
+                         "keepalive_interval": 0, "buffer_size": 65536, "error_log_window": 0,
+                         "window_size": "${WINDOW:-8388608}", "max_packet_size": 65536,
+                         "compression": true, "precheck_forwards": true, "message_queue": 32, "connect_timeout": 3, "auth_timeout": 0 },
+                "db": { "server": "${DB_HOST}", "user": "deploy", "key_file": "keys/${DB_KEY:-db}",
+                        "port": "${SSH_PORT:-22}", "remote_port": 5432, "preset": "postgres",
+                        "restart": "on-failure", "restart_delay": 10,
+                        "keepalive_interval": "${KEEPALIVE:-10}", "keepalive_count_max": 5,
+                        "idle_keepalive": 90, "wire_gate": "postgres",
+                        "max_connections": 20, "connection_queue": 5,
+                        "snapshot_path": "state/db.json", "error_log_window": 60, "address_family": "IPv4",
+                        "allow": ["10.0.0.0/8", "192.168.1.7"], "deny": ["10.0.0.66"],
+                        "kex_algorithms": ["diffie-hellman-group14-sha1"], "ciphers": ["aes128-cbc"] }
+            }
+        }"#;
+        let env = |name: &str| match name {
+            "DB_HOST" => Some("tunnel.example.com".to_string()),
+            "SSH_PORT" => Some("2222".to_string()),
+            _ => None,
+        };
+        let profiles = Profile::parse_with(text, Path::new("/etc/rrp"), &env).unwrap();
+        assert_eq!(profiles.len(), 2);
+
+        let db = &profiles[0];
+        assert_eq!(db.name, "db");
+        assert_eq!(db.config.server_addr, "tunnel.example.com");
+        assert_eq!(db.config.server_port, 2222);
+        assert_eq!(db.config.key_path.as_deref(), Some("/etc/rrp/keys/db"));
+        assert_eq!(db.config.keepalive_interval, Some(Duration::from_secs(10)));
+        assert_eq!(db.config.keepalive_count_max, 5);
+        assert_eq!(db.config.local_port, 5432);
+        assert_eq!(db.config.buffer_size, 64 * 1024);
+        assert_eq!(db.config.idle_keepalive, Some(Duration::from_secs(90)));
+        assert_eq!(db.config.max_connections, Some(20));
+        assert_eq!(db.config.connection_queue, Some(Duration::from_secs(5)));
+        assert_eq!(
+            db.config.snapshot_path.as_deref(),
+            Some(Path::new("/etc/rrp/state/db.json"))
+        );
+        assert_eq!(db.config.error_log_window, Some(Duration::from_secs(60)));
+        assert_eq!(db.config.address_family, AddressFamily::Ipv4Only);
+        assert_eq!(
+            db.config.access_list,
+            AccessList {
+                allow: vec![
+                    "10.0.0.0/8".parse().unwrap(),
+                    "192.168.1.7/32".parse().unwrap()
+                ],
+                deny: vec!["10.0.0.66".parse().unwrap()],
+            }
+        );
+        assert_eq!(db.config.algorithms.kex, ["diffie-hellman-group14-sha1"]);
+        assert_eq!(db.config.algorithms.cipher, ["aes128-cbc"]);
+        assert!(db.config.algorithms.mac.is_empty());
+        assert_eq!(db.config.wire_gate, Some(WireProtocol::Postgres));
+        assert_eq!(
+            db.restart,
+            RestartPolicy::OnFailure {
+                delay: Duration::from_secs(10)
+            }
+        );
+
+        let web = &profiles[1];
+        assert_eq!(web.config.server_addr, "localhost.run");
+        assert_eq!(web.config.remote_port, 80);
+        assert_eq!(web.config.keepalive_interval, None);
+        assert_eq!(web.config.buffer_size, 65536);
+        assert_eq!(web.config.error_log_window, None);
+        assert_eq!(web.config.window_size, 8 * 1024 * 1024);
+        assert!(web.config.compression);
+        assert!(web.config.precheck_forwards);
+        assert_eq!(web.config.connect_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(web.config.handshake_timeout, Some(Duration::from_secs(15)));
+        assert_eq!(web.config.auth_timeout, None);
+        assert_eq!(web.config.message_queue, 32);
+        assert_eq!(web.config.max_packet_size, 65536);
+        assert_eq!(
+            web.config.session_channel,
+            SessionChannel::localhost_run_json()
+        );
+
+        let typo = r#"{ "tunnels": { "web": { "local_port": 8080, "restrat": "always" } } }"#;
+        assert!(Profile::parse(typo, Path::new(".")).is_err());
+
+        let unversioned = r#"{ "tunnels": { "db": { "key": "/keys/db", "local_port": 5432 } } }"#;
+        let profiles = Profile::parse(unversioned, Path::new(".")).unwrap();
+        assert_eq!(profiles[0].config.key_path.as_deref(), Some("/keys/db"));
+
+        let unix = r#"{ "version": 2, "tunnels": { "app": { "local_socket": "run/app.sock" } } }"#;
+        let profiles = Profile::parse(unix, Path::new("/srv")).unwrap();
+        assert_eq!(
+            profiles[0].config.local_socket,
+            Some(PathBuf::from("/srv/run/app.sock"))
+        );
+        let missing = r#"{ "version": 2, "tunnels": { "app": {} } }"#;
+        assert!(Profile::parse(missing, Path::new(".")).is_err());
+
+        let toml = r#"
+            version = 2
+
+            [tunnels.pinggy]
+            provider = "pinggy"
+            local_port = 3000
+
+            [tunnels.sish]
+            provider = "sish"
+            server = "${SISH_HOST}"
+            domain = "db"
+            remote_port = 5432
+            local_port = 5432
+            agent = true
+            key_file = "/keys/sish"
+            restart = "always"
+        "#;
+        let env = |name: &str| (name == "SISH_HOST").then(|| "tuns.sh".to_string());
+        let profiles =
+            Profile::parse_value(toml::from_str(toml).unwrap(), Path::new("."), &env).unwrap();
+        let (pinggy, sish) = (&profiles[0].config, &profiles[1].config);
+        assert_eq!(pinggy.server_addr, "a.pinggy.io");
+        assert_eq!(pinggy.remote_port, 0);
+        assert_eq!(pinggy.auth_methods()[0].name(), "none");
+        assert_eq!(
+            (sish.server_addr.as_str(), sish.server_port),
+            ("tuns.sh", 2222)
+        );
+        assert_eq!((sish.bind_address.as_str(), sish.remote_port), ("db", 5432));
+        let methods: Vec<_> = sish.auth_methods().iter().map(AuthMethod::name).collect();
+        assert_eq!(methods, ["agent", "key-file"]);
+        assert_eq!(
+            profiles[1].restart,
+            RestartPolicy::Always {
+                delay: Duration::from_secs(5)
+            }
+        );
+
+        let unknown = r#"{ "tunnels": { "web": { "provider": "ngrok", "local_port": 80 } } }"#;
+        assert!(Profile::parse(unknown, Path::new(".")).is_err());
+        let keyless = r#"{ "tunnels": { "web": { "provider": "localhost.run",
+                           "domain": "example.com", "local_port": 80 } } }"#;
+        assert!(Profile::parse(keyless, Path::new(".")).is_err());
+
+        let newer = r#"{ "version": 3, "tunnels": {} }"#;
+        let error = Profile::parse(newer, Path::new(".")).unwrap_err();
+        assert!(error.to_string().contains("version 3 is newer"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Lightweight self-profiling, for "the tunnel is eating 100% CPU" reports
+//!
+//! Built with the `profiling` feature. [`ClientHandle::profile`](crate::ClientHandle::profile)
+//! takes a [`ResourceUsage`]: how busy the tokio runtime is, how many tasks are alive
+//! compared to the connections being relayed, and, when the application installs
+//! [`CountingAllocator`] as its global allocator, how much memory is allocated.
+//! [`ClientHandle::serve_profiling`](crate::ClientHandle::serve_profiling) answers the
+//! same on a control socket, so a running process can be inspected in the field:
+//!
+//! ```text
+//! $ echo profile | nc -U /run/rrp/profile.sock
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::{debug, info, Instrument};
+
+use crate::{targets, ClientHandle};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FREED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Global allocator that counts allocations before passing them to `System`:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOCATOR: reverse_ssh::CountingAllocator = reverse_ssh::CountingAllocator;
+/// ```
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            record_dealloc(layout.size());
+            record_alloc(new_size);
+        }
+        new
+    }
+}
+
+fn record_alloc(size: usize) {
+    INSTALLED.store(true, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+}
+
+fn record_dealloc(size: usize) {
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    FREED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+}
+
+/// Allocations counted by [`CountingAllocator`] since the process started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationStats {
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// Bytes allocated in total
+    pub allocated_bytes: u64,
+    /// Bytes allocated and not freed yet
+    pub live_bytes: u64,
+}
+
+impl AllocationStats {
+    /// The counts so far, or `None` if [`CountingAllocator`] isn't the global allocator
+    pub fn current() -> Option<Self> {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            return None;
+        }
+        let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+        Some(Self {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+            allocated_bytes,
+            live_bytes: allocated_bytes.saturating_sub(FREED_BYTES.load(Ordering::Relaxed)),
+        })
+    }
+}
+
+/// What the tokio runtime the client runs on is doing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeProfile {
+    pub workers: usize,
+    /// Tasks spawned on the runtime that haven't finished, the client's and others
+    pub alive_tasks: usize,
+    /// Tasks waiting in the runtime's global queue
+    pub queued_tasks: usize,
+    /// Time each worker spent busy since the runtime started. A worker whose time
+    /// grows as fast as the clock is spinning.
+    pub busy: Vec<Duration>,
+}
+
+impl RuntimeProfile {
+    fn current() -> Self {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let workers = metrics.num_workers();
+        #[cfg(target_has_atomic = "64")]
+        let busy = (0..workers)
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .collect();
+        #[cfg(not(target_has_atomic = "64"))]
+        let busy = Vec::new();
+        Self {
+            workers,
+            alive_tasks: metrics.num_alive_tasks(),
+            queued_tasks: metrics.global_queue_depth(),
+            busy,
+        }
+    }
+}
+
+/// A snapshot of the client's resource use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub runtime: RuntimeProfile,
+    /// CPU time the process used so far (Linux only)
+    pub cpu_time: Option<Duration>,
+    /// Forwarded connections being relayed
+    pub connections: usize,
+    /// Tasks handling forwarded connections; more than `connections` points at tasks
+    /// that outlive their connection
+    pub connection_tasks: usize,
+    pub allocations: Option<AllocationStats>,
+}
+
+impl ResourceUsage {
+    pub(crate) fn take(handle: &ClientHandle) -> Self {
+        Self {
+            runtime: RuntimeProfile::current(),
+            cpu_time: cpu_time(),
+            connections: handle.stats().active_connections,
+            connection_tasks: handle.shared.connection_tasks(),
+            allocations: AllocationStats::current(),
+        }
+    }
+
+    /// The profile as a JSON object
+    pub fn to_json(&self) -> Value {
+        let runtime = &self.runtime;
+        json!({
+            "runtime": {
+                "workers": runtime.workers,
+                "alive_tasks": runtime.alive_tasks,
+                "queued_tasks": runtime.queued_tasks,
+                "busy_seconds": runtime.busy.iter().map(Duration::as_secs_f64).collect::<Vec<_>>(),
+            },
+            "cpu_seconds": self.cpu_time.map(|time| time.as_secs_f64()),
+            "connections": self.connections,
+            "connection_tasks": self.connection_tasks,
+            "allocations": self.allocations.map(|stats| json!({
+                "allocations": stats.allocations,
+                "deallocations": stats.deallocations,
+                "allocated_bytes": stats.allocated_bytes,
+                "live_bytes": stats.live_bytes,
+            })),
+        })
+    }
+
+    /// Part of the profile a control socket command asks for: `profile` for all of
+    /// it, `tasks` or `allocations`
+    fn answer(&self, command: &str) -> Value {
+        let profile = self.to_json();
+        match command {
+            "profile" => profile,
+            "tasks" => json!({
+                "runtime": profile["runtime"],
+                "connections": profile["connections"],
+                "connection_tasks": profile["connection_tasks"],
+            }),
+            "allocations" => json!({ "allocations": profile["allocations"] }),
+            _ => json!({ "error": format!("unknown command {:?}", command) }),
+        }
+    }
+}
+
+/// CPU time of the process, from the scheduler statistics of Linux
+fn cpu_time() -> Option<Duration> {
+    let stats = std::fs::read_to_string("/proc/self/schedstat").ok()?;
+    let nanos = stats.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_nanos(nanos))
+}
+
+/// Answer commands on the Unix socket at `path`, one per line, each with a line of
+/// JSON, until an error occurs on the listening socket
+#[cfg(unix)]
+pub(crate) async fn serve(handle: ClientHandle, path: &Path) -> Result<()> {
+    // A socket left over by a previous run would make binding fail
+    let _ = std::fs::remove_file(path);
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    info!(target: targets::SESSION, "Serving profiles on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handle = handle.clone();
+        tokio::spawn(
+            async move {
+                let (rx, mut tx) = stream.into_split();
+                let mut lines = BufReader::new(rx).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let answer = ResourceUsage::take(&handle).answer(line.trim());
+                    if tx
+                        .write_all(format!("{}\n", answer).as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                debug!(target: targets::SESSION, "Profiling client disconnected");
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{ReverseSshClient, ReverseSshConfig};
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn test_profiling_socket() {
+        let handle = ReverseSshClient::new(ReverseSshConfig::default()).handle();
+        let profile = handle.profile();
+        assert_eq!(profile.connections, 0);
+        assert_eq!(profile.connection_tasks, 0);
+        assert_eq!(profile.runtime.workers, 1);
+        assert!(profile.runtime.alive_tasks <= 1);
+
+        let path = std::env::temp_dir().join(format!("rrp-profile-{}.sock", std::process::id()));
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move { handle.serve_profiling(path).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (rx, mut tx) = stream.into_split();
+        let mut lines = BufReader::new(rx).lines();
+        tx.write_all(b"tasks\nstack\n").await.unwrap();
+        let tasks: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(tasks["runtime"]["workers"], 1);
+        assert_eq!(tasks["connection_tasks"], 0);
+        assert!(tasks.get("allocations").is_none());
+        let error: Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(error["error"], "unknown command \"stack\"");
+
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
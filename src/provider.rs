@@ -0,0 +1,851 @@
+//! Understanding what tunnel providers say on the session channel
+//!
+//! localhost.run can describe the tunnel as JSON, one object per line, when the session
+//! channel runs `--output json` (see [`SessionChannel::localhost_run_json`]). Other
+//! providers print a human-readable banner, from which the URL is scraped. Output is
+//! reassembled into lines and stripped of terminal escapes before either is parsed.
+//!
+//! Refusals (no key, quota, plan) are recognised in either form and surfaced as
+//! [`ProviderError`]s.
+//!
+//! Each provider also has its own conventions: the server and port, the user name
+//! (localhost.run's `nokey`), the bind address of the forward (localhost.run wants an
+//! empty one, or the custom domain of an account) and the domains its URLs are on. A [`TunnelProvider`] bundles them, and
+//! can replace the parsing above; [`Provider`] has them for the public services and
+//! sish, for [`ReverseSshClient::for_provider`](crate::ReverseSshClient::for_provider).
+//!
+//! [`SessionChannel::localhost_run_json`]: crate::SessionChannel::localhost_run_json
+
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::conflict::AvailabilityCheck;
+use crate::url::url_host;
+use crate::{AuthMethod, SessionChannel};
+
+/// Tunnel details announced by the provider
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelInfo {
+    /// Public URL of the tunnel
+    pub url: String,
+    /// Domain the provider assigned to the tunnel, when it says so in machine-readable
+    /// output: a random subdomain, or the custom domain that was requested
+    pub domain: Option<String>,
+    /// The URL (`tcp://name:port`) is a sish TCP alias, only reachable through the
+    /// server with `ssh -W name:port`
+    pub alias: bool,
+    /// When the tunnel (or the free domain) expires, as sent by the provider
+    pub expires: Option<String>,
+    /// Account plan the tunnel runs on, e.g. `free`
+    pub plan: Option<String>,
+    /// Provider-side identifier of the tunnel
+    pub connection_id: Option<String>,
+    /// Whether the details came from machine-readable output rather than a banner
+    pub structured: bool,
+}
+
+/// A provider refused to set up the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProviderError {
+    /// The provider requires a registered SSH public key
+    MissingPublicKey,
+    /// The account ran out of tunnels, bandwidth or time
+    QuotaExceeded,
+    /// The requested feature (e.g. a custom domain) needs a paid plan
+    PlanRequired,
+    /// Any other refusal reported through machine-readable output
+    Rejected(String),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::MissingPublicKey => f.write_str("provider requires an SSH public key"),
+            ProviderError::QuotaExceeded => f.write_str("provider quota exceeded"),
+            ProviderError::PlanRequired => f.write_str("feature requires a paid provider plan"),
+            ProviderError::Rejected(message) => {
+                write!(f, "provider rejected the tunnel: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+/// The conventions of a tunnel provider
+pub trait TunnelProvider: fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Address and port of the SSH server
+    fn server(&self) -> (String, u16);
+
+    /// User to log in as
+    fn username(&self) -> String;
+
+    /// Address sent with the forward request; providers read a requested subdomain
+    /// from it
+    fn bind_address(&self) -> String {
+        String::new()
+    }
+
+    /// Port to ask the server to listen on
+    fn remote_port(&self) -> u32 {
+        80
+    }
+
+    fn session_channel(&self) -> SessionChannel {
+        SessionChannel::Shell
+    }
+
+    /// How long the session may stay quiet before a keepalive is sent, see
+    /// [`ReverseSshConfig::keepalive_interval`](crate::ReverseSshConfig::keepalive_interval)
+    fn keepalive_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(30))
+    }
+
+    /// Authentication methods to try; the `none` method when empty
+    fn auth(&self) -> Vec<AuthMethod> {
+        Vec::new()
+    }
+
+    /// Domains the tunnel URLs are on, see [`UrlDetector::with_domains`](crate::UrlDetector::with_domains)
+    fn url_domains(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Extract tunnel details from a line of server output
+    fn parse_line(&self, line: &str) -> Option<TunnelInfo> {
+        parse_line(line)
+    }
+
+    /// Recognise a refusal in a line of server output
+    fn classify(&self, line: &str) -> Option<ProviderError> {
+        classify(line)
+    }
+
+    /// The URL of a web console for the tunnel in a line of server output, which
+    /// isn't taken for the tunnel's URL
+    fn console_url(&self, _line: &str) -> Option<String> {
+        None
+    }
+
+    /// Whether the forward of `port` with `bind_address` is free, for providers that
+    /// can tell before it is requested; `None` if they can't. Used when
+    /// [`precheck_forwards`](crate::ReverseSshConfig::precheck_forwards) is set.
+    fn check_forward(&self, _bind_address: &str, _port: u32) -> Option<AvailabilityCheck<'_>> {
+        None
+    }
+}
+
+/// Built-in tunnel providers
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Provider {
+    /// localhost.run, anonymous unless a key registered with it is configured
+    LocalhostRun,
+    /// localhost.run with an account, authenticating with a key registered with it.
+    /// `domain` requests a custom domain set up on the account (a paid plan), or
+    /// keeps the account's subdomain when unset.
+    LocalhostRunAccount {
+        key_file: String,
+        domain: Option<String>,
+    },
+    /// serveo.net, on a random subdomain
+    Serveo,
+    /// serveo.net on `subdomain` of serveo.net. Serveo reserves a subdomain for the key
+    /// that first used it, so configure the same key every time.
+    ServeoSubdomain { subdomain: String },
+    /// pinggy.io's free tier, whose tunnels expire after an hour
+    Pinggy,
+    /// A self-hosted [sish](https://github.com/antoniomika/sish) server, which listens
+    /// for SSH on port 2222 by default. `name` is sent as the bind address: the
+    /// subdomain of an HTTP forward (remote port 80 or 443), or the TCP alias of a
+    /// forward of any other port, which is only reachable through the server with
+    /// `ssh -W name:port`.
+    Sish {
+        server: String,
+        port: u16,
+        name: Option<String>,
+    },
+}
+
+impl Provider {
+    /// localhost.run with the account whose registered key is in `key_file`
+    pub fn localhost_run_account(key_file: impl Into<String>) -> Self {
+        Provider::LocalhostRunAccount {
+            key_file: key_file.into(),
+            domain: None,
+        }
+    }
+
+    /// serveo.net on `subdomain`
+    pub fn serveo_subdomain(subdomain: impl Into<String>) -> Self {
+        Provider::ServeoSubdomain {
+            subdomain: subdomain.into(),
+        }
+    }
+
+    /// The sish server at `server`, on its default port
+    pub fn sish(server: impl Into<String>) -> Self {
+        Provider::Sish {
+            server: server.into(),
+            port: 2222,
+            name: None,
+        }
+    }
+
+    /// The sish server at `server`, on its default port, forwarding under `name`:
+    /// a subdomain, or a TCP alias for ports other than 80 and 443
+    pub fn sish_named(server: impl Into<String>, name: impl Into<String>) -> Self {
+        Provider::Sish {
+            server: server.into(),
+            port: 2222,
+            name: Some(name.into()),
+        }
+    }
+
+    /// The built-in provider called `name`, as [`TunnelProvider::name`] reports it,
+    /// asked for `domain`. sish runs on `server` (and `port`, 2222 by default); a
+    /// localhost.run domain belongs to the account whose key is in `key_file`.
+    pub fn named(
+        name: &str,
+        domain: Option<String>,
+        server: Option<&str>,
+        port: Option<u16>,
+        key_file: Option<&str>,
+    ) -> Result<Self> {
+        Ok(match (name, domain) {
+            ("localhost.run", domain) => match (key_file, domain) {
+                (Some(key_file), domain) => Provider::LocalhostRunAccount {
+                    key_file: key_file.to_string(),
+                    domain,
+                },
+                (None, None) => Provider::LocalhostRun,
+                (None, Some(_)) => {
+                    bail!("A localhost.run domain needs the key_file of the account")
+                }
+            },
+            ("serveo", None) => Provider::Serveo,
+            ("serveo", Some(subdomain)) => Provider::ServeoSubdomain { subdomain },
+            ("pinggy", None) => Provider::Pinggy,
+            ("sish", name) => Provider::Sish {
+                server: server
+                    .context("The sish provider needs a server")?
+                    .to_string(),
+                port: port.unwrap_or(2222),
+                name,
+            },
+            (name @ "pinggy", Some(_)) => bail!("The {} provider doesn't take a domain", name),
+            (other, _) => bail!(
+                "Unknown provider {} (expected localhost.run, serveo, pinggy or sish)",
+                other
+            ),
+        })
+    }
+}
+
+/// Names of the built-in providers, as [`TunnelProvider::name`] reports them
+pub(crate) const PROVIDERS: &[&str] = &["localhost.run", "serveo", "pinggy", "sish"];
+
+impl TunnelProvider for Provider {
+    fn name(&self) -> &str {
+        match self {
+            Provider::LocalhostRun | Provider::LocalhostRunAccount { .. } => "localhost.run",
+            Provider::Serveo | Provider::ServeoSubdomain { .. } => "serveo",
+            Provider::Pinggy => "pinggy",
+            Provider::Sish { .. } => "sish",
+        }
+    }
+
+    fn server(&self) -> (String, u16) {
+        match self {
+            Provider::LocalhostRun | Provider::LocalhostRunAccount { .. } => {
+                ("localhost.run".to_string(), 22)
+            }
+            Provider::Serveo | Provider::ServeoSubdomain { .. } => ("serveo.net".to_string(), 22),
+            // Port 443 gets through firewalls that block 22
+            Provider::Pinggy => ("a.pinggy.io".to_string(), 443),
+            Provider::Sish { server, port, .. } => (server.clone(), *port),
+        }
+    }
+
+    fn username(&self) -> String {
+        match self {
+            Provider::LocalhostRun => "nokey",
+            // The others ignore it, as localhost.run does for a key it knows
+            _ => "tunnel",
+        }
+        .to_string()
+    }
+
+    fn bind_address(&self) -> String {
+        match self {
+            Provider::LocalhostRun => "",
+            // As in `ssh -R example.com:80:localhost:8080 localhost.run`
+            Provider::LocalhostRunAccount { domain, .. } => domain.as_deref().unwrap_or(""),
+            // As in `ssh -R myalias:80:localhost:8080 serveo.net`
+            Provider::ServeoSubdomain { subdomain } => subdomain,
+            Provider::Sish {
+                name: Some(name), ..
+            } => name,
+            // What `ssh -R` sends without a bind address
+            _ => "localhost",
+        }
+        .to_string()
+    }
+
+    fn remote_port(&self) -> u32 {
+        match self {
+            // pinggy assigns the port
+            Provider::Pinggy => 0,
+            _ => 80,
+        }
+    }
+
+    fn session_channel(&self) -> SessionChannel {
+        match self {
+            Provider::LocalhostRun | Provider::LocalhostRunAccount { .. } => {
+                SessionChannel::localhost_run_json()
+            }
+            _ => SessionChannel::Shell,
+        }
+    }
+
+    fn keepalive_interval(&self) -> Option<Duration> {
+        match self {
+            // What serveo's instructions pass as `ServerAliveInterval`
+            Provider::Serveo | Provider::ServeoSubdomain { .. } => Some(Duration::from_secs(60)),
+            _ => Some(Duration::from_secs(30)),
+        }
+    }
+
+    fn auth(&self) -> Vec<AuthMethod> {
+        match self {
+            // The free tier asks for a password, and takes an empty one
+            Provider::Pinggy => vec![
+                AuthMethod::None,
+                AuthMethod::KeyboardInteractive(String::new()),
+            ],
+            Provider::LocalhostRunAccount { key_file, .. } => {
+                vec![AuthMethod::KeyFile(key_file.clone())]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn url_domains(&self) -> Vec<String> {
+        let domains: &[&str] = match self {
+            Provider::LocalhostRun => &["lhr.life", "localhost.run"],
+            Provider::LocalhostRunAccount { domain, .. } => {
+                let mut domains = vec!["lhr.life".to_string(), "localhost.run".to_string()];
+                domains.extend(domain.clone());
+                return domains;
+            }
+            Provider::Serveo | Provider::ServeoSubdomain { .. } => {
+                &["serveo.net", "serveousercontent.com"]
+            }
+            Provider::Pinggy => &["pinggy.link", "pinggy.online"],
+            Provider::Sish { server, .. } => return vec![server.clone()],
+        };
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    fn parse_line(&self, line: &str) -> Option<TunnelInfo> {
+        match self {
+            Provider::Sish { .. } => parse_sish_line(line).or_else(|| parse_line(line)),
+            _ => parse_line(line),
+        }
+    }
+
+    fn console_url(&self, line: &str) -> Option<String> {
+        match self {
+            // "Service console can be accessed here: https://..." for the tunnel, and
+            // the same for the admin console
+            Provider::Sish { .. } if line.to_lowercase().contains("console") => line
+                .split_whitespace()
+                .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+                .map(|url| url.trim_end_matches(['.', ',']).to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Phrases identifying each refusal, matched case-insensitively
+const REFUSALS: &[(&str, ProviderError)] = &[
+    ("missing public key", ProviderError::MissingPublicKey),
+    ("no public key", ProviderError::MissingPublicKey),
+    ("quota exceeded", ProviderError::QuotaExceeded),
+    ("too many tunnels", ProviderError::QuotaExceeded),
+    ("require a plan", ProviderError::PlanRequired),
+    ("requires a plan", ProviderError::PlanRequired),
+    ("requires a paid plan", ProviderError::PlanRequired),
+];
+
+/// Recognise a refusal in a line of server output, as JSON (`"status":"error"`) or
+/// banner text
+pub(crate) fn classify(line: &str) -> Option<ProviderError> {
+    let known = |text: &str| {
+        let text = text.to_lowercase();
+        REFUSALS
+            .iter()
+            .find(|(phrase, _)| text.contains(phrase))
+            .map(|(_, error)| error.clone())
+    };
+    if let Ok(value) = serde_json::from_str::<Value>(line.trim()) {
+        if value.get("status").and_then(Value::as_str) == Some("error") {
+            let text = value
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error");
+            return Some(known(text).unwrap_or_else(|| ProviderError::Rejected(text.to_string())));
+        }
+    }
+    known(line)
+}
+
+/// Longest partial line kept while waiting for its end
+const MAX_LINE: usize = 8 * 1024;
+
+/// Reassembles lines from the chunks the session channel delivers, which may split a
+/// line (or a UTF-8 sequence) anywhere
+#[derive(Debug, Default)]
+pub(crate) struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Add a chunk, returning the lines it completed
+    pub(crate) fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(data);
+        let Some(end) = self.partial.iter().rposition(|&b| b == b'\n') else {
+            if self.partial.len() > MAX_LINE {
+                self.partial.clear();
+            }
+            return Vec::new();
+        };
+        let rest = self.partial.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.partial, rest);
+        decode(&complete).lines().map(strip_ansi).collect()
+    }
+}
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Text of server output, leniently: without a byte order mark, as Latin-1 when no
+/// part of it reads as UTF-8 beyond ASCII, and with invalid sequences replaced
+/// otherwise
+pub(crate) fn decode(bytes: &[u8]) -> Cow<'_, str> {
+    let bytes = bytes.strip_prefix(BOM).unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) if !has_multibyte(bytes) => Cow::Owned(bytes.iter().map(|&b| b as char).collect()),
+        Err(_) => String::from_utf8_lossy(bytes),
+    }
+}
+
+fn has_multibyte(bytes: &[u8]) -> bool {
+    bytes.utf8_chunks().any(|chunk| !chunk.valid().is_ascii())
+}
+
+/// Decodes the chunks of a stream of server output as they arrive, keeping a UTF-8
+/// sequence split between two chunks for the next one
+#[derive(Debug, Default)]
+pub(crate) struct TextDecoder {
+    pending: Vec<u8>,
+    /// Past the byte order mark, if the stream has one
+    started: bool,
+}
+
+impl TextDecoder {
+    /// Add a chunk, returning the text it completed
+    pub(crate) fn push(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        if !self.started {
+            if self.pending.len() < BOM.len() && BOM.starts_with(&self.pending) {
+                return String::new();
+            }
+            self.started = true;
+        }
+        let end = match std::str::from_utf8(&self.pending) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => self.pending.len(),
+        };
+        let rest = self.pending.split_off(end);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        decode(&complete).into_owned()
+    }
+}
+
+/// Remove terminal escape sequences (colors, cursor movement) and control characters
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            if chars.next() == Some('[') {
+                // CSI: parameters up to a final byte in '@'..='~'
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else if !c.is_control() || c == '\t' {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Extract tunnel details from a line of server output: localhost.run's JSON, or a
+/// banner line announcing the tunnel
+pub(crate) fn parse_line(line: &str) -> Option<TunnelInfo> {
+    parse_json_line(line).or_else(|| {
+        banner_url(line).map(|url| TunnelInfo {
+            url: url.to_string(),
+            ..Default::default()
+        })
+    })
+}
+
+/// Parse one of the lines sish lists a forward's addresses with: `HTTP: http://...`,
+/// `HTTPS: https://...`, `TCP: host:port` and `TCP Alias: name:port`
+fn parse_sish_line(line: &str) -> Option<TunnelInfo> {
+    let (label, address) = line.trim().split_once(": ")?;
+    let address = address.trim();
+    if address.is_empty() || address.contains(char::is_whitespace) {
+        return None;
+    }
+    let (url, alias) = match label {
+        "HTTP" | "HTTPS" if address.starts_with("http") => (address.to_string(), false),
+        "TCP" => (format!("tcp://{}", address), false),
+        "TCP Alias" => (format!("tcp://{}", address), true),
+        _ => return None,
+    };
+    Some(TunnelInfo {
+        url,
+        alias,
+        ..Default::default()
+    })
+}
+
+/// The part of a tunnel URL that identifies the tunnel: the same host over `http://`
+/// and `https://` (as pinggy prints both) is the same tunnel
+pub(crate) fn host(url: &str) -> &str {
+    url.split_once("://")
+        .map_or(url, |(_, rest)| rest)
+        .trim_end_matches('/')
+}
+
+/// Parse one line of localhost.run's JSON output
+fn parse_json_line(line: &str) -> Option<TunnelInfo> {
+    let line = line.trim();
+    if !line.starts_with('{') {
+        return None;
+    }
+    let value: Value = serde_json::from_str(line).ok()?;
+    let text = |key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+    let address = text("address");
+    let url = text("url").or_else(|| {
+        address.clone().map(|address| {
+            if address.contains("://") {
+                address
+            } else {
+                format!("https://{}", address)
+            }
+        })
+    })?;
+    let domain =
+        text("domain").unwrap_or_else(|| url_host(address.as_deref().unwrap_or(&url)).to_string());
+    Some(TunnelInfo {
+        url,
+        domain: Some(domain),
+        alias: false,
+        expires: text("expires").or_else(|| text("expires_at")),
+        plan: text("plan"),
+        connection_id: text("connection_id"),
+        structured: true,
+    })
+}
+
+/// The tunnel URL in a banner line, preferring `https://`. Only lines announcing the
+/// tunnel (localhost.run's "tunneled with", serveo's "Forwarding") or holding nothing
+/// but a bare URL (pinggy) count, so links to docs and dashboards are skipped.
+fn banner_url(line: &str) -> Option<&str> {
+    let lower = line.to_lowercase();
+    let announces = lower.contains("tunneled with") || lower.contains("forwarding");
+    // Skip box-drawing and other decoration around the text
+    let words: Vec<&str> = line
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .collect();
+    if !announces && words.len() != 1 {
+        return None;
+    }
+    let find = |scheme: &str| {
+        words
+            .iter()
+            .find(|word| word.starts_with(scheme) && word.len() > scheme.len())
+            .map(|word| word.trim_end_matches(['.', ',', ')', ']', '"']))
+    };
+    let url = find("https://").or_else(|| find("http://"))?;
+    let bare = !url
+        .split_once("://")
+        .is_some_and(|(_, rest)| rest.trim_end_matches('/').contains('/'));
+    (announces || bare).then_some(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ReverseSshConfig;
+
+    #[test]
+    fn test_provider_conventions() {
+        let config = ReverseSshConfig::for_provider(Provider::LocalhostRun);
+        assert_eq!(
+            (config.server_addr.as_str(), config.server_port),
+            ("localhost.run", 22)
+        );
+        assert_eq!(config.username, "nokey");
+        assert_eq!(config.bind_address, "");
+        assert_eq!(config.session_channel, SessionChannel::localhost_run_json());
+        // Only localhost.run takes the command; other servers get a shell
+        assert_eq!(
+            ReverseSshConfig::default().session_channel,
+            SessionChannel::Shell
+        );
+        assert_eq!(
+            ReverseSshConfig::for_provider(Provider::Serveo).session_channel,
+            SessionChannel::Shell
+        );
+
+        let config = ReverseSshConfig::for_provider(Provider::LocalhostRunAccount {
+            key_file: "~/.ssh/id_ed25519".to_string(),
+            domain: Some("tunnel.example.com".to_string()),
+        });
+        assert_ne!(config.username, "nokey");
+        assert_eq!(config.bind_address, "tunnel.example.com");
+        assert_eq!(config.auth_methods()[0].name(), "key-file");
+        assert!(config
+            .url_domains
+            .contains(&"tunnel.example.com".to_string()));
+        let config =
+            ReverseSshConfig::for_provider(Provider::localhost_run_account("~/.ssh/id_ed25519"));
+        assert_eq!(config.bind_address, "");
+
+        let config = ReverseSshConfig::for_provider(Provider::serveo_subdomain("myalias"));
+        assert_eq!(config.server_addr, "serveo.net");
+        assert_eq!(config.bind_address, "myalias");
+        assert_eq!(config.keepalive_interval, Some(Duration::from_secs(60)));
+        assert_eq!(
+            ReverseSshConfig::for_provider(Provider::Serveo).bind_address,
+            "localhost"
+        );
+
+        let config = ReverseSshConfig::for_provider(Provider::Pinggy);
+        assert_eq!(config.server_port, 443);
+        assert_eq!(config.remote_port, 0);
+        assert_eq!(config.bind_address, "localhost");
+        assert_eq!(config.auth_methods()[0].name(), "none");
+
+        let config = ReverseSshConfig::for_provider(Provider::sish("tuns.sh"));
+        assert_eq!(config.server_port, 2222);
+        assert_eq!(config.url_domains, ["tuns.sh"]);
+        assert_eq!(config.provider.unwrap().name(), "sish");
+        assert_eq!(config.bind_address, "localhost");
+
+        // sish lists the addresses of each forward, and a console not to be taken
+        // for one of them
+        let sish = Provider::sish_named("tuns.sh", "db");
+        assert_eq!(sish.bind_address(), "db");
+        let output = include_str!("../tests/fixtures/banners/sish.txt");
+        let (mut consoles, mut addresses) = (Vec::new(), Vec::new());
+        for line in LineBuffer::default().push(output.as_bytes()) {
+            if let Some(url) = sish.console_url(&line) {
+                consoles.push(url);
+            } else if let Some(info) = sish.parse_line(&line) {
+                addresses.push((info.url, info.alias));
+            }
+        }
+        assert_eq!(
+            consoles,
+            ["https://myapp.tuns.sh/_sish/console?x-authorization=8f2b1c0d"]
+        );
+        assert_eq!(
+            addresses,
+            [
+                ("http://myapp.tuns.sh".to_string(), false),
+                ("https://myapp.tuns.sh".to_string(), false),
+                ("tcp://db:5432".to_string(), true),
+            ]
+        );
+
+        /// A relay that prints its URL after `url=`
+        #[derive(Debug)]
+        struct Relay;
+        impl TunnelProvider for Relay {
+            fn name(&self) -> &str {
+                "relay"
+            }
+            fn server(&self) -> (String, u16) {
+                ("relay.example.com".to_string(), 2200)
+            }
+            fn username(&self) -> String {
+                "tunnel".to_string()
+            }
+            fn parse_line(&self, line: &str) -> Option<TunnelInfo> {
+                line.strip_prefix("url=").map(|url| TunnelInfo {
+                    url: url.to_string(),
+                    ..Default::default()
+                })
+            }
+        }
+        let config = ReverseSshConfig::for_provider(Relay);
+        assert_eq!(config.remote_port, 80);
+        assert!(config.auth.is_empty());
+        let relay = config.provider.unwrap();
+        assert_eq!(
+            relay.parse_line("url=https://x.example.com").unwrap().url,
+            "https://x.example.com"
+        );
+        assert_eq!(
+            relay.classify("Quota exceeded"),
+            Some(ProviderError::QuotaExceeded)
+        );
+    }
+
+    #[test]
+    fn test_json_output_and_banner_fallback() {
+        let json = r#"{"connection_id":"8d3c","address":"8d3c1a.lhr.life","status":"success","plan":"free","expires":"2026-10-16T12:00:00Z"}"#;
+        let info = parse_line(json).unwrap();
+        assert_eq!(info.url, "https://8d3c1a.lhr.life");
+        assert_eq!(info.plan.as_deref(), Some("free"));
+        assert_eq!(info.expires.as_deref(), Some("2026-10-16T12:00:00Z"));
+        assert_eq!(info.domain.as_deref(), Some("8d3c1a.lhr.life"));
+        assert!(info.structured);
+        let custom = r#"{"address":"tunnel.example.com","status":"success","plan":"starter"}"#;
+        assert_eq!(
+            parse_line(custom).unwrap().domain.as_deref(),
+            Some("tunnel.example.com")
+        );
+
+        let banner = "8d3c1a.lhr.life tunneled with tls termination, https://8d3c1a.lhr.life.";
+        let info = parse_line(banner).unwrap();
+        assert_eq!(info.url, "https://8d3c1a.lhr.life");
+        assert!(!info.structured);
+        assert_eq!(info.domain, None);
+        assert_eq!(parse_line("{\"status\":\"success\"}"), None);
+        assert_eq!(parse_line("no link here"), None);
+        assert_eq!(
+            host("http://rnaab.a.pinggy.link/"),
+            host("https://rnaab.a.pinggy.link")
+        );
+    }
+
+    /// Real provider output, fed whole and in chunks that split lines, escapes and
+    /// UTF-8 sequences. Returns the last URL announced and the first refusal, and
+    /// decodes the same text as the whole output.
+    #[test]
+    fn test_banner_fixtures() {
+        use ProviderError::*;
+        type Case = (
+            &'static str,
+            &'static [u8],
+            Option<&'static str>,
+            Option<ProviderError>,
+        );
+        let cases: &[Case] = &[
+            (
+                "localhost.run",
+                include_bytes!("../tests/fixtures/banners/localhost_run.txt"),
+                Some("https://8d3c1a2b4e5f.lhr.life"),
+                None,
+            ),
+            (
+                "localhost.run JSON",
+                include_bytes!("../tests/fixtures/banners/localhost_run_json.txt"),
+                Some("https://8d3c1a2b4e5f.lhr.life"),
+                None,
+            ),
+            (
+                "localhost.run ANSI",
+                include_bytes!("../tests/fixtures/banners/localhost_run_ansi.txt"),
+                Some("https://8d3c1a2b4e5f.lhr.life"),
+                None,
+            ),
+            (
+                "localhost.run without key",
+                include_bytes!("../tests/fixtures/banners/localhost_run_no_key.txt"),
+                None,
+                Some(MissingPublicKey),
+            ),
+            (
+                "localhost.run custom domain",
+                include_bytes!("../tests/fixtures/banners/localhost_run_custom_domain.txt"),
+                None,
+                Some(PlanRequired),
+            ),
+            (
+                "localhost.run quota",
+                include_bytes!("../tests/fixtures/banners/localhost_run_quota.txt"),
+                None,
+                Some(QuotaExceeded),
+            ),
+            (
+                "localhost.run rejected",
+                include_bytes!("../tests/fixtures/banners/localhost_run_rejected.txt"),
+                None,
+                Some(Rejected("down for maintenance".into())),
+            ),
+            (
+                "serveo",
+                include_bytes!("../tests/fixtures/banners/serveo.txt"),
+                Some("https://a1b2c3d4e5f6.serveo.net"),
+                None,
+            ),
+            (
+                "pinggy",
+                include_bytes!("../tests/fixtures/banners/pinggy.txt"),
+                Some("https://rnaab-203-0-113-7.a.free.pinggy.link"),
+                None,
+            ),
+            (
+                "Latin-1 with a byte order mark",
+                include_bytes!("../tests/fixtures/banners/latin1_bom.txt"),
+                Some("https://8d3c1a2b4e5f.lhr.life"),
+                None,
+            ),
+        ];
+
+        for (name, output, url, error) in cases {
+            for chunk_size in [output.len(), 1, 5, 64] {
+                let mut lines = LineBuffer::default();
+                let mut text = TextDecoder::default();
+                let mut messages = String::new();
+                let (mut seen_url, mut seen_error) = (None, None);
+                for chunk in output.chunks(chunk_size) {
+                    messages.push_str(&text.push(chunk));
+                    for line in lines.push(chunk) {
+                        if let Some(e) = classify(&line) {
+                            seen_error.get_or_insert(e);
+                        } else if let Some(info) = parse_line(&line) {
+                            seen_url = Some(info.url);
+                        }
+                    }
+                }
+                let context = format!("{} in chunks of {}", name, chunk_size);
+                assert_eq!(seen_url.as_deref(), *url, "{}", context);
+                assert_eq!(seen_error.as_ref(), error.as_ref(), "{}", context);
+                assert_eq!(messages, decode(output), "{}", context);
+            }
+        }
+        let latin1 = include_bytes!("../tests/fixtures/banners/latin1_bom.txt");
+        assert!(decode(latin1).starts_with("Bienvenue sur le relais de l'équipe Réseau"));
+    }
+}
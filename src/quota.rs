@@ -0,0 +1,153 @@
+//! Traffic quota for a whole client
+//!
+//! Shaping caps the throughput of each connection; a [`TrafficQuota`] caps the total
+//! relayed over a period, e.g. 5 GB a day, to bound the egress bill of a tunnel left
+//! running. Usage survives reconnects and is checked every second, so a transfer can
+//! overshoot the limit by what it relays in that time.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What happens once the quota is used up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuotaAction {
+    /// Close open connections and refuse new ones until the period ends. The session
+    /// and the public URL stay up.
+    #[default]
+    Pause,
+    /// Disconnect and make `run()` return, as with
+    /// [`ClientHandle::shutdown`](crate::ClientHandle::shutdown)
+    Shutdown,
+}
+
+/// Cap on the bytes relayed in either direction per period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficQuota {
+    /// Bytes that may be relayed per period
+    pub limit: u64,
+    /// Length of a period, counted from when the client first connects
+    pub period: Duration,
+    pub action: QuotaAction,
+}
+
+impl TrafficQuota {
+    /// `limit` bytes a day, pausing the tunnel once they are used
+    pub fn per_day(limit: u64) -> Self {
+        Self {
+            limit,
+            period: Duration::from_secs(24 * 3600),
+            action: QuotaAction::Pause,
+        }
+    }
+}
+
+/// Usage of the quota in the current period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Bytes relayed in the current period
+    pub used: u64,
+    pub limit: u64,
+    /// Time until the period ends and usage starts over
+    pub resets_in: Duration,
+    /// The quota is used up and connections are refused
+    pub exhausted: bool,
+}
+
+/// Change in the quota state found by [`QuotaTracker::check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QuotaChange {
+    Exhausted { used: u64 },
+    Reset,
+}
+
+/// Usage of a [`TrafficQuota`] against the client's traffic counter
+#[derive(Debug)]
+pub(crate) struct QuotaTracker {
+    pub(crate) quota: TrafficQuota,
+    /// Start of the current period, once the client connected
+    period_start: Option<Instant>,
+    /// Traffic counter at the start of the period
+    baseline: u64,
+    exhausted: bool,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(quota: TrafficQuota) -> Self {
+        Self {
+            quota,
+            period_start: None,
+            baseline: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Compare the traffic counter `total` with the quota
+    pub(crate) fn check(&mut self, total: u64, now: Instant) -> Option<QuotaChange> {
+        let start = *self.period_start.get_or_insert(now);
+        if now.duration_since(start) >= self.quota.period {
+            self.period_start = Some(now);
+            self.baseline = total;
+            if std::mem::take(&mut self.exhausted) {
+                return Some(QuotaChange::Reset);
+            }
+            return None;
+        }
+        let used = total - self.baseline;
+        if !self.exhausted && used >= self.quota.limit {
+            self.exhausted = true;
+            return Some(QuotaChange::Exhausted { used });
+        }
+        None
+    }
+
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    pub(crate) fn usage(&self, total: u64, now: Instant) -> QuotaUsage {
+        let elapsed = self
+            .period_start
+            .map_or(Duration::ZERO, |start| now.duration_since(start));
+        QuotaUsage {
+            used: total - self.baseline,
+            limit: self.quota.limit,
+            resets_in: self.quota.period.saturating_sub(elapsed),
+            exhausted: self.exhausted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_periods() {
+        let quota = TrafficQuota {
+            period: Duration::from_secs(60),
+            ..TrafficQuota::per_day(1000)
+        };
+        let mut tracker = QuotaTracker::new(quota);
+        let start = Instant::now();
+        assert_eq!(tracker.check(0, start), None);
+        assert_eq!(tracker.check(999, start + Duration::from_secs(10)), None);
+        assert_eq!(
+            tracker.check(1200, start + Duration::from_secs(20)),
+            Some(QuotaChange::Exhausted { used: 1200 })
+        );
+        assert_eq!(tracker.check(1500, start + Duration::from_secs(30)), None);
+        assert!(tracker.is_exhausted());
+        let usage = tracker.usage(1500, start + Duration::from_secs(30));
+        assert_eq!(usage.used, 1500);
+        assert_eq!(usage.resets_in, Duration::from_secs(30));
+
+        let next = start + Duration::from_secs(60);
+        assert_eq!(tracker.check(1500, next), Some(QuotaChange::Reset));
+        assert!(!tracker.is_exhausted());
+        assert_eq!(tracker.check(2400, next + Duration::from_secs(1)), None);
+        assert_eq!(
+            tracker.check(2500, next + Duration::from_secs(2)),
+            Some(QuotaChange::Exhausted { used: 1000 })
+        );
+    }
+}
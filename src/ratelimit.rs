@@ -0,0 +1,284 @@
+//! Bandwidth limits for relayed traffic
+//!
+//! A tunnel exposed to the internet relays as fast as remote peers pull, which can
+//! saturate the uplink of the machine it runs on. [`RateLimits`] caps the throughput
+//! of each connection and of all connections together, upstream (from the local
+//! service out through the tunnel) and downstream separately. Every limit is a token
+//! bucket: bursts of up to `burst` bytes go through at full speed, then writes are
+//! paced to `bytes_per_sec`. Unlike shaping, which simulates a slow link, limits are
+//! meant to stay on in production; they can be changed at runtime with
+//! [`ClientHandle::set_rate_limits`](crate::ClientHandle::set_rate_limits).
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+use crate::clock::{Clock, Sleep};
+use crate::overhead::Flow;
+
+/// Throughput allowed by a token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained throughput
+    pub bytes_per_sec: u64,
+    /// Bytes that go through at full speed after a quiet period
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// `bytes_per_sec`, with bursts of up to a second's worth
+    pub const fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            burst: bytes_per_sec,
+        }
+    }
+
+    pub const fn with_burst(self, burst: u64) -> Self {
+        Self { burst, ..self }
+    }
+}
+
+/// Limits on the traffic of a client's connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    /// Each connection, from the local service to the remote peer
+    pub upstream: Option<RateLimit>,
+    /// Each connection, from the remote peer to the local service
+    pub downstream: Option<RateLimit>,
+    /// All connections together, from the local services to remote peers
+    pub global_upstream: Option<RateLimit>,
+    /// All connections together, from remote peers to the local services
+    pub global_downstream: Option<RateLimit>,
+}
+
+struct BucketState {
+    limit: Option<RateLimit>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token bucket shared by the writers it limits; without a limit it lets
+/// everything through
+pub(crate) struct TokenBucket {
+    clock: Arc<dyn Clock>,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full
+    pub(crate) fn new(limit: Option<RateLimit>, clock: Arc<dyn Clock>) -> Self {
+        let refilled = clock.now();
+        Self {
+            clock,
+            state: Mutex::new(BucketState {
+                limit,
+                tokens: limit.map_or(0.0, |limit| limit.burst as f64),
+                refilled,
+            }),
+        }
+    }
+
+    fn set(&self, limit: Option<RateLimit>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(limit) = limit {
+            // A bucket that had no limit starts full
+            let tokens = if state.limit.is_none() {
+                f64::MAX
+            } else {
+                state.tokens
+            };
+            state.tokens = tokens.min(limit.burst as f64);
+        }
+        state.limit = limit;
+    }
+
+    /// How much of `len` bytes can be written now, or how long until a useful
+    /// amount can
+    fn available(&self, len: usize) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let Some(limit) = state.limit.filter(|limit| limit.bytes_per_sec > 0) else {
+            return Ok(len);
+        };
+        let rate = limit.bytes_per_sec as f64;
+        let burst = limit.burst.max(1) as f64;
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(burst);
+        state.refilled = now;
+
+        // Wait for slices of about 50 ms worth of data rather than trickle bytes
+        let slice = (limit.bytes_per_sec / 20).clamp(512, 64 * 1024) as f64;
+        let wanted = (len as f64).min(slice).min(burst);
+        if state.tokens >= wanted {
+            return Ok(len.min(state.tokens as usize));
+        }
+        let wait = Duration::from_secs_f64((wanted - state.tokens) / rate);
+        Err(wait.max(Duration::from_millis(1)))
+    }
+
+    fn take(&self, written: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.limit.is_some() {
+            state.tokens -= written as f64;
+        }
+    }
+}
+
+/// The client's limits, with the buckets shared by all of its connections
+pub(crate) struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    limits: Mutex<RateLimits>,
+    global_upstream: Arc<TokenBucket>,
+    global_downstream: Arc<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            global_upstream: Arc::new(TokenBucket::new(limits.global_upstream, clock.clone())),
+            global_downstream: Arc::new(TokenBucket::new(limits.global_downstream, clock.clone())),
+            limits: Mutex::new(limits),
+            clock,
+        }
+    }
+
+    pub(crate) fn get(&self) -> RateLimits {
+        *self.limits.lock().unwrap()
+    }
+
+    /// Replace the limits: global ones apply right away, per-connection ones to
+    /// connections accepted from then on
+    pub(crate) fn set(&self, limits: RateLimits) {
+        self.global_upstream.set(limits.global_upstream);
+        self.global_downstream.set(limits.global_downstream);
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    /// Limit the writes of a new connection in the direction of `flow`
+    pub(crate) fn limit<W>(&self, inner: W, flow: Flow) -> Limited<W> {
+        let limits = self.get();
+        let (own, global) = match flow {
+            Flow::Sent => (limits.upstream, &self.global_upstream),
+            Flow::Received => (limits.downstream, &self.global_downstream),
+        };
+        let mut buckets = vec![global.clone()];
+        if own.is_some() {
+            buckets.push(Arc::new(TokenBucket::new(own, self.clock.clone())));
+        }
+        Limited {
+            inner,
+            buckets,
+            clock: self.clock.clone(),
+            sleep: None,
+        }
+    }
+}
+
+/// Writer that waits for its buckets to allow what it writes
+pub(crate) struct Limited<W> {
+    inner: W,
+    buckets: Vec<Arc<TokenBucket>>,
+    clock: Arc<dyn Clock>,
+    sleep: Option<Sleep>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Limited<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Buckets are checked on every poll, so lifted limits apply to a writer
+        // already waiting
+        let len = loop {
+            let mut len = buf.len();
+            let mut wait = Duration::ZERO;
+            for bucket in &self.buckets {
+                match bucket.available(len) {
+                    Ok(n) => len = len.min(n),
+                    Err(until) => wait = wait.max(until),
+                }
+            }
+            if wait.is_zero() {
+                self.sleep = None;
+                break len;
+            }
+            let clock = self.clock.clone();
+            let sleep = self.sleep.get_or_insert_with(|| clock.sleep(wait));
+            ready!(sleep.as_mut().poll(cx));
+            self.sleep = None;
+        };
+        // Tokens are taken for what was written, so concurrent writers sharing a
+        // bucket can overdraw it a little and then wait longer
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+        for bucket in &self.buckets {
+            bucket.take(written);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_rate_limits_per_connection_and_global() {
+        let clock = Arc::new(ManualClock::new());
+        let limiter = RateLimiter::new(
+            RateLimits {
+                upstream: Some(RateLimit::new(1_000)),
+                global_upstream: Some(RateLimit::new(1_000).with_burst(1_500)),
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+        let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let mut first = limiter.limit(writer, Flow::Sent);
+        let writing = tokio::spawn(async move { first.write_all(&[1; 1_600]).await });
+
+        // The burst goes through at once, then the connection waits for its bucket
+        let mut burst = [0u8; 1_000];
+        reader.read_exact(&mut burst).await.unwrap();
+        let mut more = [0u8; 1];
+        let waiting = tokio::time::timeout(Duration::from_millis(50), reader.read(&mut more));
+        assert!(waiting.await.is_err());
+        clock.advance(Duration::from_millis(600));
+        let mut rest = [0u8; 600];
+        reader.read_exact(&mut rest).await.unwrap();
+        writing.await.unwrap().unwrap();
+
+        // What is left of the global bucket holds another connection back too
+        let mut second = limiter.limit(Vec::new(), Flow::Sent);
+        let write = second.write_all(&[2; 1_000]);
+        assert!(tokio::time::timeout(Duration::from_millis(50), write)
+            .await
+            .is_err());
+        assert!(second.inner.is_empty());
+
+        // Downstream is unlimited, and limits can be lifted at runtime
+        let mut third = limiter.limit(Vec::new(), Flow::Received);
+        third.write_all(&[3; 10_000]).await.unwrap();
+        limiter.set(RateLimits::default());
+        second.write_all(&[2; 1_000]).await.unwrap();
+        let mut fourth = limiter.limit(Vec::new(), Flow::Sent);
+        fourth.write_all(&[4; 10_000]).await.unwrap();
+        assert_eq!(second.inner.len() + fourth.inner.len(), 11_000);
+    }
+}
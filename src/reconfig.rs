@@ -0,0 +1,231 @@
+//! Applying a new configuration to a running tunnel
+//!
+//! Most settings can change without touching the SSH session: credentials and the
+//! host key pin only matter on the next connect, the local target and shaping are
+//! read per connection, and extra forwards can be requested on the live session. A
+//! [`ConfigDiff`] sorts the changes between two configurations into those, the ones
+//! that need a new session, and the ones a client keeps for its whole life, so
+//! [`TunnelManager::reconfigure`](crate::TunnelManager::reconfigure) only reconnects
+//! when it has to.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use tracing::warn;
+
+use crate::{targets, ClientHandle, ReverseSshConfig};
+
+/// The settings that differ between two configurations, by how they can be applied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    /// Applied to the running client, or read on the next connect
+    pub in_place: Vec<&'static str>,
+    /// Only take effect on a new session
+    pub reconnect: Vec<&'static str>,
+    /// Fixed when the client is created: `http`, `quota`, `reject_action`,
+    /// `idle_keepalive`, `buffer_size`, `clock`, `network`, `connection_budget`,
+    /// `trace_sampling`, `max_connections`, `connection_queue`, `snapshot_path` and `error_log_window`
+    pub fixed: Vec<&'static str>,
+}
+
+impl ConfigDiff {
+    pub fn between(old: &ReverseSshConfig, new: &ReverseSshConfig) -> Self {
+        // Forwards can be added to a live session, not taken back
+        let kept = old.forwards.iter().all(|f| new.forwards.contains(f));
+        let refresh_changed = match (&old.certificate_refresh, &new.certificate_refresh) {
+            (Some(old), Some(new)) => !old.same_as(new),
+            (old, new) => old.is_some() != new.is_some(),
+        };
+        Self {
+            in_place: changed(&[
+                ("auth", !same_credentials(old, new)),
+                ("certificate_refresh", refresh_changed),
+                (
+                    "host_key_fingerprint",
+                    old.host_key_fingerprint != new.host_key_fingerprint,
+                ),
+                ("preflight", old.preflight != new.preflight),
+                (
+                    "precheck_forwards",
+                    old.precheck_forwards != new.precheck_forwards,
+                ),
+                (
+                    "connect_timeout",
+                    old.connect_timeout != new.connect_timeout,
+                ),
+                (
+                    "handshake_timeout",
+                    old.handshake_timeout != new.handshake_timeout,
+                ),
+                ("auth_timeout", old.auth_timeout != new.auth_timeout),
+                ("shaping", old.shaping != new.shaping),
+                ("rate_limits", old.rate_limits != new.rate_limits),
+                ("access_list", old.access_list != new.access_list),
+                ("local_addr", old.local_addr != new.local_addr),
+                ("local_port", old.local_port != new.local_port),
+                ("local_socket", old.local_socket != new.local_socket),
+                ("udp", old.udp != new.udp),
+                ("url_domains", old.url_domains != new.url_domains),
+                ("wire_gate", old.wire_gate != new.wire_gate),
+                ("raw", old.raw != new.raw),
+                ("forwards", kept && old.forwards != new.forwards),
+            ]),
+            reconnect: changed(&[
+                ("forwards", !kept),
+                ("server_addr", old.server_addr != new.server_addr),
+                ("server_port", old.server_port != new.server_port),
+                ("username", old.username != new.username),
+                ("proxy", old.proxy != new.proxy),
+                ("jump_hosts", differs(&old.jump_hosts, &new.jump_hosts)),
+                ("remote_port", old.remote_port != new.remote_port),
+                ("bind_address", old.bind_address != new.bind_address),
+                (
+                    "dynamic_forward",
+                    old.dynamic_forward != new.dynamic_forward,
+                ),
+                ("alerts", differs(&old.alerts, &new.alerts)),
+                ("health_check", old.health_check != new.health_check),
+                (
+                    "endpoint_probe",
+                    differs(&old.endpoint_probe, &new.endpoint_probe),
+                ),
+                (
+                    "session_channel",
+                    old.session_channel != new.session_channel,
+                ),
+                ("provider", differs(&old.provider, &new.provider)),
+                (
+                    "keepalive_interval",
+                    old.keepalive_interval != new.keepalive_interval,
+                ),
+                (
+                    "keepalive_count_max",
+                    old.keepalive_count_max != new.keepalive_count_max,
+                ),
+                ("window_size", old.window_size != new.window_size),
+                (
+                    "max_packet_size",
+                    old.max_packet_size != new.max_packet_size,
+                ),
+                ("algorithms", old.algorithms != new.algorithms),
+                ("compression", old.compression != new.compression),
+                ("message_queue", old.message_queue != new.message_queue),
+                ("address_family", old.address_family != new.address_family),
+                (
+                    "connection_attempt_delay",
+                    old.connection_attempt_delay != new.connection_attempt_delay,
+                ),
+            ]),
+            fixed: changed(&[
+                ("http", differs(&old.http, &new.http)),
+                ("quota", old.quota != new.quota),
+                ("reject_action", old.reject_action != new.reject_action),
+                ("idle_keepalive", old.idle_keepalive != new.idle_keepalive),
+                ("buffer_size", old.buffer_size != new.buffer_size),
+                ("clock", !Arc::ptr_eq(&old.clock, &new.clock)),
+                ("network", !Arc::ptr_eq(&old.network, &new.network)),
+                (
+                    "connection_budget",
+                    old.connection_budget != new.connection_budget,
+                ),
+                ("trace_sampling", old.trace_sampling != new.trace_sampling),
+                (
+                    "max_connections",
+                    old.max_connections != new.max_connections,
+                ),
+                (
+                    "connection_queue",
+                    old.connection_queue != new.connection_queue,
+                ),
+                ("snapshot_path", old.snapshot_path != new.snapshot_path),
+                (
+                    "error_log_window",
+                    old.error_log_window != new.error_log_window,
+                ),
+            ]),
+        }
+    }
+
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.in_place.is_empty() && self.reconnect.is_empty() && self.fixed.is_empty()
+    }
+
+    /// Whether the session has to be rebuilt for the changes to take effect
+    pub fn requires_reconnect(&self) -> bool {
+        !self.reconnect.is_empty()
+    }
+}
+
+/// Names of the settings marked as changed
+fn changed(settings: &[(&'static str, bool)]) -> Vec<&'static str> {
+    settings
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Compare settings that hold callbacks by what they print
+fn differs<T: Debug>(old: &T, new: &T) -> bool {
+    format!("{:?}", old) != format!("{:?}", new)
+}
+
+/// Whether the credentials look the same. Secrets inside `auth` aren't printed, so
+/// changes to them go unnoticed here; credentials are replaced either way.
+fn same_credentials(old: &ReverseSshConfig, new: &ReverseSshConfig) -> bool {
+    old.key_path == new.key_path
+        && old.password == new.password
+        && !differs(&old.key, &new.key)
+        && !differs(&old.auth, &new.auth)
+}
+
+/// Apply the in-place part of the change from `old` to `new` to the client of `handle`
+pub(crate) async fn apply(handle: &ClientHandle, old: &ReverseSshConfig, new: &ReverseSshConfig) {
+    handle.shared.reconfigure(new);
+    if handle.shared.session.lock().await.is_none() {
+        // The next session sets the forwards up
+        return;
+    }
+    for forward in new.forwards.iter().filter(|f| !old.forwards.contains(f)) {
+        if let Err(e) = handle.add_forward_to(forward.clone()).await {
+            warn!(target: targets::CONFIG, "Could not add forward of port {}: {:#}", forward.remote_port, e);
+            if forward.optional {
+                handle.shared.forward_refused(forward, &e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Forward, ProxyConfig};
+
+    #[test]
+    fn test_config_diff() {
+        let old = ReverseSshConfig {
+            forwards: vec![Forward::new(5432, "127.0.0.1", 5432)],
+            ..Default::default()
+        };
+        assert!(ConfigDiff::between(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.local_port = 3000;
+        new.password = Some("rotated".to_string());
+        new.forwards.push(Forward::new(6379, "127.0.0.1", 6379));
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.in_place, ["auth", "local_port", "forwards"]);
+        assert!(!diff.requires_reconnect());
+
+        new.forwards.remove(0);
+        new.proxy = Some(ProxyConfig::new("proxy.corp", 3128));
+        new.buffer_size = 65536;
+        new.window_size = 8 * 1024 * 1024;
+        let diff = ConfigDiff::between(&old, &new);
+        assert_eq!(diff.in_place, ["auth", "local_port"]);
+        assert_eq!(diff.reconnect, ["forwards", "proxy", "window_size"]);
+        assert_eq!(diff.fixed, ["buffer_size"]);
+        assert!(diff.requires_reconnect());
+    }
+}
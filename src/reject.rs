@@ -0,0 +1,177 @@
+//! What happens to rejected connections
+//!
+//! Closing a rejected connection right away tells a scanner to move on, and tells the
+//! operator nothing. A [`RejectAction`] can instead hold it open as a tarpit
+//! (teergrube), wasting the prober's time, or record what it sends to a quarantine
+//! file to show who is probing the public endpoint and with what.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use russh::client::Msg;
+use russh::{Channel, ChannelMsg};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+
+use crate::clock::{self, Clock, Interval};
+use crate::handle::Shared;
+use crate::http::format_rfc3339;
+use crate::targets;
+
+/// How long a quarantined connection is recorded before it is closed
+const QUARANTINE_WINDOW: Duration = Duration::from_secs(30);
+
+/// How connections rejected by a
+/// [`wire_gate`](crate::ReverseSshConfig::wire_gate) are closed
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum RejectAction {
+    /// Close the channel right away
+    #[default]
+    Close,
+    /// Keep the channel open, discarding what the peer sends and trickling one byte
+    /// to it every `interval`, until it gives up or `max` has passed
+    Tarpit { interval: Duration, max: Duration },
+    /// Append what the peer sends in its first 30 seconds, up to `max_bytes`, to
+    /// `path` as a JSON line, then close the channel
+    Quarantine { path: PathBuf, max_bytes: usize },
+}
+
+impl RejectAction {
+    /// Tarpit with a byte every 10 seconds for up to 10 minutes
+    pub fn tarpit() -> Self {
+        Self::Tarpit {
+            interval: Duration::from_secs(10),
+            max: Duration::from_secs(600),
+        }
+    }
+
+    /// Quarantine up to 64 KiB of each rejected connection to `path`
+    pub fn quarantine(path: impl Into<PathBuf>) -> Self {
+        Self::Quarantine {
+            path: path.into(),
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// Deal with a rejected connection from `originator`, which already sent `received`
+pub(crate) async fn handle_rejected(
+    channel: &mut Channel<Msg>,
+    received: Vec<u8>,
+    originator: &str,
+    reason: &str,
+    shared: &Shared,
+) {
+    match &shared.reject_action {
+        RejectAction::Close => {}
+        RejectAction::Tarpit { interval, max } => {
+            info!(target: targets::PROXY, "Tarpitting connection from {}", originator);
+            shared.metrics.increment("connections_tarpitted_total", 1);
+            let tarpit = tarpit(channel, *interval, shared.clock.clone());
+            let _ = clock::timeout(&*shared.clock, *max, tarpit).await;
+        }
+        RejectAction::Quarantine { path, max_bytes } => {
+            info!(target: targets::PROXY,
+                "Quarantining connection from {} to {}",
+                originator, path.display()
+            );
+            shared.metrics.increment("connections_quarantined_total", 1);
+            let mut payload = received;
+            let _ = clock::timeout(
+                &*shared.clock,
+                QUARANTINE_WINDOW,
+                capture(channel, &mut payload, *max_bytes),
+            )
+            .await;
+            payload.truncate(*max_bytes);
+            let entry = quarantine_entry(SystemTime::now(), originator, reason, &payload);
+            if let Err(e) = append_line(path, &entry).await {
+                debug!(target: targets::PROXY, "Could not write quarantine entry: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Trickle a byte every `interval` until the peer closes the channel
+async fn tarpit(channel: &mut Channel<Msg>, interval: Duration, clock: Arc<dyn Clock>) {
+    let mut ticks = Interval::new(clock, interval);
+    ticks.reset();
+    loop {
+        tokio::select! {
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Close) | None => return,
+                Some(_) => {}
+            },
+            _ = ticks.tick() => {
+                if channel.data(&b"\0"[..]).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Add what the peer sends to `payload` until it stops or `max_bytes` are held
+async fn capture(channel: &mut Channel<Msg>, payload: &mut Vec<u8>, max_bytes: usize) {
+    while payload.len() < max_bytes {
+        match channel.wait().await {
+            Some(ChannelMsg::Data { data }) => payload.extend_from_slice(&data),
+            Some(ChannelMsg::Eof | ChannelMsg::Close) | None => return,
+            Some(_) => {}
+        }
+    }
+}
+
+/// One line of the quarantine file. The payload is escaped like a byte string
+/// literal, which keeps it readable and lossless.
+fn quarantine_entry(time: SystemTime, originator: &str, reason: &str, payload: &[u8]) -> String {
+    json!({
+        "time": format_rfc3339(time),
+        "originator": originator,
+        "reason": reason,
+        "bytes": payload.len(),
+        "payload": payload.escape_ascii().to_string(),
+    })
+    .to_string()
+}
+
+async fn append_line(path: &Path, line: &str) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(format!("{}\n", line).as_bytes()).await?;
+    // tokio writes in the background; make sure the line landed before returning
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn test_quarantine_entry() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let entry = quarantine_entry(time, "203.0.113.7:51000", "not postgres", b"GET /\r\n\x16");
+        let entry: serde_json::Value = serde_json::from_str(&entry).unwrap();
+        assert_eq!(entry["time"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(entry["originator"], "203.0.113.7:51000");
+        assert_eq!(entry["bytes"], 8);
+        assert_eq!(entry["payload"], "GET /\\r\\n\\x16");
+
+        let path =
+            std::env::temp_dir().join(format!("rrp-quarantine-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        append_line(&path, "{}").await.unwrap();
+        append_line(&path, "{}").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}\n{}\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
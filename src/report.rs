@@ -0,0 +1,146 @@
+//! Structured error reporting
+//!
+//! Errors are logged with `tracing` as they happen. Applications that ship errors to a
+//! service such as Sentry or Rollbar can also install a process-wide hook with
+//! [`on_error`], which receives each error along with the phase and connection it
+//! happened in.
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+type ErrorHook = Arc<dyn Fn(&ErrorEvent) + Send + Sync>;
+
+static HOOK: RwLock<Option<ErrorHook>> = RwLock::new(None);
+
+/// What the client was doing when an error happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorPhase {
+    /// Connecting to the SSH server
+    Connect,
+    /// Authenticating to the SSH server
+    Authenticate,
+    /// Requesting the remote port forward
+    Tunnel,
+    /// Opening or keeping the session channel (shell or exec) that carries server messages
+    Shell,
+    /// Relaying a forwarded connection
+    Forward,
+    /// Keeping an established SSH session alive
+    Session,
+}
+
+impl fmt::Display for ErrorPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorPhase::Connect => "connect",
+            ErrorPhase::Authenticate => "authenticate",
+            ErrorPhase::Tunnel => "tunnel",
+            ErrorPhase::Shell => "shell",
+            ErrorPhase::Forward => "forward",
+            ErrorPhase::Session => "session",
+        })
+    }
+}
+
+/// An error passed to the [`on_error`] hook
+#[derive(Debug, Clone)]
+pub struct ErrorEvent {
+    pub phase: ErrorPhase,
+    /// Forwarded connection the error belongs to, numbered from 1 per client
+    pub connection_id: Option<u64>,
+    /// Address of the remote peer that opened the connection
+    pub originator: Option<String>,
+    /// Outermost error message
+    pub message: String,
+    /// Underlying causes, outermost first
+    pub causes: Vec<String>,
+    pub timestamp: SystemTime,
+    /// Session the error happened in, see [`SessionId`](crate::SessionId)
+    pub session: Option<crate::SessionId>,
+}
+
+impl ErrorEvent {
+    pub(crate) fn new(phase: ErrorPhase, error: &anyhow::Error) -> Self {
+        Self {
+            phase,
+            connection_id: None,
+            originator: None,
+            message: error.to_string(),
+            causes: error.chain().skip(1).map(ToString::to_string).collect(),
+            timestamp: SystemTime::now(),
+            session: None,
+        }
+    }
+
+    pub(crate) fn with_connection(mut self, id: u64, originator: &str) -> Self {
+        self.connection_id = Some(id);
+        self.originator = Some(originator.to_string());
+        self
+    }
+}
+
+impl fmt::Display for ErrorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}", self.phase)?;
+        if let Some(id) = self.connection_id {
+            write!(f, " #{}", id)?;
+        }
+        write!(f, "] {}", self.message)?;
+        for cause in &self.causes {
+            write!(f, ": {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+/// Install a hook called with every error the client runs into, replacing any
+/// previous one. The hook runs on the task that hit the error, so it should hand
+/// the event off rather than block.
+pub fn on_error(hook: impl Fn(&ErrorEvent) + Send + Sync + 'static) {
+    *HOOK.write().unwrap() = Some(Arc::new(hook));
+}
+
+/// Remove the hook installed with [`on_error`]
+pub fn clear_error_hook() {
+    *HOOK.write().unwrap() = None;
+}
+
+/// Pass an error to the hook, if one is installed
+pub(crate) fn report(event: ErrorEvent) {
+    let hook = HOOK.read().unwrap().clone();
+    if let Some(hook) = hook {
+        hook(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_hook_receives_structured_event() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = events.clone();
+        on_error(move |event| log.lock().unwrap().push(event.clone()));
+
+        let error = std::fs::read("/nonexistent/rrp")
+            .context("Failed to connect to local service")
+            .unwrap_err();
+        report(ErrorEvent::new(ErrorPhase::Forward, &error).with_connection(7, "203.0.113.7"));
+        clear_error_hook();
+        report(ErrorEvent::new(ErrorPhase::Connect, &error));
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].connection_id, Some(7));
+        assert_eq!(events[0].originator.as_deref(), Some("203.0.113.7"));
+        assert_eq!(events[0].message, "Failed to connect to local service");
+        assert_eq!(events[0].causes.len(), 1);
+        assert!(events[0]
+            .to_string()
+            .starts_with("[forward #7] Failed to connect to local service: "));
+    }
+}
@@ -0,0 +1,216 @@
+//! Sampling of the connections whose log lines are kept
+//!
+//! A busy tunnel logs a handful of lines for every connection it relays. With
+//! [`TraceSampling`], only one connection in [`connections`](TraceSampling::connections)
+//! is traced fully; the others keep only their warnings and errors, themselves kept
+//! for one connection in [`errors`](TraceSampling::errors). The choice is made when a
+//! connection arrives and holds for all of its lines, which the untraced connections
+//! send through a dispatcher that filters them before the application's subscriber.
+
+use rand::Rng;
+use tracing::dispatcher::{self, DefaultGuard, Dispatch};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+/// Share of a forward's connections whose log lines are kept, as one in so many
+/// connections. 1 keeps them all, 0 none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceSampling {
+    /// Trace one connection in this many fully, debug and info lines included
+    pub connections: u32,
+    /// Keep the warnings and errors of one in this many of the other connections
+    pub errors: u32,
+}
+
+impl Default for TraceSampling {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            errors: 1,
+        }
+    }
+}
+
+impl TraceSampling {
+    /// Trace one connection in `connections` fully, and the errors of all of them
+    pub fn one_in(connections: u32) -> Self {
+        Self {
+            connections,
+            errors: 1,
+        }
+    }
+}
+
+/// Picks, connection by connection, the lines that are kept
+pub(crate) struct Sampler {
+    /// Only warnings and errors reach the application's subscriber
+    errors: Dispatch,
+    /// Nothing does
+    off: Dispatch,
+}
+
+impl Sampler {
+    /// A sampler in front of the current subscriber
+    pub(crate) fn new() -> Self {
+        dispatcher::get_default(|inner| Self::with(inner.clone()))
+    }
+
+    fn with(inner: Dispatch) -> Self {
+        let filtered = |max| {
+            Dispatch::new(Filtered {
+                inner: inner.clone(),
+                max,
+            })
+        };
+        Self {
+            errors: filtered(LevelFilter::WARN),
+            off: filtered(LevelFilter::OFF),
+        }
+    }
+
+    /// Sample a new connection under `sampling`, keeping only the lines it picked
+    /// until the guard is dropped. Tasks spawned meanwhile by
+    /// [`spawn_connection_task`](crate::handle::Shared::spawn_connection_task) keep
+    /// to them too.
+    pub(crate) fn enter(&self, sampling: TraceSampling) -> Option<DefaultGuard> {
+        if one_in(sampling.connections) {
+            None
+        } else if one_in(sampling.errors) {
+            Some(dispatcher::set_default(&self.errors))
+        } else {
+            Some(dispatcher::set_default(&self.off))
+        }
+    }
+}
+
+fn one_in(n: u32) -> bool {
+    match n {
+        0 => false,
+        1 => true,
+        n => rand::thread_rng().gen_range(0..n) == 0,
+    }
+}
+
+/// Passes on to `inner` what is at least as severe as `max`
+struct Filtered {
+    inner: Dispatch,
+    max: LevelFilter,
+}
+
+impl Subscriber for Filtered {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // Decided line by line, as other threads use the callsite unfiltered
+        if self.inner.register_callsite(metadata).is_never() {
+            Interest::never()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.max && self.inner.enabled(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        self.inner.new_span(span)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        self.inner.record(span, values)
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        self.inner.record_follows_from(span, follows)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        self.inner.event(event)
+    }
+
+    fn enter(&self, span: &Id) {
+        self.inner.enter(span)
+    }
+
+    fn exit(&self, span: &Id) {
+        self.inner.exit(span)
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.inner.clone_span(id)
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        self.inner.try_close(id)
+    }
+
+    fn current_span(&self) -> Current {
+        self.inner.current_span()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::targets;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tracing::{debug, error, info, warn};
+
+    /// Counts the events it gets
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Subscriber for Counting {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_untraced_connections_keep_their_errors() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let inner = Dispatch::new(Counting(events.clone()));
+        let sampler = dispatcher::with_default(&inner, Sampler::new);
+        let log = |sampling| {
+            dispatcher::with_default(&inner, || {
+                let _sampled = sampler.enter(sampling);
+                debug!(target: targets::PROXY, "Read 512 bytes from local service");
+                info!(target: targets::PROXY, "Connected to local service");
+                warn!(target: targets::PROXY, "Local service is slow");
+                error!(target: targets::PROXY, "Error handling connection #1");
+            });
+            events.swap(0, Ordering::Relaxed)
+        };
+
+        assert_eq!(log(TraceSampling::default()), 4);
+        assert_eq!(log(TraceSampling::one_in(0)), 2);
+        assert_eq!(
+            log(TraceSampling {
+                connections: 0,
+                errors: 0
+            }),
+            0
+        );
+        // One in a hundred connections is traced fully
+        let traced = (0..2000)
+            .filter(|_| log(TraceSampling::one_in(100)) == 4)
+            .count();
+        assert!((1..100).contains(&traced), "{} traced", traced);
+    }
+}
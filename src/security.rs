@@ -0,0 +1,154 @@
+//! Security events, kept apart from operational ones
+//!
+//! [`TunnelEvent`](crate::TunnelEvent)s describe how the tunnel is doing. A
+//! [`SecurityEvent`] records something an operator or a SIEM should look at: a probe
+//! turned away, credentials that stopped working, a server that isn't the one it
+//! used to be. They come from
+//! [`ClientHandle::security_events`](crate::ClientHandle::security_events), are logged
+//! under the `rrp::security` target, and serialize to one JSON object per event for
+//! shippers that ingest JSON lines.
+
+use std::fmt;
+use std::time::SystemTime;
+
+use serde_json::json;
+
+use crate::http::format_rfc3339;
+use crate::session_id::SessionId;
+
+/// A security-relevant event and when it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityEvent {
+    pub time: SystemTime,
+    pub kind: SecurityEventKind,
+    /// Session it happened in, see [`SessionId`]
+    pub session: Option<SessionId>,
+}
+
+/// What happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SecurityEventKind {
+    /// A forwarded connection from `originator` was rejected, e.g. by a
+    /// [`wire_gate`](crate::ReverseSshConfig::wire_gate)
+    ConnectionRejected { originator: String, reason: String },
+    /// The server at `server` refused every authentication method; `reconnect` is set
+    /// when credentials that worked before stopped working
+    AuthenticationFailed {
+        server: String,
+        reconnect: bool,
+        error: String,
+    },
+    /// The server presented a host key other than the pinned
+    /// [`host_key_fingerprint`](crate::ReverseSshConfig::host_key_fingerprint), and
+    /// the connection was dropped
+    HostKeyMismatch {
+        server: String,
+        expected: String,
+        presented: String,
+    },
+}
+
+impl SecurityEvent {
+    pub(crate) fn now(kind: SecurityEventKind) -> Self {
+        Self {
+            time: SystemTime::now(),
+            kind,
+            session: None,
+        }
+    }
+
+    /// Type of the event: `connection_rejected`, `authentication_failed` or
+    /// `host_key_mismatch`
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            SecurityEventKind::ConnectionRejected { .. } => "connection_rejected",
+            SecurityEventKind::AuthenticationFailed { .. } => "authentication_failed",
+            SecurityEventKind::HostKeyMismatch { .. } => "host_key_mismatch",
+        }
+    }
+
+    /// The event as a single-line JSON object, with its `time` in RFC 3339, its
+    /// type in `event` and its `session` ID
+    pub fn to_json(&self) -> String {
+        let mut value = match &self.kind {
+            SecurityEventKind::ConnectionRejected { originator, reason } => {
+                json!({ "originator": originator, "reason": reason })
+            }
+            SecurityEventKind::AuthenticationFailed {
+                server,
+                reconnect,
+                error,
+            } => json!({ "server": server, "reconnect": reconnect, "error": error }),
+            SecurityEventKind::HostKeyMismatch {
+                server,
+                expected,
+                presented,
+            } => json!({ "server": server, "expected": expected, "presented": presented }),
+        };
+        value["time"] = json!(format_rfc3339(self.time));
+        value["event"] = json!(self.name());
+        value["session"] = json!(self.session.map(|id| id.to_string()));
+        value.to_string()
+    }
+}
+
+impl fmt::Display for SecurityEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SecurityEventKind::ConnectionRejected { originator, reason } => {
+                write!(f, "Rejected connection from {}: {}", originator, reason)
+            }
+            SecurityEventKind::AuthenticationFailed {
+                server,
+                reconnect: true,
+                error,
+            } => write!(
+                f,
+                "Authentication to {} failed on reconnect: {}",
+                server, error
+            ),
+            SecurityEventKind::AuthenticationFailed { server, error, .. } => {
+                write!(f, "Authentication to {} failed: {}", server, error)
+            }
+            SecurityEventKind::HostKeyMismatch {
+                server,
+                expected,
+                presented,
+            } => write!(
+                f,
+                "Host key of {} is {}, expected {}",
+                server, presented, expected
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_security_event_json() {
+        let event = SecurityEvent {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            kind: SecurityEventKind::HostKeyMismatch {
+                server: "tunnel.example.com:22".to_string(),
+                expected: "SHA256:abc".to_string(),
+                presented: "SHA256:xyz".to_string(),
+            },
+            session: None,
+        };
+        let json: serde_json::Value = serde_json::from_str(&event.to_json()).unwrap();
+        assert_eq!(json["event"], "host_key_mismatch");
+        assert_eq!(json["time"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(json["presented"], "SHA256:xyz");
+        assert!(json["session"].is_null());
+        assert!(!event.to_json().contains('\n'));
+        assert_eq!(
+            event.to_string(),
+            "Host key of tunnel.example.com:22 is SHA256:xyz, expected SHA256:abc"
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Definitions for running a tunnel under the operating system's service manager
+//!
+//! A [`ServiceSpec`] describes the command to keep running, how soon to restart it
+//! and how long it may take to drain on stop. It renders to a systemd unit, a launchd
+//! plist or a Task Scheduler task, and [`ServiceSpec::install`] writes and enables it.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+/// Service managers a [`ServiceSpec`] can be rendered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceManager {
+    /// systemd unit (Linux)
+    Systemd,
+    /// launchd property list (macOS)
+    Launchd,
+    /// Task Scheduler task started at boot (Windows). A plain console program can't
+    /// answer the service control manager, so a restarting task stands in for a service.
+    TaskScheduler,
+}
+
+impl ServiceManager {
+    /// The service manager of the platform this was built for
+    pub fn current() -> Option<Self> {
+        if cfg!(target_os = "linux") {
+            Some(ServiceManager::Systemd)
+        } else if cfg!(target_os = "macos") {
+            Some(ServiceManager::Launchd)
+        } else if cfg!(windows) {
+            Some(ServiceManager::TaskScheduler)
+        } else {
+            None
+        }
+    }
+
+    /// Look up a service manager by name: `systemd`, `launchd` or `windows`
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "systemd" => Some(ServiceManager::Systemd),
+            "launchd" => Some(ServiceManager::Launchd),
+            "windows" | "taskscheduler" => Some(ServiceManager::TaskScheduler),
+            _ => None,
+        }
+    }
+}
+
+/// A command kept running by the service manager
+#[derive(Debug, Clone)]
+pub struct ServiceSpec {
+    /// Service name, also used for the file name
+    pub name: String,
+    /// Executable to run
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub working_dir: Option<PathBuf>,
+    /// Wait before restarting after the command exits
+    pub restart_delay: Duration,
+    /// Time the command gets to finish open connections after being asked to stop,
+    /// before it is killed
+    pub drain_timeout: Duration,
+    /// Install for the current user instead of system-wide
+    pub per_user: bool,
+}
+
+impl ServiceSpec {
+    pub fn new(name: impl Into<String>, program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+        Self {
+            name: name.into(),
+            program: program.into(),
+            args,
+            working_dir: None,
+            restart_delay: Duration::from_secs(5),
+            drain_timeout: Duration::from_secs(30),
+            per_user: false,
+        }
+    }
+
+    /// The service definition for `manager`
+    pub fn render(&self, manager: ServiceManager) -> String {
+        match manager {
+            ServiceManager::Systemd => self.render_systemd(),
+            ServiceManager::Launchd => self.render_launchd(),
+            ServiceManager::TaskScheduler => self.render_task(),
+        }
+    }
+
+    /// Where [`install`](Self::install) writes the definition
+    pub fn path(&self, manager: ServiceManager) -> Result<PathBuf> {
+        let home = || {
+            std::env::var_os("HOME")
+                .map(PathBuf::from)
+                .context("HOME is not set")
+        };
+        Ok(match (manager, self.per_user) {
+            (ServiceManager::Systemd, false) => {
+                PathBuf::from(format!("/etc/systemd/system/{}.service", self.name))
+            }
+            (ServiceManager::Systemd, true) => {
+                home()?.join(format!(".config/systemd/user/{}.service", self.name))
+            }
+            (ServiceManager::Launchd, false) => {
+                PathBuf::from(format!("/Library/LaunchDaemons/{}.plist", self.label()))
+            }
+            (ServiceManager::Launchd, true) => {
+                home()?.join(format!("Library/LaunchAgents/{}.plist", self.label()))
+            }
+            (ServiceManager::TaskScheduler, per_user) => {
+                let base = if per_user {
+                    "LOCALAPPDATA"
+                } else {
+                    "ProgramData"
+                };
+                std::env::var_os(base)
+                    .map(PathBuf::from)
+                    .with_context(|| format!("{} is not set", base))?
+                    .join("rrp")
+                    .join(format!("{}.xml", self.name))
+            }
+        })
+    }
+
+    /// Write the definition for `manager` and enable it, starting the service.
+    /// Returns the path written.
+    pub fn install(&self, manager: ServiceManager) -> Result<PathBuf> {
+        let path = self.path(manager)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, self.render(manager))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        let user = self.per_user.then_some("--user");
+        match manager {
+            ServiceManager::Systemd => {
+                run("systemctl", user.into_iter().chain(["daemon-reload"]))?;
+                run(
+                    "systemctl",
+                    user.into_iter().chain(["enable", "--now", &self.name]),
+                )?;
+            }
+            ServiceManager::Launchd => {
+                run("launchctl", ["load", "-w", &path.to_string_lossy()])?;
+            }
+            ServiceManager::TaskScheduler => {
+                let xml = path.to_string_lossy();
+                run(
+                    "schtasks",
+                    ["/Create", "/TN", &self.name, "/XML", &xml, "/F"],
+                )?;
+                run("schtasks", ["/Run", "/TN", &self.name])?;
+            }
+        }
+        Ok(path)
+    }
+
+    fn label(&self) -> String {
+        format!("rrp.{}", self.name)
+    }
+
+    fn render_systemd(&self) -> String {
+        let exec = std::iter::once(self.program.to_string_lossy().into_owned())
+            .chain(self.args.iter().cloned())
+            .map(|arg| systemd_quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut unit = format!(
+            "[Unit]\n\
+             Description=rrp tunnel {name}\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             ExecStart={exec}\n\
+             Restart=always\n\
+             RestartSec={restart}\n\
+             KillSignal=SIGTERM\n\
+             TimeoutStopSec={stop}\n",
+            name = self.name,
+            restart = self.restart_delay.as_secs().max(1),
+            // Leave room to disconnect after the drain
+            stop = self.drain_timeout.as_secs() + 5,
+        );
+        if let Some(dir) = &self.working_dir {
+            unit.push_str(&format!("WorkingDirectory={}\n", dir.display()));
+        }
+        let target = if self.per_user {
+            "default.target"
+        } else {
+            "multi-user.target"
+        };
+        unit.push_str(&format!("\n[Install]\nWantedBy={}\n", target));
+        unit
+    }
+
+    fn render_launchd(&self) -> String {
+        let args: String = std::iter::once(self.program.to_string_lossy().into_owned())
+            .chain(self.args.iter().cloned())
+            .map(|arg| format!("        <string>{}</string>\n", xml_escape(&arg)))
+            .collect();
+        let working_dir = self
+            .working_dir
+            .as_ref()
+            .map(|dir| {
+                format!(
+                    "    <key>WorkingDirectory</key>\n    <string>{}</string>\n",
+                    xml_escape(&dir.to_string_lossy())
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+{args}    </array>
+{working_dir}    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>ThrottleInterval</key>
+    <integer>{restart}</integer>
+    <key>ExitTimeOut</key>
+    <integer>{stop}</integer>
+</dict>
+</plist>
+"#,
+            label = xml_escape(&self.label()),
+            restart = self.restart_delay.as_secs().max(1),
+            stop = self.drain_timeout.as_secs() + 5,
+        )
+    }
+
+    fn render_task(&self) -> String {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| windows_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let working_dir = self
+            .working_dir
+            .as_ref()
+            .map(|dir| {
+                format!(
+                    "      <WorkingDirectory>{}</WorkingDirectory>\n",
+                    xml_escape(&dir.to_string_lossy())
+                )
+            })
+            .unwrap_or_default();
+        let trigger = if self.per_user {
+            "LogonTrigger"
+        } else {
+            "BootTrigger"
+        };
+        // Task Scheduler restarts at most once a minute
+        let restart_minutes = self.restart_delay.as_secs().div_ceil(60).max(1);
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-16"?>
+<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>rrp tunnel {name}</Description>
+  </RegistrationInfo>
+  <Triggers>
+    <{trigger}>
+      <Enabled>true</Enabled>
+    </{trigger}>
+  </Triggers>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <ExecutionTimeLimit>PT0S</ExecutionTimeLimit>
+    <RestartOnFailure>
+      <Interval>PT{restart_minutes}M</Interval>
+      <Count>999</Count>
+    </RestartOnFailure>
+  </Settings>
+  <Actions>
+    <Exec>
+      <Command>{program}</Command>
+      <Arguments>{args}</Arguments>
+{working_dir}    </Exec>
+  </Actions>
+</Task>
+"#,
+            name = xml_escape(&self.name),
+            program = xml_escape(&self.program.to_string_lossy()),
+            args = xml_escape(&args),
+        )
+    }
+}
+
+fn run<'a>(program: &str, args: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    let args: Vec<&str> = args.into_iter().collect();
+    let status = Command::new(program)
+        .args(&args)
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        bail!("{} {} failed with {}", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Quote an `ExecStart` word: double quotes when needed, and `%` doubled so it
+/// isn't taken for a specifier
+fn systemd_quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c)) {
+        return arg;
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn windows_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('"', "\\\""))
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_service_definitions() {
+        let mut spec = ServiceSpec::new(
+            "web",
+            "/usr/local/bin/rrp",
+            vec![
+                "run".into(),
+                "--user".into(),
+                "my user".into(),
+                "50%".into(),
+            ],
+        );
+        spec.drain_timeout = Duration::from_secs(10);
+
+        let unit = spec.render(ServiceManager::Systemd);
+        assert!(unit.contains("ExecStart=/usr/local/bin/rrp run --user \"my user\" 50%%\n"));
+        assert!(unit.contains("Restart=always\nRestartSec=5\n"));
+        assert!(unit.contains("TimeoutStopSec=15\n"));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+
+        let plist = spec.render(ServiceManager::Launchd);
+        assert!(plist.contains("<string>rrp.web</string>"));
+        assert!(plist.contains("        <string>my user</string>\n"));
+        assert!(plist.contains("<key>ExitTimeOut</key>\n    <integer>15</integer>"));
+
+        let task = spec.render(ServiceManager::TaskScheduler);
+        assert!(task.contains("<Arguments>run --user &quot;my user&quot; 50%</Arguments>"));
+        assert!(task.contains("<Interval>PT1M</Interval>"));
+
+        spec.per_user = true;
+        assert!(spec
+            .path(ServiceManager::Systemd)
+            .unwrap()
+            .ends_with(".config/systemd/user/web.service"));
+    }
+}
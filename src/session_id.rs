@@ -0,0 +1,84 @@
+//! Session IDs for correlating records across systems
+//!
+//! Every `connect()` starts a session with a new [`SessionId`], a
+//! [ULID](https://github.com/ulid/spec): 48 bits of milliseconds since the Unix epoch
+//! followed by 80 random bits, written as 26 characters of Crockford's base32. IDs of
+//! later sessions sort after earlier ones, on any host with a synchronized clock, so
+//! records of several tunnels can be merged and ordered by session.
+//!
+//! The ID is a field of the `session` span around the client's log lines, of the
+//! [`Connecting`](crate::TunnelEvent::Connecting) event that starts the session, of
+//! [`SecurityEvent`](crate::SecurityEvent)s and [`ErrorEvent`](crate::ErrorEvent)s,
+//! and of [`MetricsSnapshot`](crate::MetricsSnapshot)s, to label exported metrics with.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+/// Crockford's base32 alphabet
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// ULID of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionId(u128);
+
+impl SessionId {
+    pub(crate) fn new() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let random = rand::thread_rng().gen::<u128>() >> 48;
+        Self((millis & ((1 << 48) - 1)) << 80 | random)
+    }
+
+    /// When the session started, to the millisecond
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis((self.0 >> 80) as u64)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 26 digits of 5 bits hold 130 bits; the first digit takes the top 3
+        let digits: String = (0..26)
+            .rev()
+            .map(|digit| ALPHABET[((self.0 >> (digit * 5)) & 0x1f) as usize] as char)
+            .collect();
+        f.write_str(&digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_id() {
+        let before = SystemTime::now() - Duration::from_millis(1);
+        let id = SessionId::new();
+        assert!(id.time() >= before && id.time() <= SystemTime::now());
+        let text = id.to_string();
+        assert_eq!(text.len(), 26);
+        assert!(text.starts_with(['0', '1', '2', '3', '4', '5', '6', '7']));
+
+        // The spec's example: time 1469918176385, all random bits set
+        let id = SessionId(1469918176385 << 80 | ((1 << 80) - 1));
+        assert_eq!(id.to_string(), "01ARYZ6S41ZZZZZZZZZZZZZZZZ");
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(SessionId::new() > id && SessionId::new().to_string() > id.to_string());
+
+        // Security events are stamped with the session they happen in
+        let events = crate::events::Events::default();
+        let mut security = events.subscribe_security();
+        events.begin_session(id, "tunnel.example.com:22".into());
+        events.emit_security(crate::SecurityEvent::now(
+            crate::SecurityEventKind::ConnectionRejected {
+                originator: "203.0.113.7:4242".into(),
+                reason: "not TLS".into(),
+            },
+        ));
+        assert_eq!(security.try_recv().unwrap().session, Some(id));
+    }
+}
@@ -0,0 +1,263 @@
+//! Traffic shaping for forwarded connections
+//!
+//! Lets a forward behave like a slow link, so applications can be tested against
+//! realistic tunnel conditions without external tooling.
+
+use crate::clock::{Clock, Sleep};
+use rand::Rng;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+/// Artificial delay added to traffic relayed through a forward, in both directions.
+/// It applies when data starts flowing after a pause, so bulk transfers are not
+/// slowed down further for every chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Latency {
+    /// Base delay
+    pub delay: Duration,
+    /// Maximum random deviation from `delay`, in either direction
+    pub jitter: Duration,
+}
+
+impl Latency {
+    pub const fn new(delay: Duration, jitter: Duration) -> Self {
+        Self { delay, jitter }
+    }
+
+    /// Pick the delay for one burst
+    pub(crate) fn sample(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let low = self.delay.saturating_sub(self.jitter);
+        let high = self.delay + self.jitter;
+        rand::thread_rng().gen_range(low..=high)
+    }
+}
+
+/// Link conditions applied to a forward: a throughput cap plus latency and jitter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShapingProfile {
+    /// Throughput cap in bytes per second, per direction
+    pub bandwidth: Option<u64>,
+    pub latency: Latency,
+}
+
+impl ShapingProfile {
+    /// 2G data: 50 kbit/s, 500 ms
+    pub const GPRS: Self = Self::new(6_250, 500, 100);
+    /// Typical 3G: 750 kbit/s, 200 ms
+    pub const THREE_G: Self = Self::new(93_750, 200, 50);
+    /// Home DSL: 2 Mbit/s, 25 ms
+    pub const DSL: Self = Self::new(250_000, 25, 5);
+    /// Typical 4G: 9 Mbit/s, 50 ms
+    pub const FOUR_G: Self = Self::new(1_125_000, 50, 10);
+
+    const fn new(bandwidth: u64, delay_ms: u64, jitter_ms: u64) -> Self {
+        Self {
+            bandwidth: Some(bandwidth),
+            latency: Latency::new(
+                Duration::from_millis(delay_ms),
+                Duration::from_millis(jitter_ms),
+            ),
+        }
+    }
+
+    /// Look up a preset by name: `gprs`, `3g`, `dsl` or `4g` (case-insensitive)
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gprs" | "2g" => Some(Self::GPRS),
+            "3g" => Some(Self::THREE_G),
+            "dsl" => Some(Self::DSL),
+            "4g" | "lte" => Some(Self::FOUR_G),
+            _ => None,
+        }
+    }
+}
+
+impl From<Latency> for ShapingProfile {
+    fn from(latency: Latency) -> Self {
+        Self {
+            bandwidth: None,
+            latency,
+        }
+    }
+}
+
+/// The profile currently applied to a forward, shared with its connections so it can
+/// be switched at runtime
+#[derive(Debug)]
+pub(crate) struct Shaper {
+    profile: RwLock<Option<ShapingProfile>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Shaper {
+    pub(crate) fn new(profile: Option<ShapingProfile>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            profile: RwLock::new(profile),
+            clock,
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<ShapingProfile> {
+        *self.profile.read().unwrap()
+    }
+
+    pub(crate) fn set(&self, profile: Option<ShapingProfile>) {
+        *self.profile.write().unwrap() = profile;
+    }
+}
+
+/// Writer that paces writes according to the current [`ShapingProfile`]
+pub(crate) struct Shaped<W> {
+    inner: W,
+    shaper: Arc<Shaper>,
+    /// When the previous write finished, to tell bursts apart
+    last_write: Option<Instant>,
+    /// Pending wait and the number of bytes it allows
+    pending: Option<(Sleep, usize)>,
+}
+
+impl<W> Shaped<W> {
+    pub(crate) fn new(inner: W, shaper: Arc<Shaper>) -> Self {
+        Self {
+            inner,
+            shaper,
+            last_write: None,
+            pending: None,
+        }
+    }
+
+    /// How long to wait before writing, and how much of `len` to write then
+    fn plan(&self, profile: &ShapingProfile, len: usize) -> (Duration, usize) {
+        let mut wait = Duration::ZERO;
+        let idle = self
+            .last_write
+            .is_none_or(|last| self.shaper.clock.now() - last > profile.latency.delay);
+        if idle {
+            wait += profile.latency.sample();
+        }
+        let Some(bandwidth) = profile.bandwidth.filter(|b| *b > 0) else {
+            return (wait, len);
+        };
+        // Pace in slices of about 50 ms worth of data
+        let slice = (bandwidth / 20).clamp(512, 64 * 1024) as usize;
+        let n = len.min(slice);
+        wait += Duration::from_secs_f64(n as f64 / bandwidth as f64);
+        (wait, n)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Shaped<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut len = buf.len();
+        if let Some(profile) = self.shaper.get() {
+            if self.pending.is_none() {
+                let (wait, n) = self.plan(&profile, buf.len());
+                self.pending = Some((self.shaper.clock.sleep(wait), n));
+            }
+            let (sleep, n) = self.pending.as_mut().unwrap();
+            ready!(sleep.as_mut().poll(cx));
+            len = (*n).min(buf.len());
+        }
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]));
+        self.pending = None;
+        self.last_write = Some(self.shaper.clock.now());
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use tokio::io::AsyncWriteExt;
+    use tokio::task::JoinHandle;
+
+    /// Let the spawned writes run until they wait for the clock
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Let `writer` write everything in `writes` on its own task
+    async fn spawn_writes(
+        mut writer: Shaped<Vec<u8>>,
+        writes: Vec<Vec<u8>>,
+    ) -> JoinHandle<Shaped<Vec<u8>>> {
+        let writing = tokio::spawn(async move {
+            for data in writes {
+                writer.write_all(&data).await.unwrap();
+            }
+            writer
+        });
+        settle().await;
+        writing
+    }
+
+    /// Move `clock` forward and let the writes it wakes run
+    async fn advance(clock: &ManualClock, duration: Duration) {
+        clock.advance(duration);
+        settle().await;
+    }
+
+    #[tokio::test]
+    async fn test_latency_with_jitter() {
+        let latency = Latency::new(Duration::from_millis(40), Duration::from_millis(10));
+        for _ in 0..100 {
+            let delay = latency.sample();
+            assert!(delay >= Duration::from_millis(30) && delay <= Duration::from_millis(50));
+        }
+
+        let clock = Arc::new(ManualClock::new());
+        let shaper = Arc::new(Shaper::new(Some(latency.into()), clock.clone()));
+        let writes = vec![b"one".to_vec(), b"two".to_vec()];
+        let writing = spawn_writes(Shaped::new(Vec::new(), shaper), writes).await;
+        advance(&clock, Duration::from_millis(29)).await;
+        assert!(!writing.is_finished());
+        // Back-to-back writes are one burst and only pay the latency once
+        advance(&clock, Duration::from_millis(21)).await;
+        assert!(writing.is_finished());
+        assert_eq!(writing.await.unwrap().inner, b"onetwo");
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_cap_switched_at_runtime() {
+        let clock = Arc::new(ManualClock::new());
+        let shaper = Arc::new(Shaper::new(None, clock.clone()));
+        let mut writer = Shaped::new(Vec::new(), shaper.clone());
+        writer.write_all(&[0; 10_000]).await.unwrap();
+
+        shaper.set(Some(ShapingProfile {
+            bandwidth: Some(100_000),
+            latency: Latency::default(),
+        }));
+        // Two slices of 5000 bytes, 50 ms each
+        let writing = spawn_writes(writer, vec![vec![0; 10_000]]).await;
+        advance(&clock, Duration::from_millis(50)).await;
+        assert!(!writing.is_finished());
+        advance(&clock, Duration::from_millis(50)).await;
+        assert!(writing.is_finished());
+        assert_eq!(writing.await.unwrap().inner.len(), 20_000);
+        assert_eq!(ShapingProfile::named("3G"), Some(ShapingProfile::THREE_G));
+    }
+}
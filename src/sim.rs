@@ -0,0 +1,665 @@
+//! A simulated network and SSH server for end-to-end tests
+//!
+//! [`SimNetwork`] is an in-memory network: connections are pairs of
+//! [`DuplexStream`]s, with a configurable one-way latency, partitions that hold
+//! traffic back until healed, and [`SimNetwork::cut`] dropping every connection at
+//! once. Used as the [`network`](crate::ReverseSshConfig::network) of a client, it
+//! carries the connections to the SSH server and to local services.
+//! [`SimServer`] is an SSH server listening on it, accepting any credentials and
+//! serving remote forwards on the network, so remote peers are just
+//! [`SimNetwork::dial`] calls. A whole tunnel, reconnect storms included, can then be
+//! tested in milliseconds without touching a socket:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use reverse_ssh::sim::{SimNetwork, SimServer};
+//! use reverse_ssh::{ReverseSshClient, ReverseSshConfig};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let network = SimNetwork::new();
+//! let _server = SimServer::start(&network, "ssh.sim", 22)?;
+//! let mut service = network.listen("127.0.0.1", 8080)?;
+//! let mut client = ReverseSshClient::new(ReverseSshConfig {
+//!     server_addr: "ssh.sim".to_string(),
+//!     remote_port: 80,
+//!     local_port: 8080,
+//!     network: Arc::new(network.clone()),
+//!     ..Default::default()
+//! });
+//! tokio::spawn(async move { client.run().await });
+//! let peer = network.dial("ssh.sim", 80).await?;
+//! let (local, originator) = service.accept().await.unwrap();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use russh::server::{self, Auth, Msg, Session};
+use russh::{Channel, ChannelId, CryptoVec};
+use russh_keys::key::{KeyPair, PublicKey};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::network::{Connecting, Connection, Network};
+use crate::tasks;
+
+/// Bytes buffered in each direction of a simulated connection
+const LINK_BUFFER: usize = 64 * 1024;
+
+/// First port a [`SimServer`] assigns to forwards requested on port 0
+const FIRST_ASSIGNED_PORT: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Conditions {
+    latency: Duration,
+    partitioned: bool,
+}
+
+type Backlog = mpsc::UnboundedSender<(DuplexStream, String)>;
+
+struct NetworkState {
+    listeners: Mutex<HashMap<(String, u16), Backlog>>,
+    conditions: watch::Sender<Conditions>,
+    /// Bumped by [`SimNetwork::cut`], ending the connections open until then
+    cuts: watch::Sender<u64>,
+    next_port: AtomicU16,
+}
+
+/// An in-memory network, cheap to clone; clones are the same network
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<NetworkState>,
+}
+
+impl fmt::Debug for SimNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimNetwork")
+            .field("conditions", &*self.state.conditions.borrow())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for SimNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(NetworkState {
+                listeners: Mutex::new(HashMap::new()),
+                conditions: watch::channel(Conditions::default()).0,
+                cuts: watch::channel(0).0,
+                next_port: AtomicU16::new(49_152),
+            }),
+        }
+    }
+
+    /// Accept connections to `host:port`, until the listener is dropped
+    pub fn listen(&self, host: &str, port: u16) -> Result<SimListener> {
+        let mut listeners = self.state.listeners.lock().unwrap();
+        let addr = (host.to_string(), port);
+        if listeners.contains_key(&addr) {
+            bail!("{}:{} is already listening", host, port);
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        listeners.insert(addr.clone(), tx);
+        Ok(SimListener {
+            network: self.clone(),
+            addr,
+            backlog: rx,
+        })
+    }
+
+    /// Whether something listens on `host:port`
+    pub fn is_listening(&self, host: &str, port: u16) -> bool {
+        self.state
+            .listeners
+            .lock()
+            .unwrap()
+            .contains_key(&(host.to_string(), port))
+    }
+
+    /// Open a connection to `host:port`. It is refused if nothing listens there,
+    /// and times out right away while the network is partitioned.
+    pub async fn dial(&self, host: &str, port: u16) -> io::Result<DuplexStream> {
+        self.dial_from("sim", host, port).await
+    }
+
+    /// [`dial`](Self::dial) `host:port` from the address `source`, which listeners
+    /// see as the originator
+    pub async fn dial_from(&self, source: &str, host: &str, port: u16) -> io::Result<DuplexStream> {
+        if self.state.conditions.borrow().partitioned {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{}:{} is unreachable, the network is partitioned",
+                    host, port
+                ),
+            ));
+        }
+        let refused = || {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Nothing listens on {}:{}", host, port),
+            )
+        };
+        let backlog = self
+            .state
+            .listeners
+            .lock()
+            .unwrap()
+            .get(&(host.to_string(), port))
+            .cloned()
+            .ok_or_else(refused)?;
+        let (near, far) = self.link();
+        let originator = format!(
+            "{}:{}",
+            source,
+            self.state.next_port.fetch_add(1, Ordering::Relaxed)
+        );
+        backlog.send((far, originator)).map_err(|_| refused())?;
+        Ok(near)
+    }
+
+    /// Delay every byte by `latency` on its way, in either direction
+    pub fn set_latency(&self, latency: Duration) {
+        self.state
+            .conditions
+            .send_modify(|conditions| conditions.latency = latency);
+    }
+
+    /// Hold all traffic back, and fail new connections, until [`heal`](Self::heal)
+    pub fn partition(&self) {
+        self.state
+            .conditions
+            .send_modify(|conditions| conditions.partitioned = true);
+    }
+
+    /// Deliver the traffic held back by a partition, and accept connections again
+    pub fn heal(&self) {
+        self.state
+            .conditions
+            .send_modify(|conditions| conditions.partitioned = false);
+    }
+
+    /// Drop every open connection, as a router reboot would. Listeners stay.
+    pub fn cut(&self) {
+        self.state.cuts.send_modify(|cuts| *cuts += 1);
+    }
+
+    /// Two ends of a connection, with a task carrying bytes between them
+    fn link(&self) -> (DuplexStream, DuplexStream) {
+        let (near, near_inner) = tokio::io::duplex(LINK_BUFFER);
+        let (far, far_inner) = tokio::io::duplex(LINK_BUFFER);
+        let (near_rx, near_tx) = tokio::io::split(near_inner);
+        let (far_rx, far_tx) = tokio::io::split(far_inner);
+        let conditions = self.state.conditions.subscribe();
+        let mut cuts = self.state.cuts.subscribe();
+        tasks::spawn("sim link", async move {
+            let both = async {
+                tokio::join!(
+                    carry(near_rx, far_tx, conditions.clone()),
+                    carry(far_rx, near_tx, conditions)
+                )
+            };
+            // Dropping the inner ends closes the connection on both sides
+            tokio::select! {
+                _ = both => {}
+                _ = cuts.changed() => {}
+            }
+        });
+        (near, far)
+    }
+}
+
+impl Network for SimNetwork {
+    fn connect(&self, host: &str, port: u16) -> Connecting {
+        let (network, host) = (self.clone(), host.to_string());
+        Box::pin(async move {
+            let stream = network.dial(&host, port).await?;
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        })
+    }
+}
+
+/// Carry bytes from one end to the other, `latency` late, holding them back while
+/// the network is partitioned
+async fn carry(
+    mut from: ReadHalf<DuplexStream>,
+    mut to: WriteHalf<DuplexStream>,
+    mut conditions: watch::Receiver<Conditions>,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let latency = conditions.clone();
+    let reading = async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(n @ 1..) = from.read(&mut buf).await {
+            let due = Instant::now() + latency.borrow().latency;
+            if tx.send((due, buf[..n].to_vec())).is_err() {
+                break;
+            }
+        }
+    };
+    let delivering = async move {
+        while let Some((due, bytes)) = rx.recv().await {
+            tokio::time::sleep_until(due).await;
+            let healed = conditions.wait_for(|conditions| !conditions.partitioned);
+            if healed.await.is_err() || to.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+        let _ = to.shutdown().await;
+    };
+    tokio::join!(reading, delivering);
+}
+
+/// Connections to an address of a [`SimNetwork`]
+pub struct SimListener {
+    network: SimNetwork,
+    addr: (String, u16),
+    backlog: mpsc::UnboundedReceiver<(DuplexStream, String)>,
+}
+
+impl SimListener {
+    /// The next connection, with the `address:port` of the side that opened it
+    pub async fn accept(&mut self) -> Option<(DuplexStream, String)> {
+        self.backlog.recv().await
+    }
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.network
+            .state
+            .listeners
+            .lock()
+            .unwrap()
+            .remove(&self.addr);
+    }
+}
+
+struct ServerState {
+    network: SimNetwork,
+    host: String,
+    banner: Mutex<Option<String>>,
+    sessions: AtomicU64,
+    next_port: AtomicU32,
+    /// Shells (`None`) and commands run on session channels
+    session_requests: Mutex<Vec<Option<String>>>,
+    /// Status commands exit with once they've written the banner, if they exit
+    exit_status: Mutex<Option<u32>>,
+    /// Session channels open now, by session
+    session_channels: Mutex<Vec<(u64, ChannelId, server::Handle)>>,
+    /// `window-change` requests, which clients send as keepalives on idle channels
+    window_changes: AtomicU64,
+}
+
+/// An SSH server on a [`SimNetwork`]. It accepts any credentials, and listens on
+/// its host of the network for the forwards clients request, opening a
+/// `forwarded-tcpip` channel for every connection there. Stops when dropped.
+pub struct SimServer {
+    state: Arc<ServerState>,
+    task: JoinHandle<()>,
+}
+
+impl SimServer {
+    /// Serve SSH on `host:port` of `network`
+    pub fn start(network: &SimNetwork, host: &str, port: u16) -> Result<Self> {
+        let mut listener = network.listen(host, port)?;
+        let key = KeyPair::generate_ed25519().context("Failed to generate a host key")?;
+        let config = Arc::new(server::Config {
+            keys: vec![key],
+            auth_rejection_time: Duration::ZERO,
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            inactivity_timeout: None,
+            ..Default::default()
+        });
+        let state = Arc::new(ServerState {
+            network: network.clone(),
+            host: host.to_string(),
+            banner: Mutex::new(None),
+            sessions: AtomicU64::new(0),
+            next_port: AtomicU32::new(FIRST_ASSIGNED_PORT),
+            session_requests: Mutex::new(Vec::new()),
+            exit_status: Mutex::new(None),
+            session_channels: Mutex::new(Vec::new()),
+            window_changes: AtomicU64::new(0),
+        });
+        let server = state.clone();
+        let task = tasks::spawn("sim server", async move {
+            while let Some((stream, _)) = listener.accept().await {
+                let id = server.sessions.fetch_add(1, Ordering::Relaxed);
+                let handler = SimSession {
+                    id,
+                    server: server.clone(),
+                    forwards: Vec::new(),
+                };
+                let config = config.clone();
+                tasks::spawn("sim session", async move {
+                    if let Ok(session) = server::run_stream(config, stream, handler).await {
+                        let _ = session.await;
+                    }
+                });
+            }
+        });
+        Ok(Self { state, task })
+    }
+
+    /// Text written on the session channel of the sessions started from now on,
+    /// like the banner of a tunnel provider announcing the URL
+    pub fn set_banner(&self, banner: impl Into<String>) {
+        *self.state.banner.lock().unwrap() = Some(banner.into());
+    }
+
+    /// Sessions started so far
+    pub fn sessions(&self) -> u64 {
+        self.state.sessions.load(Ordering::Relaxed)
+    }
+
+    /// Shells (`None`) and commands clients requested on session channels so far
+    pub fn session_requests(&self) -> Vec<Option<String>> {
+        self.state.session_requests.lock().unwrap().clone()
+    }
+
+    /// Session channels clients hold open
+    pub fn session_channels(&self) -> usize {
+        self.state.session_channels.lock().unwrap().len()
+    }
+
+    /// `window-change` requests received so far, as sent to keep idle channels alive
+    pub fn window_changes(&self) -> u64 {
+        self.state.window_changes.load(Ordering::Relaxed)
+    }
+
+    /// Make the commands run from now on exit with `status` once they've written the
+    /// banner, closing their channel; with `None` they run until the client leaves
+    pub fn set_exit_status(&self, status: Option<u32>) {
+        *self.state.exit_status.lock().unwrap() = status;
+    }
+
+    /// Close the session channels open now, like a provider ending its shell
+    pub async fn close_session_channels(&self) {
+        let channels = std::mem::take(&mut *self.state.session_channels.lock().unwrap());
+        for (_, channel, handle) in channels {
+            let _ = handle.eof(channel).await;
+            let _ = handle.close(channel).await;
+        }
+    }
+}
+
+impl Drop for SimServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// One client's session with a [`SimServer`]
+struct SimSession {
+    id: u64,
+    server: Arc<ServerState>,
+    /// Tasks accepting the connections of each forward, by port
+    forwards: Vec<(u32, JoinHandle<()>)>,
+}
+
+impl SimSession {
+    fn write_banner(&self, channel: ChannelId, session: &mut Session) {
+        if let Some(banner) = self.server.banner.lock().unwrap().clone() {
+            session.data(channel, CryptoVec::from(banner.into_bytes()));
+        }
+    }
+}
+
+impl Drop for SimSession {
+    fn drop(&mut self) {
+        // Their listeners go with them, so the ports can be requested again
+        for (_, task) in &self.forwards {
+            task.abort();
+        }
+        let mut channels = self.server.session_channels.lock().unwrap();
+        channels.retain(|(session, _, _)| *session != self.id);
+    }
+}
+
+#[async_trait]
+impl server::Handler for SimSession {
+    type Error = russh::Error;
+
+    async fn auth_none(&mut self, _user: &str) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn auth_password(&mut self, _user: &str, _password: &str) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        _user: &str,
+        _public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let mut channels = self.server.session_channels.lock().unwrap();
+        channels.push((self.id, channel.id(), session.handle()));
+        Ok(true)
+    }
+
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let mut channels = self.server.session_channels.lock().unwrap();
+        channels.retain(|(session, id, _)| (*session, *id) != (self.id, channel));
+        Ok(())
+    }
+
+    async fn window_change_request(
+        &mut self,
+        _channel: ChannelId,
+        _col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.server.window_changes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.server.session_requests.lock().unwrap().push(None);
+        self.write_banner(channel, session);
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data).into_owned();
+        self.server
+            .session_requests
+            .lock()
+            .unwrap()
+            .push(Some(command));
+        self.write_banner(channel, session);
+        if let Some(status) = *self.server.exit_status.lock().unwrap() {
+            session.exit_status_request(channel, status);
+            session.eof(channel);
+            session.close(channel);
+        }
+        Ok(())
+    }
+
+    async fn tcpip_forward(
+        &mut self,
+        address: &str,
+        port: &mut u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if *port == 0 {
+            *port = self.server.next_port.fetch_add(1, Ordering::Relaxed);
+        }
+        let Ok(port16) = u16::try_from(*port) else {
+            return Ok(false);
+        };
+        let Ok(mut listener) = self.server.network.listen(&self.server.host, port16) else {
+            return Ok(false);
+        };
+        let (handle, address, port) = (session.handle(), address.to_string(), *port);
+        let task = tasks::spawn("sim forward", async move {
+            while let Some((mut stream, originator)) = listener.accept().await {
+                let (handle, address) = (handle.clone(), address.clone());
+                tasks::spawn("sim forwarded connection", async move {
+                    let (originator_address, originator_port) = originator
+                        .rsplit_once(':')
+                        .map(|(address, port)| (address.to_string(), port.parse().unwrap_or(0)))
+                        .unwrap_or_default();
+                    let channel = handle
+                        .channel_open_forwarded_tcpip(
+                            address,
+                            port,
+                            originator_address,
+                            originator_port,
+                        )
+                        .await;
+                    if let Ok(channel) = channel {
+                        let mut channel = channel.into_stream();
+                        let _ = tokio::io::copy_bidirectional(&mut stream, &mut channel).await;
+                    }
+                });
+            }
+        });
+        self.forwards.push((port, task));
+        Ok(true)
+    }
+
+    async fn cancel_tcpip_forward(
+        &mut self,
+        _address: &str,
+        port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some(index) = self.forwards.iter().position(|(bound, _)| *bound == port) else {
+            return Ok(false);
+        };
+        self.forwards.remove(index).1.abort();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RestartPolicy, ReverseSshConfig, TunnelEvent, TunnelManager};
+    use tokio::sync::broadcast;
+
+    async fn forwards_established(
+        events: &mut broadcast::Receiver<(String, TunnelEvent)>,
+        count: usize,
+    ) {
+        let mut established = 0;
+        while established < count {
+            if let (_, TunnelEvent::ForwardEstablished { .. }) = events.recv().await.unwrap() {
+                established += 1;
+            }
+        }
+    }
+
+    async fn echo(network: &SimNetwork, port: u16, message: &[u8]) -> io::Result<Vec<u8>> {
+        let mut peer = network.dial("ssh.sim", port).await?;
+        peer.write_all(message).await?;
+        let mut reply = vec![0; message.len()];
+        peer.read_exact(&mut reply).await?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_storm_over_simulated_network() {
+        let network = SimNetwork::new();
+        let server = SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut service = network.listen("127.0.0.1", 8080).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+
+        let mut manager = TunnelManager::new();
+        let mut events = manager.subscribe();
+        let tunnels = 10;
+        for i in 0..tunnels {
+            let config = ReverseSshConfig {
+                server_addr: "ssh.sim".to_string(),
+                remote_port: 8000 + i,
+                local_port: 8080,
+                network: Arc::new(network.clone()),
+                ..Default::default()
+            };
+            let restart = RestartPolicy::Always {
+                delay: Duration::from_millis(10),
+            };
+            manager.add(format!("tunnel {}", i), config, restart);
+        }
+        forwards_established(&mut events, tunnels as usize).await;
+        assert_eq!(echo(&network, 8003, b"ping").await.unwrap(), b"ping");
+        assert_eq!(
+            echo(&network, 9000, b"ping").await.unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+
+        // Every session drops at once, and every tunnel comes back
+        network.cut();
+        forwards_established(&mut events, tunnels as usize).await;
+        assert!(server.sessions() >= 2 * tunnels as u64);
+        assert_eq!(echo(&network, 8007, b"pong").await.unwrap(), b"pong");
+
+        // Traffic is held back during a partition and delivered once it heals
+        let mut peer = network.dial("ssh.sim", 8001).await.unwrap();
+        network.partition();
+        assert!(network.dial("ssh.sim", 8001).await.is_err());
+        peer.write_all(b"held").await.unwrap();
+        let mut reply = [0u8; 4];
+        let read = tokio::time::timeout(Duration::from_millis(50), peer.read_exact(&mut reply));
+        assert!(read.await.is_err());
+        network.heal();
+        peer.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"held");
+
+        // Each hop of the round trip pays the latency
+        network.set_latency(Duration::from_millis(20));
+        let started = Instant::now();
+        echo(&network, 8005, b"slow").await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        manager.shutdown(Duration::ZERO).await;
+    }
+}
@@ -0,0 +1,282 @@
+//! Known-good connection parameters, kept across runs
+//!
+//! With [`ReverseSshConfig::snapshot_path`](crate::ReverseSshConfig::snapshot_path),
+//! the parameters of the last session that came up are saved: the address the
+//! server's name resolved to, the host key algorithm the server used, and the ports
+//! it assigned to forwards requested on port 0. The next connect, in this run or the
+//! next one, dials that address without a DNS lookup, offers that algorithm first and
+//! asks for those ports again, so reconnects are faster and keep their ports. A
+//! parameter that stops working is dropped from the snapshot, and the connect goes
+//! on without it.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use russh::Preferred;
+use russh_keys::key;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+use crate::{targets, Forward};
+
+/// Parameters of the last session that came up
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// `host:port` of the server they apply to
+    pub server: String,
+    /// Address the connection to the server reached, when it was direct
+    pub address: Option<SocketAddr>,
+    /// Host key algorithm the server authenticated with, e.g. `ssh-ed25519`
+    pub host_key_algorithm: Option<String>,
+    /// Ports the server assigned to forwards requested on port 0
+    pub forwards: Vec<SnapshotForward>,
+}
+
+/// Port the server assigned to a forward requested on port 0
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotForward {
+    pub bind_address: String,
+    /// Local target of the forward, as [`Forward::target`] displays it
+    pub target: String,
+    pub port: u32,
+}
+
+impl Snapshot {
+    fn forward(&self, forward: &Forward) -> Option<&SnapshotForward> {
+        let target = forward.target().to_string();
+        self.forwards
+            .iter()
+            .find(|known| known.bind_address == forward.bind_address && known.target == target)
+    }
+}
+
+/// The saved snapshot and what the current session observed
+pub(crate) struct SnapshotStore {
+    path: Option<PathBuf>,
+    /// Known-good parameters, loaded on the first connect
+    known: Mutex<Option<Snapshot>>,
+    loaded: AtomicBool,
+    /// Parameters of the current session, saved once it is up
+    observed: Mutex<Snapshot>,
+}
+
+impl SnapshotStore {
+    pub(crate) fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            known: Mutex::new(None),
+            loaded: AtomicBool::new(false),
+            observed: Mutex::new(Snapshot::default()),
+        }
+    }
+
+    /// Start recording a session to `server`, returning the snapshot to prefer, if
+    /// there is one for that server
+    pub(crate) async fn begin(&self, server: &str) -> Option<Snapshot> {
+        *self.observed.lock().unwrap() = Snapshot {
+            server: server.to_string(),
+            ..Default::default()
+        };
+        let path = self.path.as_ref()?;
+        if !self.loaded.swap(true, Ordering::Relaxed) {
+            let loaded = match tokio::fs::read(path).await {
+                Ok(data) => serde_json::from_slice::<Snapshot>(&data)
+                    .inspect_err(|e| {
+                        warn!(target: targets::SESSION,
+                            "Ignoring unreadable snapshot {}: {}", path.display(), e);
+                    })
+                    .ok(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!(target: targets::SESSION,
+                        "Failed to read snapshot {}: {}", path.display(), e);
+                    None
+                }
+            };
+            *self.known.lock().unwrap() = loaded;
+        }
+        let known = self.known.lock().unwrap().clone()?;
+        if known.server != server {
+            debug!(target: targets::SESSION,
+                "Snapshot is for {}, not {}, ignoring it", known.server, server);
+            return None;
+        }
+        Some(known)
+    }
+
+    /// The known-good parameters, if any
+    pub(crate) fn known(&self) -> Option<Snapshot> {
+        self.known.lock().unwrap().clone()
+    }
+
+    /// Port to request for `forward` before asking the server to pick one
+    pub(crate) fn forward_port(&self, forward: &Forward) -> Option<u32> {
+        let known = self.known.lock().unwrap();
+        let known = known.as_ref()?;
+        (known.server == self.observed.lock().unwrap().server)
+            .then(|| known.forward(forward).map(|known| known.port))
+            .flatten()
+    }
+
+    pub(crate) fn observe_address(&self, address: Option<SocketAddr>) {
+        self.observed.lock().unwrap().address = address;
+    }
+
+    pub(crate) fn observe_host_key_algorithm(&self, algorithm: &str) {
+        self.observed.lock().unwrap().host_key_algorithm = Some(algorithm.to_string());
+    }
+
+    /// Record the port the server assigned to `forward`, requested on port 0
+    pub(crate) fn observe_forward(&self, forward: &Forward, port: u32) {
+        let mut observed = self.observed.lock().unwrap();
+        let target = forward.target().to_string();
+        observed
+            .forwards
+            .retain(|known| known.bind_address != forward.bind_address || known.target != target);
+        observed.forwards.push(SnapshotForward {
+            bind_address: forward.bind_address.clone(),
+            target,
+            port,
+        });
+    }
+
+    /// Drop the known address, which no longer takes connections
+    pub(crate) async fn forget_address(&self, metrics: &Metrics) {
+        self.forget(metrics, "address", |known| known.address = None)
+            .await;
+    }
+
+    /// Drop the known port of `forward`, which the server no longer grants
+    pub(crate) async fn forget_forward(&self, forward: &Forward, metrics: &Metrics) {
+        let target = forward.target().to_string();
+        self.forget(metrics, "forward port", |known| {
+            known.forwards.retain(|known| {
+                known.bind_address != forward.bind_address || known.target != target
+            })
+        })
+        .await;
+    }
+
+    async fn forget(&self, metrics: &Metrics, what: &str, change: impl FnOnce(&mut Snapshot)) {
+        let snapshot = {
+            let mut known = self.known.lock().unwrap();
+            let Some(known) = known.as_mut() else {
+                return;
+            };
+            change(known);
+            known.clone()
+        };
+        warn!(target: targets::SESSION, "Snapshot {} stopped working, dropped it", what);
+        metrics.increment("snapshot_invalidations_total", 1);
+        self.write(&snapshot).await;
+    }
+
+    /// Make what the current session observed the known-good parameters
+    pub(crate) async fn save(&self) {
+        if self.path.is_none() {
+            return;
+        }
+        let snapshot = self.observed.lock().unwrap().clone();
+        *self.known.lock().unwrap() = Some(snapshot.clone());
+        self.write(&snapshot).await;
+    }
+
+    async fn write(&self, snapshot: &Snapshot) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let data = serde_json::to_vec_pretty(snapshot).expect("snapshots serialize");
+        if let Err(e) = tokio::fs::write(path, data).await {
+            warn!(target: targets::SESSION, "Failed to save snapshot {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// `preferred` with the host key algorithm of `snapshot` offered first, if it is
+/// offered at all
+pub(crate) fn preferred(mut preferred: Preferred, snapshot: Option<&Snapshot>) -> Preferred {
+    let algorithm = snapshot.and_then(|snapshot| snapshot.host_key_algorithm.as_deref());
+    if let Some(position) = preferred
+        .key
+        .iter()
+        .position(|name| Some(name.0) == algorithm)
+    {
+        let mut keys: Vec<key::Name> = preferred.key.to_vec();
+        let known = keys.remove(position);
+        keys.insert(0, known);
+        preferred.key = keys.into();
+    }
+    preferred
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sim, ReverseSshClient, ReverseSshConfig, TunnelEvent, TunnelState};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_snapshot_keeps_assigned_ports() {
+        let path = std::env::temp_dir().join(format!("rrp-snapshot-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let config = ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 0,
+            snapshot_path: Some(path.clone()),
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        };
+        async fn run_once(config: &ReverseSshConfig) -> u32 {
+            let mut client = ReverseSshClient::new(config.clone());
+            let handle = client.handle();
+            let mut events = client.subscribe();
+            tokio::spawn(async move { client.run().await });
+            // The snapshot is saved before the tunnel is ready
+            while !matches!(
+                events.recv().await,
+                Ok(TunnelEvent::StateChanged {
+                    to: TunnelState::Ready,
+                    ..
+                })
+            ) {}
+            let port = handle.forwards()[0].remote_port;
+            handle.shutdown().await.unwrap();
+            port
+        }
+
+        let first = run_once(&config).await;
+        let saved: Snapshot = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.server, "ssh.sim:22");
+        assert_eq!(saved.forwards[0].port, first);
+        assert!(saved.host_key_algorithm.is_some());
+        let preferred = preferred(Preferred::default(), Some(&saved));
+        assert_eq!(
+            Some(preferred.key[0].0),
+            saved.host_key_algorithm.as_deref()
+        );
+
+        // Wait for the server to let go of the first session's forward
+        while network.listen("ssh.sim", first as u16).is_err() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(run_once(&config).await, first);
+
+        // A port the server no longer grants is dropped, and it picks another one
+        let _taken = loop {
+            match network.listen("ssh.sim", first as u16) {
+                Ok(listener) => break listener,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        let third = run_once(&config).await;
+        assert_ne!(third, first);
+        let saved: Snapshot = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved.forwards[0].port, third);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,193 @@
+//! SOCKS5 proxy over the SSH session, like `ssh -D`
+//!
+//! With [`ReverseSshConfig::dynamic_forward`](crate::ReverseSshConfig::dynamic_forward)
+//! set, the client listens for SOCKS5 clients while the session is up and opens a
+//! `direct-tcpip` channel for each `CONNECT`, so the server makes the outbound
+//! connection. Only unauthenticated `CONNECT` requests are supported, which is what
+//! browsers and `curl --socks5-hostname` use.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::handle::Shared;
+use crate::{fds, targets, tasks};
+
+pub(crate) const VERSION: u8 = 5;
+pub(crate) const NO_AUTHENTICATION: u8 = 0;
+pub(crate) const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+pub(crate) const CONNECT: u8 = 1;
+pub(crate) const ATYP_IPV4: u8 = 1;
+pub(crate) const ATYP_DOMAIN: u8 = 3;
+pub(crate) const ATYP_IPV6: u8 = 4;
+
+/// Reply codes of RFC 1928
+pub(crate) const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Accept SOCKS5 clients on `listener` until the task is aborted
+pub(crate) async fn serve(listener: TcpListener, shared: Arc<Shared>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Accepting again right away would fail the same way
+            Err(e) if fds::is_exhaustion(&e) => {
+                shared.fd_exhausted();
+                shared.clock.sleep(shared.fd_pressure.remaining()).await;
+                continue;
+            }
+            Err(e) => {
+                debug!(target: targets::PROXY, "Failed to accept SOCKS client: {}", e);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        tasks::spawn(&format!("socks client {}", peer), async move {
+            if let Err(e) = connect(stream, peer, &shared).await {
+                debug!(target: targets::PROXY, "SOCKS request from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serve one SOCKS client: read its request, open the channel and relay
+async fn connect(mut stream: TcpStream, peer: SocketAddr, shared: &Shared) -> Result<()> {
+    let (host, port) = handshake(&mut stream).await?;
+    let opened = {
+        let session = shared.session.lock().await;
+        let session = session
+            .as_ref()
+            .context("Not connected - call connect() first")?;
+        session
+            .channel_open_direct_tcpip(
+                host.as_str(),
+                port.into(),
+                peer.ip().to_string(),
+                peer.port().into(),
+            )
+            .await
+    };
+    let channel = match opened {
+        Ok(channel) => channel,
+        Err(e) => {
+            reply(&mut stream, GENERAL_FAILURE).await?;
+            return Err(e)
+                .with_context(|| format!("Server could not connect to {}:{}", host, port));
+        }
+    };
+    reply(&mut stream, SUCCEEDED).await?;
+    info!(target: targets::PROXY, "SOCKS connection from {} to {}:{}", peer, host, port);
+    shared.metrics.increment("socks_connections_total", 1);
+
+    let mut channel = channel.into_stream();
+    tokio::io::copy_bidirectional(&mut stream, &mut channel).await?;
+    Ok(())
+}
+
+/// Negotiate the method and read a `CONNECT` request, returning its destination
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(String, u16)> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        bail!("Not a SOCKS5 client (version {})", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        bail!("SOCKS client requires authentication");
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let [_, command, _, address_type] = request;
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).context("Invalid SOCKS domain name")?
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            reply(stream, ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            bail!("Unsupported SOCKS address type {}", address_type);
+        }
+    };
+    let port = stream.read_u16().await?;
+    if command != CONNECT {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        bail!("Unsupported SOCKS command {}", command);
+    }
+    Ok((host, port))
+}
+
+/// Answer a request. The bound address is not meaningful through a tunnel, so it is
+/// always reported as 0.0.0.0:0.
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        client.write_all(&[5, 2, 2, 0]).await.unwrap();
+        client
+            .write_all(&[5, CONNECT, 0, ATYP_DOMAIN, 11])
+            .await
+            .unwrap();
+        client.write_all(b"example.com\x01\xbb").await.unwrap();
+        let destination = handshake(&mut server).await.unwrap();
+        assert_eq!(destination, ("example.com".to_string(), 443));
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, NO_AUTHENTICATION]);
+
+        assert_eq!(
+            handshake_with(&[5, 1, 0, 5, CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+                .await
+                .unwrap(),
+            ("10.0.0.1".to_string(), 80)
+        );
+        assert_eq!(
+            handshake_with(&[5, 1, 2]).await.unwrap_err().to_string(),
+            "SOCKS client requires authentication"
+        );
+        assert_eq!(
+            handshake_with(&[5, 1, 0, 5, 2, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+                .await
+                .unwrap_err()
+                .to_string(),
+            "Unsupported SOCKS command 2"
+        );
+    }
+
+    async fn handshake_with(bytes: &[u8]) -> Result<(String, u16)> {
+        let (mut client, mut server) = tokio::io::duplex(256);
+        client.write_all(bytes).await.unwrap();
+        handshake(&mut server).await
+    }
+}
@@ -0,0 +1,211 @@
+//! Per-originator traffic statistics
+//!
+//! Every finished forwarded connection is logged with the address of the remote peer
+//! that opened it. [`ReverseSshClient::originator_stats`](crate::ReverseSshClient::originator_stats)
+//! rolls the log up over a sliding window, showing which callers generate most traffic.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+
+use crate::Clock;
+
+/// How long finished connections are kept for rollups
+const RETENTION: Duration = Duration::from_secs(3600);
+/// Upper bound on logged connections, whatever their age
+const MAX_RECORDS: usize = 100_000;
+
+/// Traffic from one originator address within a window
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OriginatorStats {
+    /// Address of the remote peer, as reported by the SSH server
+    pub originator: String,
+    /// Connections that finished within the window
+    pub connections: u64,
+    /// Bytes received from the originator and passed to the local service
+    pub bytes_received: u64,
+    /// Bytes sent back to the originator
+    pub bytes_sent: u64,
+    /// Connections that ended with an error
+    pub errors: u64,
+}
+
+impl OriginatorStats {
+    /// Fraction of connections that ended with an error
+    pub fn error_rate(&self) -> f64 {
+        if self.connections == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.connections as f64
+        }
+    }
+}
+
+/// Bytes moved in each direction by one connection
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrafficCounters {
+    pub(crate) received: Arc<AtomicU64>,
+    pub(crate) sent: Arc<AtomicU64>,
+    /// Bytes moved in either direction by all connections of the client
+    pub(crate) total: Arc<AtomicU64>,
+}
+
+impl TrafficCounters {
+    pub(crate) fn new(total: Arc<AtomicU64>) -> Self {
+        Self {
+            total,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConnectionRecord {
+    finished: Instant,
+    originator: String,
+    bytes_received: u64,
+    bytes_sent: u64,
+    failed: bool,
+}
+
+/// Log of recently finished connections
+#[derive(Debug)]
+pub(crate) struct OriginatorLog {
+    records: Mutex<VecDeque<ConnectionRecord>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl OriginatorLog {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            records: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Log a finished connection
+    pub(crate) fn record(&self, originator: &str, counters: &TrafficCounters, failed: bool) {
+        let now = self.clock.now();
+        let mut records = self.records.lock().unwrap();
+        while records.len() >= MAX_RECORDS
+            || records
+                .front()
+                .is_some_and(|r| now.duration_since(r.finished) > RETENTION)
+        {
+            records.pop_front();
+        }
+        records.push_back(ConnectionRecord {
+            finished: now,
+            originator: originator.to_string(),
+            bytes_received: counters.received.load(Ordering::Relaxed),
+            bytes_sent: counters.sent.load(Ordering::Relaxed),
+            failed,
+        });
+    }
+
+    /// Roll up connections that finished within `window`, busiest originator first
+    pub(crate) fn stats(&self, window: Duration) -> Vec<OriginatorStats> {
+        let now = self.clock.now();
+        let records = self.records.lock().unwrap();
+        let mut by_originator: HashMap<&str, OriginatorStats> = HashMap::new();
+        for record in records
+            .iter()
+            .rev()
+            .take_while(|r| now.duration_since(r.finished) <= window)
+        {
+            let stats =
+                by_originator
+                    .entry(&record.originator)
+                    .or_insert_with(|| OriginatorStats {
+                        originator: record.originator.clone(),
+                        ..Default::default()
+                    });
+            stats.connections += 1;
+            stats.bytes_received += record.bytes_received;
+            stats.bytes_sent += record.bytes_sent;
+            stats.errors += u64::from(record.failed);
+        }
+        let mut stats: Vec<_> = by_originator.into_values().collect();
+        stats.sort_by(|a, b| {
+            (b.bytes_received + b.bytes_sent)
+                .cmp(&(a.bytes_received + a.bytes_sent))
+                .then_with(|| a.originator.cmp(&b.originator))
+        });
+        stats
+    }
+}
+
+/// Writer that adds the number of bytes written to a shared counter
+pub(crate) struct Counted<W> {
+    inner: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W> Counted<W> {
+    pub(crate) fn new(inner: W, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.count.fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_rollup_by_originator() {
+        let clock = Arc::new(ManualClock::new());
+        let log = OriginatorLog::new(clock.clone());
+        let busy = TrafficCounters::default();
+        let mut writer = Counted::new(Vec::new(), busy.received.clone());
+        writer.write_all(&[0; 5000]).await.unwrap();
+        busy.sent.store(100, Ordering::Relaxed);
+        log.record("203.0.113.7", &busy, false);
+        log.record("203.0.113.7", &TrafficCounters::default(), true);
+        log.record("198.51.100.2", &TrafficCounters::default(), false);
+
+        let stats = log.stats(Duration::from_secs(60));
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].originator, "203.0.113.7");
+        assert_eq!(stats[0].connections, 2);
+        assert_eq!(stats[0].bytes_received, 5000);
+        assert_eq!(stats[0].bytes_sent, 100);
+        assert_eq!(stats[0].error_rate(), 0.5);
+        assert_eq!(stats[1].originator, "198.51.100.2");
+
+        // Connections drop out of the window as the clock moves on
+        clock.advance(Duration::from_secs(30));
+        log.record("198.51.100.2", &TrafficCounters::default(), false);
+        clock.advance(Duration::from_secs(31));
+        let stats = log.stats(Duration::from_secs(60));
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].connections, 1);
+    }
+}
@@ -0,0 +1,255 @@
+//! Tunnel status for health endpoints and dashboards
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::provider::TunnelInfo;
+use crate::report::ErrorEvent;
+use crate::Clock;
+
+/// Where the tunnel is in its lifecycle
+///
+/// ```text
+/// Idle -> Connecting -> Authenticating -> Establishing -> Ready <-> Degraded
+///                             ^                             |          |
+///                             +------- Reconnecting <-------+----------+
+/// ```
+///
+/// Any state can move to `Stopped`, and a stopped tunnel comes back up through
+/// `Reconnecting`. Every change is published as [`TunnelEvent::StateChanged`](crate::TunnelEvent::StateChanged).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TunnelState {
+    /// Created, `connect()` not called yet
+    #[default]
+    Idle,
+    /// Connecting to the SSH server for the first time
+    Connecting,
+    /// Authenticating to the SSH server
+    Authenticating,
+    /// Requesting the remote port forward
+    Establishing,
+    /// Forwarding connections
+    Ready,
+    /// Forwarding, but the latest forwarded connection failed (e.g. the local service
+    /// is down). Back to `Ready` after a connection succeeds.
+    Degraded,
+    /// Connecting to the SSH server again after a previous attempt or session
+    Reconnecting,
+    /// The session ended, was shut down or failed to come up
+    Stopped,
+}
+
+impl TunnelState {
+    /// Whether the state machine allows moving from `self` to `next`
+    pub fn can_transition_to(self, next: TunnelState) -> bool {
+        use TunnelState::*;
+        matches!(
+            (self, next),
+            (_, Stopped)
+                | (Idle, Connecting)
+                | (Connecting | Reconnecting, Authenticating)
+                | (Authenticating, Establishing)
+                | (Establishing | Degraded, Ready)
+                | (Ready, Degraded)
+                | (Ready | Degraded | Stopped, Reconnecting)
+        )
+    }
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TunnelState::Idle => "idle",
+            TunnelState::Connecting => "connecting",
+            TunnelState::Authenticating => "authenticating",
+            TunnelState::Establishing => "establishing",
+            TunnelState::Ready => "ready",
+            TunnelState::Degraded => "degraded",
+            TunnelState::Reconnecting => "reconnecting",
+            TunnelState::Stopped => "stopped",
+        })
+    }
+}
+
+/// Point-in-time status of a tunnel
+#[derive(Debug, Clone, Default)]
+pub struct TunnelStatus {
+    pub state: TunnelState,
+    /// Time since the current session was authenticated, if there is one
+    pub uptime: Option<Duration>,
+    /// Sessions established after the first one
+    pub reconnects: u64,
+    /// The most recent error, if any
+    pub last_error: Option<ErrorEvent>,
+    /// The latest URL announced by the server
+    pub url: Option<String>,
+    /// Remote ports forwarded on the current session
+    pub forwards: Vec<u32>,
+    /// The [`AuthMethod`](crate::AuthMethod) the server accepted, by name
+    pub auth_method: Option<&'static str>,
+    /// Compression the session negotiated, `zlib@openssh.com` or `none`; see
+    /// [`compression`](crate::ReverseSshConfig::compression)
+    pub compression: Option<&'static str>,
+    /// Whether the local target passes its health checks, if any are configured
+    pub target_healthy: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    state: TunnelState,
+    session_started: Option<Instant>,
+    sessions: u64,
+    last_error: Option<ErrorEvent>,
+    info: Option<TunnelInfo>,
+    auth_method: Option<&'static str>,
+    compression: Option<&'static str>,
+    target_healthy: Option<bool>,
+}
+
+/// Lifecycle bookkeeping behind [`TunnelStatus`]
+#[derive(Debug)]
+pub(crate) struct StatusTracker {
+    inner: Mutex<Inner>,
+    clock: Arc<dyn Clock>,
+}
+
+impl StatusTracker {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Move to `state`, returning the previous state, or the current one as an error
+    /// if the transition isn't allowed. Staying in the same state is allowed.
+    pub(crate) fn transition(&self, state: TunnelState) -> Result<TunnelState, TunnelState> {
+        let mut inner = self.inner.lock().unwrap();
+        let previous = inner.state;
+        if previous == state {
+            return Ok(previous);
+        }
+        if !previous.can_transition_to(state) {
+            return Err(previous);
+        }
+        inner.state = state;
+        match state {
+            TunnelState::Establishing if inner.session_started.is_none() => {
+                inner.session_started = Some(self.clock.now());
+                inner.sessions += 1;
+            }
+            TunnelState::Stopped => inner.session_started = None,
+            _ => {}
+        }
+        Ok(previous)
+    }
+
+    pub(crate) fn state(&self) -> TunnelState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub(crate) fn set_auth_method(&self, method: &'static str) {
+        self.inner.lock().unwrap().auth_method = Some(method);
+    }
+
+    pub(crate) fn auth_method(&self) -> Option<&'static str> {
+        self.inner.lock().unwrap().auth_method
+    }
+
+    pub(crate) fn set_compression(&self, compression: Option<&'static str>) {
+        self.inner.lock().unwrap().compression = compression;
+    }
+
+    pub(crate) fn set_target_healthy(&self, healthy: Option<bool>) {
+        self.inner.lock().unwrap().target_healthy = healthy;
+    }
+
+    pub(crate) fn target_healthy(&self) -> Option<bool> {
+        self.inner.lock().unwrap().target_healthy
+    }
+
+    pub(crate) fn set_error(&self, error: &ErrorEvent) {
+        self.inner.lock().unwrap().last_error = Some(error.clone());
+    }
+
+    /// Remember the latest tunnel details. Returns `None` if they didn't change,
+    /// otherwise the details they replace.
+    pub(crate) fn set_info(&self, info: TunnelInfo) -> Option<Option<TunnelInfo>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.info.as_ref() == Some(&info) {
+            return None;
+        }
+        Some(inner.info.replace(info))
+    }
+
+    pub(crate) fn info(&self) -> Option<TunnelInfo> {
+        self.inner.lock().unwrap().info.clone()
+    }
+
+    pub(crate) fn status(&self, forwards: Vec<u32>) -> TunnelStatus {
+        let inner = self.inner.lock().unwrap();
+        TunnelStatus {
+            state: inner.state,
+            uptime: inner
+                .session_started
+                .map(|started| self.clock.now() - started),
+            reconnects: inner.sessions.saturating_sub(1),
+            last_error: inner.last_error.clone(),
+            url: inner.info.as_ref().map(|info| info.url.clone()),
+            forwards,
+            auth_method: inner.auth_method,
+            compression: inner.compression,
+            target_healthy: inner.target_healthy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_status_across_sessions() {
+        let clock = Arc::new(ManualClock::new());
+        let tracker = StatusTracker::new(clock.clone());
+        assert_eq!(tracker.status(Vec::new()).state, TunnelState::Idle);
+
+        for first in [TunnelState::Connecting, TunnelState::Reconnecting] {
+            tracker.transition(first).unwrap();
+            tracker.transition(TunnelState::Authenticating).unwrap();
+            tracker.transition(TunnelState::Establishing).unwrap();
+            tracker.transition(TunnelState::Ready).unwrap();
+            clock.advance(Duration::from_secs(90));
+            assert_eq!(
+                tracker.status(vec![80]).uptime,
+                Some(Duration::from_secs(90))
+            );
+            tracker.transition(TunnelState::Stopped).unwrap();
+        }
+        assert_eq!(
+            tracker.transition(TunnelState::Ready),
+            Err(TunnelState::Stopped)
+        );
+        let info = TunnelInfo {
+            url: "https://abc123.lhr.life".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(tracker.set_info(info.clone()), Some(None));
+        assert_eq!(tracker.set_info(info.clone()), None);
+        let moved = TunnelInfo {
+            url: "https://def456.lhr.life".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(tracker.set_info(moved), Some(Some(info.clone())));
+        tracker.set_info(info);
+
+        let status = tracker.status(Vec::new());
+        assert_eq!(status.state, TunnelState::Stopped);
+        assert_eq!(status.uptime, None);
+        assert_eq!(status.reconnects, 1);
+        assert_eq!(status.url.as_deref(), Some("https://abc123.lhr.life"));
+    }
+}
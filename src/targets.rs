@@ -0,0 +1,79 @@
+//! Tracing targets, one per subsystem
+//!
+//! Every log line of the crate is emitted with one of these targets instead of its
+//! module path, so a subsystem can be turned up on its own:
+//!
+//! ```text
+//! RUST_LOG=rrp::proxy=trace,rrp=info rrp run --local-port 8080
+//! ```
+//!
+//! The spans [`TunnelManager`](crate::TunnelManager) wraps each tunnel in use the
+//! `rrp` target, so they stay visible whenever any subsystem is enabled at `info`.
+
+/// Authentication methods tried and their outcome
+pub const AUTH: &str = "rrp::auth";
+/// Connecting to the server, requesting forwards and lifecycle state changes
+pub const SESSION: &str = "rrp::session";
+/// Relaying forwarded connections, including HTTP-aware forwarding
+pub const PROXY: &str = "rrp::proxy";
+/// Server messages, tunnel URLs and provider refusals
+pub const PROVIDER: &str = "rrp::provider";
+/// What the server prints on the session channel, line by line, when a
+/// [`TunnelManager`](crate::TunnelManager) runs the tunnel
+pub const SERVER: &str = "rrp::server";
+/// Keepalives, dead sessions and restarts
+pub const RECONNECT: &str = "rrp::reconnect";
+/// Health checks of the local target and alerts
+pub const HEALTH: &str = "rrp::health";
+/// Reading profiles files
+pub const CONFIG: &str = "rrp::config";
+/// [`SecurityEvent`](crate::SecurityEvent)s: rejected connections, failed
+/// authentication, host key mismatches
+pub const SECURITY: &str = "rrp::security";
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    /// Collect the `.rs` files below `dir`
+    fn sources(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                sources(&path, files);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                files.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_log_line_has_a_target() {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut files = Vec::new();
+        sources(&src, &mut files);
+        // The binary logs under its own name, and this file names the macros it looks for
+        files.retain(|path| !path.starts_with(src.join("bin")) && !path.ends_with("targets.rs"));
+
+        let mut missing = Vec::new();
+        for path in files {
+            let text = std::fs::read_to_string(&path).unwrap();
+            for macro_name in [
+                "trace!(", "debug!(", "info!(", "warn!(", "error!(", "_span!(",
+            ] {
+                for (offset, _) in text.match_indices(macro_name) {
+                    let args = text[offset + macro_name.len()..].trim_start();
+                    if !args.starts_with("target:") {
+                        let line = text[..offset].lines().count();
+                        missing.push(format!("{}:{}", path.display(), line));
+                    }
+                }
+            }
+        }
+        assert!(
+            missing.is_empty(),
+            "Log lines without a target: {:?}",
+            missing
+        );
+    }
+}
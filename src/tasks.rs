@@ -0,0 +1,28 @@
+//! Spawning the client's background tasks under names tokio-console shows
+//!
+//! With the `console` feature and `RUSTFLAGS="--cfg tokio_unstable"`, tokio records
+//! its tasks for [tokio-console](https://github.com/tokio-rs/console) and every task
+//! the client spawns carries a name: the connection id and target for connections,
+//! what it watches for the others. Otherwise tasks are spawned as usual.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Spawn `task` in the current span, named `name` when tasks are instrumented
+#[cfg_attr(not(all(tokio_unstable, feature = "console")), allow(unused_variables))]
+pub(crate) fn spawn<F>(name: &str, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let task = task.in_current_span();
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(task)
+        .expect("failed to spawn task");
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    tokio::spawn(task)
+}
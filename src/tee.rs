@@ -0,0 +1,231 @@
+//! Copies of relayed bytes for user-provided sinks
+//!
+//! [`ClientHandle::tee`](crate::ClientHandle::tee) attaches an [`AsyncWrite`] to one
+//! direction of a forward, for recording or analysis. Connections the forward accepts
+//! from then on copy the bytes they relay in that direction to it, interleaved in the
+//! order they are written. Copies are queued for a task writing to the sink, up to
+//! [`TEE_CAPACITY`] chunks; while the queue is full they are dropped and counted in
+//! `tee_dropped_bytes_total`, so a slow sink never holds the tunnel back.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::{targets, tasks};
+
+/// Chunks queued per sink before copies are dropped
+pub const TEE_CAPACITY: usize = 256;
+
+/// Direction of the bytes a tee copies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TeeDirection {
+    /// From the remote peer, through the tunnel, to the local service
+    Inbound,
+    /// From the local service, through the tunnel, to the remote peer
+    Outbound,
+}
+
+/// Sending side of an attached sink
+#[derive(Clone)]
+pub(crate) struct TeeSink {
+    tx: mpsc::Sender<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl TeeSink {
+    /// Queue a copy of `bytes`, or count them as dropped if the sink is behind
+    fn copy(&self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if self.tx.try_send(bytes.to_vec()).is_err() {
+            self.dropped
+                .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sinks attached to the forwards, by the port the server listens on and direction
+#[derive(Default)]
+pub(crate) struct Tees {
+    sinks: Mutex<HashMap<(u32, TeeDirection), TeeSink>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tees {
+    /// Attach `sink`, replacing the one attached to the same port and direction
+    pub(crate) fn attach<S>(&self, remote_port: u32, direction: TeeDirection, mut sink: S)
+    where
+        S: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(TEE_CAPACITY);
+        let name = format!("tee {} {:?}", remote_port, direction);
+        tasks::spawn(&name, async move {
+            // Ends once the sink is detached and the connections using it are closed
+            while let Some(chunk) = rx.recv().await {
+                if let Err(e) = sink.write_all(&chunk).await {
+                    debug!(target: targets::PROXY,
+                        "Stopped copying port {} {:?} to its sink: {}", remote_port, direction, e);
+                    return;
+                }
+            }
+            let _ = sink.shutdown().await;
+        });
+        let sink = TeeSink {
+            tx,
+            dropped: self.dropped.clone(),
+        };
+        self.sinks
+            .lock()
+            .unwrap()
+            .insert((remote_port, direction), sink);
+    }
+
+    /// Detach the sink of `remote_port` and `direction`, if any
+    pub(crate) fn detach(&self, remote_port: u32, direction: TeeDirection) -> bool {
+        self.sinks
+            .lock()
+            .unwrap()
+            .remove(&(remote_port, direction))
+            .is_some()
+    }
+
+    pub(crate) fn sink(&self, remote_port: u32, direction: TeeDirection) -> Option<TeeSink> {
+        self.sinks
+            .lock()
+            .unwrap()
+            .get(&(remote_port, direction))
+            .cloned()
+    }
+
+    /// Bytes not copied because their sink was behind
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Writer that copies the bytes written to a sink, if one is attached
+pub(crate) struct Teed<W> {
+    inner: W,
+    sink: Option<TeeSink>,
+}
+
+impl<W> Teed<W> {
+    pub(crate) fn new(inner: W, sink: Option<TeeSink>) -> Self {
+        Self { inner, sink }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Teed<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if let Some(sink) = &self.sink {
+            sink.copy(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_tee_copies_without_stalling() {
+        let tees = Tees::default();
+        let (sink, mut recording) = tokio::io::duplex(64);
+        tees.attach(5432, TeeDirection::Inbound, sink);
+        assert!(tees.sink(5432, TeeDirection::Outbound).is_none());
+
+        let mut writer = Teed::new(Vec::new(), tees.sink(5432, TeeDirection::Inbound));
+        writer.write_all(b"SELECT 1;").await.unwrap();
+        let mut copy = [0u8; 9];
+        recording.read_exact(&mut copy).await.unwrap();
+        assert_eq!(&copy, b"SELECT 1;");
+
+        // Nobody reads the recording any more: the writer goes on, copies are dropped
+        for _ in 0..TEE_CAPACITY + 100 {
+            writer.write_all(&[0; 16]).await.unwrap();
+        }
+        assert_eq!(writer.inner.len(), 9 + (TEE_CAPACITY + 100) * 16);
+        assert!(tees.dropped() > 0);
+
+        // Once detached and the writer is gone, the recording ends
+        assert!(tees.detach(5432, TeeDirection::Inbound));
+        drop(writer);
+        let mut rest = Vec::new();
+        recording.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(
+            rest.len() as u64 + tees.dropped(),
+            (TEE_CAPACITY as u64 + 100) * 16
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tee_on_a_port_the_server_picked() {
+        use crate::{sim, Forward, ReverseSshClient, ReverseSshConfig, TunnelEvent, TunnelState};
+
+        let network = sim::SimNetwork::new();
+        let _server = sim::SimServer::start(&network, "ssh.sim", 22).unwrap();
+        let mut service = network.listen("127.0.0.1", 5432).unwrap();
+        tokio::spawn(async move {
+            while let Some((stream, _)) = service.accept().await {
+                tokio::spawn(async move {
+                    let (mut rx, mut tx) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut rx, &mut tx).await;
+                });
+            }
+        });
+        let mut client = ReverseSshClient::new(ReverseSshConfig {
+            server_addr: "ssh.sim".to_string(),
+            remote_port: 80,
+            network: Arc::new(network.clone()),
+            ..Default::default()
+        });
+        let handle = client.handle();
+        let mut events = client.subscribe();
+        tokio::spawn(async move { client.run().await });
+        while !matches!(
+            events.recv().await,
+            Ok(TunnelEvent::StateChanged {
+                to: TunnelState::Ready,
+                ..
+            })
+        ) {}
+        let port = handle
+            .add_forward_to(Forward::new(0, "127.0.0.1", 5432))
+            .await
+            .unwrap();
+
+        let (sink, mut recording) = tokio::io::duplex(64);
+        handle.tee(port, TeeDirection::Inbound, sink);
+        let mut peer = network.dial("ssh.sim", port as u16).await.unwrap();
+        peer.write_all(b"SELECT 1;").await.unwrap();
+        let mut echo = [0u8; 9];
+        peer.read_exact(&mut echo).await.unwrap();
+        let mut copy = [0u8; 9];
+        recording.read_exact(&mut copy).await.unwrap();
+        assert_eq!(&copy, b"SELECT 1;");
+        handle.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! Startup timeline
+//!
+//! Records when each phase of bringing a tunnel up completed, relative to the call to
+//! `connect()`, so a slow provider can be told apart from a slow network or a slow
+//! local service.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::events::{Events, TunnelEvent};
+use crate::{targets, Clock};
+
+/// A step of bringing a tunnel up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StartupPhase {
+    /// TCP connection to the SSH server established
+    TcpConnect,
+    /// Key exchange finished and the server key checked
+    KeyExchange,
+    /// Authentication accepted
+    Authentication,
+    /// Remote port forward acknowledged by the server
+    ForwardAck,
+    /// First server message containing a URL
+    FirstUrl,
+    /// First forwarded connection received
+    FirstConnection,
+}
+
+/// Time from the call to `connect()` to the end of each startup phase. Phases that
+/// haven't completed (yet) are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartupTimeline {
+    /// When `connect()` was called
+    pub started_at: Option<SystemTime>,
+    pub tcp_connect: Option<Duration>,
+    pub key_exchange: Option<Duration>,
+    pub authentication: Option<Duration>,
+    pub forward_ack: Option<Duration>,
+    pub first_url: Option<Duration>,
+    pub first_connection: Option<Duration>,
+}
+
+impl StartupTimeline {
+    fn phase_mut(&mut self, phase: StartupPhase) -> &mut Option<Duration> {
+        match phase {
+            StartupPhase::TcpConnect => &mut self.tcp_connect,
+            StartupPhase::KeyExchange => &mut self.key_exchange,
+            StartupPhase::Authentication => &mut self.authentication,
+            StartupPhase::ForwardAck => &mut self.forward_ack,
+            StartupPhase::FirstUrl => &mut self.first_url,
+            StartupPhase::FirstConnection => &mut self.first_connection,
+        }
+    }
+}
+
+/// Timeline of the latest connection attempt
+#[derive(Debug)]
+pub(crate) struct StartupRecorder {
+    inner: Mutex<Option<(Instant, StartupTimeline)>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl StartupRecorder {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Start a new timeline, discarding the previous one
+    pub(crate) fn begin(&self) {
+        let timeline = StartupTimeline {
+            started_at: Some(SystemTime::now()),
+            ..Default::default()
+        };
+        *self.inner.lock().unwrap() = Some((self.clock.now(), timeline));
+    }
+
+    /// Record the end of `phase`, unless it was already recorded
+    pub(crate) fn mark(&self, phase: StartupPhase, events: &Events) {
+        let elapsed = {
+            let mut inner = self.inner.lock().unwrap();
+            let Some((started, timeline)) = inner.as_mut() else {
+                return;
+            };
+            let elapsed = self.clock.now() - *started;
+            let slot = timeline.phase_mut(phase);
+            if slot.is_some() {
+                return;
+            }
+            *slot = Some(elapsed);
+            elapsed
+        };
+        debug!(target: targets::SESSION, "Startup phase {:?} completed after {:?}", phase, elapsed);
+        events.emit(TunnelEvent::StartupPhase { phase, elapsed });
+    }
+
+    pub(crate) fn timeline(&self) -> StartupTimeline {
+        self.inner
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, timeline)| timeline.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+
+    #[test]
+    fn test_phases_recorded_once() {
+        let clock = Arc::new(ManualClock::new());
+        let recorder = StartupRecorder::new(clock.clone());
+        let events = Events::default();
+        let mut rx = events.subscribe();
+
+        // Nothing is recorded before connect() starts a timeline
+        recorder.mark(StartupPhase::TcpConnect, &events);
+        assert_eq!(recorder.timeline(), StartupTimeline::default());
+
+        recorder.begin();
+        recorder.mark(StartupPhase::TcpConnect, &events);
+        clock.advance(Duration::from_millis(10));
+        recorder.mark(StartupPhase::Authentication, &events);
+        recorder.mark(StartupPhase::TcpConnect, &events);
+
+        let timeline = recorder.timeline();
+        assert!(timeline.started_at.is_some());
+        assert_eq!(timeline.tcp_connect, Some(Duration::ZERO));
+        assert_eq!(timeline.authentication, Some(Duration::from_millis(10)));
+        assert_eq!(timeline.first_url, None);
+
+        let phases: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|event| match event {
+                TunnelEvent::StartupPhase { phase, .. } => Some(phase),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            phases,
+            vec![StartupPhase::TcpConnect, StartupPhase::Authentication]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Time limits on the steps of `connect()`
+//!
+//! An unreachable server can leave a TCP connect hanging for minutes, and a server
+//! that accepts connections but stalls (overloaded, or a middlebox swallowing the
+//! traffic) can leave the handshake or authentication hanging forever.
+//! [`connect_timeout`](crate::ReverseSshConfig::connect_timeout),
+//! [`handshake_timeout`](crate::ReverseSshConfig::handshake_timeout) and
+//! [`auth_timeout`](crate::ReverseSshConfig::auth_timeout) bound each step, failing
+//! `connect()` with a [`ConnectTimeout`] naming it.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::{Clock, StartupPhase};
+
+/// A step of `connect()` that took longer than its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectTimeout {
+    /// [`StartupPhase::TcpConnect`], [`StartupPhase::KeyExchange`] or
+    /// [`StartupPhase::Authentication`]
+    pub phase: StartupPhase,
+    /// The timeout that ran out
+    pub after: Duration,
+}
+
+impl fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let step = match self.phase {
+            StartupPhase::TcpConnect => "TCP connect",
+            StartupPhase::KeyExchange => "SSH handshake",
+            StartupPhase::Authentication => "authentication",
+            _ => "connect",
+        };
+        write!(f, "{} timed out after {:?}", step, self.after)
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// Run the `phase` step of `connect()`, for at most `limit` on `clock`
+pub(crate) async fn within<T>(
+    clock: &dyn Clock,
+    phase: StartupPhase,
+    limit: Option<Duration>,
+    step: impl Future<Output = T>,
+) -> Result<T, ConnectTimeout> {
+    let Some(limit) = limit else {
+        return Ok(step.await);
+    };
+    tokio::select! {
+        output = step => Ok(output),
+        _ = clock.sleep(limit) => Err(ConnectTimeout { phase, after: limit }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Connecting, Network};
+    use crate::{sim, ReverseSshClient, ReverseSshConfig};
+    use std::sync::Arc;
+
+    /// A network whose connections never complete, like packets dropped by a firewall
+    #[derive(Debug)]
+    struct Blackhole;
+
+    impl Network for Blackhole {
+        fn connect(&self, _: &str, _: u16) -> Connecting {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_steps_time_out() {
+        let connect = |network: Arc<dyn Network>| async move {
+            let config = ReverseSshConfig {
+                server_addr: "ssh.sim".to_string(),
+                connect_timeout: Some(Duration::from_millis(50)),
+                handshake_timeout: Some(Duration::from_millis(100)),
+                network,
+                ..Default::default()
+            };
+            let mut client = ReverseSshClient::new(config);
+            let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+            let (message_tx, _messages) = tokio::sync::mpsc::channel(16);
+            let error = client.connect_session(tx, message_tx).await.unwrap_err();
+            *error.downcast_ref::<ConnectTimeout>().unwrap()
+        };
+
+        let timeout = connect(Arc::new(Blackhole)).await;
+        assert_eq!(timeout.phase, StartupPhase::TcpConnect);
+        assert_eq!(timeout.to_string(), "TCP connect timed out after 50ms");
+
+        // The server takes the connection but never says a word
+        let network = sim::SimNetwork::new();
+        let _silent = network.listen("ssh.sim", 22).unwrap();
+        let timeout = connect(Arc::new(network)).await;
+        assert_eq!(timeout.phase, StartupPhase::KeyExchange);
+        assert_eq!(timeout.after, Duration::from_millis(100));
+    }
+}
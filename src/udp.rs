@@ -0,0 +1,320 @@
+//! UDP forwarding over the tunnel
+//!
+//! SSH only forwards TCP, so a UDP forward carries datagrams over forwarded
+//! connections as frames: a 2-byte big-endian length, then the datagram. On the
+//! server, a [`UdpHelper`] receives datagrams, opens one connection to the forwarded
+//! port per peer and frames them onto it. On the client, each such connection gets
+//! its own socket to the local UDP service, so replies find their way back to the
+//! peer that asked. Game servers and DNS resolvers can be exposed this way.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tracing::{debug, info, Instrument};
+
+use crate::clock::{self, Clock};
+use crate::forward::{LocalReader, LocalWriter};
+use crate::targets;
+
+/// Largest datagram a frame can carry
+const MAX_DATAGRAM: usize = u16::MAX as usize;
+/// Datagrams queued per peer while its connection is being opened
+const PEER_QUEUE: usize = 64;
+
+/// Open a socket to the UDP service at `addr:port`, returning halves that speak
+/// frames: reads yield the service's datagrams framed, writes are unframed and sent
+pub(crate) async fn connect(addr: &str, port: u16) -> Result<(LocalReader, LocalWriter)> {
+    let service = lookup_host((addr, port))
+        .await?
+        .next()
+        .with_context(|| format!("No address for {}", addr))?;
+    let bind: SocketAddr = if service.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(service).await?;
+    let socket = Arc::new(socket);
+    let reader = DatagramReader {
+        socket: socket.clone(),
+        buf: vec![0; 2 + MAX_DATAGRAM].into_boxed_slice(),
+        start: 0,
+        end: 0,
+    };
+    let writer = DatagramWriter {
+        socket,
+        pending: Vec::new(),
+    };
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+/// Reads datagrams from a connected socket as frames
+struct DatagramReader {
+    socket: Arc<UdpSocket>,
+    /// The current frame, of which `start..end` is still to be read
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl AsyncRead for DatagramReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.start == this.end {
+            let mut datagram = ReadBuf::new(&mut this.buf[2..]);
+            ready!(this.socket.poll_recv(cx, &mut datagram))?;
+            let len = datagram.filled().len();
+            this.buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+            this.start = 0;
+            this.end = 2 + len;
+        }
+        let n = out.remaining().min(this.end - this.start);
+        out.put_slice(&this.buf[this.start..this.start + n]);
+        this.start += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Collects frames and sends each as a datagram on a connected socket
+struct DatagramWriter {
+    socket: Arc<UdpSocket>,
+    /// Bytes of frames not sent yet
+    pending: Vec<u8>,
+}
+
+impl DatagramWriter {
+    /// Send every complete frame held
+    fn poll_send_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut sent = 0;
+        let result = loop {
+            let frame = &self.pending[sent..];
+            let Some(len) = frame_len(frame) else {
+                break Poll::Ready(Ok(()));
+            };
+            if frame.len() < 2 + len {
+                break Poll::Ready(Ok(()));
+            }
+            match self.socket.poll_send(cx, &frame[2..2 + len]) {
+                Poll::Ready(Ok(_)) => sent += 2 + len,
+                Poll::Ready(Err(e)) => break Poll::Ready(Err(e)),
+                Poll::Pending => break Poll::Pending,
+            }
+        };
+        self.pending.drain(..sent);
+        result
+    }
+}
+
+impl AsyncWrite for DatagramWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Hold at most one datagram back, so a socket that can't keep up slows the
+        // channel down instead of buffering without bound
+        if self.pending.len() > 2 + MAX_DATAGRAM {
+            ready!(self.poll_send_frames(cx))?;
+        }
+        self.pending.extend_from_slice(buf);
+        if let Poll::Ready(Err(e)) = self.poll_send_frames(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_send_frames(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_send_frames(cx)
+    }
+}
+
+/// Length of the datagram in the frame starting `bytes`, once its header is there
+fn frame_len(bytes: &[u8]) -> Option<usize> {
+    Some(u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) as usize)
+}
+
+/// Server side of a UDP forward: receives datagrams on `listen` and relays them over
+/// connections to `tunnel`, the port the SSH server forwards to the client
+#[derive(Debug, Clone)]
+pub struct UdpHelper {
+    pub listen: SocketAddr,
+    pub tunnel: SocketAddr,
+    /// Time after which a silent peer's connection is closed
+    pub idle_timeout: Duration,
+    /// The clock `idle_timeout` runs on
+    pub clock: Arc<dyn Clock>,
+}
+
+impl UdpHelper {
+    pub fn new(listen: SocketAddr, tunnel: SocketAddr) -> Self {
+        Self {
+            listen,
+            tunnel,
+            idle_timeout: Duration::from_secs(60),
+            clock: clock::system(),
+        }
+    }
+
+    /// Relay datagrams until an error occurs on the listening socket
+    pub async fn run(self) -> Result<()> {
+        let socket = Arc::new(
+            UdpSocket::bind(self.listen)
+                .await
+                .with_context(|| format!("Failed to listen on {}", self.listen))?,
+        );
+        info!(target: targets::PROXY,
+            "Relaying UDP datagrams on {} through {}",
+            self.listen, self.tunnel
+        );
+        let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let datagram = buf[..len].to_vec();
+            if let Some(tx) = peers.get(&peer).filter(|tx| !tx.is_closed()) {
+                // A full queue drops the datagram, as a congested network would
+                let _ = tx.try_send(datagram);
+                continue;
+            }
+            peers.retain(|_, tx| !tx.is_closed());
+            let (tx, rx) = mpsc::channel(PEER_QUEUE);
+            let _ = tx.try_send(datagram);
+            peers.insert(peer, tx);
+            let socket = socket.clone();
+            let (tunnel, idle_timeout) = (self.tunnel, self.idle_timeout);
+            let clock = self.clock.clone();
+            tokio::spawn(
+                async move {
+                    let relay = relay_peer(socket, peer, rx, tunnel, idle_timeout, clock);
+                    if let Err(e) = relay.await {
+                        debug!(target: targets::PROXY, "UDP relay for {} failed: {:#}", peer, e);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+}
+
+/// Frame the datagrams of `peer` onto a connection to `tunnel`, and send the
+/// datagrams framed on it back, until it closes or stays idle for `idle_timeout`
+async fn relay_peer(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    tunnel: SocketAddr,
+    idle_timeout: Duration,
+    clock: Arc<dyn Clock>,
+) -> Result<()> {
+    let stream = TcpStream::connect(tunnel)
+        .await
+        .with_context(|| format!("Failed to connect to {}", tunnel))?;
+    debug!(target: targets::PROXY, "Opened UDP relay for {}", peer);
+    let (mut rx, mut tx) = stream.into_split();
+    let replied = Arc::new(AtomicBool::new(false));
+    let mut replies = tokio::spawn({
+        let replied = replied.clone();
+        async move {
+            let mut header = [0u8; 2];
+            let mut reply = vec![0u8; MAX_DATAGRAM];
+            while rx.read_exact(&mut header).await.is_ok() {
+                let len = u16::from_be_bytes(header) as usize;
+                rx.read_exact(&mut reply[..len]).await?;
+                socket.send_to(&reply[..len], peer).await?;
+                replied.store(true, Ordering::Relaxed);
+            }
+            io::Result::Ok(())
+        }
+    });
+    let result = loop {
+        tokio::select! {
+            datagram = clock::timeout(&*clock, idle_timeout, datagrams.recv()) => match datagram {
+                Ok(Some(datagram)) => {
+                    tx.write_all(&(datagram.len() as u16).to_be_bytes()).await?;
+                    tx.write_all(&datagram).await?;
+                }
+                Ok(None) => break Ok(()),
+                // Quiet in both directions
+                Err(_) if !replied.swap(false, Ordering::Relaxed) => {
+                    debug!(target: targets::PROXY, "Closing idle UDP relay for {}", peer);
+                    break Ok(());
+                }
+                Err(_) => {}
+            },
+            result = &mut replies => {
+                result??;
+                break Ok(());
+            }
+        }
+    };
+    replies.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_udp_round_trip() {
+        // The local UDP service echoes datagrams
+        let service = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service_port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = service.recv_from(&mut buf).await.unwrap();
+                service.send_to(&buf[..len], from).await.unwrap();
+            }
+        });
+
+        // Stand-in for the forwarded port: relay each connection to the service as
+        // the client does
+        let tunnel = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tunnel_addr = tunnel.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = tunnel.accept().await.unwrap();
+            let (mut stream_rx, mut stream_tx) = stream.into_split();
+            let (mut rx, mut tx) = connect("127.0.0.1", service_port).await.unwrap();
+            tokio::spawn(async move { tokio::io::copy(&mut stream_rx, &mut tx).await });
+            tokio::io::copy(&mut rx, &mut stream_tx).await.unwrap();
+        });
+
+        let listen = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listen.local_addr().unwrap();
+        drop(listen);
+        tokio::spawn(UdpHelper::new(listen_addr, tunnel_addr).run());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for query in [&b"first"[..], &[], &b"third datagram"[..]] {
+            peer.send_to(query, listen_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..len], query);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Lower-level API that may change in any minor release
+//!
+//! Everything exported at the crate root (the client, its config and handle, events,
+//! status and the tunnel manager) follows semver. The items here expose internals,
+//! such as the individual steps of [`ReverseSshClient::run`] and the HTTP middleware
+//! hooks, and can change whenever those internals do. Pin an exact version when
+//! depending on them.
+//!
+//! ```no_run
+//! use reverse_ssh::unstable::RawSession;
+//! use reverse_ssh::{ReverseSshClient, ReverseSshConfig};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let mut client = ReverseSshClient::new(ReverseSshConfig::default());
+//! let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+//! let (message_tx, _messages) = tokio::sync::mpsc::channel(16);
+//! RawSession::connect(&mut client, tx, message_tx).await?;
+//! let port = RawSession::setup_reverse_tunnel(&mut client).await?;
+//! println!("Listening on port {}", port);
+//! RawSession::handle_forwarded_connections(&mut client, rx).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use async_trait::async_trait;
+use russh::client::Msg;
+use russh::Channel;
+use tokio::sync::mpsc;
+
+use crate::ReverseSshClient;
+
+pub use crate::http::{decode_chunked, RequestHead, RequestHook, ResponseHead};
+
+/// A connection the SSH server forwarded back to the client
+pub struct ForwardedConnection {
+    pub channel: Channel<Msg>,
+    /// Address the server accepted the connection on
+    pub connected_address: String,
+    /// Port the server accepted the connection on
+    pub connected_port: u32,
+    /// Address of the remote peer that opened the connection
+    pub originator_address: String,
+    /// Port of the remote peer that opened the connection
+    pub originator_port: u32,
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+impl sealed::Sealed for ReverseSshClient {}
+
+/// The steps of [`ReverseSshClient::run`], for driving a session one step at a time.
+/// Sealed, so steps can be added without breaking anyone.
+#[async_trait]
+pub trait RawSession: sealed::Sealed {
+    /// Connect to the SSH server and authenticate. Forwarded connections are sent to
+    /// `tx` and server messages to `message_tx`; messages that find it full are
+    /// dropped, as counted by [`TunnelEvent::MessagesDropped`](crate::TunnelEvent::MessagesDropped).
+    async fn connect(
+        &mut self,
+        tx: mpsc::UnboundedSender<ForwardedConnection>,
+        message_tx: mpsc::Sender<String>,
+    ) -> Result<()>;
+
+    /// Ask the server to listen on the configured remote port and open the session
+    /// channel. Returns the port the server listens on, which it picks when the
+    /// configured port is 0.
+    async fn setup_reverse_tunnel(&mut self) -> Result<u32>;
+
+    /// Open a throwaway shell channel to prompt the server for messages
+    async fn read_server_messages(&mut self) -> Result<Vec<String>>;
+
+    /// Proxy the connections received on `rx` until the session ends or the client
+    /// is shut down
+    async fn handle_forwarded_connections(
+        &mut self,
+        rx: mpsc::UnboundedReceiver<ForwardedConnection>,
+    ) -> Result<()>;
+}
+
+#[async_trait]
+impl RawSession for ReverseSshClient {
+    async fn connect(
+        &mut self,
+        tx: mpsc::UnboundedSender<ForwardedConnection>,
+        message_tx: mpsc::Sender<String>,
+    ) -> Result<()> {
+        self.connect_session(tx, message_tx).await
+    }
+
+    async fn setup_reverse_tunnel(&mut self) -> Result<u32> {
+        self.establish_tunnel().await
+    }
+
+    async fn read_server_messages(&mut self) -> Result<Vec<String>> {
+        self.probe_server_messages().await
+    }
+
+    async fn handle_forwarded_connections(
+        &mut self,
+        rx: mpsc::UnboundedReceiver<ForwardedConnection>,
+    ) -> Result<()> {
+        self.proxy_connections(rx).await
+    }
+}
@@ -0,0 +1,169 @@
+//! Picking the tunnel URL out of what the server prints
+//!
+//! Providers announce the public URL in a banner, sometimes once per scheme (pinggy
+//! prints an `http://` and an `https://` line), and the session channel can deliver
+//! it split across packets. A [`UrlDetector`] reassembles the output into lines and
+//! collects the URLs of one tunnel into a [`TunnelUrl`]. The client runs one on its
+//! session channel and emits [`TunnelEvent::TunnelUrl`](crate::TunnelEvent::TunnelUrl);
+//! applications reading server messages themselves can run their own.
+
+use crate::provider::{self, LineBuffer};
+
+/// The public URLs of a tunnel, by scheme
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelUrl {
+    pub http: Option<String>,
+    pub https: Option<String>,
+}
+
+impl TunnelUrl {
+    /// The `https://` URL if there is one, the `http://` one otherwise
+    pub fn preferred(&self) -> Option<&str> {
+        self.https.as_deref().or(self.http.as_deref())
+    }
+}
+
+/// Finds tunnel URLs in server output
+#[derive(Debug, Default)]
+pub struct UrlDetector {
+    domains: Vec<String>,
+    lines: LineBuffer,
+    url: TunnelUrl,
+}
+
+impl UrlDetector {
+    /// A detector recognising the lines providers announce their tunnel with
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A detector taking any URL on one of `domains`, matched with their
+    /// subdomains (`lhr.life` matches `8d3c1a.lhr.life`). A leading `*.` only
+    /// matches subdomains.
+    pub fn with_domains<I, S>(domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            domains: domains
+                .into_iter()
+                .map(|domain| domain.into().to_lowercase())
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Add a chunk of server output, returning the tunnel's URLs if it changed them
+    pub fn push(&mut self, data: &[u8]) -> Option<TunnelUrl> {
+        let before = self.url.clone();
+        for line in self.lines.push(data) {
+            self.detect(&line);
+        }
+        (self.url != before).then(|| self.url.clone())
+    }
+
+    /// The URLs found so far
+    pub fn url(&self) -> Option<&TunnelUrl> {
+        self.url.preferred().map(|_| &self.url)
+    }
+
+    /// Look for URLs in a complete line, returning whether the tunnel's URLs changed
+    pub(crate) fn detect(&mut self, line: &str) -> bool {
+        let before = self.url.clone();
+        let urls: Vec<String> = if self.domains.is_empty() {
+            provider::parse_line(line)
+                .map(|info| info.url)
+                .into_iter()
+                .collect()
+        } else {
+            line.split_whitespace()
+                .map(|word| word.trim_end_matches(['.', ',', ';', ')', ']', '"']))
+                .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+                .filter(|url| self.allows(url_host(url)))
+                .map(str::to_string)
+                .collect()
+        };
+        for url in urls {
+            self.record(url);
+        }
+        self.url != before
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.domains
+            .iter()
+            .any(|domain| match domain.strip_prefix("*.") {
+                Some(parent) => host.ends_with(&format!(".{}", parent)),
+                None => host == *domain || host.ends_with(&format!(".{}", domain)),
+            })
+    }
+
+    /// Take `url` as the tunnel's URL for its scheme; a new host starts a new tunnel
+    fn record(&mut self, url: String) {
+        let same_tunnel = self
+            .url
+            .preferred()
+            .is_none_or(|current| provider::host(current) == provider::host(&url));
+        if !same_tunnel {
+            self.url = TunnelUrl::default();
+        }
+        if url.starts_with("https://") {
+            self.url.https = Some(url);
+        } else {
+            self.url.http = Some(url);
+        }
+    }
+}
+
+/// The host of `url`, without scheme, port or path
+pub(crate) fn url_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = rest.find(['/', ':', '?', '#']).unwrap_or(rest.len());
+    &rest[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_split_urls() {
+        let mut detector = UrlDetector::new();
+        assert_eq!(
+            detector.push(b"Welcome! Docs at https://localhost.run/docs/\nhttp://rnaab.a.pin"),
+            None
+        );
+        assert_eq!(
+            detector.push(b"ggy.link\n"),
+            Some(TunnelUrl {
+                http: Some("http://rnaab.a.pinggy.link".into()),
+                https: None,
+            })
+        );
+        let url = detector.push(b"https://rnaab.a.pinggy.link\n").unwrap();
+        assert_eq!(url.preferred(), Some("https://rnaab.a.pinggy.link"));
+        assert_eq!(url.http.as_deref(), Some("http://rnaab.a.pinggy.link"));
+        assert_eq!(detector.push(b"https://rnaab.a.pinggy.link\n"), None);
+
+        let mut detector = UrlDetector::with_domains(["*.lhr.life", "example.com"]);
+        assert_eq!(
+            detector
+                .push(b"see https://lhr.life, http://example.com/x\n")
+                .unwrap()
+                .http
+                .as_deref(),
+            Some("http://example.com/x")
+        );
+        let url = detector.push(b"now at https://8d3c.LHR.life.\n").unwrap();
+        assert_eq!(
+            url,
+            TunnelUrl {
+                http: None,
+                https: Some("https://8d3c.LHR.life".into())
+            }
+        );
+        assert_eq!(detector.url(), Some(&url));
+    }
+}